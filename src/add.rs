@@ -1,6 +1,8 @@
-use crate::assembler::{base_index_table, register_table};
+use crate::assembler::{
+    fits_in_i8, immediate, modrm_table, operand_rule, operand_wbit, register_table, SymbolTable,
+};
 use crate::memory::Memory;
-use crate::parser::{self, imm_to_num, mem_to_num, Rule};
+use crate::parser::{imm_to_num, mem_to_num, Rule};
 use crate::{cpucontext::CpuContext, define_handler_two};
 use paste::paste;
 use pest::iterators::Pair;
@@ -77,71 +79,103 @@ imm to accumulator: 0000_010w data data if w>1
 
 const OPCODE1_SHIFT: u8 = 2;
 const DBIT_SHIFT: u8 = 1;
-const SBIT_SHIFT: u8 = 1; // signed extended: NOT USED
+// S bit of imm to register/memory is at the place of D bit
+const SBIT_SHIFT: u8 = DBIT_SHIFT;
 const WBIT_SHIFT: u8 = 0;
 const MOD_SHIFT: u8 = 6;
 const OPCODE2_SHIFT: u8 = 3;
 const REG_SHIFT: u8 = OPCODE2_SHIFT;
 const RM_SHIFT: u8 = 0;
 
-fn assemble_add(first: &Pair<Rule>, second: &Pair<Rule>) -> Vec<u8> {
+pub fn assemble_add(
+    first: &Pair<Rule>,
+    second: &Pair<Rule>,
+    symbols: &SymbolTable,
+) -> Result<Vec<u8>, String> {
     let mut v: Vec<u8> = Vec::new();
-    if first.as_str() == "ax" && second.as_rule() == Rule::imm {
-        let imm = imm_to_num(&second).unwrap();
+    if first.as_str().eq_ignore_ascii_case("ax") && operand_rule(second) == Rule::imm {
+        let imm = immediate(second, 1, symbols)?;
         let opcode = 0x04;
         let wbit = 1;
         v.push(opcode | wbit);
         v.push((imm & 0xff) as u8);
         v.push(((imm & 0xff00) >> 8) as u8);
+    } else if first.as_str().eq_ignore_ascii_case("al") && operand_rule(second) == Rule::imm {
+        let imm = immediate(second, 0, symbols)?;
+        let opcode = 0x04;
+        v.push(opcode);
+        v.push((imm & 0xff) as u8);
     } else {
-        match (first.as_rule(), second.as_rule()) {
-            (Rule::reg16, Rule::reg16) => {
+        match (first.as_rule(), operand_rule(second)) {
+            (Rule::reg16, Rule::reg16) | (Rule::reg8, Rule::reg8) => {
                 let opcode1 = 0 << OPCODE1_SHIFT;
                 let dbit = 1 << DBIT_SHIFT; // regbit=first-operand, rmbit=second-operand
-                let wbit = 1 << WBIT_SHIFT;
+                let wbit = operand_wbit(first)? << WBIT_SHIFT;
                 v.push(opcode1 | dbit | wbit);
 
                 let modbit = 3 << MOD_SHIFT; // rmbit => register
                                              // no opcode2 but reg
-                let regbit = register_table(first.as_str()).unwrap() << REG_SHIFT;
-                let rmbit = register_table(second.as_str()).unwrap() << RM_SHIFT;
+                let regbit = register_table(first.as_str())? << REG_SHIFT;
+                let rmbit = register_table(second.as_str())? << RM_SHIFT;
                 v.push(modbit | regbit | rmbit);
             }
-            (Rule::reg16, Rule::imm) => {
+            (Rule::reg16, Rule::imm)
+            | (Rule::reg8, Rule::imm)
+            | (Rule::mem16, Rule::imm)
+            | (Rule::mem8, Rule::imm)
+            | (Rule::indirect16, Rule::imm)
+            | (Rule::indirect8, Rule::imm) => {
                 let opcode1 = 0x20 << OPCODE1_SHIFT;
-                let wbit = 1 << WBIT_SHIFT;
-                v.push(opcode1 | wbit);
-
-                let modbit = 2 << MOD_SHIFT; // 2: 16-bit contents of next two bytes are imm
-                let rmbit = register_table(first.as_str()).unwrap() << RM_SHIFT;
-                v.push(modbit | rmbit);
-
-                let imm = parser::imm_to_num(&second).unwrap();
-                v.push((imm & 0xff).try_into().unwrap());
-                v.push(((imm & 0xff00) >> 8).try_into().unwrap());
+                let wbit = operand_wbit(first)? << WBIT_SHIFT;
+                let imm = immediate(second, wbit, symbols)?;
+                // sbit=1: 8-bit immediate sign-extended to 16 bits
+                let sbit = (wbit != 0 && symbols.optimize() && fits_in_i8(imm)) as u8;
+                v.push(opcode1 | sbit << SBIT_SHIFT | wbit);
+
+                // mod and r/m of the first operand, opcode2 is 000
+                v.extend(modrm_table(first, symbols)?);
+
+                v.push((imm & 0xff) as u8);
+                if wbit != 0 && sbit == 0 {
+                    v.push(((imm & 0xff00) >> 8) as u8);
+                }
             }
-            (Rule::mem16, Rule::imm) => {
-                // todo
-            }
-            (Rule::mem16, Rule::reg16) => {
-                // todo
+            (Rule::mem16, Rule::reg16)
+            | (Rule::mem8, Rule::reg8)
+            | (Rule::indirect16, Rule::reg16)
+            | (Rule::indirect8, Rule::reg8) => {
+                let opcode1 = 0 << OPCODE1_SHIFT;
+                let dbit = 0 << DBIT_SHIFT; // regbit=second-operand, rmbit=first-operand
+                let wbit = operand_wbit(first)? << WBIT_SHIFT;
+                v.push(opcode1 | dbit | wbit);
+
+                let mut modrm = modrm_table(first, symbols)?;
+                modrm[0] |= register_table(second.as_str())? << REG_SHIFT;
+                v.extend(modrm);
             }
-            (Rule::indirect16, Rule::imm) => {
-                // todo
+            (Rule::reg16, Rule::mem16)
+            | (Rule::reg8, Rule::mem8)
+            | (Rule::reg16, Rule::indirect16)
+            | (Rule::reg8, Rule::indirect8) => {
+                let opcode1 = 0 << OPCODE1_SHIFT;
+                let dbit = 1 << DBIT_SHIFT; // regbit=first-operand, rmbit=second-operand
+                let wbit = operand_wbit(first)? << WBIT_SHIFT;
+                v.push(opcode1 | dbit | wbit);
+
+                let mut modrm = modrm_table(second, symbols)?;
+                modrm[0] |= register_table(first.as_str())? << REG_SHIFT;
+                v.extend(modrm);
             }
-            (Rule::indirect16, Rule::reg16) => {
-                // todo
+            _ => {
+                return Err(format!(
+                    "Unknown format of ADD instruction, add {}, {}",
+                    first.as_str(),
+                    second.as_str()
+                ))
             }
-
-            _ => panic!(
-                "Unknown format of ADD instruction, add {}, {}",
-                first.as_str(),
-                second.as_str()
-            ),
         }
     }
-    // TODO: Other cases
-    v
+    Ok(v)
 }
 
 fn do_add16(cpu: &mut CpuContext, l: u16, r: u16) -> u16 {
@@ -153,9 +187,9 @@ fn do_add16(cpu: &mut CpuContext, l: u16, r: u16) -> u16 {
     // Setting OF cases
     // 1. the sum of two numbers with the sign bit off yields a result number with the sign bit on.
     // 2. the sum of two numbers with the sign bit on yields a result number with the sign bit off.
-    if (l & 0x8000) == 0 && (r & 0x8000) == 0 && (r_added & 0x8000) == 0x8000 {
-        cpu.set_OF();
-    } else if (l & 0x8000) == 0x8000 && (r & 0x8000) == 0x8000 && (r_added & 0x8000) == 0 {
+    if ((l & 0x8000) == 0 && (r & 0x8000) == 0 && (r_added & 0x8000) == 0x8000)
+        || ((l & 0x8000) == 0x8000 && (r & 0x8000) == 0x8000 && (r_added & 0x8000) == 0)
+    {
         cpu.set_OF();
     } else {
         cpu.reset_OF();
@@ -184,9 +218,9 @@ fn _do_add8(cpu: &mut CpuContext, l: u8, r: u8) -> u8 {
     // Setting OF cases
     // 1. the sum of two numbers with the sign bit off yields a result number with the sign bit on.
     // 2. the sum of two numbers with the sign bit on yields a result number with the sign bit off.
-    if (l & 0x80) == 0 && (r & 0x80) == 0 && (r_added & 0x80) == 0x80 {
-        cpu.set_OF();
-    } else if (l & 0x80) == 0x80 && (r & 0x80) == 0x80 && (r_added & 0x80) == 0 {
+    if ((l & 0x80) == 0 && (r & 0x80) == 0 && (r_added & 0x80) == 0x80)
+        || ((l & 0x80) == 0x80 && (r & 0x80) == 0x80 && (r_added & 0x80) == 0)
+    {
         cpu.set_OF();
     } else {
         cpu.reset_OF();
@@ -196,7 +230,7 @@ fn _do_add8(cpu: &mut CpuContext, l: u8, r: u8) -> u8 {
     // 1. the addition of two numbers causes a carry out of the most significant (leftmost) bits added.
     // 2. the subtraction of two numbers requires a borrow into the most significant (leftmost) bits subtracted.
     // 2nd case will be implemented in sub instruction handler.
-    if (r32 & 0x80) == 0 && (r32 & 0x100) == 1 {
+    if (r32 & 0x80) == 0 && (r32 & 0x100) != 0 {
         cpu.set_CF();
     } else {
         cpu.reset_CF();
@@ -206,14 +240,12 @@ fn _do_add8(cpu: &mut CpuContext, l: u8, r: u8) -> u8 {
 }
 
 define_handler_two!(add, first, second, cpu, memory, {
-    if first.as_str() == "ax" && second.as_rule() == Rule::imm {
+    if first.as_str().eq_ignore_ascii_case("ax") && second.as_rule() == Rule::imm {
         let l: u16 = cpu.get_register16(first.as_str());
-        let r = imm_to_num(&second).unwrap();
+        let r = imm_to_num(&second)?;
         let v = do_add16(cpu, l, r);
         cpu.set_register16(first.as_str(), v);
-        let code = assemble_add(&first, &second);
-        println!("Code for add ax, {} = {:?}", r, code);
-    } else if first.as_str() == "al" && second.as_rule() == Rule::imm {
+    } else if first.as_str().eq_ignore_ascii_case("al") && second.as_rule() == Rule::imm {
         // TODO
     } else {
         match (first.as_rule(), second.as_rule()) {
@@ -232,16 +264,16 @@ define_handler_two!(add, first, second, cpu, memory, {
             }
             (Rule::reg16, Rule::imm) => {
                 let l = cpu.get_register16(first.as_str());
-                let r = imm_to_num(&second).unwrap();
+                let r = imm_to_num(&second)?;
                 let v = do_add16(cpu, l, r);
-                let _ = cpu.set_register16(first.as_str(), v);
+                cpu.set_register16(first.as_str(), v);
             }
             (Rule::reg16, Rule::mem16) => {
-                let address = mem_to_num(&second).unwrap();
-                let l = memory.read16(address);
+                let address = mem_to_num(&second)?;
+                let l = memory.read16(cpu.get_register16("ds"), address);
                 let r = cpu.get_register16(first.as_str());
                 let v = do_add16(cpu, l, r);
-                let _ = cpu.set_register16(first.as_str(), v);
+                cpu.set_register16(first.as_str(), v);
             }
             (Rule::reg8, Rule::reg8) => {
                 // TODO
@@ -253,19 +285,19 @@ define_handler_two!(add, first, second, cpu, memory, {
                 // Todo
             }
             (Rule::mem16, Rule::reg16) => {
-                let address = mem_to_num(&first).unwrap();
-                let l = memory.read16(address);
+                let address = mem_to_num(&first)?;
+                let l = memory.read16(cpu.get_register16("ds"), address);
                 let r = cpu.get_register16(second.as_str());
                 let v = do_add16(cpu, l, r);
-                memory.write16(address, v);
+                memory.write16(cpu.get_register16("ds"), address, v);
             }
             (Rule::mem16, Rule::imm) => {
                 // Todo
-                let address = mem_to_num(&first).unwrap();
-                let l = memory.read16(address);
-                let r = imm_to_num(&second).unwrap();
+                let address = mem_to_num(&first)?;
+                let l = memory.read16(cpu.get_register16("ds"), address);
+                let r = imm_to_num(&second)?;
                 let v = do_add16(cpu, l, r);
-                memory.write16(address, v);
+                memory.write16(cpu.get_register16("ds"), address, v);
             }
             (Rule::mem8, Rule::reg8) => {
                 // Todo
@@ -273,14 +305,20 @@ define_handler_two!(add, first, second, cpu, memory, {
             (Rule::mem8, Rule::imm) => {
                 // Todo
             }
-            _ => println!("Not supported yet:{:?} {:?}", first, second),
+            _ => {
+                return Err(format!(
+                    "add {}, {} is not supported yet",
+                    first.as_str(),
+                    second.as_str()
+                ))
+            }
         }
     }
 });
 
 #[cfg(test)]
 mod tests {
-    use crate::parser::{self, AssemblyParser};
+    use crate::parser::AssemblyParser;
 
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
@@ -319,6 +357,13 @@ mod tests {
         do_add16(&mut cpu, 1, 1);
         assert_eq!(0, cpu.get_OF());
         assert_eq!(0, cpu.get_CF());
+
+        // 0x80 + 0x80 = 0x100 => 0x0 as u8 with a carry out of bit 7
+        assert_eq!(0, _do_add8(&mut cpu, 0x80, 0x80));
+        assert_ne!(0, cpu.get_CF());
+
+        _do_add8(&mut cpu, 1, 1);
+        assert_eq!(0, cpu.get_CF());
     }
 
     #[test]
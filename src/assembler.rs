@@ -1,5 +1,262 @@
+use crate::error::AsmError;
+use crate::linker::{self, Segment};
+use crate::parser::{self, AssemblyParser, IncludeFile, Rule, SourceLine};
+use crate::{add, data, expr, inc, jmp, mov, stack};
+use pest::error::LineColLocation;
+use pest::iterators::Pair;
+use pest::Parser;
+use std::collections::{HashMap, HashSet};
+
+/*
+Two-pass assembler
+
+The macros are expanded by the preprocessor before the 1st pass.
+
+1st pass: translate each line into machine code and save the address of each label and data.
+          Undefined symbols are 0 because only the size of the code is needed.
+2nd pass: translate every line again with the complete symbol table.
+          The size of each line should be same to the 1st pass.
+
+The location counter starts at 0 and org directive changes it.
+Each segment has its own location counter and the linker places the segments
+after the 1st pass (see linker.rs). Then the labels are relative to the frame of their segment.
+*/
+
+/// Frames are moved by this value to find the relocations in the 2nd pass.
+/// Both bytes change so the first changed byte is the start of the word.
+const RELOCATION_PROBE: u16 = 0x1001;
+// The sizes of the code do not change after the passes: e.g. times with a label
+const MAX_SIZING_PASSES: usize = 100;
+
+/// name equ expr: the expression is evaluated when the name is used
+/// So it can refer the symbols defined later.
+#[derive(Debug, Clone)]
+pub struct Constant<'i> {
+    pub value: Pair<'i, Rule>,
+    // Address of the equ line: $ in the expression
+    pub location: u16,
+    // Global label before the equ line: .name in the expression
+    pub scope: String,
+}
+
+/// Symbol lookup for the instruction encoders
+#[derive(Default, Clone)]
+pub struct SymbolTable<'a> {
+    // label or data name => address
+    symbols: Option<&'a HashMap<String, u16>>,
+    // name equ expr
+    constants: Option<&'a HashMap<String, Constant<'a>>>,
+    // name = expr: the last value in the source order
+    variables: Option<&'a HashMap<String, i64>>,
+    // label, segment or group name => paragraph of the segment or group
+    frames: Option<&'a HashMap<String, u16>>,
+    // 1st pass: undefined symbols are 0 because they can be defined later.
+    lenient: bool,
+    // Address of the current line: $ in the expression
+    location: u16,
+    // Global label before the current line: .name is scope.name
+    scope: &'a str,
+    // Numeric label => the number of its definitions before the current line
+    numeric: Option<&'a HashMap<String, usize>>,
+    // Constants being evaluated to find circular definitions
+    resolving: Vec<&'a str>,
+    // Shortest encodings: e.g. jmp rel8, add r/m16 with imm8, mod=01 disp8
+    optimize: bool,
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn new(symbols: &'a HashMap<String, u16>, lenient: bool) -> Self {
+        SymbolTable {
+            symbols: Some(symbols),
+            lenient,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_constants(
+        self,
+        constants: &'a HashMap<String, Constant<'a>>,
+        variables: &'a HashMap<String, i64>,
+    ) -> Self {
+        SymbolTable {
+            constants: Some(constants),
+            variables: Some(variables),
+            ..self
+        }
+    }
+
+    pub fn with_frames(self, frames: &'a HashMap<String, u16>) -> Self {
+        SymbolTable {
+            frames: Some(frames),
+            ..self
+        }
+    }
+
+    pub fn at(self, location: u16) -> Self {
+        SymbolTable { location, ..self }
+    }
+
+    /// Scope of the local labels and the numeric labels defined so far
+    pub fn in_scope(self, scope: &'a str, numeric: &'a HashMap<String, usize>) -> Self {
+        SymbolTable {
+            scope,
+            numeric: Some(numeric),
+            ..self
+        }
+    }
+
+    /// Encoders pick the shortest form when the value fits in it.
+    pub fn optimized(self, optimize: bool) -> Self {
+        SymbolTable { optimize, ..self }
+    }
+
+    /// Name in the symbol table
+    /// e.g. .again => main.again, 1b => 1:0 (1st definition of 1:), 1f => 1:1
+    pub fn resolve(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            return format!("{}{}", self.scope, name);
+        }
+        let (digits, direction) = name.split_at(name.len().saturating_sub(1));
+        if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+            let count = self
+                .numeric
+                .and_then(|n| n.get(digits))
+                .copied()
+                .unwrap_or(0);
+            match direction {
+                "b" | "B" if count > 0 => return numeric_label(digits, count - 1),
+                "f" | "F" => return numeric_label(digits, count),
+                _ => {}
+            }
+        }
+        name.to_owned()
+    }
+
+    /// Address of label or data
+    pub fn get(&self, name: &str) -> Result<u16, String> {
+        match self.symbols.and_then(|s| s.get(&self.resolve(name))) {
+            Some(v) => Ok(*v),
+            None if self.lenient => Ok(0),
+            None => Err(format!("Undefined symbol {}", name)),
+        }
+    }
+
+    /// The label or data name is defined.
+    pub fn contains(&self, name: &str) -> bool {
+        self.symbols
+            .is_some_and(|s| s.contains_key(&self.resolve(name)))
+    }
+
+    /// Paragraph of the segment or group: seg name
+    pub fn frame(&self, name: &str) -> Result<u16, String> {
+        match self.frames.and_then(|f| f.get(&self.resolve(name))) {
+            Some(v) => Ok(*v),
+            None if self.lenient => Ok(0),
+            None => Err(format!("{} has no segment", name)),
+        }
+    }
+
+    /// Value of the name in the expression: = variable, equ constant, address or segment
+    pub fn value(&self, name: &str) -> Result<i64, String> {
+        if let Some(v) = self.variables.and_then(|v| v.get(name)) {
+            return Ok(*v);
+        }
+        if let Some((name, constant)) = self.constants.and_then(|c| c.get_key_value(name)) {
+            if self.resolving.contains(&name.as_str()) {
+                return Err(format!("Circular definition of {}", name));
+            }
+            let mut table = self.clone().at(constant.location);
+            table.scope = &constant.scope;
+            table.resolving.push(name);
+            return expr::evaluate(&constant.value, &table);
+        }
+        let is_symbol = self.contains(name);
+        // Segment or group name is its paragraph: e.g. mov ax, data
+        if let Some(frame) = self.frames.and_then(|f| f.get(name)).filter(|_| !is_symbol) {
+            return Ok(*frame as i64);
+        }
+        Ok(self.get(name)? as i64)
+    }
+
+    pub fn lenient(&self) -> bool {
+        self.lenient
+    }
+
+    pub fn location(&self) -> u16 {
+        self.location
+    }
+
+    pub fn optimize(&self) -> bool {
+        self.optimize
+    }
+}
+
+/// The 16-bit value is same to the sign-extended 8-bit value: e.g. 0xfff0 => 0xf0
+pub fn fits_in_i8(value: u16) -> bool {
+    (value as i16) >= -128 && (value as i16) <= 127
+}
+
+/// Name of the nth definition of the numeric label: e.g. 1:0
+pub fn numeric_label(digits: &str, nth: usize) -> String {
+    format!("{}:{}", digits, nth)
+}
+
+/// Rule of the operand for matching the instruction forms
+/// An expression is a constant value. So it is same to imm.
+pub fn operand_rule(operand: &Pair<Rule>) -> Rule {
+    match operand.as_rule() {
+        Rule::expr => Rule::imm,
+        r => r,
+    }
+}
+
+/// Value of imm or expr operand in size bytes
+/// Negative values are stored as two's complement: e.g. -1 in 1 byte => 0xff
+/// The range is not checked in the 1st pass because undefined symbols are 0.
+pub fn sized_value(
+    operand: &Pair<Rule>,
+    size: usize,
+    symbols: &SymbolTable,
+) -> Result<u32, String> {
+    let value = expr::evaluate(operand, symbols)?;
+    let bits = size as u32 * 8;
+    let min = -(1i64 << (bits - 1));
+    let max = (1i64 << bits) - 1;
+    if !symbols.lenient() && (value < min || value > max) {
+        return Err(format!(
+            "{} = {} does not fit in {} bits",
+            operand.as_str(),
+            value,
+            bits
+        ));
+    }
+    Ok((value & max) as u32)
+}
+
+/// 16-bit value of imm or expr operand
+/// e.g. 1234h => 0x1234
+/// e.g. offset msg + 2 => address of msg + 2
+pub fn operand_value(operand: &Pair<Rule>, symbols: &SymbolTable) -> Result<u16, String> {
+    Ok(sized_value(operand, 2, symbols)? as u16)
+}
+
+/// Immediate value for the operand size: 0-8bit, 1-16bit
+pub fn immediate(operand: &Pair<Rule>, wbit: u8, symbols: &SymbolTable) -> Result<u16, String> {
+    Ok(sized_value(operand, wbit as usize + 1, symbols)? as u16)
+}
+
+/// Address of direct addressing operand
+/// e.g. word ptr [1234h] => 0x1234
+/// e.g. [msg + 2] => address of msg + 2
+pub fn memory_address(operand: &Pair<Rule>, symbols: &SymbolTable) -> Result<u16, String> {
+    match operand.clone().into_inner().next() {
+        Some(value) => operand_value(&value, symbols),
+        None => Err(format!("{} has no address", operand.as_str())),
+    }
+}
+
 pub fn register_table(reg: &str) -> Result<u8, String> {
-    match reg {
+    match reg.to_ascii_lowercase().as_str() {
         "ax" | "al" => Ok(0),
         "cx" | "cl" => Ok(1),
         "dx" | "dl" => Ok(2),
@@ -13,11 +270,13 @@ pub fn register_table(reg: &str) -> Result<u8, String> {
 }
 
 pub fn base_index_table(base: Option<&str>, index: Option<&str>) -> Result<u8, String> {
-    match (base, index) {
+    let base = base.map(|r| r.to_ascii_lowercase());
+    let index = index.map(|r| r.to_ascii_lowercase());
+    match (base.as_deref(), index.as_deref()) {
         (Some("bx"), Some("si")) => Ok(0),
         (Some("bx"), Some("di")) => Ok(1),
         (Some("bp"), Some("si")) => Ok(2),
-        (Some("bp"), Some("fi")) => Ok(3),
+        (Some("bp"), Some("di")) => Ok(3),
         (None, /* */ Some("si")) => Ok(4),
         (None, /* */ Some("di")) => Ok(5),
         (Some("bp"), None) => Ok(6),
@@ -28,3 +287,1444 @@ pub fn base_index_table(base: Option<&str>, index: Option<&str>) -> Result<u8, S
         )),
     }
 }
+
+pub fn segment_register_table(reg: &str) -> Result<u8, String> {
+    match reg.to_ascii_lowercase().as_str() {
+        "es" => Ok(0),
+        "cs" => Ok(1),
+        "ss" => Ok(2),
+        "ds" => Ok(3),
+        _ => Err(format!("{} is not in the segment register table", reg)),
+    }
+}
+
+/// W-bit of the operand: 0-8bit, 1-16bit
+pub fn operand_wbit(operand: &Pair<Rule>) -> Result<u8, String> {
+    match operand.as_rule() {
+        Rule::reg8 | Rule::mem8 | Rule::indirect8 => Ok(0),
+        Rule::reg16 | Rule::mem16 | Rule::indirect16 => Ok(1),
+        _ => Err(format!("{} has no size", operand.as_str())),
+    }
+}
+
+/// Make ModR/M byte and displacement of r/m operand
+/// The reg field is filled by the caller.
+/// e.g. cx => [0xc1]
+/// e.g. word ptr [1234h] => [0x06, 0x34, 0x12]
+/// e.g. [bx + si + 1234h] => [0x80, 0x34, 0x12]
+pub fn modrm_table(operand: &Pair<Rule>, symbols: &SymbolTable) -> Result<Vec<u8>, String> {
+    let mut v: Vec<u8> = Vec::new();
+    match operand.as_rule() {
+        Rule::reg8 | Rule::reg16 => {
+            // mod=11: register operand
+            v.push(0xc0 | register_table(operand.as_str())?);
+        }
+        Rule::mem8 | Rule::mem16 => {
+            // direct addressing: mod=00, rm=110
+            v.push(0x06);
+            let address = memory_address(operand, symbols)?;
+            v.push((address & 0xff) as u8);
+            v.push(((address & 0xff00) >> 8) as u8);
+        }
+        Rule::indirect8 | Rule::indirect16 => {
+            let mut basereg = None;
+            let mut indexreg = None;
+            let mut disp = None;
+            for p in operand.clone().into_inner() {
+                match p.as_rule() {
+                    Rule::base => basereg = Some(p.as_str()),
+                    Rule::index => indexreg = Some(p.as_str()),
+                    _ => disp = Some(operand_value(&p, symbols)?),
+                }
+            }
+            let rmbit = base_index_table(basereg, indexreg)?;
+            match disp {
+                // [bp] has no mod=00 form because it is the direct addressing.
+                None if rmbit != 6 => v.push(rmbit),
+                // mod=01: 8-bit displacement sign-extended by the CPU
+                d if symbols.optimize() && fits_in_i8(d.unwrap_or(0)) => {
+                    v.push(0x40 | rmbit);
+                    v.push(d.unwrap_or(0) as u8);
+                }
+                _ => {
+                    // mod=10: 16-bit displacement
+                    let disp = disp.unwrap_or(0);
+                    v.push(0x80 | rmbit);
+                    v.push((disp & 0xff) as u8);
+                    v.push(((disp & 0xff00) >> 8) as u8);
+                }
+            }
+        }
+        _ => return Err(format!("{} is not a register or memory", operand.as_str())),
+    }
+    Ok(v)
+}
+
+/// Label and the source line defining it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    // Name in the symbol table: e.g. main.again for .again, 1:0 for the 1st 1:
+    pub name: String,
+    pub linenum: usize,
+    pub address: u16,
+    // Index of the segment: None if the program has no segments
+    pub segment: Option<usize>,
+}
+
+/// Result of assembling one source line
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembledLine {
+    pub linenum: usize,
+    pub address: u16,
+    pub code: Vec<u8>,
+    // Line made by the macro expansion or include: the source line is the macro call or include
+    pub expansion: Option<String>,
+    // Include file name and line number
+    pub location: Option<(String, usize)>,
+    // Index of the segment: None if the program has no segments
+    pub segment: Option<usize>,
+    // Offsets of the words in the code that need the load segment
+    pub relocations: Vec<usize>,
+}
+
+/// Options of the assembler
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    // Shortest encodings: off for the same bytes as the other assemblers
+    pub optimize: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { optimize: true }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Assembly {
+    // Address of the first byte: set by the first org directive
+    pub origin: u16,
+    // Only the lines generating machine code
+    pub lines: Vec<AssembledLine>,
+    // label or data name => address
+    pub symbols: HashMap<String, u16>,
+    // equ and = name => value
+    pub constants: HashMap<String, i64>,
+    // Segments placed by the linker: empty if the program has no segment directives
+    pub segments: Vec<Segment>,
+    // Frame and offset of the end label
+    pub entry: Option<(u16, u16)>,
+    // Labels in the source order
+    pub labels: Vec<Label>,
+    // Diagnostics not stopping the build
+    pub warnings: Vec<AsmError>,
+}
+
+impl Assembly {
+    /// Flat binary image starting at the origin
+    /// Gaps between the lines are filled with 0.
+    pub fn binary(&self) -> Result<Vec<u8>, String> {
+        let mut image: Vec<u8> = Vec::new();
+        for line in self.lines.iter() {
+            let start = match line.segment {
+                // Segments are placed from the start of the image.
+                Some(index) => self.segments[index].frame as usize * 16 + line.address as usize,
+                None if line.address < self.origin => {
+                    return Err(format!(
+                        "Line {} at {:04X} is below the origin {:04X}",
+                        line.linenum + 1,
+                        line.address,
+                        self.origin
+                    ));
+                }
+                None => (line.address - self.origin) as usize,
+            };
+            let end = start + line.code.len();
+            if image.len() < end {
+                image.resize(end, 0);
+            }
+            image[start..end].copy_from_slice(&line.code);
+        }
+        Ok(image)
+    }
+
+    /// .COM image is a flat binary loaded at 100h
+    pub fn com(&self) -> Result<Vec<u8>, String> {
+        if self.origin != 0x100 {
+            return Err(format!(
+                "COM program should start at 100h but the origin is {:04X}: add \"org 100h\"",
+                self.origin
+            ));
+        }
+        if self.segments.is_empty() {
+            return self.binary();
+        }
+        // Tiny model: one segment or group with org 100h
+        if !self.relocations().is_empty() {
+            return Err("COM program cannot have segment relocations".to_string());
+        }
+        if self.segments.iter().any(|s| s.frame != 0) {
+            return Err("COM program should have one segment or group".to_string());
+        }
+        if let Some(line) = self.lines.iter().find(|l| l.address < self.origin) {
+            return Err(format!(
+                "Line {} at {:04X} is below the origin {:04X}",
+                line.linenum + 1,
+                line.address,
+                self.origin
+            ));
+        }
+        Ok(self.binary()?.split_off(self.origin as usize))
+    }
+}
+
+/// Translate one instruction into machine code
+/// address is the address of the instruction, which is used for relative jumps.
+fn assemble_instruction(
+    instruction: &Pair<Rule>,
+    address: u16,
+    symbols: &SymbolTable,
+) -> Result<Vec<u8>, String> {
+    let mut inner = instruction.clone().into_inner();
+    match instruction.as_rule() {
+        Rule::mov => {
+            let first = inner.next().unwrap();
+            let second = inner.next().unwrap();
+            mov::assemble_mov(&first, &second, symbols)
+        }
+        Rule::add => {
+            let first = inner.next().unwrap();
+            let second = inner.next().unwrap();
+            add::assemble_add(&first, &second, symbols)
+        }
+        Rule::inc => inc::assemble_inc(&inner.next().unwrap(), symbols),
+        Rule::jmp => jmp::assemble_jmp(&inner.next().unwrap(), address, symbols),
+        Rule::jmp_far => jmp::assemble_jmp_far(&inner.next().unwrap(), symbols),
+        Rule::hlt => Ok(vec![0xf4]),
+        Rule::call => stack::assemble_call(&inner.next().unwrap(), address, symbols),
+        Rule::ret => stack::assemble_ret(inner.next()),
+        Rule::push => stack::assemble_push(&inner.next().unwrap()),
+        Rule::pop => stack::assemble_pop(&inner.next().unwrap()),
+        Rule::data => {
+            // The name of data is not a part of the data
+            let directive = inner.find(|p| p.as_rule() != Rule::name).unwrap();
+            data::assemble_data(&directive, symbols)
+        }
+        _ => Err(format!(
+            "Not supported instruction: {}",
+            instruction.as_str()
+        )),
+    }
+}
+
+/// Name defined by the line: label or named data
+fn defined_name<'a>(instruction: &Pair<'a, Rule>) -> Option<&'a str> {
+    match instruction.as_rule() {
+        Rule::label | Rule::data => {
+            let first = instruction.clone().into_inner().next()?;
+            matches!(first.as_rule(), Rule::name | Rule::numeric_name).then(|| first.as_str())
+        }
+        _ => None,
+    }
+}
+
+/// Symbols defined by the program
+#[derive(Default)]
+struct Definitions<'i> {
+    // label or data name => address
+    symbols: HashMap<String, u16>,
+    // name equ expr
+    constants: HashMap<String, Constant<'i>>,
+    // name = expr: value at the current line
+    variables: HashMap<String, i64>,
+    // Segments in the order of the first appearance
+    segments: Vec<Segment>,
+    // group name => segment names
+    groups: HashMap<String, Vec<String>>,
+    // label or data name => index of its segment
+    symbol_segments: HashMap<String, usize>,
+    // label, segment or group name => paragraph: set by the linker after the 1st pass
+    frames: HashMap<String, u16>,
+    // frames moved by RELOCATION_PROBE to find the relocations
+    moved_frames: HashMap<String, u16>,
+    // Label of the end directive
+    entry: Option<String>,
+    // Global label before the current line: the scope of the local labels
+    scope: String,
+    // Numeric label => the number of its definitions before the current line
+    numeric: HashMap<String, usize>,
+    // Labels with their lines: made by the 2nd pass
+    labels: Vec<Label>,
+    // Shortest encodings of the instructions
+    optimize: bool,
+    // Instruction index => size of its code in the last sizing pass
+    sizes: HashMap<usize, usize>,
+    // Instructions with the longest encoding: their size grew again or depends on the relocation
+    long: HashSet<usize>,
+    // Addresses found by the sizing pass: the symbols of the next pass
+    resized: HashMap<String, u16>,
+    // A size is changed by the sizing pass.
+    changed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pass {
+    // Defines the symbols: undefined symbols are 0.
+    Define,
+    // Sizes the code with the symbols of the previous pass: the jumps are relaxed.
+    Size,
+    // Makes the code with the final symbols
+    Final,
+}
+
+impl<'i> Definitions<'i> {
+    fn is_defined(&self, name: &str) -> bool {
+        self.symbols.contains_key(name)
+            || self.constants.contains_key(name)
+            || self.variables.contains_key(name)
+    }
+
+    fn table(&self, lenient: bool, location: u16) -> SymbolTable<'_> {
+        SymbolTable::new(&self.symbols, lenient)
+            .with_constants(&self.constants, &self.variables)
+            .with_frames(&self.frames)
+            .at(location)
+            .in_scope(&self.scope, &self.numeric)
+    }
+
+    /// The instruction has the shortest encoding.
+    fn optimized(&self, index: usize) -> bool {
+        self.optimize && !self.long.contains(&index)
+    }
+
+    /// Size of the code in the sizing pass
+    /// A code growing again after the 1st sizing pass gets the longest encoding.
+    /// So each size changes a few times at most and the sizing passes end.
+    fn resize(&mut self, index: usize, size: usize) {
+        match self.sizes.insert(index, size) {
+            Some(previous) if previous == size => {}
+            Some(previous) => {
+                if size > previous {
+                    self.long.insert(index);
+                }
+                self.changed = true;
+            }
+            None => self.changed = true,
+        }
+    }
+
+    fn segment_index(&self, name: &str) -> Option<usize> {
+        self.segments.iter().position(|s| s.name == name)
+    }
+
+    /// Place the segments after the 1st pass
+    /// The labels become relative to the frame of their segment.
+    fn link(&mut self) -> Result<(), String> {
+        linker::layout(&mut self.segments, &self.groups)?;
+        for (name, index) in self.symbol_segments.iter() {
+            let segment = &self.segments[*index];
+            if let Some(address) = self.symbols.get_mut(name) {
+                *address = address.wrapping_add(segment.displacement());
+            }
+            self.frames.insert(name.clone(), segment.frame);
+        }
+        for segment in self.segments.iter() {
+            self.frames.insert(segment.name.clone(), segment.frame);
+            if let Some(group) = &segment.group {
+                self.frames.insert(group.clone(), segment.frame);
+            }
+        }
+        self.moved_frames = self
+            .frames
+            .iter()
+            .map(|(name, frame)| (name.clone(), frame.wrapping_add(RELOCATION_PROBE)))
+            .collect();
+        Ok(())
+    }
+
+    /// Code made with the frames moved by RELOCATION_PROBE
+    fn moved_code(
+        &self,
+        instruction: &Pair<Rule>,
+        location: u16,
+        lenient: bool,
+        optimize: bool,
+    ) -> Result<Vec<u8>, String> {
+        let table = SymbolTable::new(&self.symbols, lenient)
+            .with_constants(&self.constants, &self.variables)
+            .with_frames(&self.moved_frames)
+            .at(location)
+            .in_scope(&self.scope, &self.numeric)
+            .optimized(optimize);
+        assemble_instruction(instruction, location, &table)
+    }
+
+    /// Offsets of the relocations in the code
+    /// The code is made again with the moved frames
+    /// and a relocation is the word changed by RELOCATION_PROBE.
+    /// e.g. mov ax, seg msg => [1]
+    fn relocations(
+        &self,
+        instruction: &Pair<Rule>,
+        location: u16,
+        code: &[u8],
+        optimize: bool,
+    ) -> Result<Vec<usize>, String> {
+        if self.segments.is_empty() {
+            return Ok(Vec::new());
+        }
+        let moved = self.moved_code(instruction, location, false, optimize)?;
+        if moved.len() != code.len() {
+            return Err("Segment value is not relocatable".to_string());
+        }
+        let word = |c: &[u8], i: usize| c.get(i..i + 2).map(|w| u16::from_le_bytes([w[0], w[1]]));
+        let mut v: Vec<usize> = Vec::new();
+        let mut i = 0;
+        while i < code.len() {
+            if code[i] == moved[i] {
+                i += 1;
+                continue;
+            }
+            match (word(code, i), word(&moved, i)) {
+                (Some(a), Some(b)) if b.wrapping_sub(a) == RELOCATION_PROBE => {
+                    v.push(i);
+                    i += 2;
+                }
+                _ => return Err("Segment value is not relocatable".to_string()),
+            }
+        }
+        Ok(v)
+    }
+}
+
+/// Name, alignment and stack combine type of segment or section directive
+fn segment_attributes<'a>(instruction: &Pair<'a, Rule>) -> (&'a str, usize, bool) {
+    let mut name = "";
+    let mut align = linker::segment_align("para");
+    let mut stack = false;
+    for p in instruction.clone().into_inner() {
+        match p.as_rule() {
+            Rule::name | Rule::section_name => name = p.as_str(),
+            Rule::segment_align => align = linker::segment_align(&p.as_str().to_ascii_lowercase()),
+            Rule::segment_combine => stack = p.as_str().eq_ignore_ascii_case("stack"),
+            _ => {}
+        }
+    }
+    (name, align, stack)
+}
+
+/// One pass over the parsed lines
+/// The 1st pass defines the symbols, the sizing passes move them and the last pass uses them.
+fn assemble_pass<'i>(
+    instructions: &[(&SourceLine, Pair<'i, Rule>)],
+    definitions: &mut Definitions<'i>,
+    pass: Pass,
+    errors: &mut Vec<AsmError>,
+) -> (Vec<AssembledLine>, Option<u16>) {
+    let mut lines: Vec<AssembledLine> = Vec::new();
+    let mut location: u16 = 0;
+    let mut origin: Option<u16> = None;
+    // Current segment and the location counter of each segment
+    let mut segment: Option<usize> = None;
+    let mut opened_by_section = false;
+    // Line of the segment directive: the error of a segment not closed is reported there
+    let mut opened_at: Option<&SourceLine> = None;
+    let mut locations: Vec<u16> = definitions
+        .segments
+        .iter()
+        .map(|s| s.displacement())
+        .collect();
+    // Location of each segment start: the sizes and symbols are relative to it
+    let mut starts = locations.clone();
+    let lenient = pass != Pass::Final;
+    // = variables should be defined before they are used in each pass.
+    definitions.variables.clear();
+    definitions.scope.clear();
+    definitions.numeric.clear();
+    definitions.labels.clear();
+    definitions.changed = false;
+
+    for (i, (source, instruction)) in instructions.iter().enumerate() {
+        let linenum = source.linenum;
+        let column = instruction.as_span().start_pos().line_col().1;
+        // The error is reported and the assembler continues with the next line.
+        macro_rules! fail {
+            ($e:expr) => {{
+                push_error(errors, source.error_at(column, &$e));
+                continue;
+            }};
+        }
+        macro_rules! try_line {
+            ($r:expr) => {
+                match $r {
+                    Ok(v) => v,
+                    Err(e) => fail!(e),
+                }
+            };
+        }
+        if let Some(name) = defined_name(instruction) {
+            // Name in the symbol table: e.g. main.again, 1:0
+            let key = if name.starts_with(|c: char| c.is_ascii_digit()) {
+                let count = definitions.numeric.entry(name.to_owned()).or_default();
+                *count += 1;
+                numeric_label(name, *count - 1)
+            } else if name.starts_with('.') {
+                format!("{}{}", definitions.scope, name)
+            } else {
+                definitions.scope = name.to_owned();
+                name.to_owned()
+            };
+            match pass {
+                Pass::Define => {
+                    if definitions.is_defined(&key) {
+                        fail!(format!("label {} is already defined", name));
+                    }
+                    definitions.symbols.insert(key.clone(), location);
+                    if let Some(index) = segment {
+                        definitions.symbol_segments.insert(key.clone(), index);
+                    }
+                }
+                Pass::Size => {
+                    let start = segment.map_or(0, |i| starts[i]);
+                    definitions
+                        .resized
+                        .insert(key, location.wrapping_sub(start));
+                }
+                Pass::Final if instruction.as_rule() == Rule::label => {
+                    definitions.labels.push(Label {
+                        name: key,
+                        linenum,
+                        address: location,
+                        segment,
+                    });
+                }
+                Pass::Final => {}
+            }
+        }
+
+        match instruction.as_rule() {
+            Rule::label => {}
+            Rule::org => {
+                let imm = instruction.clone().into_inner().next().unwrap();
+                // org in a segment is relative to the segment start
+                let start = segment.map_or(0, |i| definitions.segments[i].displacement());
+                location = start.wrapping_add(try_line!(parser::imm_to_num(&imm)));
+                if origin.is_none() {
+                    origin = Some(location);
+                }
+            }
+            Rule::segment | Rule::section => {
+                let (name, align, stack) = segment_attributes(instruction);
+                if let Some(current) = segment {
+                    if !opened_by_section || instruction.as_rule() == Rule::segment {
+                        let current = &definitions.segments[current].name;
+                        fail!(format!("Segment {} is not closed", current));
+                    }
+                    locations[current] = location;
+                }
+                // The segment with the same name continues.
+                let index = match definitions.segment_index(name) {
+                    Some(index) => index,
+                    None => {
+                        definitions.segments.push(Segment::new(name, align, stack));
+                        locations.push(0);
+                        starts.push(0);
+                        definitions.segments.len() - 1
+                    }
+                };
+                location = locations[index];
+                segment = Some(index);
+                opened_at = Some(source);
+                opened_by_section = instruction.as_rule() == Rule::section;
+            }
+            Rule::ends => {
+                let name = instruction.clone().into_inner().next().unwrap().as_str();
+                match segment {
+                    Some(index)
+                        if !opened_by_section && definitions.segments[index].name == name =>
+                    {
+                        locations[index] = location;
+                        segment = None;
+                    }
+                    _ => fail!(format!("{} ends without segment", name)),
+                }
+            }
+            Rule::group => {
+                let mut inner = instruction.clone().into_inner();
+                let name = inner.next().unwrap().as_str();
+                if pass == Pass::Define {
+                    let members = inner.map(|p| p.as_str().to_owned());
+                    definitions
+                        .groups
+                        .entry(name.to_owned())
+                        .or_default()
+                        .extend(members);
+                }
+            }
+            Rule::assume => {
+                // Only checked: the segment registers are set by the program.
+                for item in instruction.clone().into_inner() {
+                    let mut inner = item.into_inner();
+                    let reg = inner.next().unwrap().as_str();
+                    let name = inner.next().unwrap().as_str();
+                    try_line!(segment_register_table(reg));
+                    let known = name.eq_ignore_ascii_case("nothing")
+                        || definitions.segment_index(name).is_some()
+                        || definitions.groups.contains_key(name);
+                    if pass == Pass::Final && !known {
+                        fail!(format!("{} is not a segment or group", name));
+                    }
+                }
+            }
+            Rule::end => {
+                if let Some(name) = instruction.clone().into_inner().next() {
+                    let table = definitions.table(lenient, location);
+                    definitions.entry = Some(table.resolve(name.as_str()));
+                    if pass == Pass::Final {
+                        try_line!(definitions.table(false, location).get(name.as_str()));
+                    }
+                }
+                // The lines after end are ignored.
+                if let Some((next, _)) = instructions.get(i + 1).filter(|_| pass == Pass::Final) {
+                    push_error(errors, next.error("Lines after end are ignored").warning());
+                }
+                break;
+            }
+            Rule::equ => {
+                let mut inner = instruction.clone().into_inner();
+                let name = inner.next().unwrap().as_str();
+                let value = inner.next().unwrap();
+                match pass {
+                    Pass::Define => {
+                        if definitions.is_defined(name) {
+                            fail!(format!("{} is already defined", name));
+                        }
+                        let scope = definitions.scope.clone();
+                        definitions.constants.insert(
+                            name.to_owned(),
+                            Constant {
+                                value,
+                                location,
+                                scope,
+                            },
+                        );
+                    }
+                    // $ of the constant moves with the code before it.
+                    Pass::Size => {
+                        if let Some(constant) = definitions.constants.get_mut(name) {
+                            constant.location = location;
+                        }
+                    }
+                    // Undefined symbol or circular definition
+                    Pass::Final => {
+                        try_line!(definitions.table(false, location).value(name));
+                    }
+                }
+            }
+            Rule::assign => {
+                let mut inner = instruction.clone().into_inner();
+                let name = inner.next().unwrap().as_str();
+                let value = inner.next().unwrap();
+                if definitions.symbols.contains_key(name)
+                    || definitions.constants.contains_key(name)
+                {
+                    fail!(format!("{} cannot be redefined", name));
+                }
+                let v = try_line!(expr::evaluate(
+                    &value,
+                    &definitions.table(lenient, location)
+                ));
+                definitions.variables.insert(name.to_owned(), v);
+            }
+            _ => {
+                if segment.is_none() && !definitions.segments.is_empty() {
+                    fail!("Code outside of a segment");
+                }
+                let optimize = definitions.optimized(i);
+                let table = definitions.table(lenient, location).optimized(optimize);
+                let code = try_line!(assemble_instruction(instruction, location, &table));
+                let relocations = match pass {
+                    Pass::Final => {
+                        try_line!(definitions.relocations(instruction, location, &code, optimize))
+                    }
+                    _ => Vec::new(),
+                };
+                if pass == Pass::Size {
+                    // A segment value is not shortened: it is changed by the loader.
+                    if optimize && !definitions.segments.is_empty() {
+                        let moved = definitions.moved_code(instruction, location, true, true);
+                        if moved.is_ok_and(|moved| moved.len() != code.len()) {
+                            definitions.long.insert(i);
+                        }
+                    }
+                    definitions.resize(i, code.len());
+                }
+                if origin.is_none() {
+                    origin = Some(location);
+                }
+                let size = code.len() as u16;
+                lines.push(AssembledLine {
+                    linenum,
+                    address: location,
+                    code,
+                    expansion: source.expanded.then(|| instruction.as_str().to_string()),
+                    location: source.location.clone(),
+                    segment,
+                    relocations,
+                });
+                location = location.wrapping_add(size);
+                if let Some(index) = segment.filter(|_| pass != Pass::Final) {
+                    let s = &mut definitions.segments[index];
+                    s.size = s.size.max(location.wrapping_sub(starts[index]));
+                }
+            }
+        }
+    }
+    if let (Some(index), Some(source)) = (segment.filter(|_| !opened_by_section), opened_at) {
+        let e = format!("Segment {} is not closed", definitions.segments[index].name);
+        push_error(errors, source.error(&e));
+    }
+    (lines, origin)
+}
+
+/// Both passes find the same errors: report once
+fn push_error(errors: &mut Vec<AsmError>, e: AsmError) {
+    if !errors.contains(&e) {
+        errors.push(e);
+    }
+}
+
+/// Parse error of pest at the column of the line
+fn parse_error(line: &SourceLine, e: &pest::error::Error<Rule>) -> AsmError {
+    let column = match e.line_col {
+        LineColLocation::Pos((_, column)) => column,
+        LineColLocation::Span((_, column), _) => column,
+    };
+    line.error_at(column, &e.variant.message())
+}
+
+/// Assemble the program lines with the passes
+/// include reads the lines of the include files.
+/// The sizing passes run until no size changes when the optimization is on.
+/// All the errors of the program are returned: the warnings are in Assembly.
+pub fn assemble(
+    program: &[String],
+    include: IncludeFile,
+    options: &Options,
+) -> Result<Assembly, Vec<AsmError>> {
+    let source = parser::preprocess(program, include).map_err(|e| vec![e])?;
+    let mut errors: Vec<AsmError> = Vec::new();
+    let mut instructions: Vec<(&SourceLine, Pair<Rule>)> = Vec::new();
+    for line in source.iter() {
+        let parsed = match AssemblyParser::parse(Rule::program, &line.text) {
+            Ok(mut pairs) => pairs.next().unwrap(),
+            Err(e) => {
+                errors.push(parse_error(line, &e));
+                continue;
+            }
+        };
+        // Empty line or comment has only EOI, and a label can have an instruction.
+        for i in parsed.into_inner().filter(|i| i.as_rule() != Rule::EOI) {
+            instructions.push((line, i));
+        }
+    }
+
+    let mut definitions = Definitions {
+        optimize: options.optimize,
+        ..Default::default()
+    };
+    // 1st pass
+    let (mut sized, _) = assemble_pass(&instructions, &mut definitions, Pass::Define, &mut errors);
+    if let Err(e) = definitions.link() {
+        errors.push(AsmError::new(&e));
+        return Err(errors);
+    }
+    // Sizing passes: the errors are found by the last pass.
+    let mut passes = 0;
+    while definitions.optimize {
+        for segment in definitions.segments.iter_mut() {
+            segment.size = 0;
+        }
+        (sized, _) = assemble_pass(&instructions, &mut definitions, Pass::Size, &mut Vec::new());
+        definitions.symbols = std::mem::take(&mut definitions.resized);
+        if let Err(e) = definitions.link() {
+            errors.push(AsmError::new(&e));
+            return Err(errors);
+        }
+        if !definitions.changed {
+            break;
+        }
+        passes += 1;
+        if passes == MAX_SIZING_PASSES {
+            let e = format!("Sizes of the code change after {} passes", passes);
+            errors.push(AsmError::new(&e));
+            return Err(errors);
+        }
+    }
+    // Last pass
+    let (lines, origin) = assemble_pass(&instructions, &mut definitions, Pass::Final, &mut errors);
+    // In the order of the source: the main program and then each include file
+    errors.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    if errors.iter().any(|e| e.is_error()) {
+        return Err(errors);
+    }
+
+    for (first, second) in sized.iter().zip(lines.iter()) {
+        if first.code.len() != second.code.len() {
+            let e = format!(
+                "size of the code is changed from {} to {} bytes",
+                first.code.len(),
+                second.code.len()
+            );
+            let (file, linenum) = match &second.location {
+                Some((file, linenum)) => (Some(file.clone()), *linenum),
+                None => (None, second.linenum),
+            };
+            return Err(vec![AsmError::at(file, linenum + 1, 1, &e)]);
+        }
+    }
+    let mut constants: HashMap<String, i64> = definitions.variables.clone();
+    for name in definitions.constants.keys() {
+        let value = definitions
+            .table(false, 0)
+            .value(name)
+            .map_err(|e| vec![AsmError::new(&e)])?;
+        constants.insert(name.clone(), value);
+    }
+    let entry = match &definitions.entry {
+        Some(name) => {
+            let address = definitions
+                .table(false, 0)
+                .get(name)
+                .map_err(|e| vec![AsmError::new(&e)])?;
+            Some((definitions.frames.get(name).copied().unwrap_or(0), address))
+        }
+        None => None,
+    };
+    Ok(Assembly {
+        origin: origin.unwrap_or(0),
+        lines,
+        symbols: definitions.symbols,
+        constants,
+        segments: definitions.segments,
+        entry,
+        labels: definitions.labels,
+        warnings: errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::error;
+    use std::fs::read_to_string;
+
+    fn lines(s: &str) -> Vec<String> {
+        s.lines().map(|l| l.to_owned()).collect()
+    }
+
+    fn no_include(name: &str) -> Result<Vec<String>, String> {
+        Err(format!("{} is not found", name))
+    }
+
+    #[test]
+    fn test_assembler_example_file() {
+        let program = lines(&read_to_string("example.as").unwrap());
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(0x100, assembly.origin);
+        assert_eq!(
+            vec![
+                0xbb, 0x10, 0x00, // mov bx, 10h
+                0x8b, 0xc3, // mov ax, bx
+                0xb9, 0x34, 0x12, // mov cx, 0x1234
+                0x81, 0xc1, 0xba, 0xdc, // add cx, 0dcbah
+                0x89, 0x0e, 0x00, 0x00, // mov [0h], cx
+                0xa1, 0x00, 0x00, // mov ax, [0h]
+                0xc7, 0x06, 0x00, 0x10, 0x34, 0x12, // mov word ptr [1000h], 0x1234
+                0xb9, 0x34, 0x12, // mov cx, 0x1234
+                0x01, 0x0e, 0x00, 0x10, // add word ptr [1000h], cx
+                0x03, 0x0e, 0x00, 0x10, // add cx, word ptr [1000h]
+            ],
+            assembly.com().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_assembler_label() {
+        let program = lines("org 100h\nstart:\ninc ax\njmp end\ninc bx\nend:\njmp start");
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(Some(&0x100), assembly.symbols.get("start"));
+        assert_eq!(Some(&0x104), assembly.symbols.get("end"));
+        assert_eq!(
+            vec![0x40, 0xeb, 0x01, 0x43, 0xeb, 0xfa],
+            assembly.binary().unwrap()
+        );
+        assert_eq!(
+            AssembledLine {
+                linenum: 3,
+                address: 0x101,
+                code: vec![0xeb, 0x01],
+                expansion: None,
+                location: None,
+                segment: None,
+                relocations: Vec::new(),
+            },
+            assembly.lines[1]
+        );
+    }
+
+    #[test]
+    fn test_assembler_org_gap() {
+        let program = lines("org 10h\ninc ax\norg 14h\ninc cx");
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(0x10, assembly.origin);
+        assert_eq!(vec![0x40, 0, 0, 0, 0x41], assembly.binary().unwrap());
+        assert!(assembly.com().is_err());
+    }
+
+    #[test]
+    fn test_assembler_data() {
+        // Data is referred before it is defined
+        let program = lines(
+            "org 100h\nmov dx, offset msg\nmov al, byte ptr [msg]\ninc word ptr [count]\nmsg db 'Hi', 0h\ncount dw ?\npmsg dw msg",
+        );
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(Some(&0x10a), assembly.symbols.get("msg"));
+        assert_eq!(Some(&0x10d), assembly.symbols.get("count"));
+        assert_eq!(
+            vec![
+                0xba, 0x0a, 0x01, // mov dx, offset msg
+                0xa0, 0x0a, 0x01, // mov al, byte ptr [msg]
+                0xff, 0x06, 0x0d, 0x01, // inc word ptr [count]
+                0x48, 0x69, 0x00, // msg db 'Hi', 0h
+                0x00, 0x00, // count dw ?
+                0x0a, 0x01, // pmsg dw msg
+            ],
+            assembly.com().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_assembler_keyword_prefix() {
+        // The names starting with a keyword are not the keyword and a name.
+        let program = lines(
+            "org 100h\ninc word ptr [incr]\nmov ax, income\nincr dw 3\nincome equ 5\njmpaddr dw 0\norigin db 1\nhltx:\nendx db 2",
+        );
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(Some(&0x107), assembly.symbols.get("incr"));
+        assert_eq!(Some(&0x109), assembly.symbols.get("jmpaddr"));
+        assert_eq!(Some(&0x10b), assembly.symbols.get("origin"));
+        assert_eq!(Some(&0x10c), assembly.symbols.get("endx"));
+        assert_eq!(
+            vec![
+                0xff, 0x06, 0x07, 0x01, // inc word ptr [incr]
+                0xb8, 0x05, 0x00, // mov ax, income
+                0x03, 0x00, // incr dw 3
+                0x00, 0x00, // jmpaddr dw 0
+                0x01, // origin db 1
+                0x02, // endx db 2
+            ],
+            assembly.com().unwrap()
+        );
+        // A keyword needs a space before the operand.
+        assert!(assemble(&lines("incax"), &no_include, &Options::default()).is_err());
+    }
+
+    #[test]
+    fn test_assembler_expr() {
+        let program = lines(
+            "org 100h\nmov al, -1\nmov cx, msgend - msg\nmov ax, [msg + 1]\nadd byte ptr [bx + msg], 'a' - 'A'\nmov bx, $\nmsg db 'Hi'\nmsgend:",
+        );
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(
+            vec![
+                0xb0, 0xff, // mov al, -1
+                0xb9, 0x02, 0x00, // mov cx, msgend - msg
+                0xa1, 0x11, 0x01, // mov ax, [msg + 1]
+                0x80, 0x87, 0x10, 0x01, 0x20, // add byte ptr [bx + msg], 'a' - 'A'
+                0xbb, 0x0d, 0x01, // mov bx, $
+                0x48, 0x69, // msg db 'Hi'
+            ],
+            assembly.com().unwrap()
+        );
+
+        // Range check with the operand size
+        assert!(assemble(&lines("mov al, 256"), &no_include, &Options::default()).is_err());
+        assert!(assemble(&lines("mov al, -129"), &no_include, &Options::default()).is_err());
+        assert!(assemble(&lines("mov ax, 65536"), &no_include, &Options::default()).is_err());
+        assert!(assemble(&lines("add ax, 10 / 0"), &no_include, &Options::default()).is_err());
+    }
+
+    #[test]
+    fn test_assembler_constants() {
+        // equ can be used before it is defined
+        let program = lines(
+            "org 100h\nmov cx, count\nmov al, byte ptr [msg + last]\nsize = 2\nsize = size * 3\nadd ax, size\nmsg db 'Hello'\nlen equ $ - msg\nlast equ len - 1\ncount equ last * 2 + 2",
+        );
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(
+            vec![
+                0xb9, 0x0a, 0x00, // mov cx, count
+                0xa0, 0x0d, 0x01, // mov al, byte ptr [msg + last]
+                0x05, 0x06, 0x00, // add ax, size
+                0x48, 0x65, 0x6c, 0x6c, 0x6f, // msg db 'Hello'
+            ],
+            assembly.com().unwrap()
+        );
+        assert_eq!(Some(&5), assembly.constants.get("len"));
+        assert_eq!(Some(&6), assembly.constants.get("size"));
+        assert_eq!(Some(&10), assembly.constants.get("count"));
+
+        // = is redefined in the source order
+        let assembly = assemble(
+            &lines("nn = 1\ndb nn\nnn = nn + 1\ndb nn"),
+            &no_include,
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(vec![1, 2], assembly.binary().unwrap());
+
+        assert!(assemble(
+            &lines("aa equ bb\nbb equ aa"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        assert!(assemble(&lines("aa equ aa + 1"), &no_include, &Options::default()).is_err());
+        assert!(assemble(&lines("aa equ nothing"), &no_include, &Options::default()).is_err());
+        assert!(assemble(
+            &lines("aa equ 1\naa equ 2"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        assert!(assemble(&lines("aa equ 1\naa = 2"), &no_include, &Options::default()).is_err());
+        assert!(assemble(&lines("aa = 1\naa:"), &no_include, &Options::default()).is_err());
+        // = should be defined before it is used
+        assert!(assemble(&lines("db nn\nnn = 1"), &no_include, &Options::default()).is_err());
+    }
+
+    #[test]
+    fn test_assembler_macro() {
+        let program = lines(
+            "twice macro reg\n  local again\nagain:\n  inc reg\n  jmp again\nendm\norg 100h\ntwice ax\ntwice bx",
+        );
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(Some(&0x100), assembly.symbols.get("??0000"));
+        assert_eq!(Some(&0x103), assembly.symbols.get("??0001"));
+        assert_eq!(
+            vec![0x40, 0xeb, 0xfd, 0x43, 0xeb, 0xfd],
+            assembly.binary().unwrap()
+        );
+        // Expanded lines have the line number of the macro call
+        assert_eq!(
+            AssembledLine {
+                linenum: 8,
+                address: 0x103,
+                code: vec![0x43],
+                expansion: Some("inc bx".to_string()),
+                location: None,
+                segment: None,
+                relocations: Vec::new(),
+            },
+            assembly.lines[2]
+        );
+    }
+
+    #[test]
+    fn test_assembler_failure() {
+        assert!(assemble(&lines("jmp nowhere"), &no_include, &Options::default()).is_err());
+        assert!(assemble(&lines("a1:\na1:"), &no_include, &Options::default()).is_err());
+        assert!(assemble(&lines("sub ax, bx"), &no_include, &Options::default()).is_err());
+        assert!(assemble(&lines("mov ax"), &no_include, &Options::default()).is_err());
+        assert!(assemble(
+            &lines("mov ax, [nowhere]"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        assert!(assemble(
+            &lines("msg db 1h\nmsg dw 2h"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_assembler_textbook_style() {
+        // Uppercase and tab-indented code as like the textbooks
+        let program = lines(
+            "\tORG\t100H\nCOUNT\tEQU\t2\nSTART:\tMOV\tAX, COUNT\t; label and instruction\n\tADD\tWORD PTR [BX+SI+2], AX\n\tMov\tDs, Ax\n\tJMP\tSTART\nMSG\tDB\t'Hi', 0DH\nCODE\tSEGMENT\tPARA STACK\nCODE\tENDS",
+        );
+        let assembly = assemble(&program[..7], &no_include, &Options::default()).unwrap();
+        assert_eq!(Some(&0x100), assembly.symbols.get("START"));
+        assert_eq!(
+            vec![
+                vec![0xb8, 2, 0],
+                vec![0x01, 0x40, 2],
+                vec![0x8e, 0xd8],
+                vec![0xeb, 0xf6],
+                vec![b'H', b'i', 0x0d],
+            ],
+            assembly
+                .lines
+                .iter()
+                .map(|l| l.code.clone())
+                .collect::<Vec<Vec<u8>>>()
+        );
+        let assembly = assemble(&program[7..], &no_include, &Options::default()).unwrap();
+        assert!(assembly.segments[0].stack);
+        assert_eq!(16, assembly.segments[0].align);
+    }
+
+    #[test]
+    fn test_assembler_local_label() {
+        let program = lines(
+            "org 100h\nmain:\n.again: inc ax\n  jmp .again\n_sub.1:\n.again:\n  jmp .again\nsize equ $ - .again\n@x: i db 1\n  jmp main.again",
+        );
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(Some(&0x100), assembly.symbols.get("main.again"));
+        assert_eq!(Some(&0x103), assembly.symbols.get("_sub.1.again"));
+        assert_eq!(Some(&0x105), assembly.symbols.get("i"));
+        assert_eq!(Some(&0x105), assembly.symbols.get("@x"));
+        // .again in the equ is the local label of its scope
+        assert_eq!(Some(&2), assembly.constants.get("size"));
+        assert_eq!(vec![0xeb, 0xfd], assembly.lines[1].code);
+        assert_eq!(vec![0xeb, 0xfe], assembly.lines[2].code);
+        assert_eq!(vec![0xeb, 0xf8], assembly.lines[4].code);
+        assert_eq!(
+            vec![
+                ("main", 1),
+                ("main.again", 2),
+                ("_sub.1", 4),
+                ("_sub.1.again", 5),
+                ("@x", 8)
+            ],
+            assembly
+                .labels
+                .iter()
+                .map(|l| (l.name.as_str(), l.linenum))
+                .collect::<Vec<(&str, usize)>>()
+        );
+        assert!(assemble(&lines("a:\n.x:\n.x:"), &no_include, &Options::default()).is_err());
+        // Local labels of the other global labels are not in the scope
+        assert!(assemble(
+            &lines("a:\n.x:\nb:\njmp .x"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_assembler_numeric_label() {
+        let program = lines("org 100h\n1: inc ax\n  jmp 1f\n  jmp 1b\n1:\n  jmp 1B\n  jmp 2f\n2:");
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(Some(&0x100), assembly.symbols.get("1:0"));
+        assert_eq!(Some(&0x105), assembly.symbols.get("1:1"));
+        // jmp 1f => 105h, jmp 1b => 100h, jmp 1b => 105h, jmp 2f => 109h
+        assert_eq!(vec![0xeb, 2], assembly.lines[1].code);
+        assert_eq!(vec![0xeb, 0xfb], assembly.lines[2].code);
+        assert_eq!(vec![0xeb, 0xfe], assembly.lines[3].code);
+        assert_eq!(vec![0xeb, 0], assembly.lines[4].code);
+        // No label before or after
+        assert!(assemble(&lines("jmp 1b\n1:"), &no_include, &Options::default()).is_err());
+        assert!(assemble(&lines("1:\njmp 1f"), &no_include, &Options::default()).is_err());
+        // 1b in the expression is a binary number
+        let assembly =
+            assemble(&lines("1:\nmov ax, 1b"), &no_include, &Options::default()).unwrap();
+        assert_eq!(vec![0xb8, 1, 0], assembly.lines[0].code);
+    }
+
+    #[test]
+    fn test_assembler_optimize() {
+        let program = lines(
+            "org 100h\nadd bx, 2\nadd word ptr [bx+si+2], -1\nadd bx, 200h\nmov al, byte ptr [100h]\nmov [100h], ax\ninc word ptr [bp]\ninc cx\nadd ax, 1",
+        );
+        let codes = |options: &Options| {
+            assemble(&program, &no_include, options)
+                .unwrap()
+                .lines
+                .iter()
+                .map(|l| l.code.clone())
+                .collect::<Vec<Vec<u8>>>()
+        };
+        assert_eq!(
+            vec![
+                vec![0x83, 0xc3, 2],
+                vec![0x83, 0x40, 2, 0xff],
+                vec![0x81, 0xc3, 0, 2],
+                vec![0xa0, 0, 1],
+                vec![0xa3, 0, 1],
+                vec![0xff, 0x46, 0],
+                vec![0x41],
+                vec![0x05, 1, 0],
+            ],
+            codes(&Options::default())
+        );
+        // The same bytes as the assemblers without the optimization
+        assert_eq!(
+            vec![
+                vec![0x81, 0xc3, 2, 0],
+                vec![0x81, 0x80, 2, 0, 0xff, 0xff],
+                vec![0x81, 0xc3, 0, 2],
+                vec![0x8a, 0x06, 0, 1],
+                vec![0x89, 0x06, 0, 1],
+                vec![0xff, 0x86, 0, 0],
+                vec![0x41],
+                vec![0x05, 1, 0],
+            ],
+            codes(&Options { optimize: false })
+        );
+
+        // A segment value is changed by the loader: it has 16 bits.
+        let program =
+            lines("code segment\nadd bx, seg x\ncode ends\ndata segment\nx dw 0\ndata ends");
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(vec![0x81, 0xc3, 1, 0], assembly.lines[0].code);
+        assert_eq!(vec![2], assembly.lines[0].relocations);
+    }
+
+    #[test]
+    fn test_assembler_jump_relaxation() {
+        let jumps = |program: &str| {
+            let assembly = assemble(&lines(program), &no_include, &Options::default()).unwrap();
+            assembly
+                .lines
+                .iter()
+                .filter(|l| l.code[0] != 0)
+                .map(|l| l.code.clone())
+                .collect::<Vec<Vec<u8>>>()
+        };
+        // Forward and backward jumps in -128..127 bytes are rel8.
+        assert_eq!(
+            vec![vec![0xeb, 0x7f], vec![0xeb, 0x7f], vec![0xe9, 0x7e, 0xff]],
+            jumps("org 100h\njmp a\ndb 125 dup(0)\njmp b\na: db 127 dup(0)\nb: jmp a")
+        );
+        // The 1st jump becomes rel16 and the 2nd jump is still rel8 after it.
+        assert_eq!(
+            vec![
+                vec![0xe9, 0x80, 0],
+                vec![0xeb, 0x7f],
+                vec![0xe9, 0x7e, 0xff]
+            ],
+            jumps("org 100h\njmp a\ndb 126 dup(0)\njmp b\na: db 127 dup(0)\nb: jmp a")
+        );
+        // A jump to itself
+        assert_eq!(vec![vec![0xeb, 0xfe]], jumps("again: jmp again"));
+    }
+
+    #[test]
+    fn test_assembler_diagnostics() {
+        // All the errors of the program with the column
+        let e = assemble(
+            &lines("mov ax, 1h\n  mov ax, [nowhere]\nmov ax, bx,\na1:\na1:"),
+            &no_include,
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            vec![(2, 3), (3, 11), (5, 1)],
+            e.iter()
+                .map(|e| (e.line, e.column))
+                .collect::<Vec<(usize, usize)>>()
+        );
+        assert_eq!("Line 2: Undefined symbol nowhere", e[0].to_string());
+
+        // Segment not closed is reported at the segment directive
+        let e = assemble(
+            &lines("inc ax\ncode segment\ninc ax"),
+            &no_include,
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            "Line 1: Code outside of a segment\nLine 2: Segment code is not closed",
+            error::join(&e)
+        );
+
+        // Warning does not stop the build
+        let assembly = assemble(
+            &lines("inc ax\nend\ninc bx"),
+            &no_include,
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(1, assembly.lines.len());
+        assert_eq!(
+            "Line 3: warning: Lines after end are ignored",
+            error::join(&assembly.warnings)
+        );
+    }
+
+    #[test]
+    fn test_assembler_segments() {
+        let program = lines(
+            "data segment\nmsg db 1h, 2h\ndata ends\ncode segment\nassume cs:code, ds:data\nstart:\n  mov ax, seg msg\n  mov bx, data\n  mov dx, offset msg\n  jmp far ptr next\ncode ends\nfar segment byte\nnext:\n  inc ax\nfar ends\nend start\ninc ax",
+        );
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(
+            vec![
+                ("data", 0x00, 2, 0),
+                ("code", 0x10, 14, 1),
+                ("far", 0x1e, 1, 1)
+            ],
+            assembly
+                .segments
+                .iter()
+                .map(|s| (s.name.as_str(), s.start, s.size, s.frame))
+                .collect::<Vec<(&str, usize, u16, u16)>>()
+        );
+        // Offset in the frame: byte aligned segment continues in the frame 1
+        assert_eq!(Some(&0), assembly.symbols.get("msg"));
+        assert_eq!(Some(&0), assembly.symbols.get("start"));
+        assert_eq!(Some(&0x0e), assembly.symbols.get("next"));
+        assert_eq!(Some((1, 0)), assembly.entry);
+        // mov ax, 0 / mov bx, 0 / mov dx, 0 / jmp 0001:000e
+        assert_eq!(
+            vec![0xb8, 0, 0, 0xbb, 0, 0, 0xba, 0, 0, 0xea, 0x0e, 0, 1, 0],
+            assembly.lines[1].code[..]
+                .iter()
+                .chain(assembly.lines[2].code.iter())
+                .chain(assembly.lines[3].code.iter())
+                .chain(assembly.lines[4].code.iter())
+                .copied()
+                .collect::<Vec<u8>>()
+        );
+        assert_eq!(vec![0x11, 0x14, 0x1c], assembly.relocations());
+        // The lines after end are ignored.
+        assert_eq!(6, assembly.lines.len());
+        assert_eq!(0x1f, assembly.binary().unwrap().len());
+        assert!(assembly.com().is_err());
+    }
+
+    #[test]
+    fn test_assembler_group_section() {
+        let program = lines(
+            "dgroup group data, bss\nsection .text\n  mov ax, dgroup\n  mov bx, offset value\nsection data\n  db 1h\nsection bss\nvalue dw ?\nsection .text\n  inc ax",
+        );
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        // bss is placed after data in the frame of dgroup
+        assert_eq!(Some(&0x10), assembly.symbols.get("value"));
+        assert_eq!(vec![0xb8, 1, 0], assembly.lines[0].code);
+        assert_eq!(vec![0xbb, 0x10, 0], assembly.lines[1].code);
+        assert_eq!(vec![0x40], assembly.lines[4].code);
+        assert_eq!(Some(0), assembly.lines[4].segment);
+        assert_eq!(6, assembly.lines[4].address);
+        assert_eq!(vec![1], assembly.relocations());
+
+        // Tiny model COM program
+        let program = lines("code segment\norg 100h\nstart:\n  inc ax\ncode ends\nend start");
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        assert_eq!(vec![0x40], assembly.com().unwrap());
+    }
+
+    #[test]
+    fn test_assembler_segments_failure() {
+        assert!(assemble(
+            &lines("code segment\ninc ax"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        assert!(assemble(
+            &lines("code segment\ndata segment"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        assert!(assemble(
+            &lines("code segment\ndata ends"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        assert!(assemble(
+            &lines("inc ax\ncode segment\ncode ends"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        assert!(assemble(
+            &lines("code segment\nassume ds:nothing\nassume ds:data\ncode ends"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        assert!(assemble(
+            &lines("code segment\nassume ax:code\ncode ends"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        assert!(assemble(
+            &lines("dgroup group data\ncode segment\ncode ends"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        assert!(assemble(
+            &lines("mov ax, seg nowhere"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        // seg without segments
+        assert!(assemble(
+            &lines("start:\nmov ax, seg start"),
+            &no_include,
+            &Options::default()
+        )
+        .is_err());
+        let e = assemble(
+            &lines("code segment\nstart:\nmov ax, seg start + seg start\ncode ends"),
+            &no_include,
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!("Line 3: Segment value is not relocatable", error::join(&e));
+    }
+
+    #[test]
+    fn test_assembler_include() {
+        let include = |name: &str| match name {
+            "consts.inc" => Ok(lines("count equ 3\nmsg db 'Hi'")),
+            "error.inc" => Ok(lines("\nmov al, 256")),
+            _ => Err(format!("{} is not found", name)),
+        };
+        let program = lines("org 100h\nmov cx, count\ninclude \"consts.inc\"");
+        let assembly = assemble(&program, &include, &Options::default()).unwrap();
+        assert_eq!(Some(&3), assembly.constants.get("count"));
+        assert_eq!(Some(&0x103), assembly.symbols.get("msg"));
+        assert_eq!(
+            AssembledLine {
+                linenum: 2,
+                address: 0x103,
+                code: vec![0x48, 0x69],
+                expansion: Some("msg db 'Hi'".to_string()),
+                location: Some(("consts.inc".to_string(), 1)),
+                segment: None,
+                relocations: Vec::new(),
+            },
+            assembly.lines[1]
+        );
+
+        // Error in the include file has the file name
+        let e = assemble(
+            &lines("include \"error.inc\""),
+            &include,
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!(Some("error.inc".to_string()), e[0].file);
+        assert_eq!(2, e[0].line);
+        assert!(assemble(
+            &lines("include \"nothing.inc\""),
+            &include,
+            &Options::default()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use crate::condition;
+use crate::memory::{Access, Watch, MEMORY_SIZE};
+use serde_json::{json, Value};
+
+/*
+Breakpoints of the debugger
+
+A breakpoint stops /run before the instruction at its location runs.
+e.g. {"line": 3} => the 4th line of the source (0 is the 1st line as like /step)
+e.g. {"cs": 0, "ip": 259} => the instruction at 0000:0103
+e.g. {"label": "main.again"} => the line or address of the label
+e.g. {"line": 3, "condition": "cx == 0 && zf"} => only when the condition holds
+e.g. {"condition": "al > 10"} => any instruction when the condition holds
+The instruction at the start of /run always runs: it continues from the breakpoint.
+
+A watchpoint stops /run after the instruction accessing the memory range.
+e.g. {"start": 512, "len": 2, "access": "change"} => a different value is written to 0200-0201
+access: read, write or change
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    // Source line: 0 is the 1st line
+    Line(usize),
+    // CS and IP of the instruction
+    Address(u16, u16),
+    // Name in the symbol table: e.g. main.again for .again
+    Label(String),
+    // Every instruction: the condition of the breakpoint decides the stop.
+    Anywhere,
+    // Watchpoint: physical start, len and access of the memory range
+    Watch(usize, u16, Access),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub location: Location,
+    pub enabled: bool,
+    // Number of the stops at the breakpoint
+    pub hits: usize,
+    // Expression over the registers, flags and memory: see condition.rs
+    pub condition: Option<String>,
+}
+
+impl Breakpoint {
+    pub fn to_json(&self) -> Value {
+        let mut v = match &self.location {
+            Location::Line(line) => json!({ "line": line }),
+            Location::Address(cs, ip) => json!({ "cs": cs, "ip": ip }),
+            Location::Label(name) => json!({ "label": name }),
+            Location::Anywhere => json!({}),
+            Location::Watch(start, len, access) => {
+                json!({ "start": start, "len": len, "access": access.name() })
+            }
+        };
+        if let Some(condition) = &self.condition {
+            v["condition"] = json!(condition);
+        }
+        v["id"] = json!(self.id);
+        v["enabled"] = json!(self.enabled);
+        v["hits"] = json!(self.hits);
+        v
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    // id of the last breakpoint: ids start at 1
+    last_id: usize,
+}
+
+impl Breakpoints {
+    /// A breakpoint at the same location with the same condition is not added again.
+    /// return: id of the breakpoint
+    pub fn add(&mut self, location: Location, condition: Option<String>) -> usize {
+        if let Some(b) = self
+            .list
+            .iter()
+            .find(|b| b.location == location && b.condition == condition)
+        {
+            return b.id;
+        }
+        self.last_id += 1;
+        self.list.push(Breakpoint {
+            id: self.last_id,
+            location,
+            enabled: true,
+            hits: 0,
+            condition,
+        });
+        self.last_id
+    }
+
+    /// Breakpoints of a snapshot: ids after last_id are not used.
+    pub fn from_list(list: Vec<Breakpoint>, last_id: usize) -> Self {
+        Breakpoints { list, last_id }
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.list
+    }
+
+    pub fn last_id(&self) -> usize {
+        self.last_id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Result<(), String> {
+        let index = self.index(id)?;
+        self.list.remove(index);
+        Ok(())
+    }
+
+    pub fn enable(&mut self, id: usize, enabled: bool) -> Result<(), String> {
+        let index = self.index(id)?;
+        self.list[index].enabled = enabled;
+        Ok(())
+    }
+
+    /// id of the first enabled breakpoint at the next instruction
+    /// at: the location is the next instruction.
+    /// holds: the condition holds now.
+    pub fn find(
+        &self,
+        at: impl Fn(&Location) -> bool,
+        holds: impl Fn(&str) -> Result<bool, String>,
+    ) -> Result<Option<usize>, String> {
+        for b in self.list.iter().filter(|b| b.enabled) {
+            if matches!(b.location, Location::Watch(..)) || !at(&b.location) {
+                continue;
+            }
+            let stop = match &b.condition {
+                Some(condition) => holds(condition)
+                    .map_err(|e| format!("Condition of breakpoint {}: {}", b.id, e))?,
+                None => true,
+            };
+            if stop {
+                return Ok(Some(b.id));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Enabled watchpoints for the memory
+    pub fn watches(&self) -> Vec<Watch> {
+        self.list
+            .iter()
+            .filter(|b| b.enabled)
+            .filter_map(|b| match b.location {
+                Location::Watch(start, len, access) => Some(Watch {
+                    id: b.id,
+                    start,
+                    len,
+                    access,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The execution stopped at the breakpoint.
+    pub fn hit(&mut self, id: usize) {
+        if let Ok(index) = self.index(id) {
+            self.list[index].hits += 1;
+        }
+    }
+
+    /// e.g. {"breakpoints":[{"id":1,"line":3,"enabled":true,"hits":0}]}
+    pub fn to_json(&self) -> Value {
+        let list: Vec<Value> = self.list.iter().map(|b| b.to_json()).collect();
+        json!({ "breakpoints": list })
+    }
+
+    fn index(&self, id: usize) -> Result<usize, String> {
+        self.list
+            .iter()
+            .position(|b| b.id == id)
+            .ok_or_else(|| format!("Breakpoint {} is not found", id))
+    }
+}
+
+/// Reason why /run stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    // id of the breakpoint at the next instruction
+    Breakpoint(usize),
+    // id of the watchpoint, the address accessed and IP of the instruction accessing it
+    Watchpoint { id: usize, address: usize, ip: u16 },
+    // hlt instruction
+    Halt,
+    // No more lines in the program
+    End,
+    Error(String),
+    // The number of steps of the request
+    Budget,
+    // step over or step out finished
+    Step,
+}
+
+impl Stop {
+    /// e.g. {"reason": "breakpoint", "breakpoint": 1}
+    pub fn to_json(&self) -> Value {
+        match self {
+            Stop::Breakpoint(id) => json!({ "reason": "breakpoint", "breakpoint": id }),
+            Stop::Watchpoint { id, address, ip } => json!({
+                "reason": "watchpoint",
+                "breakpoint": id,
+                "address": address,
+                "ip": ip,
+            }),
+            Stop::Halt => json!({ "reason": "halt" }),
+            Stop::End => json!({ "reason": "end" }),
+            Stop::Error(e) => json!({ "reason": "error", "message": e }),
+            Stop::Budget => json!({ "reason": "budget" }),
+            Stop::Step => json!({ "reason": "step" }),
+        }
+    }
+}
+
+/// Location in the request: {"line": 3}, {"cs": 0, "ip": 259} or {"label": "again"}
+pub fn request_location(v: &Value) -> Result<Location, String> {
+    let number = |key: &str| -> Result<u16, String> {
+        v[key]
+            .as_u64()
+            .and_then(|n| u16::try_from(n).ok())
+            .ok_or_else(|| format!("{} should be a 16-bit number", key))
+    };
+    if let Some(line) = v["line"].as_u64() {
+        Ok(Location::Line(line as usize))
+    } else if let Some(name) = v["label"].as_str() {
+        Ok(Location::Label(name.to_owned()))
+    } else if !v["ip"].is_null() {
+        Ok(Location::Address(number("cs")?, number("ip")?))
+    } else if let Some(access) = v["access"].as_str() {
+        let len = if v["len"].is_null() {
+            1
+        } else {
+            number("len")?
+        };
+        if len == 0 {
+            return Err("len of the watchpoint should not be 0".to_string());
+        }
+        let start = v["start"]
+            .as_u64()
+            .filter(|n| *n < MEMORY_SIZE as u64)
+            .ok_or_else(|| "start should be a physical address below 100000h".to_string())?;
+        Ok(Location::Watch(
+            start as usize,
+            len,
+            Access::from_name(access)?,
+        ))
+    } else if !v["condition"].is_null() {
+        Ok(Location::Anywhere)
+    } else {
+        Err("Breakpoint needs line, label, cs and ip, access or condition".to_string())
+    }
+}
+
+/// Location and condition in the request
+/// The syntax of the condition is checked here.
+pub fn request_breakpoint(v: &Value) -> Result<(Location, Option<String>), String> {
+    let location = request_location(v)?;
+    let condition = match &v["condition"] {
+        Value::Null => None,
+        Value::String(text) => {
+            condition::parse(text)?;
+            Some(text.trim().to_owned())
+        }
+        _ => return Err("condition should be a string".to_string()),
+    };
+    if condition.is_some() && matches!(location, Location::Watch(..)) {
+        return Err("Watchpoint has no condition".to_string());
+    }
+    Ok((location, condition))
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_breakpoints() {
+        let mut breakpoints = Breakpoints::default();
+        assert_eq!(1, breakpoints.add(Location::Line(3), None));
+        assert_eq!(
+            2,
+            breakpoints.add(Location::Label("again".to_string()), None)
+        );
+        assert_eq!(1, breakpoints.add(Location::Line(3), None));
+        assert_eq!(3, breakpoints.add(Location::Address(0, 0x103), None));
+
+        let always = |_: &str| Ok(true);
+        assert_eq!(
+            Ok(Some(1)),
+            breakpoints.find(|l| *l == Location::Line(3), always)
+        );
+        breakpoints.enable(1, false).unwrap();
+        assert_eq!(
+            Ok(None),
+            breakpoints.find(|l| *l == Location::Line(3), always)
+        );
+        breakpoints.hit(3);
+        breakpoints.hit(3);
+
+        breakpoints.remove(2).unwrap();
+        assert!(breakpoints.remove(2).is_err());
+        assert!(breakpoints.enable(5, true).is_err());
+        assert_eq!(
+            json!({"breakpoints": [
+                {"id": 1, "line": 3, "enabled": false, "hits": 0},
+                {"id": 3, "cs": 0, "ip": 0x103, "enabled": true, "hits": 2},
+            ]}),
+            breakpoints.to_json()
+        );
+        // ids are not reused
+        assert_eq!(4, breakpoints.add(Location::Line(5), None));
+    }
+
+    #[test]
+    fn test_request_location() {
+        assert_eq!(Ok(Location::Line(2)), request_location(&json!({"line": 2})));
+        assert_eq!(
+            Ok(Location::Label("main.again".to_string())),
+            request_location(&json!({"label": "main.again"}))
+        );
+        assert_eq!(
+            Ok(Location::Address(0x1000, 0x100)),
+            request_location(&json!({"cs": 0x1000, "ip": 0x100}))
+        );
+        assert!(request_location(&json!({"ip": 0x100})).is_err());
+        assert!(request_location(&json!({"cs": 0, "ip": 0x10000})).is_err());
+        assert!(request_location(&json!({})).is_err());
+    }
+
+    #[test]
+    fn test_conditions_watches() {
+        let mut breakpoints = Breakpoints::default();
+        let condition = Some("cx == 0".to_string());
+        assert_eq!(1, breakpoints.add(Location::Line(3), condition.clone()));
+        assert_eq!(2, breakpoints.add(Location::Line(3), None));
+        assert_eq!(1, breakpoints.add(Location::Line(3), condition));
+        assert_eq!(
+            3,
+            breakpoints.add(Location::Anywhere, Some("zf".to_string()))
+        );
+        assert_eq!(
+            4,
+            breakpoints.add(Location::Watch(0x200, 2, Access::Change), None)
+        );
+
+        let at_line = |l: &Location| *l == Location::Line(3);
+        let cx_is_0 = |c: &str| Ok(c == "cx == 0");
+        assert_eq!(Ok(Some(1)), breakpoints.find(at_line, cx_is_0));
+        assert_eq!(Ok(Some(2)), breakpoints.find(at_line, |_| Ok(false)));
+        let anywhere = |l: &Location| *l == Location::Anywhere;
+        assert_eq!(Ok(Some(3)), breakpoints.find(anywhere, |c| Ok(c == "zf")));
+        // Watchpoints are not instruction locations.
+        breakpoints.remove(3).unwrap();
+        breakpoints.enable(2, false).unwrap();
+        assert_eq!(Ok(None), breakpoints.find(|_| true, |_| Ok(false)));
+        assert!(breakpoints
+            .find(at_line, |_| Err("bad".to_string()))
+            .is_err());
+
+        assert_eq!(
+            vec![Watch {
+                id: 4,
+                start: 0x200,
+                len: 2,
+                access: Access::Change
+            }],
+            breakpoints.watches()
+        );
+        breakpoints.enable(4, false).unwrap();
+        assert!(breakpoints.watches().is_empty());
+        assert_eq!(
+            json!({"id": 4, "start": 0x200, "len": 2, "access": "change", "enabled": false, "hits": 0}),
+            breakpoints.list[2].to_json()
+        );
+    }
+
+    #[test]
+    fn test_request_breakpoint() {
+        assert_eq!(
+            Ok((Location::Line(2), Some("cx == 0 && ZF".to_string()))),
+            request_breakpoint(&json!({"line": 2, "condition": " cx == 0 && ZF "}))
+        );
+        assert_eq!(
+            Ok((Location::Anywhere, Some("al".to_string()))),
+            request_breakpoint(&json!({"condition": "al"}))
+        );
+        assert_eq!(
+            Ok((Location::Watch(0x200, 1, Access::Write), None)),
+            request_breakpoint(&json!({"start": 0x200, "access": "write"}))
+        );
+        assert!(request_breakpoint(&json!({"line": 2, "condition": "cx =="})).is_err());
+        assert!(request_breakpoint(&json!({"start": 0x200, "access": "exec"})).is_err());
+        assert!(request_breakpoint(&json!({"start": 0x200, "len": 0, "access": "read"})).is_err());
+        assert!(
+            request_breakpoint(&json!({"start": 0, "access": "read", "condition": "zf"})).is_err()
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{memory, Hardware8086};
+use serde_json::{json, Value};
+
+/*
+Call stack of the procedures
+
+The return addresses are the words above SP pointing after a call instruction (E8 rel16).
+The called address should be at or before the instruction of the callee: the pushed values are not the return addresses.
+BP of the frame is in the BP chain of push bp, mov bp, sp: [BP] is BP of the caller.
+The frames are innermost first: the current instruction and the call instructions of the callers.
+e.g. count calling itself after push ax, call count in main
+{"name":"count","line":10,"cs":0,"ip":270,"sp":502,"bp":502,"return":504,"words":[506]}
+{"name":"count","line":11,"cs":0,"ip":271,"sp":506,"bp":506,"return":508,"words":[0]}
+{"name":"main","line":5,"cs":0,"ip":263,"sp":510,"bp":null,"return":null,"words":[7,...]}
+words are the stack of the frame from SP to the return address: the arguments are the first words of the caller.
+*/
+
+pub const MAX_FRAMES: usize = 64;
+// The return addresses are searched in this number of words above SP.
+const STACK_WORDS: u32 = 1024;
+// Words of the frame in the JSON
+const FRAME_WORDS: u32 = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    // The procedure: the last label at or before IP
+    pub name: Option<String>,
+    pub line: Option<usize>,
+    pub cs: u16,
+    // The next instruction of the innermost frame, or the call instruction of the caller
+    pub ip: u16,
+    // The top of the stack of the frame
+    pub sp: u16,
+    pub bp: Option<u16>,
+    // Address of the return address to the caller: None for the outermost frame
+    pub ret: Option<u16>,
+}
+
+/// The frames of the line, or of CS:IP in the binary mode: innermost first
+pub fn frames(hardware: &Hardware8086, linenum: usize) -> Vec<Frame> {
+    let cpu = &hardware.cpu;
+    let ss = cpu.get_register16("ss");
+    let sp = cpu.get_register16("sp");
+    let line = (!hardware.binary && hardware.program.contains_key(&linenum)).then_some(linenum);
+    let segment = line.and_then(|l| hardware.line_segment(l));
+    let (cs, ip) = line
+        .and_then(|l| hardware.line_address(l))
+        .unwrap_or((cpu.get_register16("cs"), cpu.get_register16("ip")));
+    let mut frames = vec![Frame {
+        name: procedure(hardware, segment, ip),
+        line: line.or_else(|| hardware.code_line(segment, ip)),
+        cs,
+        ip,
+        sp,
+        bp: None,
+        ret: None,
+    }];
+
+    let mut slot = sp as u32;
+    while slot < sp as u32 + STACK_WORDS * 2 && slot < 0xffff && frames.len() < MAX_FRAMES {
+        let value = hardware.memory.read_stack(ss, slot as u16);
+        let callee = frames.last().unwrap().ip;
+        if call_target(hardware, segment, cs, value).is_some_and(|target| target <= callee) {
+            let call = value.wrapping_sub(3);
+            frames.last_mut().unwrap().ret = Some(slot as u16);
+            frames.push(Frame {
+                name: procedure(hardware, segment, call),
+                line: hardware.code_line(segment, call),
+                cs,
+                ip: call,
+                // The stack of the caller wraps around to 0 after SS:FFFE.
+                sp: (slot as u16).wrapping_add(2),
+                bp: None,
+                ret: None,
+            });
+        }
+        slot += 2;
+    }
+
+    // BP before push bp, mov bp, sp of the callee is BP of the caller.
+    let mut bp = cpu.get_register16("bp");
+    for frame in frames.iter_mut() {
+        let top = frame.ret.map_or(0x10000, |ret| ret as u32);
+        if frame.sp <= bp && (bp as u32) < top {
+            frame.bp = Some(bp);
+            bp = hardware.memory.read_stack(ss, bp);
+        }
+    }
+    frames
+}
+
+/// Address called by the call instruction before the return address
+fn call_target(hardware: &Hardware8086, segment: Option<usize>, cs: u16, ret: u16) -> Option<u16> {
+    let address = ret.wrapping_sub(3);
+    let code = if hardware.binary {
+        hardware
+            .memory
+            .dump(memory::physical_address(cs, address), 3)
+    } else {
+        let assembly = hardware.assembly.as_ref()?;
+        let line = assembly
+            .lines
+            .iter()
+            .find(|l| l.segment == segment && l.address == address)?;
+        line.code.clone()
+    };
+    match code[..] {
+        [0xe8, low, high] => Some(ret.wrapping_add(u16::from_le_bytes([low, high]))),
+        _ => None,
+    }
+}
+
+/// The last label at or before the address: local and numeric labels are not procedures.
+pub fn procedure(hardware: &Hardware8086, segment: Option<usize>, ip: u16) -> Option<String> {
+    let assembly = hardware.assembly.as_ref()?;
+    assembly
+        .labels
+        .iter()
+        .filter(|l| l.segment == segment && l.address <= ip)
+        .filter(|l| !l.name.contains('.') && !l.name.contains(':'))
+        .max_by_key(|l| l.address)
+        .map(|l| l.name.clone())
+}
+
+/// Call stack in program_response
+pub fn to_json(hardware: &Hardware8086, frames: &[Frame]) -> Value {
+    let ss = hardware.cpu.get_register16("ss");
+    let frames: Vec<Value> = frames
+        .iter()
+        .map(|frame| {
+            let end = frame
+                .ret
+                .map_or(0x10000, |ret| ret as u32)
+                .min(frame.sp as u32 + FRAME_WORDS * 2);
+            let words: Vec<u16> = (frame.sp as u32..end)
+                .step_by(2)
+                .filter(|slot| *slot < 0xffff)
+                .map(|slot| hardware.memory.read_stack(ss, slot as u16))
+                .collect();
+            json!({
+                "name": frame.name,
+                "line": frame.line,
+                "cs": frame.cs,
+                "ip": frame.ip,
+                "sp": frame.sp,
+                "bp": frame.bp,
+                "return": frame.ret,
+                "words": words,
+            })
+        })
+        .collect();
+    Value::Array(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    /// Source lines of the test program
+    fn program(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    fn recursive_program() -> Vec<String> {
+        program(&[
+            "org 100h",
+            "main:",
+            "  mov sp, 200h",
+            "  mov ax, 7",
+            "  push ax",
+            "  call count",
+            "  hlt",
+            "count:",
+            "  push bp",
+            "  mov bp, sp",
+            "  inc cx",
+            "  call count",
+            "  pop bp",
+            "  ret",
+        ])
+    }
+
+    #[test]
+    fn test_callstack_frames() {
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&recursive_program(), &HashMap::new());
+        let (line, _, _) = hardware.run(0, 12);
+        let line_frames = frames(&hardware, line);
+        let names: Vec<_> = line_frames.iter().map(|f| f.name.as_deref()).collect();
+        assert_eq!(vec![Some("count"), Some("count"), Some("main")], names);
+        let lines: Vec<_> = line_frames.iter().map(|f| f.line).collect();
+        assert_eq!(vec![Some(10), Some(11), Some(5)], lines);
+        assert_eq!(
+            Frame {
+                name: Some("count".to_string()),
+                line: Some(11),
+                cs: 0,
+                ip: 0x10f,
+                sp: 0x1fa,
+                bp: Some(0x1fa),
+                ret: Some(0x1fc),
+            },
+            line_frames[1]
+        );
+        // The argument pushed by main is the first word of its frame.
+        let json = to_json(&hardware, &line_frames);
+        assert_eq!(serde_json::json!([0x1fa]), json[0]["words"]);
+        assert_eq!(serde_json::json!(7), json[2]["words"][0]);
+        assert_eq!(Value::Null, json[2]["return"]);
+
+        // The pushed values are not the return addresses.
+        hardware.build_program_table(&recursive_program(), &HashMap::new());
+        hardware.run(0, 5);
+        assert_eq!(1, frames(&hardware, 5).len());
+
+        // The binary program has the same frames at CS:IP.
+        let image = hardware.assembly.as_ref().unwrap().binary().unwrap();
+        hardware.load_com(&image, 0, "").unwrap();
+        hardware.run(0, 10);
+        let binary_frames = frames(&hardware, 0);
+        let ips: Vec<_> = binary_frames.iter().map(|f| (f.cs, f.ip)).collect();
+        assert_eq!(vec![(0, 0x10e), (0, 0x10f), (0, 0x107)], ips);
+    }
+
+    #[test]
+    fn test_callstack_stack_top() {
+        // SP 0 pushes the return address at SS:FFFE.
+        let program = program(&["org 100h", "mov sp, 0", "call f", "hlt", "f:", "ret"]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        let (line, _, _) = hardware.run(0, 3);
+        assert_eq!(5, line);
+        let stack = frames(&hardware, line);
+        assert_eq!(2, stack.len());
+        assert_eq!(Some(0xfffe), stack[0].ret);
+        assert_eq!(0, stack[1].sp);
+        assert_eq!(
+            Some(2),
+            to_json(&hardware, &stack).as_array().map(|a| a.len())
+        );
+    }
+}
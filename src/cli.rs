@@ -0,0 +1,330 @@
+use crate::memory::Memory;
+use crate::snapshot::Snapshot;
+use crate::{assembler, dap, error, hexfile, listing, loader, parser, Hardware8086};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{read, read_to_string, write};
+use std::path::{Component, Path};
+
+/*
+Command line interface
+
+remu8086
+    Run the web server
+remu8086 build <source.as> [-o <output>] [-l <listing>] [-I <root>] [-O0]
+    Assemble the source and write the machine code.
+    include "file.inc" reads the file in the project root: the directory of the source by default.
+    .com output is the COM program, .exe output is the MZ executable and the others are the flat binary.
+    The default output is <source>.exe if the source has segments,
+    <source>.com if the source has "org 100h", otherwise <source>.bin.
+    -l writes the listing file with addresses, machine code and the symbol table.
+    -O0 keeps the longest encodings (e.g. jmp rel16) for the same bytes as the other assemblers.
+remu8086 convert <input> <output> [-a <address>]
+    Convert a memory image between the raw binary, Intel HEX and S-record.
+    The format is given by the file extension: .hex/.ihx, .srec/.s19/.s28/.mot or others(raw binary).
+    -a is the hex address to load the raw binary (default 0).
+remu8086 snapshot <source.as|program.com|program.exe> -o <machine.snap> [-I <root>] [-O0] [-n <steps>] [-t <trace>]
+    Build or load the program, run the steps (0 by default) and save the machine.
+remu8086 restore <machine.snap> [-n <steps>] [-o <machine.snap>] [-t <trace>]
+    Restore the machine, run the steps, print the registers and save the machine with -o.
+    -t writes the trace of the steps: JSON Lines if the file name ends with ".jsonl", otherwise text.
+remu8086 dap [<address>]
+    Serve the Debug Adapter Protocol for the editors at the address (127.0.0.1:4711 by default).
+*/
+
+const USAGE: &str =
+    "Usage: remu8086 build <source.as> [-o <output.com|output.exe|output.bin>] [-l <listing.lst>] [-I <root>] [-O0]
+       remu8086 convert <input> <output> [-a <address>]
+       remu8086 snapshot <source.as|program.com|program.exe> -o <machine.snap> [-I <root>] [-O0] [-n <steps>] [-t <trace>]
+       remu8086 restore <machine.snap> [-n <steps>] [-o <machine.snap>] [-t <trace>]
+       remu8086 dap [<address>]";
+
+enum ImageFormat {
+    Binary,
+    IntelHex,
+    SRecord,
+}
+
+fn image_format(path: &str) -> ImageFormat {
+    let extension = Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "hex" | "ihx" => ImageFormat::IntelHex,
+        "srec" | "s19" | "s28" | "mot" => ImageFormat::SRecord,
+        _ => ImageFormat::Binary,
+    }
+}
+
+fn read_program(path: &str) -> Result<Vec<String>, String> {
+    let source = read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    Ok(source.lines().map(|l| l.to_owned()).collect())
+}
+
+// Include file in the project root: the name cannot go out of the root.
+fn read_include(root: &Path, name: &str) -> Result<Vec<String>, String> {
+    let relative = Path::new(name)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !relative {
+        return Err(format!("{} is not in the project root", name));
+    }
+    read_program(&root.join(name).to_string_lossy())
+}
+
+fn build(args: &[String]) -> Result<(), String> {
+    let mut source: Option<&str> = None;
+    let mut output: Option<String> = None;
+    let mut listing_file: Option<&str> = None;
+    let mut root: Option<&str> = None;
+    let mut options = assembler::Options::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = Some(iter.next().ok_or(USAGE)?.to_owned()),
+            "-l" => listing_file = Some(iter.next().ok_or(USAGE)?),
+            "-I" => root = Some(iter.next().ok_or(USAGE)?),
+            "-O0" => options.optimize = false,
+            _ if source.is_none() => source = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let source = source.ok_or(USAGE)?;
+
+    let root = match root {
+        Some(root) => Path::new(root),
+        None => Path::new(source).parent().unwrap_or(Path::new("")),
+    };
+
+    let program = read_program(source)?;
+    let assembly = assembler::assemble(&program, &|name| read_include(root, name), &options)
+        .map_err(|e| error::join(&e))?;
+    for warning in assembly.warnings.iter() {
+        eprintln!("{}", warning);
+    }
+    if let Some(path) = listing_file {
+        write(path, listing::listing(&program, &assembly))
+            .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        println!("{}: listing", path);
+    }
+    let output = output.unwrap_or_else(|| {
+        let extension = if !assembly.segments.is_empty() {
+            "exe"
+        } else if assembly.origin == 0x100 {
+            "com"
+        } else {
+            "bin"
+        };
+        Path::new(source)
+            .with_extension(extension)
+            .to_string_lossy()
+            .into_owned()
+    });
+    let image = if output.to_lowercase().ends_with(".com") {
+        assembly.com()?
+    } else if output.to_lowercase().ends_with(".exe") {
+        assembly.exe()?
+    } else {
+        assembly.binary()?
+    };
+    write(&output, &image).map_err(|e| format!("Failed to write {}: {}", output, e))?;
+    println!("{}: {} bytes", output, image.len());
+    Ok(())
+}
+
+fn convert(args: &[String]) -> Result<(), String> {
+    let mut files: Vec<&str> = Vec::new();
+    let mut address: usize = 0;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-a" => {
+                let a = iter.next().ok_or(USAGE)?;
+                address = usize::from_str_radix(a.trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("Invalid address {}", a))?;
+            }
+            _ => files.push(arg),
+        }
+    }
+    let (input, output) = match files[..] {
+        [input, output] => (input, output),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let mut memory = Memory::boot();
+    let range = match image_format(input) {
+        ImageFormat::Binary => {
+            let image = read(input).map_err(|e| format!("Failed to read {}: {}", input, e))?;
+            memory.load(address, &image)?;
+            Some((address, address + image.len()))
+        }
+        ImageFormat::IntelHex => hexfile::load_intel_hex(
+            &mut memory,
+            &read_to_string(input).map_err(|e| e.to_string())?,
+        )?,
+        ImageFormat::SRecord => hexfile::load_srecord(
+            &mut memory,
+            &read_to_string(input).map_err(|e| e.to_string())?,
+        )?,
+    };
+    let (start, end) = range.ok_or(format!("{} has no data", input))?;
+
+    let r = match image_format(output) {
+        ImageFormat::Binary => write(output, memory.dump(start, end - start)),
+        ImageFormat::IntelHex => write(output, hexfile::to_intel_hex(&memory, start, end - start)?),
+        ImageFormat::SRecord => write(output, hexfile::to_srecord(&memory, start, end - start)?),
+    };
+    r.map_err(|e| format!("Failed to write {}: {}", output, e))?;
+    println!("{}: {:05X}-{:05X}", output, start, end);
+    Ok(())
+}
+
+fn parse_steps(arg: Option<&String>) -> Result<usize, String> {
+    let arg = arg.ok_or(USAGE)?;
+    arg.parse()
+        .map_err(|_| format!("Invalid number of steps {}", arg))
+}
+
+// Run the steps, print why it stopped and write the trace
+// return: next line
+fn run_steps(
+    hardware: &mut Hardware8086,
+    line: usize,
+    steps: usize,
+    trace: Option<&str>,
+) -> Result<usize, String> {
+    if steps == 0 {
+        return Ok(line);
+    }
+    hardware.tracer.enabled = trace.is_some();
+    hardware.tracer.set_capacity(steps)?;
+    let (line, stop, steps) = hardware.run(line, steps);
+    println!("{} steps: {}", steps, stop.to_json());
+    if let Some(path) = trace {
+        let text = if path.to_lowercase().ends_with(".jsonl") {
+            hardware.tracer.to_jsonl()
+        } else {
+            hardware.tracer.to_text()
+        };
+        write(path, text).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        println!("{}: trace", path);
+    }
+    Ok(line)
+}
+
+fn save_snapshot(hardware: &Hardware8086, line: usize, path: &str) -> Result<(), String> {
+    let bytes = hardware.snapshot(line).to_bytes()?;
+    write(path, &bytes).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    println!("{}: {} bytes", path, bytes.len());
+    Ok(())
+}
+
+fn snapshot(args: &[String]) -> Result<(), String> {
+    let mut source: Option<&str> = None;
+    let mut output: Option<&str> = None;
+    let mut root: Option<&str> = None;
+    let mut steps = 0;
+    let mut trace: Option<&str> = None;
+    let mut hardware = Hardware8086::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = Some(iter.next().ok_or(USAGE)?),
+            "-I" => root = Some(iter.next().ok_or(USAGE)?),
+            "-O0" => hardware.options.optimize = false,
+            "-n" => steps = parse_steps(iter.next())?,
+            "-t" => trace = Some(iter.next().ok_or(USAGE)?),
+            _ if source.is_none() => source = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let (source, output) = source.zip(output).ok_or(USAGE)?;
+
+    let extension = Path::new(source)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let segment = loader::DEFAULT_LOAD_SEGMENT;
+    match extension.as_str() {
+        "com" => hardware.load_com(&read_image(source)?, segment, "")?,
+        "exe" => hardware.load_exe(&read_image(source)?, segment, "")?,
+        _ => build_source(&mut hardware, source, root)?,
+    }
+    let line = run_steps(&mut hardware, 0, steps, trace)?;
+    save_snapshot(&hardware, line, output)
+}
+
+/// Build the source file with its include files in the root: the directory of the source by default
+pub fn build_source(
+    hardware: &mut Hardware8086,
+    source: &str,
+    root: Option<&str>,
+) -> Result<(), String> {
+    let root = match root {
+        Some(root) => Path::new(root),
+        None => Path::new(source).parent().unwrap_or(Path::new("")),
+    };
+    let program = read_program(source)?;
+    // The include files read by the preprocessor are saved with the source.
+    let files = RefCell::new(HashMap::new());
+    let include = |name: &str| {
+        let lines = read_include(root, name)?;
+        files.borrow_mut().insert(name.to_owned(), lines.clone());
+        Ok(lines)
+    };
+    parser::preprocess(&program, &include).map_err(|e| e.to_string())?;
+    hardware.build_program_table(&program, &files.into_inner());
+    if hardware.assembly.is_none() {
+        return Err(error::join(&hardware.diagnostics));
+    }
+    Ok(())
+}
+
+fn read_image(path: &str) -> Result<Vec<u8>, String> {
+    read(path).map_err(|e| format!("Failed to read {}: {}", path, e))
+}
+
+fn restore(args: &[String]) -> Result<(), String> {
+    let mut input: Option<&str> = None;
+    let mut output: Option<&str> = None;
+    let mut steps = 0;
+    let mut trace: Option<&str> = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = Some(iter.next().ok_or(USAGE)?),
+            "-n" => steps = parse_steps(iter.next())?,
+            "-t" => trace = Some(iter.next().ok_or(USAGE)?),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let input = input.ok_or(USAGE)?;
+
+    let mut hardware = Hardware8086::new();
+    let line = hardware.restore(Snapshot::from_bytes(&read_image(input)?)?)?;
+    let line = run_steps(&mut hardware, line, steps, trace)?;
+    println!("{:?}", hardware.cpu);
+    if !hardware.binary {
+        println!("next line: {}", line + 1);
+    }
+    match output {
+        Some(path) => save_snapshot(&hardware, line, path),
+        None => Ok(()),
+    }
+}
+
+/// Run the command given by the command line arguments except the program name
+pub fn run(args: &[String]) -> Result<(), String> {
+    match args.first().map(|a| a.as_str()) {
+        Some("build") => build(&args[1..]),
+        Some("convert") => convert(&args[1..]),
+        Some("snapshot") => snapshot(&args[1..]),
+        Some("restore") => restore(&args[1..]),
+        Some("dap") if args.len() <= 2 => {
+            dap::serve(args.get(1).map_or(dap::DAP_ADDRESS, |a| a.as_str()))
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
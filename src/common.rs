@@ -1,6 +1,9 @@
 /*
 paste macro works like the token concatenation(# and ##) of C language.
 e.g. [<caller_ $mod>] => caller_mov
+
+The handlers return the error of the operands instead of panic
+and the caller returns it with ? operator.
 */
 
 #[macro_export]
@@ -9,7 +12,7 @@ macro_rules! caller_one {
         paste! {
             let mut inner_rule = $pairs.into_inner();
             let first_operand = inner_rule.next().unwrap();
-            $mod::[<handler_ $mod>](&mut $cpu, &mut $memory, first_operand);
+            $mod::[<handler_ $mod>](&mut $cpu, &mut $memory, first_operand)?;
         }
     };
 }
@@ -18,8 +21,9 @@ macro_rules! caller_one {
 macro_rules! define_handler_one {
     ($mod:ident, $first:ident, $cpu:ident, $memory:ident, $body:block) => {
         paste! {
-            pub fn [<handler_ $mod>]($cpu: &mut CpuContext, $memory: &mut Memory, $first: Pair<Rule>) {
+            pub fn [<handler_ $mod>]($cpu: &mut CpuContext, $memory: &mut Memory, $first: Pair<Rule>) -> Result<(), String> {
                 $body
+                Ok(())
             }
         }
     };
@@ -32,7 +36,7 @@ macro_rules! caller_two {
             let mut inner_rule = $pairs.into_inner();
             let first_operand = inner_rule.next().unwrap();
             let second_operand = inner_rule.next().unwrap();
-            $mod::[<handler_ $mod>](&mut $cpu, &mut $memory, first_operand, second_operand);
+            $mod::[<handler_ $mod>](&mut $cpu, &mut $memory, first_operand, second_operand)?;
         }
     };
 }
@@ -41,13 +45,15 @@ macro_rules! caller_two {
 macro_rules! define_handler_two {
     ($mod:ident, $first:ident, $second:ident, $cpu:ident, $memory:ident, $body:block) => {
         paste! {
-            pub fn [<handler_ $mod>]($cpu: &mut CpuContext, $memory: &mut Memory, $first: Pair<Rule>, $second: Pair<Rule>) {
+            pub fn [<handler_ $mod>]($cpu: &mut CpuContext, $memory: &mut Memory, $first: Pair<Rule>, $second: Pair<Rule>) -> Result<(), String> {
                 $body
+                Ok(())
             }
         }
     };
 }
 
+#[allow(dead_code)]
 pub fn count_bit(v: u16) -> i32 {
     let mut c = 0;
     let mut v = v;
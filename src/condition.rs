@@ -0,0 +1,177 @@
+use crate::assembler::{memory_address, SymbolTable};
+use crate::cpucontext::CpuContext;
+use crate::expr;
+use crate::memory::{physical_address, Memory};
+use crate::parser::{AssemblyParser, Rule};
+use pest::iterators::{Pair, Pairs};
+use pest::pratt_parser::{Assoc, Op, PrattParser};
+use pest::Parser;
+
+/*
+Condition of the breakpoint evaluated before each instruction
+
+Operator precedence from the lowest:
+||
+&&
+|
+&
+== !=
+< <= > >=
++ -
+! - (unary)
+
+e.g. cx == 0 && zf => stops when the loop counter is 0 and ZF is set
+e.g. word ptr [count] > 10 => the word at the address of count in the symbol table
+Memory is read without the watchpoints.
+*/
+
+fn pratt_parser() -> PrattParser<Rule> {
+    PrattParser::new()
+        .op(Op::infix(Rule::op_lor, Assoc::Left))
+        .op(Op::infix(Rule::op_land, Assoc::Left))
+        .op(Op::infix(Rule::op_bor, Assoc::Left))
+        .op(Op::infix(Rule::op_band, Assoc::Left))
+        .op(Op::infix(Rule::op_eq, Assoc::Left) | Op::infix(Rule::op_ne, Assoc::Left))
+        .op(Op::infix(Rule::op_lt, Assoc::Left)
+            | Op::infix(Rule::op_le, Assoc::Left)
+            | Op::infix(Rule::op_gt, Assoc::Left)
+            | Op::infix(Rule::op_ge, Assoc::Left))
+        .op(Op::infix(Rule::op_add, Assoc::Left) | Op::infix(Rule::op_sub, Assoc::Left))
+        .op(Op::prefix(Rule::op_lnot) | Op::prefix(Rule::op_neg))
+}
+
+/// Parse the condition: it is checked when the breakpoint is set.
+pub fn parse(text: &str) -> Result<Pair<'_, Rule>, String> {
+    let condition = AssemblyParser::parse(Rule::condition, text)
+        .map_err(|e| format!("Wrong condition {}: {}", text, e.variant.message()))?
+        .next()
+        .unwrap();
+    Ok(condition.into_inner().next().unwrap())
+}
+
+/// The condition holds with the registers and memory.
+/// symbols: names of the program in the memory operands, e.g. [count]
+pub fn holds(
+    text: &str,
+    cpu: &CpuContext,
+    memory: &Memory,
+    symbols: &SymbolTable,
+) -> Result<bool, String> {
+    let condition = parse(text)?;
+    Ok(evaluate_pairs(condition.into_inner(), cpu, memory, symbols)? != 0)
+}
+
+fn evaluate_pairs(
+    pairs: Pairs<Rule>,
+    cpu: &CpuContext,
+    memory: &Memory,
+    symbols: &SymbolTable,
+) -> Result<i64, String> {
+    pratt_parser()
+        .map_primary(|primary| value(&primary, cpu, memory, symbols))
+        .map_prefix(|op, rhs| {
+            let rhs = rhs?;
+            match op.as_rule() {
+                Rule::op_lnot => Ok((rhs == 0) as i64),
+                _ => Ok(rhs.wrapping_neg()),
+            }
+        })
+        .map_infix(|lhs, op, rhs| {
+            let (lhs, rhs) = (lhs?, rhs?);
+            match op.as_rule() {
+                Rule::op_lor => Ok((lhs != 0 || rhs != 0) as i64),
+                Rule::op_land => Ok((lhs != 0 && rhs != 0) as i64),
+                Rule::op_bor => Ok(lhs | rhs),
+                Rule::op_band => Ok(lhs & rhs),
+                Rule::op_eq => Ok((lhs == rhs) as i64),
+                Rule::op_ne => Ok((lhs != rhs) as i64),
+                Rule::op_lt => Ok((lhs < rhs) as i64),
+                Rule::op_le => Ok((lhs <= rhs) as i64),
+                Rule::op_gt => Ok((lhs > rhs) as i64),
+                Rule::op_ge => Ok((lhs >= rhs) as i64),
+                Rule::op_add => Ok(lhs.wrapping_add(rhs)),
+                _ => Ok(lhs.wrapping_sub(rhs)),
+            }
+        })
+        .parse(pairs)
+}
+
+/// Value of register, flag, memory or number
+fn value(
+    primary: &Pair<Rule>,
+    cpu: &CpuContext,
+    memory: &Memory,
+    symbols: &SymbolTable,
+) -> Result<i64, String> {
+    match primary.as_rule() {
+        Rule::reg8 | Rule::reg16 | Rule::cpu_register => {
+            Ok(cpu.get_register(primary.as_str())? as i64)
+        }
+        Rule::flag => Ok(cpu.get_flag(primary.as_str())? as i64),
+        Rule::mem8 | Rule::mem16 => {
+            // The memory operand is in DS as like the instructions.
+            let address = memory_address(primary, symbols)?;
+            let bytes = memory.dump(physical_address(cpu.get_register16("ds"), address), 2);
+            match primary.as_rule() {
+                Rule::mem8 => Ok(bytes[0] as i64),
+                _ => Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as i64),
+            }
+        }
+        Rule::cond_expr => evaluate_pairs(primary.clone().into_inner(), cpu, memory, symbols),
+        _ => expr::evaluate(primary, symbols),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_condition_registers() {
+        let mut cpu = CpuContext::boot();
+        let memory = Memory::boot();
+        let symbols = SymbolTable::default();
+        let check = |text: &str, cpu: &CpuContext| holds(text, cpu, &memory, &symbols);
+
+        cpu.set_register16("cx", 0);
+        assert_eq!(Ok(true), check("cx == 0", &cpu));
+        assert_eq!(Ok(false), check("cx == 0 && zf", &cpu));
+        cpu.set_ZF();
+        assert_eq!(Ok(true), check("CX == 0 && ZF", &cpu));
+        assert_eq!(Ok(true), check("!cf || cx != 0", &cpu));
+        cpu.set_register16("ax", 0x1241);
+        assert_eq!(Ok(true), check("al >= 'A' && ah == 12h", &cpu));
+        assert_eq!(Ok(true), check("ax - 1 == 1240h", &cpu));
+        assert_eq!(Ok(true), check("(flags & 40h) != 0", &cpu));
+        assert_eq!(Ok(true), check("ax", &cpu));
+        assert_eq!(Ok(false), check("!(ax > 0)", &cpu));
+        assert_eq!(Ok(true), check("ip < 1 || cx > 1", &cpu));
+
+        assert!(parse("cx ==").is_err());
+        assert!(parse("cx = 0").is_err());
+        // A name is resolved by the symbol table when it is checked.
+        assert!(check("cx == nothing", &cpu).is_err());
+    }
+
+    #[test]
+    fn test_condition_memory() {
+        let cpu = CpuContext::boot();
+        let mut memory = Memory::boot();
+        memory.write16(0, 0x200, 0x1234);
+        let mut table = HashMap::new();
+        table.insert("count".to_string(), 0x200);
+        let symbols = SymbolTable::new(&table, false);
+
+        assert_eq!(Ok(true), holds("[200h] == 1234h", &cpu, &memory, &symbols));
+        assert_eq!(
+            Ok(true),
+            holds("byte ptr [count + 1] == 12h", &cpu, &memory, &symbols)
+        );
+        assert_eq!(
+            Ok(true),
+            holds("word ptr [count] > count", &cpu, &memory, &symbols)
+        );
+    }
+}
@@ -54,17 +54,17 @@ macro_rules! setter_and_resetter_flag {
     ( $($flag:ident),+ ) => {
         paste! {
             $(
-                #[allow(non_snake_case)]
+                #[allow(non_snake_case, dead_code)]
                 pub fn [<set_ $flag>](&mut self) {
                     self.flags |= [<$flag _MASK>];
                 }
 
-                #[allow(non_snake_case)]
+                #[allow(non_snake_case, dead_code)]
                 pub fn [<reset_ $flag>](&mut self) {
                     self.flags &= ![<$flag _MASK>];
                 }
 
-                #[allow(non_snake_case)]
+                #[allow(non_snake_case, dead_code)]
                 pub fn [<get_ $flag>](&mut self) -> u16 {
                     self.flags & [<$flag _MASK>]
                 }
@@ -95,7 +95,12 @@ const DF_MASK: u16 = 1 << DF;
 const OF: u16 = 11; // Overflow: 1=overflow, 0=not-overflow
 const OF_MASK: u16 = 1 << OF;
 
-#[derive(Default)]
+/// 16-bit registers in the order of the snapshot and the trace
+pub const REGISTERS16: [&str; 14] = [
+    "ax", "bx", "cx", "dx", "si", "di", "bp", "sp", "cs", "ds", "es", "ss", "ip", "flags",
+];
+
+#[derive(Default, Clone, PartialEq)]
 pub struct CpuContext {
     // General Registers
     ax: u16,
@@ -172,10 +177,12 @@ impl CpuContext {
 
     Generic interface get_register and set_register should be used
     only when the number of bits is ambiguous.
+
+    The register names are case-insensitive as like the parser: e.g. "AX"
     */
 
     pub fn get_register(&self, reg: &str) -> Result<u16, String> {
-        match reg {
+        match reg.to_ascii_lowercase().as_str() {
             "ax" | "bx" | "cx" | "dx" | "si" | "di" | "bp" | "sp" | "cs" | "ds" | "es" | "ss"
             | "ip" | "flags" => Ok(self.get_register16(reg)),
             "al" | "ah" | "bl" | "bh" | "cl" | "ch" | "dl" | "dh" => {
@@ -188,8 +195,23 @@ impl CpuContext {
         }
     }
 
+    /// Flag by its name: e.g. "ZF" => true if zero
+    pub fn get_flag(&self, flag: &str) -> Result<bool, String> {
+        let mask = match flag.to_ascii_lowercase().as_str() {
+            "cf" => CF_MASK,
+            "pf" => PF_MASK,
+            "zf" => ZF_MASK,
+            "sf" => SF_MASK,
+            "if" => IF_MASK,
+            "df" => DF_MASK,
+            "of" => OF_MASK,
+            _ => return Err(format!("Wrong flag specified for get_flag: {}", flag)),
+        };
+        Ok(self.flags & mask != 0)
+    }
+
     pub fn get_register16(&self, reg: &str) -> u16 {
-        let r = match reg {
+        let r = match reg.to_ascii_lowercase().as_str() {
             "ax" => self.get_ax(),
             "bx" => self.get_bx(),
             "cx" => self.get_cx(),
@@ -210,7 +232,7 @@ impl CpuContext {
     }
 
     pub fn get_register8(&self, reg: &str) -> u8 {
-        let r: u8 = match reg {
+        let r: u8 = match reg.to_ascii_lowercase().as_str() {
             "al" => self.get_al(),
             "ah" => self.get_ah(),
             "bl" => self.get_bl(),
@@ -225,18 +247,22 @@ impl CpuContext {
     }
 
     pub fn set_register(&mut self, reg: &str, v: u16) -> Result<(), String> {
-        match reg {
+        match reg.to_ascii_lowercase().as_str() {
             "ax" | "bx" | "cx" | "dx" | "si" | "di" | "bp" | "sp" | "cs" | "ds" | "es" | "ss"
-            | "ip" | "flags" => Ok(self.set_register16(reg, v)),
+            | "ip" | "flags" => {
+                self.set_register16(reg, v);
+                Ok(())
+            }
             "al" | "ah" | "bl" | "bh" | "cl" | "ch" | "dl" | "dh" => {
-                Ok(self.set_register8(reg, (v & 0xff) as u8))
+                self.set_register8(reg, (v & 0xff) as u8);
+                Ok(())
             }
             _ => Err(format!("Wrong register specified for set_register:{}", reg)),
         }
     }
 
     pub fn set_register16(&mut self, reg: &str, v: u16) {
-        match reg {
+        match reg.to_ascii_lowercase().as_str() {
             "ax" => self.set_ax(v),
             "bx" => self.set_bx(v),
             "cx" => self.set_cx(v),
@@ -256,7 +282,7 @@ impl CpuContext {
     }
 
     pub fn set_register8(&mut self, reg: &str, v: u8) {
-        match reg {
+        match reg.to_ascii_lowercase().as_str() {
             "al" => self.set_al(v),
             "ah" => self.set_ah(v),
             "bl" => self.set_bl(v),
@@ -292,7 +318,7 @@ impl CpuContext {
         if self.flags & OF_MASK != 0 {
             r.push_str(" OF");
         }
-        if r.len() == 0 {
+        if r.is_empty() {
             r.push_str("no flag yet");
         }
         r
@@ -351,29 +377,34 @@ mod tests {
         assert_eq!(ZF_MASK, cpu.get_ZF());
         cpu.reset_ZF();
         assert_eq!(0, cpu.flags);
+
+        cpu.set_CF();
+        assert_eq!(Ok(true), cpu.get_flag("CF"));
+        assert_eq!(Ok(false), cpu.get_flag("zf"));
+        assert!(cpu.get_flag("xf").is_err());
     }
 
     #[test]
     fn test_cpucontext_get_set_register() {
         let mut cpu = CpuContext::boot();
 
-        let reg = vec!["ax", "bx", "cx", "dx"];
-        let regh = vec!["ah", "bh", "ch", "dh"];
-        let regl = vec!["al", "bl", "cl", "dl"];
+        let reg = ["ax", "bx", "cx", "dx"];
+        let regh = ["ah", "bh", "ch", "dh"];
+        let regl = ["al", "bl", "cl", "dl"];
 
         // 8/16bit-operations
         for i in 0..reg.len() {
-            let _ = cpu.set_register16(reg[i], 0x1234);
+            cpu.set_register16(reg[i], 0x1234);
             assert_eq!(0x1234, cpu.get_register16(reg[i]));
             assert_eq!(0x12, cpu.get_register8(regh[i]));
             assert_eq!(0x34, cpu.get_register8(regl[i]));
 
-            let _ = cpu.set_register8(regh[i], 0x37);
+            cpu.set_register8(regh[i], 0x37);
             assert_eq!(0x3734, cpu.get_register16(reg[i]));
             assert_eq!(0x37, cpu.get_register8(regh[i]));
             assert_eq!(0x34, cpu.get_register8(regl[i]));
 
-            let _ = cpu.set_register8(regl[i], 0x11);
+            cpu.set_register8(regl[i], 0x11);
             assert_eq!(0x3711, cpu.get_register16(reg[i]));
             assert_eq!(0x37, cpu.get_register8(regh[i]));
             assert_eq!(0x11, cpu.get_register8(regl[i]));
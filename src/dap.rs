@@ -0,0 +1,565 @@
+use crate::breakpoint::{Location, Stop};
+use crate::cpucontext::REGISTERS16;
+use crate::{callstack, cli, memory, Hardware8086};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
+
+/*
+Debug Adapter Protocol server
+
+An editor debugs an assembly source with the messages of "Content-Length: N\r\n\r\n{json}".
+VS Code connects to the server with "debugServer" in launch.json:
+{"type": "remu8086", "request": "launch", "name": "Debug", "program": "${file}", "stopOnEntry": true, "debugServer": 4711}
+request           | action
+launch            | build the program and its include files in the directory of the program
+setBreakpoints    | breakpoints and conditions on the lines of the program
+next              | step over: a call runs until it returns
+stepIn            | one line
+stepOut           | run until a ret returns from the procedure
+continue          | run until a breakpoint, hlt or the end of the program
+stackTrace        | the current line and the calls of the callers on the stack
+scopes, variables | registers, flags and the data of the program
+The lines without code (labels, comments and directives) are stepped over.
+pause fails: the server reads the next request after continue, next or stepOut stops.
+Each connection debugs its own machine: not the machine of the web page.
+*/
+
+pub const DAP_ADDRESS: &str = "127.0.0.1:4711";
+// continue, next and stepOut stop after this number of steps: e.g. infinite loop
+const RUN_STEPS: usize = 1_000_000;
+// The only thread of the CPU
+const THREAD_ID: u64 = 1;
+// variablesReference of the scopes
+const REGISTERS_REFERENCE: u64 = 1;
+const FLAGS_REFERENCE: u64 = 2;
+const DATA_REFERENCE: u64 = 3;
+const FLAGS: [&str; 7] = ["cf", "pf", "zf", "sf", "if", "df", "of"];
+
+/// Serve the editors on the address: one connection at a time
+pub fn serve(address: &str) -> Result<(), String> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+    println!("DAP server started at {}", address);
+    for stream in listener.incoming() {
+        let stream = stream.map_err(|e| e.to_string())?;
+        let reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+        if let Err(e) = Session::new(reader, stream).run() {
+            println!("dap: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Body of the next message: None at the end of the stream
+fn read_message(reader: &mut impl BufRead) -> Result<Option<Value>, String> {
+    let mut length: Option<usize> = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = Some(
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid header {}", header))?,
+                );
+            }
+        }
+    }
+    let length = length.ok_or("No Content-Length in the message")?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> Result<(), String> {
+    let body = message.to_string();
+    let message = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+    writer
+        .write_all(message.as_bytes())
+        .map_err(|e| e.to_string())?;
+    writer.flush().map_err(|e| e.to_string())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    match bytes {
+        [byte] => format!("{:02X}h", byte),
+        [low, high] => format!("{:04X}h", u16::from_le_bytes([*low, *high])),
+        _ => bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+struct Session<R, W> {
+    reader: R,
+    writer: W,
+    seq: u64,
+    hardware: Hardware8086,
+    // Next line of the program
+    line: usize,
+    // Path of the program given by launch
+    program: Option<String>,
+    stop_on_entry: bool,
+    // Breakpoints of setBreakpoints replaced by the next setBreakpoints
+    breakpoint_ids: Vec<usize>,
+}
+
+impl<R: BufRead, W: Write> Session<R, W> {
+    fn new(reader: R, writer: W) -> Self {
+        Session {
+            reader,
+            writer,
+            seq: 0,
+            hardware: Hardware8086::new(),
+            line: 0,
+            program: None,
+            stop_on_entry: false,
+            breakpoint_ids: Vec::new(),
+        }
+    }
+
+    fn run(&mut self) -> Result<(), String> {
+        while let Some(request) = read_message(&mut self.reader)? {
+            let command = request["command"].as_str().unwrap_or("").to_owned();
+            let result = self.handle(&command, &request["arguments"]);
+            let mut response = json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": result.is_ok(),
+            });
+            match &result {
+                Ok(body) => response["body"] = body.clone(),
+                Err(e) => response["message"] = json!(e),
+            }
+            self.send(response)?;
+            if result.is_ok() {
+                match command.as_str() {
+                    "launch" => self.event("initialized", Value::Null)?,
+                    "configurationDone" if self.stop_on_entry => self.stopped("entry", None)?,
+                    "configurationDone" | "continue" => {
+                        let stop = self.hardware.run(self.line, RUN_STEPS);
+                        self.report(stop)?;
+                    }
+                    "next" => {
+                        let stop = self.hardware.step_over(self.line, RUN_STEPS);
+                        self.report(stop)?;
+                    }
+                    "stepIn" => {
+                        let stop = self.hardware.run_until(self.line, 1, |_, _, _| true);
+                        self.report(stop)?;
+                    }
+                    "stepOut" => {
+                        let stop = self.hardware.step_out(self.line, RUN_STEPS);
+                        self.report(stop)?;
+                    }
+                    "disconnect" | "terminate" => return Ok(()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Body of the response
+    fn handle(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportTerminateDebuggee": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "8086" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                { "name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false },
+                { "name": "Data", "variablesReference": DATA_REFERENCE, "expensive": false },
+            ]})),
+            "variables" => Ok(json!({ "variables": self.variables(&args["variablesReference"]) })),
+            "configurationDone" | "continue" | "next" | "stepIn" | "stepOut" | "disconnect"
+            | "terminate" => Ok(json!({})),
+            // The requests are read after continue stops: pause would come too late.
+            "pause" => Err(format!(
+                "pause is not supported: continue stops after {} steps",
+                RUN_STEPS
+            )),
+            _ => Err(format!("{} is not supported", command)),
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or("launch should have the program")?;
+        cli::build_source(&mut self.hardware, program, None)?;
+        self.program = Some(program.to_owned());
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.line = 0;
+        self.skip_empty_lines();
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        for id in self.breakpoint_ids.drain(..) {
+            // The breakpoints of the previous program are gone.
+            let _ = self.hardware.breakpoints.remove(id);
+        }
+        let path = args["source"]["path"].as_str().unwrap_or("");
+        let in_program = self.program.as_deref().is_some_and(|p| same_file(p, path));
+        let mut list = Vec::new();
+        for b in args["breakpoints"].as_array().into_iter().flatten() {
+            let Some(line) = b["line"].as_u64().filter(|l| *l > 0) else {
+                continue;
+            };
+            let linenum = line as usize - 1;
+            let verified = in_program && self.hardware.opcode_at(linenum).is_some();
+            if !verified {
+                list.push(
+                    json!({ "verified": false, "line": line, "message": "No code on the line" }),
+                );
+                continue;
+            }
+            let condition = b["condition"]
+                .as_str()
+                .filter(|c| !c.trim().is_empty())
+                .map(|c| c.to_owned());
+            if let Some(condition) = &condition {
+                crate::condition::parse(condition)?;
+            }
+            let id = self
+                .hardware
+                .breakpoints
+                .add(Location::Line(linenum), condition);
+            self.breakpoint_ids.push(id);
+            list.push(json!({ "id": id, "verified": true, "line": line }));
+        }
+        Ok(json!({ "breakpoints": list }))
+    }
+
+    /// The current line and the call instructions of the callers
+    fn stack_trace(&self) -> Value {
+        let frames: Vec<Value> = callstack::frames(&self.hardware, self.line)
+            .iter()
+            .enumerate()
+            .map(|(id, frame)| self.frame(id, frame))
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn frame(&self, id: usize, frame: &callstack::Frame) -> Value {
+        let address = format!("{:04X}:{:04X}", frame.cs, frame.ip);
+        let mut value = json!({
+            "id": id,
+            "name": frame.name.clone().unwrap_or_else(|| address.clone()),
+            "line": frame.line.map_or(0, |l| l + 1),
+            "column": 1,
+            "instructionPointerReference": address,
+        });
+        if let (Some(program), Some(_)) = (&self.program, frame.line) {
+            let name = Path::new(program)
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned());
+            value["source"] = json!({ "name": name, "path": program });
+        }
+        value
+    }
+
+    fn variables(&self, reference: &Value) -> Vec<Value> {
+        let cpu = &self.hardware.cpu;
+        match reference.as_u64() {
+            Some(REGISTERS_REFERENCE) => REGISTERS16
+                .iter()
+                .map(|reg| variable(reg, format!("{:04X}h", cpu.get_register16(reg))))
+                .collect(),
+            Some(FLAGS_REFERENCE) => FLAGS
+                .iter()
+                .map(|flag| {
+                    let value = cpu.get_flag(flag).unwrap_or(false) as u8;
+                    variable(flag, value.to_string())
+                })
+                .collect(),
+            Some(DATA_REFERENCE) => self.data(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Named data in the order of the addresses: the bytes of the data line
+    fn data(&self) -> Vec<Value> {
+        let Some(assembly) = self.hardware.assembly.as_ref() else {
+            return Vec::new();
+        };
+        let mut names: Vec<(&String, u16)> = assembly
+            .symbols
+            .iter()
+            .filter(|(name, _)| !assembly.labels.iter().any(|l| &l.name == *name))
+            .map(|(name, address)| (name, *address))
+            .collect();
+        names.sort_by_key(|(name, address)| (*address, name.to_string()));
+        names
+            .into_iter()
+            .map(|(name, address)| {
+                let line = assembly
+                    .lines
+                    .iter()
+                    .find(|l| l.address == address && !l.code.is_empty());
+                let len = line.map_or(2, |l| l.code.len().min(16));
+                // The data of a segment is loaded after the PSP, otherwise it is in DS.
+                let (segment, offset) = line
+                    .and_then(|l| self.hardware.line_address(l.linenum))
+                    .unwrap_or((self.hardware.cpu.get_register16("ds"), address));
+                let physical = memory::physical_address(segment, offset);
+                let bytes = self.hardware.memory.dump(physical, len);
+                variable(name, hex(&bytes))
+            })
+            .collect()
+    }
+
+    /// The lines without code take a step each as like /step.
+    fn skip_empty_lines(&mut self) {
+        while !self.hardware.binary
+            && self.line < self.hardware.program.len()
+            && self.hardware.opcode_at(self.line).is_none()
+        {
+            match self.hardware.step(self.line) {
+                Ok(line) => self.line = line,
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Event of the stop: hlt and the end of the program terminate the debugging.
+    fn report(&mut self, (line, stop, _steps): (usize, Stop, usize)) -> Result<(), String> {
+        self.line = line;
+        match stop {
+            Stop::Breakpoint(id) => self.stopped("breakpoint", Some(json!([id]))),
+            Stop::Watchpoint { id, .. } => self.stopped("data breakpoint", Some(json!([id]))),
+            Stop::Step => {
+                self.skip_empty_lines();
+                self.stopped("step", None)
+            }
+            Stop::Budget => self.stopped("pause", None),
+            Stop::Error(e) => {
+                self.event(
+                    "output",
+                    json!({ "category": "stderr", "output": format!("{}\n", e) }),
+                )?;
+                self.stopped("exception", None)
+            }
+            Stop::Halt | Stop::End => {
+                self.event("exited", json!({ "exitCode": 0 }))?;
+                self.event("terminated", json!({}))
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, breakpoints: Option<Value>) -> Result<(), String> {
+        let mut body =
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(ids) = breakpoints {
+            body["hitBreakpointIds"] = ids;
+        }
+        self.event("stopped", body)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<(), String> {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.send(message)
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<(), String> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.writer, &message)
+    }
+}
+
+/// The editor may give another form of the same path.
+fn same_file(a: &str, b: &str) -> bool {
+    match (Path::new(a).canonicalize(), Path::new(b).canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::io::Cursor;
+
+    fn request(seq: usize, command: &str, arguments: Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let message =
+            json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments });
+        write_message(&mut bytes, &message).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_dap_session() {
+        let path = std::env::temp_dir().join(format!("remu8086_dap_{}.as", std::process::id()));
+        let source = [
+            "org 100h",
+            "  mov sp, 200h",
+            "  mov ax, 1",
+            "  call double",
+            "  inc ax",
+            "  hlt",
+            "double:",
+            "  push bp",
+            "  mov bp, sp",
+            "  add ax, ax",
+            "  pop bp",
+            "  ret",
+            "count dw 7",
+        ];
+        std::fs::write(&path, source.join("\n")).unwrap();
+        let program = path.to_string_lossy().into_owned();
+
+        let requests: Vec<(&str, Value)> = vec![
+            ("initialize", json!({ "adapterID": "remu8086" })),
+            ("launch", json!({ "program": program, "stopOnEntry": true })),
+            (
+                "setBreakpoints",
+                json!({ "source": { "path": program }, "breakpoints": [{ "line": 10 }, { "line": 7 }] }),
+            ),
+            ("configurationDone", json!({})),
+            ("next", json!({ "threadId": 1 })),
+            ("next", json!({ "threadId": 1 })),
+            ("next", json!({ "threadId": 1 })),
+            ("stackTrace", json!({ "threadId": 1 })),
+            (
+                "variables",
+                json!({ "variablesReference": REGISTERS_REFERENCE }),
+            ),
+            ("variables", json!({ "variablesReference": DATA_REFERENCE })),
+            ("stepOut", json!({ "threadId": 1 })),
+            ("stackTrace", json!({ "threadId": 1 })),
+            ("continue", json!({ "threadId": 1 })),
+            ("disconnect", json!({})),
+            ("threads", json!({})),
+        ];
+        let mut input = Vec::new();
+        for (i, (command, arguments)) in requests.into_iter().enumerate() {
+            input.extend(request(i + 1, command, arguments));
+        }
+        let mut output = Vec::new();
+        Session::new(Cursor::new(input), &mut output).run().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut reader = Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        let summary: Vec<String> = messages
+            .iter()
+            .map(|m| match m["type"].as_str() {
+                Some("response") => format!("{} {}", m["command"].as_str().unwrap(), m["success"]),
+                _ if m["event"] == "stopped" => {
+                    format!("stopped {}", m["body"]["reason"].as_str().unwrap())
+                }
+                _ => m["event"].as_str().unwrap().to_string(),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "initialize true",
+                "launch true",
+                "initialized",
+                "setBreakpoints true",
+                "configurationDone true",
+                "stopped entry",
+                "next true",
+                "stopped step",
+                "next true",
+                "stopped step",
+                "next true",
+                "stopped breakpoint",
+                "stackTrace true",
+                "variables true",
+                "variables true",
+                "stepOut true",
+                "stopped step",
+                "stackTrace true",
+                "continue true",
+                "exited",
+                "terminated",
+                "disconnect true",
+            ],
+            summary
+        );
+        let body = |i: usize| &messages[i]["body"];
+        // The 7th line is a label without code.
+        assert_eq!(
+            json!([{ "id": 1, "verified": true, "line": 10 }, { "verified": false, "line": 7, "message": "No code on the line" }]),
+            body(3)["breakpoints"]
+        );
+        assert_eq!(json!([1]), body(11)["hitBreakpointIds"]);
+
+        // Frames of the BP chain: double is called at the 4th line.
+        let frames = body(12)["stackFrames"].as_array().unwrap();
+        assert_eq!(2, frames.len());
+        assert_eq!(
+            (&json!("double"), &json!(10)),
+            (&frames[0]["name"], &frames[0]["line"])
+        );
+        assert_eq!(json!(4), frames[1]["line"]);
+        assert_eq!(json!(program), frames[1]["source"]["path"]);
+        assert_eq!(
+            json!({ "name": "ax", "value": "0001h", "variablesReference": 0 }),
+            body(13)["variables"][0]
+        );
+        assert_eq!(
+            json!([{ "name": "count", "value": "0007h", "variablesReference": 0 }]),
+            body(14)["variables"]
+        );
+        let frames = body(17)["stackFrames"].as_array().unwrap();
+        assert_eq!((1, &json!(5)), (frames.len(), &frames[0]["line"]));
+    }
+
+    #[test]
+    fn test_dap_errors() {
+        let mut input = request(1, "launch", json!({ "program": "no_such_file.as" }));
+        input.extend(request(2, "evaluate", json!({ "expression": "ax" })));
+        input.extend(request(3, "pause", json!({ "threadId": 1 })));
+        let mut output = Vec::new();
+        Session::new(Cursor::new(input), &mut output).run().unwrap();
+        let mut reader = Cursor::new(output);
+        let launch = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(json!(false), launch["success"]);
+        assert!(launch["message"]
+            .as_str()
+            .unwrap()
+            .contains("no_such_file.as"));
+        let evaluate = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(json!("evaluate is not supported"), evaluate["message"]);
+        let pause = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(json!(false), pause["success"]);
+        assert_eq!(None, read_message(&mut reader).unwrap());
+    }
+}
@@ -0,0 +1,152 @@
+use crate::assembler::{operand_value, sized_value, SymbolTable};
+use crate::parser::Rule;
+use pest::iterators::{Pair, Pairs};
+
+/*
+Data directives do not generate instructions but the bytes of the data.
+
+db: byte, dw: word (2 bytes), dd: double word (4 bytes)
+Each item is stored in little-endian with the size of the directive.
+
+e.g. db 12h, 'AB', ? => 12 41 42 00
+e.g. dw 1234h, 'A' => 34 12 41 00
+e.g. dw 2 dup(1, ?) => 01 00 00 00 01 00 00 00
+e.g. dw msg => address of msg
+e.g. db -1, 'A' + 1 => ff 42
+
+Uninitialized data(?) is filled with 0.
+*/
+
+fn item_size(directive: &Pair<Rule>) -> Result<usize, String> {
+    match directive.as_rule() {
+        Rule::db => Ok(1),
+        Rule::dw => Ok(2),
+        Rule::dd => Ok(4),
+        _ => Err(format!("{} is not a data directive", directive.as_str())),
+    }
+}
+
+fn push_value(v: &mut Vec<u8>, value: u32, size: usize) {
+    // Little-endian: first low byte, second high byte
+    v.extend_from_slice(&value.to_le_bytes()[..size]);
+}
+
+fn assemble_items(
+    items: Pairs<Rule>,
+    size: usize,
+    symbols: &SymbolTable,
+    v: &mut Vec<u8>,
+) -> Result<(), String> {
+    for item in items {
+        match item.as_rule() {
+            Rule::imm | Rule::expr => {
+                let value = sized_value(&item, size, symbols)?;
+                push_value(v, value, size);
+            }
+            Rule::string => {
+                // Remove the quotes and each character is a byte
+                let s = item.as_str();
+                let start = v.len();
+                v.extend(s[1..s.len() - 1].bytes());
+                // Pad to the item size: e.g. dw 'A' => 41 00
+                let len = v.len() - start;
+                v.resize(start + len.div_ceil(size).max(1) * size, 0);
+            }
+            Rule::uninit => v.resize(v.len() + size, 0),
+            Rule::dup => {
+                let mut inner = item.into_inner();
+                let count = operand_value(&inner.next().unwrap(), symbols)?;
+                let mut once: Vec<u8> = Vec::new();
+                assemble_items(inner, size, symbols, &mut once)?;
+                for _ in 0..count {
+                    v.extend_from_slice(&once);
+                }
+            }
+            _ => return Err(format!("Unknown data item {}", item.as_str())),
+        }
+    }
+    Ok(())
+}
+
+/// Translate db/dw/dd directive into the bytes of the data
+pub fn assemble_data(directive: &Pair<Rule>, symbols: &SymbolTable) -> Result<Vec<u8>, String> {
+    let size = item_size(directive)?;
+    let mut v: Vec<u8> = Vec::new();
+    assemble_items(directive.clone().into_inner(), size, symbols, &mut v)?;
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::parser::AssemblyParser;
+    use pest::Parser;
+    use std::collections::HashMap;
+
+    fn assemble(s: &str, symbols: &SymbolTable) -> Result<Vec<u8>, String> {
+        let data = AssemblyParser::parse(Rule::instruction, s)
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(Rule::data, data.as_rule());
+        let directive = data
+            .into_inner()
+            .find(|p| p.as_rule() != Rule::name)
+            .unwrap();
+        assemble_data(&directive, symbols)
+    }
+
+    #[test]
+    fn test_data_db() {
+        let symbols = SymbolTable::default();
+        assert_eq!(Ok(vec![0x12]), assemble("db 12h", &symbols));
+        assert_eq!(
+            Ok(vec![0x48, 0x69, 0x0d, 0x0a, 0x24]),
+            assemble("msg db 'Hi', 0dh, 0ah, \"$\"", &symbols)
+        );
+        assert_eq!(Ok(vec![0, 0, 0]), assemble("buffer db 3h dup(?)", &symbols));
+        assert_eq!(
+            Ok(vec![0xff, 0x42, 0x80]),
+            assemble("db -1, 'A' + 1, -128", &symbols)
+        );
+        assert!(assemble("db 100h", &symbols).is_err());
+        assert!(assemble("db -129", &symbols).is_err());
+    }
+
+    #[test]
+    fn test_data_dw_dd() {
+        let symbols = SymbolTable::default();
+        assert_eq!(
+            Ok(vec![0x34, 0x12, 0x00, 0x00]),
+            assemble("value dw 1234h, ?", &symbols)
+        );
+        assert_eq!(Ok(vec![0x41, 0x00]), assemble("dw 'A'", &symbols));
+        assert_eq!(
+            Ok(vec![0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]),
+            assemble("dw 2h dup(1h, ?)", &symbols)
+        );
+        assert_eq!(
+            Ok(vec![0x78, 0x56, 0x34, 0x12]),
+            assemble("dd 12345678h", &symbols)
+        );
+        assert_eq!(
+            Ok(vec![0xff, 0xff, 0x0a, 0x00]),
+            assemble("dw -1, 2 * 5", &symbols)
+        );
+        assert!(assemble("dw 65536", &symbols).is_err());
+    }
+
+    #[test]
+    fn test_data_symbol() {
+        let mut table = HashMap::new();
+        table.insert("msg".to_string(), 0x0123);
+        let symbols = SymbolTable::new(&table, false);
+        assert_eq!(Ok(vec![0x23, 0x01]), assemble("pmsg dw msg", &symbols));
+        assert!(assemble("dw nothing", &symbols).is_err());
+
+        // 1st pass: undefined symbol is 0
+        let symbols = SymbolTable::new(&table, true);
+        assert_eq!(Ok(vec![0, 0]), assemble("dw nothing", &symbols));
+    }
+}
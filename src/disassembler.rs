@@ -0,0 +1,359 @@
+/*
+Disassembler for the machine code that the emulator can execute
+
+The instruction handlers take a parsed assembly line.
+So a machine instruction is decoded into the assembly text first
+and the text is parsed and passed to the handler as like the source code.
+
+Only the forms that the handlers understand are decoded.
+e.g. B8 34 12 => mov ax, 0x1234
+e.g. FF 87 34 12 => inc word ptr [bx + 0x1234]
+
+ModR/M byte
+bit 7-6: mod
+bit 5-3: reg (or opcode extension)
+bit 2-0: r/m
+
+See add.rs and inc.rs for the mod, register and base/index tables.
+*/
+
+const REG16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+const REG8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+const SREG: [&str; 4] = ["es", "cs", "ss", "ds"];
+const BASE_INDEX: [&str; 8] = [
+    "bx + si", "bx + di", "bp + si", "bp + di", "si", "di", "bp", "bx",
+];
+
+fn byte_at(code: &[u8], i: usize) -> Result<u8, String> {
+    code.get(i)
+        .copied()
+        .ok_or_else(|| format!("Instruction is truncated: {:02X?}", code))
+}
+
+fn word_at(code: &[u8], i: usize) -> Result<u16, String> {
+    // Little-endian: first low byte, second high byte
+    Ok(byte_at(code, i)? as u16 | (byte_at(code, i + 1)? as u16) << 8)
+}
+
+fn hex(v: u16) -> String {
+    format!("0x{:x}", v)
+}
+
+fn ptr(wbit: u8) -> &'static str {
+    if wbit == 1 {
+        "word ptr"
+    } else {
+        "byte ptr"
+    }
+}
+
+fn register(wbit: u8, reg: u8) -> &'static str {
+    if wbit == 1 {
+        REG16[reg as usize]
+    } else {
+        REG8[reg as usize]
+    }
+}
+
+/// Decode ModR/M byte and displacement at code[i]
+/// return: (reg field, r/m operand text, length of ModR/M and displacement)
+fn modrm(code: &[u8], i: usize, wbit: u8) -> Result<(u8, String, usize), String> {
+    let m = byte_at(code, i)?;
+    let modbit = m >> 6;
+    let reg = (m >> 3) & 0x7;
+    let rm = m & 0x7;
+    let r = match (modbit, rm) {
+        (0, 6) => {
+            // direct addressing: mod=00, rm=110
+            let address = word_at(code, i + 1)?;
+            return Ok((reg, format!("{} [{}]", ptr(wbit), hex(address)), 3));
+        }
+        (0, _) => (format!("{} [{}]", ptr(wbit), BASE_INDEX[rm as usize]), 1),
+        (1, _) => {
+            // 8-bit displacement is sign extended to 16 bits
+            let disp = byte_at(code, i + 1)? as i8 as i16 as u16;
+            (
+                format!(
+                    "{} [{} + {}]",
+                    ptr(wbit),
+                    BASE_INDEX[rm as usize],
+                    hex(disp)
+                ),
+                2,
+            )
+        }
+        (2, _) => {
+            let disp = word_at(code, i + 1)?;
+            (
+                format!(
+                    "{} [{} + {}]",
+                    ptr(wbit),
+                    BASE_INDEX[rm as usize],
+                    hex(disp)
+                ),
+                3,
+            )
+        }
+        _ => (register(wbit, rm).to_string(), 1),
+    };
+    Ok((reg, r.0, r.1))
+}
+
+/// Decode one instruction at the beginning of code
+/// return: (assembly text, length of the instruction)
+pub fn decode(code: &[u8]) -> Result<(String, usize), String> {
+    let opcode = byte_at(code, 0)?;
+    let wbit = opcode & 0x1;
+    match opcode {
+        // ADD r/m, reg or ADD reg, r/m: 0000_00dw mod reg r/m
+        // MOV r/m, reg or MOV reg, r/m: 1000_10dw mod reg r/m
+        0x00..=0x03 | 0x88..=0x8b => {
+            let mnemonic = if opcode < 0x88 { "add" } else { "mov" };
+            let (reg, rm, len) = modrm(code, 1, wbit)?;
+            let reg = register(wbit, reg);
+            if opcode & 0x2 != 0 {
+                Ok((format!("{} {}, {}", mnemonic, reg, rm), 1 + len))
+            } else {
+                Ok((format!("{} {}, {}", mnemonic, rm, reg), 1 + len))
+            }
+        }
+        // MOV r/m16, sreg or MOV sreg, r/m16: 1000_11d0 mod 0sreg r/m
+        0x8c | 0x8e => {
+            let (reg, rm, len) = modrm(code, 1, 1)?;
+            let sreg = SREG
+                .get(reg as usize)
+                .ok_or_else(|| format!("Not supported opcode {:02X} /{}", opcode, reg))?;
+            if opcode == 0x8e {
+                Ok((format!("mov {}, {}", sreg, rm), 1 + len))
+            } else {
+                Ok((format!("mov {}, {}", rm, sreg), 1 + len))
+            }
+        }
+        // ADD AL, imm8
+        0x04 => Ok((format!("add al, {}", hex(byte_at(code, 1)? as u16)), 2)),
+        // ADD AX, imm16
+        0x05 => Ok((format!("add ax, {}", hex(word_at(code, 1)?)), 3)),
+        // INC reg16: 0100_0reg
+        0x40..=0x47 => Ok((format!("inc {}", REG16[(opcode & 0x7) as usize]), 1)),
+        // PUSH reg16: 0101_0reg, POP reg16: 0101_1reg
+        0x50..=0x57 => Ok((format!("push {}", REG16[(opcode & 0x7) as usize]), 1)),
+        0x58..=0x5f => Ok((format!("pop {}", REG16[(opcode & 0x7) as usize]), 1)),
+        // PUSH sreg: 000s_r110, POP sreg: 000s_r111 (0F is not pop cs)
+        0x06 | 0x0e | 0x16 | 0x1e => Ok((format!("push {}", SREG[(opcode >> 3) as usize]), 1)),
+        0x07 | 0x17 | 0x1f => Ok((format!("pop {}", SREG[(opcode >> 3) as usize]), 1)),
+        // ADD r/m, imm: 1000_00sw mod 000 r/m
+        0x80 | 0x81 | 0x83 => {
+            let (reg, rm, len) = modrm(code, 1, wbit)?;
+            if reg != 0 {
+                return Err(format!("Not supported opcode {:02X} /{}", opcode, reg));
+            }
+            let imm = match opcode {
+                0x80 => byte_at(code, 1 + len)? as u16,
+                0x81 => word_at(code, 1 + len)?,
+                _ => byte_at(code, 1 + len)? as i8 as i16 as u16, // sign extended
+            };
+            let immlen = if opcode == 0x81 { 2 } else { 1 };
+            Ok((format!("add {}, {}", rm, hex(imm)), 1 + len + immlen))
+        }
+        // MOV AL/AX, mem: 1010_000w
+        0xa0 | 0xa1 => Ok((
+            format!(
+                "mov {}, {} [{}]",
+                register(wbit, 0),
+                ptr(wbit),
+                hex(word_at(code, 1)?)
+            ),
+            3,
+        )),
+        // MOV mem, AL/AX: 1010_001w
+        0xa2 | 0xa3 => Ok((
+            format!(
+                "mov {} [{}], {}",
+                ptr(wbit),
+                hex(word_at(code, 1)?),
+                register(wbit, 0)
+            ),
+            3,
+        )),
+        // MOV reg8, imm8: 1011_0reg
+        0xb0..=0xb7 => Ok((
+            format!(
+                "mov {}, {}",
+                REG8[(opcode & 0x7) as usize],
+                hex(byte_at(code, 1)? as u16)
+            ),
+            2,
+        )),
+        // MOV reg16, imm16: 1011_1reg
+        0xb8..=0xbf => Ok((
+            format!(
+                "mov {}, {}",
+                REG16[(opcode & 0x7) as usize],
+                hex(word_at(code, 1)?)
+            ),
+            3,
+        )),
+        // MOV r/m, imm: 1100_011w mod 000 r/m
+        0xc6 | 0xc7 => {
+            let (_, rm, len) = modrm(code, 1, wbit)?;
+            if wbit == 1 {
+                Ok((
+                    format!("mov {}, {}", rm, hex(word_at(code, 1 + len)?)),
+                    1 + len + 2,
+                ))
+            } else {
+                Ok((
+                    format!("mov {}, {}", rm, hex(byte_at(code, 1 + len)? as u16)),
+                    1 + len + 1,
+                ))
+            }
+        }
+        // INC r/m: 1111_111w mod 000 r/m
+        0xfe | 0xff => {
+            let (reg, rm, len) = modrm(code, 1, wbit)?;
+            if reg != 0 {
+                return Err(format!("Not supported opcode {:02X} /{}", opcode, reg));
+            }
+            Ok((format!("inc {}", rm), 1 + len))
+        }
+        _ => Err(format!("Not supported opcode {:02X}", opcode)),
+    }
+}
+
+/// Text and length of the machine instruction at ip for the trace and the memory viewer
+/// The control transfers of step_machine are decoded here: the handlers do not take them.
+pub fn machine_instruction(code: &[u8], ip: u16) -> Result<(String, usize), String> {
+    match byte_at(code, 0)? {
+        0xcd if byte_at(code, 1)? == 0x20 => Ok(("int 20h".to_string(), 2)),
+        0xc3 => Ok(("ret".to_string(), 1)),
+        0xc2 => Ok((format!("ret {:04X}h", word_at(code, 1)?), 3)),
+        0xe8 => {
+            let target = ip.wrapping_add(3).wrapping_add(word_at(code, 1)?);
+            Ok((format!("call {:04X}h", target), 3))
+        }
+        0xea => Ok((
+            format!("jmp {:04X}:{:04X}", word_at(code, 3)?, word_at(code, 1)?),
+            5,
+        )),
+        0xe9 => {
+            let target = ip.wrapping_add(3).wrapping_add(word_at(code, 1)?);
+            Ok((format!("jmp {:04X}h", target), 3))
+        }
+        0xeb => {
+            let target = ip
+                .wrapping_add(2)
+                .wrapping_add(byte_at(code, 1)? as i8 as u16);
+            Ok((format!("jmp {:04X}h", target), 2))
+        }
+        0xf4 => Ok(("hlt".to_string(), 1)),
+        _ => decode(code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::parser::{AssemblyParser, Rule};
+    use pest::Parser;
+
+    #[test]
+    fn test_disassembler_mov() {
+        assert_eq!(
+            Ok(("mov ax, 0x1234".to_string(), 3)),
+            decode(&[0xb8, 0x34, 0x12])
+        );
+        assert_eq!(Ok(("mov ax, bx".to_string(), 2)), decode(&[0x8b, 0xc3]));
+        assert_eq!(Ok(("mov ax, bx".to_string(), 2)), decode(&[0x89, 0xd8]));
+        assert_eq!(
+            Ok(("mov word ptr [0x1000], 0x1234".to_string(), 6)),
+            decode(&[0xc7, 0x06, 0x00, 0x10, 0x34, 0x12])
+        );
+        assert_eq!(
+            Ok(("mov ax, word ptr [0x0]".to_string(), 3)),
+            decode(&[0xa1, 0x00, 0x00])
+        );
+        assert_eq!(Ok(("mov ds, ax".to_string(), 2)), decode(&[0x8e, 0xd8]));
+        assert_eq!(
+            Ok(("mov word ptr [0x10], es".to_string(), 4)),
+            decode(&[0x8c, 0x06, 0x10, 0x00])
+        );
+        assert!(decode(&[0x8e, 0xe0]).is_err());
+    }
+
+    #[test]
+    fn test_disassembler_add_inc() {
+        assert_eq!(
+            Ok(("add cx, 0xdcba".to_string(), 4)),
+            decode(&[0x81, 0xc1, 0xba, 0xdc])
+        );
+        assert_eq!(
+            Ok(("add cx, word ptr [0x1000]".to_string(), 4)),
+            decode(&[0x03, 0x0e, 0x00, 0x10])
+        );
+        assert_eq!(Ok(("inc di".to_string(), 1)), decode(&[0x47]));
+        assert_eq!(Ok(("push bp".to_string(), 1)), decode(&[0x55]));
+        assert_eq!(Ok(("pop di".to_string(), 1)), decode(&[0x5f]));
+        assert_eq!(Ok(("push cs".to_string(), 1)), decode(&[0x0e]));
+        assert_eq!(Ok(("pop ds".to_string(), 1)), decode(&[0x1f]));
+        assert_eq!(
+            Ok(("inc byte ptr [bx + 0x10]".to_string(), 3)),
+            decode(&[0xfe, 0x47, 0x10])
+        );
+        assert_eq!(
+            Ok(("inc word ptr [bx + si + 0x1234]".to_string(), 4)),
+            decode(&[0xff, 0x80, 0x34, 0x12])
+        );
+    }
+
+    #[test]
+    fn test_disassembler_failure() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[0xb8, 0x34]).is_err());
+        assert!(decode(&[0xf4]).is_err());
+    }
+
+    #[test]
+    fn test_disassembler_parse_result() {
+        // Decoded text should be parsed by the assembly parser
+        let codes: Vec<&[u8]> = vec![
+            &[0xb8, 0x34, 0x12],
+            &[0x89, 0x0e, 0x00, 0x00],
+            &[0x01, 0x06, 0x00, 0x10],
+            &[0xff, 0x06, 0x12, 0x00],
+            &[0xfe, 0x84, 0x12, 0x00],
+            &[0x55],
+            &[0x1e],
+        ];
+        for code in codes {
+            let (text, _) = decode(code).unwrap();
+            let instruction = AssemblyParser::parse(Rule::instruction, &text)
+                .unwrap()
+                .next()
+                .unwrap();
+            assert_eq!(text, instruction.as_str());
+        }
+    }
+
+    #[test]
+    fn test_disassembler_machine_instruction() {
+        assert_eq!(
+            Ok(("call 0110h".to_string(), 3)),
+            machine_instruction(&[0xe8, 0x0d, 0x00], 0x100)
+        );
+        assert_eq!(
+            Ok(("jmp 00FEh".to_string(), 2)),
+            machine_instruction(&[0xeb, 0xfc], 0x100)
+        );
+        assert_eq!(
+            Ok(("jmp 1000:0100".to_string(), 5)),
+            machine_instruction(&[0xea, 0x00, 0x01, 0x00, 0x10], 0)
+        );
+        assert_eq!(
+            Ok(("mov ax, 0x1234".to_string(), 3)),
+            machine_instruction(&[0xb8, 0x34, 0x12], 0x100)
+        );
+        // A truncated instruction is an error, not a panic.
+        assert!(machine_instruction(&[0xe9, 0x00], 0x100).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+
+/*
+Diagnostics of the assembler
+
+Each error has the position in the source: file name (None for the main program),
+line and column starting from 1.
+The position of an expanded macro line is the line of the macro call
+and the column is in the expanded text.
+line 0 means the error is not on a line: e.g. the layout of the segments.
+
+The assembler checks all the lines and returns every error of the build.
+Warnings do not stop the build.
+e.g. Line 3: Undefined symbol nowhere
+e.g. consts.inc line 2: warning: Lines after end are ignored
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl AsmError {
+    /// Error without the position
+    pub fn new(message: &str) -> Self {
+        AsmError {
+            file: None,
+            line: 0,
+            column: 0,
+            severity: Severity::Error,
+            message: message.to_owned(),
+        }
+    }
+
+    /// Error at the line and column of the file
+    pub fn at(file: Option<String>, line: usize, column: usize, message: &str) -> Self {
+        AsmError {
+            file,
+            line,
+            column,
+            ..AsmError::new(message)
+        }
+    }
+
+    pub fn warning(self) -> Self {
+        AsmError {
+            severity: Severity::Warning,
+            ..self
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "file": self.file,
+            "line": self.line,
+            "column": self.column,
+            "severity": self.severity.to_string(),
+            "message": self.message,
+        })
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.line) {
+            (_, 0) => {}
+            (Some(file), line) => write!(f, "{} line {}: ", file, line)?,
+            (None, line) => write!(f, "Line {}: ", line)?,
+        }
+        if self.severity == Severity::Warning {
+            write!(f, "{}: ", self.severity)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// One error per line
+pub fn join(errors: &[AsmError]) -> String {
+    errors
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        let errors = vec![
+            AsmError::at(None, 3, 5, "Undefined symbol nowhere"),
+            AsmError::at(
+                Some("consts.inc".to_string()),
+                2,
+                1,
+                "Lines after end are ignored",
+            )
+            .warning(),
+            AsmError::new("Group dgroup is larger than 64KB"),
+        ];
+        assert_eq!(
+            "Line 3: Undefined symbol nowhere\nconsts.inc line 2: warning: Lines after end are ignored\nGroup dgroup is larger than 64KB",
+            join(&errors)
+        );
+        assert!(errors[0].is_error());
+        assert!(!errors[1].is_error());
+        assert_eq!(
+            serde_json::json!({
+                "file": "consts.inc",
+                "line": 2,
+                "column": 1,
+                "severity": "warning",
+                "message": "Lines after end are ignored",
+            }),
+            errors[1].to_json()
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::assembler::SymbolTable;
+use crate::parser::{imm_to_value, Rule};
+use pest::iterators::{Pair, Pairs};
+use pest::pratt_parser::{Assoc, Op, PrattParser};
+
+/*
+Constant expression evaluated by the assembler
+
+Operator precedence from the lowest:
+or
+and
+shl shr
++ -
+* / mod
+- not offset seg (unary)
+
+Values are calculated with 64-bit and the caller checks the range of the operand size.
+e.g. mov al, -1 => 0xff
+e.g. mov ax, msgend - msg => length of msg
+e.g. mov ax, seg msg => paragraph of the segment of msg
+*/
+
+fn pratt_parser() -> PrattParser<Rule> {
+    PrattParser::new()
+        .op(Op::infix(Rule::op_or, Assoc::Left))
+        .op(Op::infix(Rule::op_and, Assoc::Left))
+        .op(Op::infix(Rule::op_shl, Assoc::Left) | Op::infix(Rule::op_shr, Assoc::Left))
+        .op(Op::infix(Rule::op_add, Assoc::Left) | Op::infix(Rule::op_sub, Assoc::Left))
+        .op(Op::infix(Rule::op_mul, Assoc::Left)
+            | Op::infix(Rule::op_div, Assoc::Left)
+            | Op::infix(Rule::op_mod, Assoc::Left))
+        .op(Op::prefix(Rule::op_neg) | Op::prefix(Rule::op_not) | Op::prefix(Rule::op_offset))
+}
+
+fn evaluate_pairs(pairs: Pairs<Rule>, symbols: &SymbolTable) -> Result<i64, String> {
+    pratt_parser()
+        .map_primary(|primary| evaluate(&primary, symbols))
+        .map_prefix(|op, rhs| {
+            let rhs = rhs?;
+            match op.as_rule() {
+                Rule::op_neg => Ok(rhs.wrapping_neg()),
+                Rule::op_not => Ok(!rhs),
+                _ => Ok(rhs), // offset
+            }
+        })
+        .map_infix(|lhs, op, rhs| {
+            let (lhs, rhs) = (lhs?, rhs?);
+            match op.as_rule() {
+                Rule::op_add => Ok(lhs.wrapping_add(rhs)),
+                Rule::op_sub => Ok(lhs.wrapping_sub(rhs)),
+                Rule::op_mul => Ok(lhs.wrapping_mul(rhs)),
+                Rule::op_div | Rule::op_mod if rhs == 0 => {
+                    if symbols.lenient() {
+                        // 1st pass: the divisor can be an undefined symbol
+                        Ok(0)
+                    } else {
+                        Err("Division by zero".to_string())
+                    }
+                }
+                Rule::op_div => Ok(lhs.wrapping_div(rhs)),
+                Rule::op_mod => Ok(lhs.wrapping_rem(rhs)),
+                Rule::op_shl => Ok(lhs << (rhs & 0x3f)),
+                Rule::op_shr => Ok(lhs >> (rhs & 0x3f)),
+                Rule::op_and => Ok(lhs & rhs),
+                _ => Ok(lhs | rhs),
+            }
+        })
+        .parse(pairs)
+}
+
+/// Value of imm, name or expr
+pub fn evaluate(value: &Pair<Rule>, symbols: &SymbolTable) -> Result<i64, String> {
+    match value.as_rule() {
+        Rule::imm => Ok(imm_to_value(value)? as i64),
+        Rule::name => symbols.value(value.as_str()),
+        Rule::location => Ok(symbols.location() as i64),
+        Rule::segment_of => {
+            let name = value.clone().into_inner().last().unwrap();
+            Ok(symbols.frame(name.as_str())? as i64)
+        }
+        Rule::expr => evaluate_pairs(value.clone().into_inner(), symbols),
+        _ => Err(format!("{} is not a value", value.as_str())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::parser::AssemblyParser;
+    use pest::Parser;
+    use std::collections::HashMap;
+
+    fn calc(s: &str, symbols: &SymbolTable) -> Result<i64, String> {
+        let expr = AssemblyParser::parse(Rule::expr, s)
+            .unwrap()
+            .next()
+            .unwrap();
+        evaluate(&expr, symbols)
+    }
+
+    #[test]
+    fn test_expr_operators() {
+        let symbols = SymbolTable::default();
+        assert_eq!(Ok(7), calc("1 + 2 * 3", &symbols));
+        assert_eq!(Ok(9), calc("(1 + 2) * 3", &symbols));
+        assert_eq!(Ok(-5), calc("-5", &symbols));
+        assert_eq!(Ok(3), calc("10 - 5 - 2", &symbols));
+        assert_eq!(Ok(1), calc("10 mod 3", &symbols));
+        assert_eq!(Ok(3), calc("10 / 3", &symbols));
+        assert_eq!(Ok(0x100), calc("1 shl 8", &symbols));
+        assert_eq!(Ok(0x0f), calc("0ffh shr 4", &symbols));
+        assert_eq!(Ok(0x0f), calc("1fh and 0fh", &symbols));
+        assert_eq!(Ok(0xf0f), calc("0f00h or 0fh", &symbols));
+        assert_eq!(Ok(0xff00), calc("not 0ffh and 0ffffh", &symbols));
+        assert_eq!(Ok(0x42), calc("'A' + 1", &symbols));
+        assert_eq!(Ok(13), calc("1010b + 3", &symbols));
+        assert!(calc("1 / 0", &symbols).is_err());
+    }
+
+    #[test]
+    fn test_expr_symbols() {
+        let mut table = HashMap::new();
+        table.insert("msg".to_string(), 0x110);
+        table.insert("msgend".to_string(), 0x11d);
+        let symbols = SymbolTable::new(&table, false).at(0x105);
+        assert_eq!(Ok(13), calc("msgend - msg", &symbols));
+        assert_eq!(Ok(0x112), calc("offset msg + 2", &symbols));
+        assert_eq!(Ok(0x107), calc("$ + 2", &symbols));
+        assert!(calc("nothing + 1", &symbols).is_err());
+
+        // 1st pass: undefined symbol is 0
+        let symbols = SymbolTable::new(&table, true);
+        assert_eq!(Ok(0), calc("10 / nothing", &symbols));
+    }
+}
@@ -0,0 +1,565 @@
+use crate::{memory, Hardware8086, HardwareLock};
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+
+/*
+GDB remote serial protocol stub
+
+gdb connects with "target remote 127.0.0.1:1234" after "set architecture i8086".
+packet          | reply
+?               | S05
+g, G <regs>     | registers in the order of the i386 core: eax ecx edx ebx esp ebp esi edi eip eflags cs ss ds es fs gs
+p <n>, P <n>=<v>| one register
+m <addr>,<len>  | bytes in hex at the physical address
+M <addr>,<len>:<bytes>
+c, s            | continue and single step: S05 at a breakpoint, hlt or after the step
+Z0,<addr>,<kind>| software breakpoint at the physical address of CS:IP
+z0,<addr>,<kind>| remove it
+qXfer:features:read:target.xml | target description of i8086
+The registers are 32-bit in the packets and the upper 16 bits are 0: fs and gs are always 0.
+Ctrl-C (0x03) stops c with S02. int 20h exits the program with W00.
+The line mode steps the next line of the journal as like /step.
+G, P and M drop the entries of the journal: stepping back does not undo the writes of gdb.
+*/
+
+pub const GDB_ADDRESS: &str = "127.0.0.1:1234";
+
+// Registers of the i386 core feature: None is not in 8086.
+const REGISTERS: [Option<&str>; 16] = [
+    Some("ax"),
+    Some("cx"),
+    Some("dx"),
+    Some("bx"),
+    Some("sp"),
+    Some("bp"),
+    Some("si"),
+    Some("di"),
+    Some("ip"),
+    Some("flags"),
+    Some("cs"),
+    Some("ss"),
+    Some("ds"),
+    Some("es"),
+    None,
+    None,
+];
+// st0-st7 (10 bytes each) and fctrl, fstat, ftag, fiseg, fioff, foseg, fooff, fop (4 bytes each)
+const FPU_BYTES: usize = 8 * 10 + 8 * 4;
+// Instructions between the checks of Ctrl-C while the machine continues
+const STEPS_PER_CHECK: usize = 1000;
+const MAX_MEMORY_READ: usize = 0x1000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>i8086</architecture>
+  <feature name="org.gnu.gdb.i386.core">
+    <reg name="eax" bitsize="32" type="int32" regnum="0"/>
+    <reg name="ecx" bitsize="32" type="int32"/>
+    <reg name="edx" bitsize="32" type="int32"/>
+    <reg name="ebx" bitsize="32" type="int32"/>
+    <reg name="esp" bitsize="32" type="data_ptr"/>
+    <reg name="ebp" bitsize="32" type="data_ptr"/>
+    <reg name="esi" bitsize="32" type="int32"/>
+    <reg name="edi" bitsize="32" type="int32"/>
+    <reg name="eip" bitsize="32" type="code_ptr"/>
+    <reg name="eflags" bitsize="32" type="int32"/>
+    <reg name="cs" bitsize="32" type="int32"/>
+    <reg name="ss" bitsize="32" type="int32"/>
+    <reg name="ds" bitsize="32" type="int32"/>
+    <reg name="es" bitsize="32" type="int32"/>
+    <reg name="fs" bitsize="32" type="int32"/>
+    <reg name="gs" bitsize="32" type="int32"/>
+    <reg name="st0" bitsize="80" type="i387_ext"/>
+    <reg name="st1" bitsize="80" type="i387_ext"/>
+    <reg name="st2" bitsize="80" type="i387_ext"/>
+    <reg name="st3" bitsize="80" type="i387_ext"/>
+    <reg name="st4" bitsize="80" type="i387_ext"/>
+    <reg name="st5" bitsize="80" type="i387_ext"/>
+    <reg name="st6" bitsize="80" type="i387_ext"/>
+    <reg name="st7" bitsize="80" type="i387_ext"/>
+    <reg name="fctrl" bitsize="32" type="int" group="float"/>
+    <reg name="fstat" bitsize="32" type="int" group="float"/>
+    <reg name="ftag" bitsize="32" type="int" group="float"/>
+    <reg name="fiseg" bitsize="32" type="int" group="float"/>
+    <reg name="fioff" bitsize="32" type="int" group="float"/>
+    <reg name="foseg" bitsize="32" type="int" group="float"/>
+    <reg name="fooff" bitsize="32" type="int" group="float"/>
+    <reg name="fop" bitsize="32" type="int" group="float"/>
+  </feature>
+</target>
+"#;
+
+/// Serve gdb on the address in a thread: one connection at a time
+pub fn listen(address: &str, machine: Arc<HardwareLock>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    std::thread::spawn(move || serve(listener, machine));
+    Ok(())
+}
+
+fn serve(listener: TcpListener, machine: Arc<HardwareLock>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                println!("gdb: connected from {:?}", stream.peer_addr());
+                let mut session = Session {
+                    stream,
+                    machine: &machine,
+                    breakpoints: HashSet::new(),
+                    ack: true,
+                };
+                if let Err(e) = session.run() {
+                    println!("gdb: {}", e);
+                }
+            }
+            Err(e) => println!("gdb: {}", e),
+        }
+    }
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, b| sum.wrapping_add(b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("Odd hex digits: {}", text));
+    }
+    // The packet is lossy UTF-8: the slices are at the byte boundaries of the hex digits.
+    if !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hex {}", text));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| format!("Invalid hex {}", text))
+        })
+        .collect()
+}
+
+fn number(text: &str) -> Result<usize, String> {
+    usize::from_str_radix(text, 16).map_err(|_| format!("Invalid number {}", text))
+}
+
+/// "addr,len" of m, M, Z0 and z0
+fn address_length(text: &str) -> Result<(usize, usize), String> {
+    let (address, len) = text
+        .split_once(',')
+        .ok_or_else(|| format!("No length in {}", text))?;
+    let address = number(address)?;
+    if address >= memory::MEMORY_SIZE {
+        return Err(format!("Address {:X} is beyond 1MB", address));
+    }
+    Ok((address, number(len)?))
+}
+
+/// Physical address of the next instruction
+fn next_address(hardware: &Hardware8086) -> usize {
+    let (cs, ip) = match hardware.binary {
+        true => None,
+        false => hardware.line_address(hardware.journal.next_line()),
+    }
+    .unwrap_or((
+        hardware.cpu.get_register16("cs"),
+        hardware.cpu.get_register16("ip"),
+    ));
+    memory::physical_address(cs, ip)
+}
+
+/// Execute one instruction: the stop reply if the machine cannot continue
+fn step(hardware: &mut Hardware8086) -> Option<String> {
+    let line = hardware.journal.next_line();
+    if !hardware.binary && line >= hardware.program.len() {
+        return Some("W00".to_string());
+    }
+    match hardware.step(line) {
+        Err(e) if e.starts_with("Program terminated") => Some("W00".to_string()),
+        Err(e) => {
+            println!("gdb: {}", e);
+            // SIGILL
+            Some("S04".to_string())
+        }
+        Ok(_) if hardware.halted => Some("S05".to_string()),
+        Ok(_) => None,
+    }
+}
+
+struct Session<'a> {
+    stream: TcpStream,
+    machine: &'a HardwareLock,
+    // Physical addresses of Z0
+    breakpoints: HashSet<usize>,
+    // QStartNoAckMode turns off + and -.
+    ack: bool,
+}
+
+impl Session<'_> {
+    fn run(&mut self) -> Result<(), String> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                _ => self.handle(&packet),
+            };
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// Reply of the packet: empty for the packets not supported
+    fn handle(&mut self, packet: &str) -> String {
+        let result = match packet.as_bytes().first() {
+            Some(b'?') => Ok("S05".to_string()),
+            Some(b'g') => Ok(self.read_registers()),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'c') => Ok(self.resume(false)),
+            Some(b's') => Ok(self.resume(true)),
+            Some(b'Z') | Some(b'z') if packet[1..].starts_with("0,") => {
+                address_length(&packet[3..]).map(|(address, _)| {
+                    if packet.starts_with('Z') {
+                        self.breakpoints.insert(address);
+                    } else {
+                        self.breakpoints.remove(&address);
+                    }
+                    "OK".to_string()
+                })
+            }
+            Some(b'H') => Ok("OK".to_string()),
+            _ => Ok(self.query(packet)),
+        };
+        result.unwrap_or_else(|e| {
+            println!("gdb: {}", e);
+            "E01".to_string()
+        })
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match address_length(range) {
+                Ok((offset, len)) if offset < TARGET_XML.len() => {
+                    let end = (offset + len).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[offset..end])
+                }
+                Ok(_) => "l".to_string(),
+                Err(_) => "E01".to_string(),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.ack = false;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let hardware = self.machine.hardware.lock().unwrap();
+        let mut bytes = Vec::new();
+        for reg in REGISTERS.iter() {
+            let value = reg.map_or(0, |reg| hardware.cpu.get_register16(reg)) as u32;
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[0; FPU_BYTES]);
+        hex(&bytes)
+    }
+
+    fn write_registers(&self, text: &str) -> Result<String, String> {
+        let bytes = from_hex(text)?;
+        if bytes.len() < REGISTERS.len() * 4 {
+            return Err(format!("{} bytes of G are too short", bytes.len()));
+        }
+        let mut hardware = self.machine.hardware.lock().unwrap();
+        for (i, reg) in REGISTERS.iter().enumerate() {
+            if let Some(reg) = reg {
+                let value = u16::from_le_bytes([bytes[i * 4], bytes[i * 4 + 1]]);
+                hardware.cpu.set_register16(reg, value);
+            }
+        }
+        hardware.journal.forget();
+        Ok("OK".to_string())
+    }
+
+    fn read_register(&self, text: &str) -> Result<String, String> {
+        let n = number(text)?;
+        let hardware = self.machine.hardware.lock().unwrap();
+        Ok(match REGISTERS.get(n) {
+            Some(reg) => {
+                let value = reg.map_or(0, |reg| hardware.cpu.get_register16(reg)) as u32;
+                hex(&value.to_le_bytes())
+            }
+            // st0-st7 and the others of the FPU
+            None if n < 24 => "00".repeat(10),
+            None => "00000000".to_string(),
+        })
+    }
+
+    fn write_register(&self, text: &str) -> Result<String, String> {
+        let (n, value) = text
+            .split_once('=')
+            .ok_or_else(|| format!("No value in {}", text))?;
+        let bytes = from_hex(value)?;
+        if let Some(Some(reg)) = REGISTERS.get(number(n)?) {
+            let value = u16::from_le_bytes([
+                bytes.first().copied().unwrap_or(0),
+                bytes.get(1).copied().unwrap_or(0),
+            ]);
+            let mut hardware = self.machine.hardware.lock().unwrap();
+            hardware.cpu.set_register16(reg, value);
+            hardware.journal.forget();
+        }
+        Ok("OK".to_string())
+    }
+
+    fn read_memory(&self, text: &str) -> Result<String, String> {
+        let (address, len) = address_length(text)?;
+        let hardware = self.machine.hardware.lock().unwrap();
+        Ok(hex(&hardware
+            .memory
+            .dump(address, len.min(MAX_MEMORY_READ))))
+    }
+
+    fn write_memory(&self, text: &str) -> Result<String, String> {
+        let (range, data) = text
+            .split_once(':')
+            .ok_or_else(|| format!("No data in {}", text))?;
+        let (address, len) = address_length(range)?;
+        let bytes = from_hex(data)?;
+        if bytes.len() != len {
+            return Err(format!("{} bytes are not {}", bytes.len(), len));
+        }
+        let mut hardware = self.machine.hardware.lock().unwrap();
+        hardware.memory.load(address, &bytes)?;
+        hardware.journal.forget();
+        Ok("OK".to_string())
+    }
+
+    /// Continue until a breakpoint, hlt, an error or Ctrl-C, or execute one instruction
+    /// The instruction at a breakpoint runs: it continues from the breakpoint.
+    fn resume(&mut self, single: bool) -> String {
+        let mut first = true;
+        loop {
+            {
+                let mut hardware = self.machine.hardware.lock().unwrap();
+                hardware.halted = false;
+                for _ in 0..STEPS_PER_CHECK {
+                    if !first && self.breakpoints.contains(&next_address(&hardware)) {
+                        return "S05".to_string();
+                    }
+                    first = false;
+                    if let Some(stop) = step(&mut hardware) {
+                        return stop;
+                    }
+                    if single {
+                        return "S05".to_string();
+                    }
+                }
+            }
+            if self.interrupted() {
+                return "S02".to_string();
+            }
+        }
+    }
+
+    /// Ctrl-C from gdb: a closed connection also stops the machine.
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0u8; 1];
+        if self.stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let r = self.stream.read(&mut byte);
+        let _ = self.stream.set_nonblocking(false);
+        match r {
+            Ok(1) => byte[0] == 0x03,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            _ => true,
+        }
+    }
+
+    /// Data of the next packet: None if the connection is closed
+    /// Ctrl-C out of c is ignored.
+    fn read_packet(&mut self) -> Result<Option<String>, String> {
+        let mut byte = [0u8; 1];
+        loop {
+            // Skip + and - until $
+            loop {
+                if self.stream.read(&mut byte).map_err(|e| e.to_string())? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                if self.stream.read(&mut byte).map_err(|e| e.to_string())? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut sum = [0u8; 2];
+            self.stream
+                .read_exact(&mut sum)
+                .map_err(|e| e.to_string())?;
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                == Some(checksum(&data));
+            if self.ack {
+                let ack: &[u8] = if valid { b"+" } else { b"-" };
+                self.stream.write_all(ack).map_err(|e| e.to_string())?;
+            }
+            if valid {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<(), String> {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        self.stream
+            .write_all(packet.as_bytes())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::sync::Mutex;
+
+    // Scripted gdb: send the packet and return the reply
+    fn request(stream: &mut TcpStream, data: &str) -> String {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        stream.write_all(packet.as_bytes()).unwrap();
+        reply(stream)
+    }
+
+    fn reply(stream: &mut TcpStream) -> String {
+        let mut byte = [0u8; 1];
+        let mut reply = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum).unwrap();
+        let reply = String::from_utf8(reply).unwrap();
+        assert_eq!(format!("{:02x}", checksum(&reply)).as_bytes(), &sum);
+        stream.write_all(b"+").unwrap();
+        reply
+    }
+
+    // 32-bit register in the g packet
+    fn register(registers: &str, n: usize) -> u32 {
+        let bytes = from_hex(&registers[n * 8..n * 8 + 8]).unwrap();
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    #[test]
+    fn test_gdbstub_packets() {
+        // inc ax, inc bx, hlt, jmp $
+        let image = [0x40, 0x43, 0xf4, 0xeb, 0xfe];
+        let mut hardware = Hardware8086::new();
+        hardware.load_com(&image, 0, "").unwrap();
+        let machine = Arc::new(HardwareLock {
+            hardware: Mutex::new(hardware),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = machine.clone();
+        std::thread::spawn(move || serve(listener, server));
+        let mut gdb = TcpStream::connect(address).unwrap();
+
+        assert!(request(&mut gdb, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+        let xml = request(&mut gdb, "qXfer:features:read:target.xml:0,4000");
+        assert!(xml.starts_with('l') && xml.contains("<architecture>i8086</architecture>"));
+        assert_eq!(
+            "m<?xml",
+            request(&mut gdb, "qXfer:features:read:target.xml:0,5")
+        );
+        assert_eq!("", request(&mut gdb, "vMustReplyEmpty"));
+        assert_eq!("S05", request(&mut gdb, "?"));
+
+        let registers = request(&mut gdb, "g");
+        assert_eq!((16 * 4 + FPU_BYTES) * 2, registers.len());
+        assert_eq!(0x100, register(&registers, 8));
+        assert_eq!("00010000", request(&mut gdb, "p8"));
+        assert_eq!("4043f4", request(&mut gdb, "m100,3"));
+
+        assert_eq!("OK", request(&mut gdb, "Z0,102,1"));
+        assert_eq!("S05", request(&mut gdb, "c"));
+        let registers = request(&mut gdb, "g");
+        assert_eq!(0x102, register(&registers, 8));
+        assert_eq!((1, 1), (register(&registers, 0), register(&registers, 3)));
+        // hlt at the breakpoint runs.
+        assert_eq!("OK", request(&mut gdb, "z0,102,1"));
+        assert_eq!("S05", request(&mut gdb, "s"));
+        assert!(machine.hardware.lock().unwrap().halted);
+
+        // Registers and memory written by gdb
+        assert_eq!("OK", request(&mut gdb, "P0=34120000"));
+        assert_eq!("OK", request(&mut gdb, "M200,2:abcd"));
+        assert_eq!("abcd", request(&mut gdb, "m200,2"));
+        // The instructions before the writes cannot be stepped back.
+        assert!(machine.hardware.lock().unwrap().step_back().is_err());
+        let mut registers = request(&mut gdb, "g");
+        assert_eq!(0x1234, register(&registers, 0));
+        registers.replace_range(8..16, "05000000");
+        assert_eq!("OK", request(&mut gdb, &format!("G{}", registers)));
+        assert_eq!(5, machine.hardware.lock().unwrap().cpu.get_register16("cx"));
+        assert_eq!("E01", request(&mut gdb, "M200,2:ab"));
+        // The addresses beyond 1MB and the data not in hex are errors.
+        assert_eq!("E01", request(&mut gdb, "mffffffffffffffff,2"));
+        assert_eq!("E01", request(&mut gdb, "M100000,1:00"));
+        assert_eq!("E01", request(&mut gdb, "M200,2:0\u{e9}0"));
+        assert_eq!("abcd", request(&mut gdb, "m200,2"));
+
+        // Ctrl-C stops jmp $.
+        let packet = format!("$c#{:02x}", checksum("c"));
+        gdb.write_all(packet.as_bytes()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        gdb.write_all(&[0x03]).unwrap();
+        assert_eq!("S02", reply(&mut gdb));
+        assert_eq!(
+            0x103,
+            machine.hardware.lock().unwrap().cpu.get_register16("ip")
+        );
+        assert_eq!("OK", request(&mut gdb, "D"));
+    }
+}
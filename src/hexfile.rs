@@ -0,0 +1,330 @@
+use crate::memory::{Memory, MEMORY_SIZE};
+
+/*
+Intel HEX and Motorola S-record: text formats of memory images for EPROM tools
+
+Intel HEX record
+:LLAAAATTDD...CC
+LL: number of data bytes, AAAA: 16-bit address, TT: record type, CC: checksum
+record type
+00 data
+01 end of file
+02 extended segment address: data is the segment, address = segment * 16 + AAAA
+03 start segment address: CS:IP (ignored)
+04 extended linear address: data is the upper 16 bits of the address
+05 start linear address (ignored)
+checksum: two's complement of the sum of all bytes from LL to the last data byte
+
+Motorola S-record
+STLLAAAA...DD...CC
+T: record type, LL: number of bytes of address, data and checksum
+record type
+S0 header, S1/S2/S3 data with 16/24/32-bit address, S5/S6 record count
+S7/S8/S9 start address with 32/24/16-bit address (terminator)
+checksum: one's complement of the sum of all bytes from LL to the last data byte
+*/
+
+const BYTES_PER_RECORD: usize = 16;
+
+/// Address range of the loaded image: [start, end)
+pub type Range = (usize, usize);
+
+fn hex_record(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn parse_bytes(s: &str, linenum: usize) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("Line {}: odd number of hex digits", linenum + 1));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .map_err(|_| format!("Line {}: invalid hex digits {}", linenum + 1, &s[i..i + 2]))
+        })
+        .collect()
+}
+
+fn check_range(address: usize, len: usize) -> Result<(), String> {
+    if address.checked_add(len).is_none_or(|end| end > MEMORY_SIZE) {
+        return Err(format!(
+            "Address range {:05X}+{:X} is out of memory",
+            address, len
+        ));
+    }
+    Ok(())
+}
+
+fn merge_range(range: Option<Range>, address: usize, len: usize) -> Option<Range> {
+    match range {
+        Some((start, end)) => Some((start.min(address), end.max(address + len))),
+        None => Some((address, address + len)),
+    }
+}
+
+fn intel_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+    record.push(sum.wrapping_neg());
+    format!(":{}\n", hex_record(&record))
+}
+
+/// Export memory[start..start+len] as Intel HEX with extended segment address records
+pub fn to_intel_hex(memory: &Memory, start: usize, len: usize) -> Result<String, String> {
+    check_range(start, len)?;
+    let mut s = String::new();
+    let mut segment: Option<usize> = None;
+    let mut address = start;
+    let end = start + len;
+    while address < end {
+        // A record cannot cross the 64KB boundary of the segment.
+        let seg = (address >> 4) & 0xf000;
+        let offset = address - (seg << 4);
+        let count = BYTES_PER_RECORD.min(end - address).min(0x10000 - offset);
+        if segment != Some(seg) && !(segment.is_none() && seg == 0) {
+            s.push_str(&intel_record(0x02, 0, &[(seg >> 8) as u8, seg as u8]));
+        }
+        segment = Some(seg);
+        s.push_str(&intel_record(
+            0x00,
+            offset as u16,
+            &memory.dump(address, count),
+        ));
+        address += count;
+    }
+    s.push_str(&intel_record(0x01, 0, &[]));
+    Ok(s)
+}
+
+/// Load Intel HEX into memory and return the range of the loaded data
+pub fn load_intel_hex(memory: &mut Memory, text: &str) -> Result<Option<Range>, String> {
+    let mut base: usize = 0;
+    let mut range: Option<Range> = None;
+    for (linenum, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| format!("Line {}: record should start with ':'", linenum + 1))?;
+        let bytes = parse_bytes(record, linenum)?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(format!("Line {}: wrong record length", linenum + 1));
+        }
+        if bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b)) != 0 {
+            return Err(format!("Line {}: checksum error", linenum + 1));
+        }
+        let offset = (bytes[1] as usize) << 8 | bytes[2] as usize;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => {
+                let address = base + offset;
+                check_range(address, data.len())?;
+                memory.load(address, data)?;
+                range = merge_range(range, address, data.len());
+            }
+            0x01 => break,
+            0x02 if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 4,
+            0x04 if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 16,
+            0x03 | 0x05 => {}
+            kind => {
+                return Err(format!(
+                    "Line {}: unknown record type {:02X}",
+                    linenum + 1,
+                    kind
+                ))
+            }
+        }
+    }
+    Ok(range)
+}
+
+fn srecord(kind: u8, address: usize, address_size: usize, data: &[u8]) -> String {
+    let mut record = vec![(address_size + data.len() + 1) as u8];
+    for i in (0..address_size).rev() {
+        record.push((address >> (i * 8)) as u8);
+    }
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+    record.push(!sum);
+    format!("S{}{}\n", kind, hex_record(&record))
+}
+
+/// Export memory[start..start+len] as S-records
+/// S1 records are used when all addresses fit in 16 bits, otherwise S2 records.
+pub fn to_srecord(memory: &Memory, start: usize, len: usize) -> Result<String, String> {
+    check_range(start, len)?;
+    let end = start + len;
+    let (data_kind, end_kind, address_size) = if end > 0x10000 { (2, 8, 3) } else { (1, 9, 2) };
+    let mut s = srecord(0, 0, 2, b"remu8086");
+    let mut count = 0;
+    for address in (start..end).step_by(BYTES_PER_RECORD) {
+        let n = BYTES_PER_RECORD.min(end - address);
+        s.push_str(&srecord(
+            data_kind,
+            address,
+            address_size,
+            &memory.dump(address, n),
+        ));
+        count += 1;
+    }
+    // S6 has the 24-bit count of more than FFFFh records.
+    if count > 0xffff {
+        s.push_str(&srecord(6, count, 3, &[]));
+    } else {
+        s.push_str(&srecord(5, count, 2, &[]));
+    }
+    s.push_str(&srecord(end_kind, start, address_size, &[]));
+    Ok(s)
+}
+
+/// Load S-records into memory and return the range of the loaded data
+pub fn load_srecord(memory: &mut Memory, text: &str) -> Result<Option<Range>, String> {
+    let mut range: Option<Range> = None;
+    for (linenum, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.len() < 4 || !line.is_ascii() || !line.starts_with('S') {
+            return Err(format!(
+                "Line {}: record should start with 'S'",
+                linenum + 1
+            ));
+        }
+        let kind = line.as_bytes()[1];
+        let bytes = parse_bytes(&line[2..], linenum)?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(format!("Line {}: wrong record length", linenum + 1));
+        }
+        if bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b)) != 0xff {
+            return Err(format!("Line {}: checksum error", linenum + 1));
+        }
+        let address_size = match kind {
+            b'0' | b'1' | b'5' | b'9' => 2,
+            b'2' | b'6' | b'8' => 3,
+            b'3' | b'7' => 4,
+            _ => {
+                return Err(format!(
+                    "Line {}: unknown record type S{}",
+                    linenum + 1,
+                    kind as char
+                ))
+            }
+        };
+        if bytes.len() < address_size + 2 {
+            return Err(format!("Line {}: wrong record length", linenum + 1));
+        }
+        if let b'1' | b'2' | b'3' = kind {
+            let address = bytes[1..=address_size]
+                .iter()
+                .fold(0usize, |a, b| a << 8 | *b as usize);
+            let data = &bytes[address_size + 1..bytes.len() - 1];
+            check_range(address, data.len())?;
+            memory.load(address, data)?;
+            range = merge_range(range, address, data.len());
+        }
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_hexfile_intel_hex() {
+        let mut memory = Memory::boot();
+        memory.load(0x100, &[0xbb, 0x10, 0x00, 0x8b, 0xc3]).unwrap();
+        let s = to_intel_hex(&memory, 0x100, 5).unwrap();
+        assert_eq!(":05010000BB10008BC3E1\n:00000001FF\n", s);
+
+        let mut other = Memory::boot();
+        assert_eq!(
+            Some((0x100, 0x105)),
+            load_intel_hex(&mut other, &s).unwrap()
+        );
+        assert_eq!(memory.dump(0x100, 5), other.dump(0x100, 5));
+    }
+
+    #[test]
+    fn test_hexfile_intel_hex_segment() {
+        // 20-bit address needs the extended segment address record
+        let mut memory = Memory::boot();
+        memory.load(0xffff8, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        memory.load(0x1fffe, &[9, 10, 11, 12]).unwrap();
+        let s = to_intel_hex(&memory, 0x1fffe, 4).unwrap();
+        let records: Vec<&str> = s.lines().collect();
+        assert_eq!(":020000021000EC", records[0]);
+        assert_eq!(":02FFFE00090AEE", records[1]);
+        assert_eq!(":020000022000DC", records[2]);
+        assert_eq!(":020000000B0CE7", records[3]);
+
+        let mut other = Memory::boot();
+        assert_eq!(
+            Some((0x1fffe, 0x20002)),
+            load_intel_hex(&mut other, &s).unwrap()
+        );
+        assert_eq!(vec![9, 10, 11, 12], other.dump(0x1fffe, 4));
+
+        let s = to_intel_hex(&memory, 0xffff8, 8).unwrap();
+        let mut other = Memory::boot();
+        load_intel_hex(&mut other, &s).unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8], other.dump(0xffff8, 8));
+        assert!(to_intel_hex(&memory, 0xffff8, 9).is_err());
+    }
+
+    #[test]
+    fn test_hexfile_intel_hex_failure() {
+        let mut memory = Memory::boot();
+        assert!(load_intel_hex(&mut memory, "05010000BB10008BC3E1").is_err());
+        assert!(load_intel_hex(&mut memory, ":05010000BB10008BC3E2").is_err());
+        assert!(load_intel_hex(&mut memory, ":05010000BB10008BAF").is_err());
+        assert!(load_intel_hex(&mut memory, ":0001000AF5").is_err());
+    }
+
+    #[test]
+    fn test_hexfile_srecord() {
+        let mut memory = Memory::boot();
+        memory.load(0x100, &[0xbb, 0x10, 0x00, 0x8b, 0xc3]).unwrap();
+        let s = to_srecord(&memory, 0x100, 5).unwrap();
+        let records: Vec<&str> = s.lines().collect();
+        assert_eq!("S0", &records[0][..2]);
+        assert_eq!("S1080100BB10008BC3DD", records[1]);
+        assert_eq!("S5030001FB", records[2]);
+        assert_eq!("S9030100FB", records[3]);
+
+        let mut other = Memory::boot();
+        assert_eq!(Some((0x100, 0x105)), load_srecord(&mut other, &s).unwrap());
+        assert_eq!(memory.dump(0x100, 5), other.dump(0x100, 5));
+
+        // 20-bit address uses S2 records
+        memory.load(0x12345, &[0xaa, 0x55]).unwrap();
+        let s = to_srecord(&memory, 0x12345, 2).unwrap();
+        assert!(s.lines().nth(1).unwrap().starts_with("S206012345AA55"));
+        assert!(s.lines().last().unwrap().starts_with("S8"));
+        let mut other = Memory::boot();
+        load_srecord(&mut other, &s).unwrap();
+        assert_eq!(vec![0xaa, 0x55], other.dump(0x12345, 2));
+
+        // The whole memory has 10000h records: S6 has the count.
+        let s = to_srecord(&memory, 0, MEMORY_SIZE).unwrap();
+        let records: Vec<&str> = s.lines().collect();
+        assert_eq!(0x10003, records.len());
+        assert!(records[0x10001].starts_with("S604010000"));
+        assert!(to_srecord(&memory, usize::MAX, 1).is_err());
+        assert!(to_intel_hex(&memory, usize::MAX, 1).is_err());
+    }
+
+    #[test]
+    fn test_hexfile_srecord_failure() {
+        let mut memory = Memory::boot();
+        assert!(load_srecord(&mut memory, "S1080100BB10008BC3AA").is_err());
+        assert!(load_srecord(&mut memory, "X1080100BB10008BC3DD").is_err());
+        assert!(load_srecord(&mut memory, "S4030001FB").is_err());
+    }
+}
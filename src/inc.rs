@@ -1,4 +1,4 @@
-use crate::assembler::{base_index_table, register_table};
+use crate::assembler::{memory_address, modrm_table, operand_wbit, register_table, SymbolTable};
 use crate::memory::Memory;
 use crate::parser::{self, Rule};
 use crate::{cpucontext::CpuContext, define_handler_one};
@@ -9,7 +9,7 @@ use pest::iterators::Pair;
 INC instruction has 5 forms of code
 
 1. 1-byte form: inc 16-bit registers
-e.g. INC DI => 47
+e.g. INC DI => 47 (the 2-byte FF C7 is not used)
 Opcode bit 7-3: 01000
 bit 2-0: register table
 
@@ -29,10 +29,9 @@ e.g. inc dl FE C2
   * register table when mod=11
 
 3. 3-byte form: inc memory location with 8-bit address (e.g. INC BYTE PTR [BX+10h])
->>>> DO we really need to implement 3-byte form? 3-byte form is for memory optimization.
->>>> It could be enough to implement only 4-byte form because 4-byte form is a extended version of 3-byte form.
+The assembler picks it when the displacement is in -128..127, unless the optimization is off.
 e.g. INC BYTE PTR [BX+10h] => FE 47 10
-e.g. INC WORD PTR [BX+SI+10h] => FF 84 10
+e.g. INC WORD PTR [BX+SI+10h] => FF 40 10
 1-byte Opcode bit 7-1: 1111111
 1-byte W bit 0: 0-8bit, 1-16bit
 2-byte mod-bit 7-6: 01-use base register and 8-bit displacement of next one byte of instruction
@@ -103,10 +102,10 @@ const MOD_SHIFT: u8 = 6;
 const OPCODE2_SHIFT: u8 = 3;
 const RM_SHIFT: u8 = 0; // register table or base-index-register table
 
-fn assemble_inc(operand: &Pair<Rule>) -> Vec<u8> {
+pub fn assemble_inc(operand: &Pair<Rule>, symbols: &SymbolTable) -> Result<Vec<u8>, String> {
     let mut v: Vec<u8> = Vec::new();
     if operand.as_rule() == Rule::reg16 {
-        let reg = register_table(operand.as_str()).unwrap();
+        let reg = register_table(operand.as_str())?;
         let opcode = 0x40;
         v.push(reg | opcode);
     } else if operand.as_rule() == Rule::reg8 {
@@ -116,7 +115,7 @@ fn assemble_inc(operand: &Pair<Rule>) -> Vec<u8> {
 
         let modbit = 0x3 << MOD_SHIFT;
         let opcode2 = 0x0 << OPCODE2_SHIFT;
-        let rmbit = register_table(operand.as_str()).unwrap() << RM_SHIFT;
+        let rmbit = register_table(operand.as_str())? << RM_SHIFT;
         v.push(modbit | opcode2 | rmbit);
     } else if operand.as_rule() == Rule::mem16 {
         // inc word ptr [12h] or inc word ptr [1234h]
@@ -129,7 +128,7 @@ fn assemble_inc(operand: &Pair<Rule>) -> Vec<u8> {
         let rmbit = 0x6 << RM_SHIFT; // direct addressing: mod=00, rm=110
         v.push(modbit | opcode2 | rmbit);
 
-        let address = parser::mem_to_num(&operand).unwrap();
+        let address = memory_address(operand, symbols)?;
         // Little-endian: first low byte, second high byte
         v.push((address & 0xff).try_into().unwrap());
         v.push(((address & 0xff00) >> 8).try_into().unwrap());
@@ -145,119 +144,47 @@ fn assemble_inc(operand: &Pair<Rule>) -> Vec<u8> {
         let rmbit = 0x6 << RM_SHIFT; // direct addressing: mod=00, rm=110
         v.push(modbit | opcode2 | rmbit);
 
-        let address = parser::mem_to_num(&operand).unwrap();
+        let address = memory_address(operand, symbols)?;
         // Little-endian: first low byte, second high byte
         v.push((address & 0xff).try_into().unwrap());
         v.push(((address & 0xff00) >> 8).try_into().unwrap());
-    } else if operand.as_rule() == Rule::indirect16 {
-        // inc [bx + si + 1234h]
+    } else if operand.as_rule() == Rule::indirect16 || operand.as_rule() == Rule::indirect8 {
+        // inc [bx + si + 1234h] or inc byte ptr [bx + si + 1234h]
         let opcode1 = 0x7f << OPCODE1_SHIFT;
-        let wbit = 0x1 << WBIT_SHIFT;
+        let wbit = operand_wbit(operand)? << WBIT_SHIFT;
         v.push(opcode1 | wbit);
 
-        // We use only 00 and 10 for indirect addressing
-        let modbit = 0x2 << MOD_SHIFT;
-        let opcode2 = 0 << OPCODE2_SHIFT;
-
-        // Get rmbit value from base/index register table
-        let basereg;
-        let indexreg;
-        let displacement;
-        let mut inner = operand.clone().into_inner();
-        let index;
-        let base = inner.next().unwrap();
-        if base.as_rule() == Rule::base {
-            basereg = Some(base.as_str());
-            index = inner.next().unwrap();
-        } else {
-            basereg = None;
-            index = base;
-        }
-        if index.as_rule() == Rule::index {
-            indexreg = Some(index.as_str());
-            displacement = inner.next().unwrap();
-        } else {
-            indexreg = None;
-            displacement = index;
-        }
-        let rmbit = base_index_table(basereg, indexreg).unwrap();
-        v.push(modbit | opcode2 | rmbit);
-
-        assert_eq!(Rule::imm, displacement.as_rule());
-        let disp = parser::imm_to_num(&displacement).unwrap();
-        v.push((disp & 0xff).try_into().unwrap());
-        v.push(((disp & 0xff00) >> 8).try_into().unwrap());
-    } else if operand.as_rule() == Rule::indirect8 {
-        // inc byte ptr [bx + si + 1234h]
-        let opcode1 = 0x7f << OPCODE1_SHIFT;
-        let wbit = 0x0 << WBIT_SHIFT;
-        v.push(opcode1 | wbit);
-
-        // We use only 00 and 10 for indirect addressing
-        let modbit = 0x2 << MOD_SHIFT;
-        let opcode2 = 0 << OPCODE2_SHIFT;
-
-        // Get rmbit value from base/index register table
-        let basereg;
-        let indexreg;
-        let displacement;
-        let mut inner = operand.clone().into_inner();
-        let index;
-        let base = inner.next().unwrap();
-        if base.as_rule() == Rule::base {
-            basereg = Some(base.as_str());
-            index = inner.next().unwrap();
-        } else {
-            basereg = None;
-            index = base;
-        }
-        if index.as_rule() == Rule::index {
-            indexreg = Some(index.as_str());
-            displacement = inner.next().unwrap();
-        } else {
-            indexreg = None;
-            displacement = index;
-        }
-        let rmbit = base_index_table(basereg, indexreg).unwrap();
-        v.push(modbit | opcode2 | rmbit);
-
-        assert_eq!(Rule::imm, displacement.as_rule());
-        let disp = parser::imm_to_num(&displacement).unwrap();
-        v.push((disp & 0xff).try_into().unwrap());
-        v.push(((disp & 0xff00) >> 8).try_into().unwrap());
+        // mod is 00 without displacement and 10 with 16-bit displacement
+        // r/m is from base/index register table and opcode2 is 000
+        v.extend(modrm_table(operand, symbols)?);
     } else {
-        panic!("Unknown form of inc operation")
+        return Err(format!(
+            "Unknown form of inc operation: inc {}",
+            operand.as_str()
+        ));
     }
-    v
+    Ok(v)
 }
 
 define_handler_one!(inc, first, cpu, memory, {
     match first.as_rule() {
         Rule::reg16 => {
-            let code = assemble_inc(&first);
-            println!("inc code {:?}", code);
             let v = cpu.get_register16(first.as_str());
-            cpu.set_register16(first.as_str(), v + 1);
+            cpu.set_register16(first.as_str(), v.wrapping_add(1));
         }
         Rule::reg8 => {
-            let code = assemble_inc(&first);
-            println!("inc code {:?}", code);
             let v = cpu.get_register8(first.as_str());
-            cpu.set_register8(first.as_str(), v + 1);
+            cpu.set_register8(first.as_str(), v.wrapping_add(1));
         }
         Rule::mem16 => {
-            let code = assemble_inc(&first);
-            println!("inc code {:?}", code);
-            let address = parser::mem_to_num(&first).unwrap();
-            let v = memory.read16(address);
-            memory.write16(address, v + 1);
+            let address = parser::mem_to_num(&first)?;
+            let v = memory.read16(cpu.get_register16("ds"), address);
+            memory.write16(cpu.get_register16("ds"), address, v.wrapping_add(1));
         }
         Rule::mem8 => {
-            let code = assemble_inc(&first);
-            println!("inc code {:?}", code);
-            let address = parser::mem_to_num(&first).unwrap();
-            let v = memory.read8(address);
-            memory.write8(address, v + 1);
+            let address = parser::mem_to_num(&first)?;
+            let v = memory.read8(cpu.get_register16("ds"), address);
+            memory.write8(cpu.get_register16("ds"), address, v.wrapping_add(1));
         }
         Rule::indirect16 => {
             let basereg;
@@ -281,19 +208,24 @@ define_handler_one!(inc, first, cpu, memory, {
                 displacement = index;
             }
             assert_eq!(Rule::imm, displacement.as_rule());
-            let disp = parser::imm_to_num(&displacement).unwrap();
+            let disp = parser::imm_to_num(&displacement)?;
 
             let mut address = disp;
             if let Some(r) = basereg {
                 let d = cpu.get_register16(r);
-                address += d;
+                address = address.wrapping_add(d);
             }
             if let Some(r) = indexreg {
                 let d = cpu.get_register16(r);
-                address += d;
+                address = address.wrapping_add(d);
             }
-            let v = memory.read16(address);
-            memory.write16(address, v + 1);
+            // BP addresses the stack segment.
+            let segment = match basereg {
+                Some(r) if r.eq_ignore_ascii_case("bp") => cpu.get_register16("ss"),
+                _ => cpu.get_register16("ds"),
+            };
+            let v = memory.read16(segment, address);
+            memory.write16(segment, address, v.wrapping_add(1));
         }
         Rule::indirect8 => {
             let basereg;
@@ -317,21 +249,26 @@ define_handler_one!(inc, first, cpu, memory, {
                 displacement = index;
             }
             assert_eq!(Rule::imm, displacement.as_rule());
-            let disp = parser::imm_to_num(&displacement).unwrap();
+            let disp = parser::imm_to_num(&displacement)?;
 
             let mut address = disp;
             if let Some(r) = basereg {
                 let d = cpu.get_register16(r);
-                address += d;
+                address = address.wrapping_add(d);
             }
             if let Some(r) = indexreg {
                 let d = cpu.get_register16(r);
-                address += d;
+                address = address.wrapping_add(d);
             }
-            let v = memory.read8(address);
-            memory.write8(address, v + 1);
+            // BP addresses the stack segment.
+            let segment = match basereg {
+                Some(r) if r.eq_ignore_ascii_case("bp") => cpu.get_register16("ss"),
+                _ => cpu.get_register16("ds"),
+            };
+            let v = memory.read8(segment, address);
+            memory.write8(segment, address, v.wrapping_add(1));
         }
-        _ => println!("Not supported operand for org:{:?}", first),
+        _ => return Err(format!("inc {} is not supported yet", first.as_str())),
     }
 });
 
@@ -353,7 +290,7 @@ mod tests {
         let operand = parsed.into_inner().next().unwrap();
         assert_eq!(Rule::reg16, operand.as_rule());
         assert_eq!("di", operand.as_str());
-        let v = assemble_inc(&operand);
+        let v = assemble_inc(&operand, &SymbolTable::default()).unwrap();
         assert_eq!(0x47, v[0]);
     }
 
@@ -367,7 +304,7 @@ mod tests {
         let operand = parsed.into_inner().next().unwrap();
         assert_eq!(Rule::reg8, operand.as_rule());
         assert_eq!("dl", operand.as_str());
-        let v = assemble_inc(&operand);
+        let v = assemble_inc(&operand, &SymbolTable::default()).unwrap();
         assert_eq!(0xfe, v[0]);
         assert_eq!(0xc2, v[1]);
     }
@@ -384,7 +321,7 @@ mod tests {
         assert_eq!("[1234h]", operand.as_str());
         assert_eq!(0x1234, parser::mem_to_num(&operand).unwrap());
 
-        let v = assemble_inc(&operand);
+        let v = assemble_inc(&operand, &SymbolTable::default()).unwrap();
         assert_eq!(0xff, v[0]);
         assert_eq!(0x06, v[1]);
         assert_eq!(0x34, v[2]);
@@ -396,7 +333,7 @@ mod tests {
             .unwrap();
         assert_eq!(Rule::inc, parsed.as_rule());
         let operand = parsed.into_inner().next().unwrap();
-        let v = assemble_inc(&operand);
+        let v = assemble_inc(&operand, &SymbolTable::default()).unwrap();
         assert_eq!(0xff, v[0]);
         assert_eq!(0x06, v[1]);
         assert_eq!(0x12, v[2]);
@@ -408,7 +345,7 @@ mod tests {
             .unwrap();
         assert_eq!(Rule::inc, parsed.as_rule());
         let operand = parsed.into_inner().next().unwrap();
-        let v = assemble_inc(&operand);
+        let v = assemble_inc(&operand, &SymbolTable::default()).unwrap();
         assert_eq!(0xfe, v[0]);
         assert_eq!(0x06, v[1]);
         assert_eq!(0x12, v[2]);
@@ -426,7 +363,7 @@ mod tests {
         assert_eq!(Rule::indirect16, operand.as_rule());
         assert_eq!("[bx + si + 1234h]", operand.as_str());
 
-        let v = assemble_inc(&operand);
+        let v = assemble_inc(&operand, &SymbolTable::default()).unwrap();
         assert_eq!(0xff, v[0]);
         assert_eq!(0x80, v[1]);
         assert_eq!(0x34, v[2]);
@@ -439,7 +376,7 @@ mod tests {
         assert_eq!(Rule::inc, parsed.as_rule());
         let operand = parsed.into_inner().next().unwrap();
         assert_eq!(Rule::indirect16, operand.as_rule());
-        let v = assemble_inc(&operand);
+        let v = assemble_inc(&operand, &SymbolTable::default()).unwrap();
         assert_eq!(0xff, v[0]);
         assert_eq!(0x87, v[1]);
         assert_eq!(0x12, v[2]);
@@ -452,7 +389,7 @@ mod tests {
         assert_eq!(Rule::inc, parsed.as_rule());
         let operand = parsed.into_inner().next().unwrap();
         assert_eq!(Rule::indirect16, operand.as_rule());
-        let v = assemble_inc(&operand);
+        let v = assemble_inc(&operand, &SymbolTable::default()).unwrap();
         assert_eq!(0xff, v[0]);
         assert_eq!(0x84, v[1]);
         assert_eq!(0x12, v[2]);
@@ -465,7 +402,7 @@ mod tests {
         assert_eq!(Rule::inc, parsed.as_rule());
         let operand = parsed.into_inner().next().unwrap();
         assert_eq!(Rule::indirect8, operand.as_rule());
-        let v = assemble_inc(&operand);
+        let v = assemble_inc(&operand, &SymbolTable::default()).unwrap();
         assert_eq!(0xfe, v[0]);
         assert_eq!(0x84, v[1]);
         assert_eq!(0x12, v[2]);
@@ -484,7 +421,7 @@ mod tests {
             .unwrap();
         let mut inner = instruction.into_inner();
         let operand = inner.next().unwrap();
-        handler_inc(&mut cpu, &mut memory, operand);
+        handler_inc(&mut cpu, &mut memory, operand).unwrap();
         assert_eq!(0x1235, cpu.get_register16("bx"));
 
         let instruction = AssemblyParser::parse(Rule::instruction, "inc bl")
@@ -493,7 +430,7 @@ mod tests {
             .unwrap();
         let mut inner = instruction.into_inner();
         let operand = inner.next().unwrap();
-        handler_inc(&mut cpu, &mut memory, operand);
+        handler_inc(&mut cpu, &mut memory, operand).unwrap();
         assert_eq!(0x1236, cpu.get_register16("bx"));
 
         let instruction = AssemblyParser::parse(Rule::instruction, "inc bh")
@@ -502,7 +439,7 @@ mod tests {
             .unwrap();
         let mut inner = instruction.into_inner();
         let operand = inner.next().unwrap();
-        handler_inc(&mut cpu, &mut memory, operand);
+        handler_inc(&mut cpu, &mut memory, operand).unwrap();
         assert_eq!(0x1336, cpu.get_register16("bx"));
     }
 
@@ -511,7 +448,7 @@ mod tests {
         let mut cpu = crate::cpucontext::CpuContext::boot();
         let mut memory = crate::memory::Memory::boot();
 
-        memory.write16(0x1110, 0x1234);
+        memory.write16(0, 0x1110, 0x1234);
         let instruction = AssemblyParser::parse(Rule::instruction, "inc word ptr [1110h]")
             .unwrap()
             .next()
@@ -519,8 +456,8 @@ mod tests {
         let mut inner = instruction.into_inner();
         let operand = inner.next().unwrap();
         assert_eq!("word ptr [1110h]", operand.as_str());
-        handler_inc(&mut cpu, &mut memory, operand);
-        assert_eq!(0x1235, memory.read16(0x1110));
+        handler_inc(&mut cpu, &mut memory, operand).unwrap();
+        assert_eq!(0x1235, memory.read16(0, 0x1110));
 
         let instruction = AssemblyParser::parse(Rule::instruction, "inc byte ptr [1110h]")
             .unwrap()
@@ -529,9 +466,9 @@ mod tests {
         let mut inner = instruction.into_inner();
         let operand = inner.next().unwrap();
         assert_eq!("byte ptr [1110h]", operand.as_str());
-        handler_inc(&mut cpu, &mut memory, operand);
-        assert_eq!(0x1236, memory.read16(0x1110));
-        assert_eq!(0x36, memory.read8(0x1110));
+        handler_inc(&mut cpu, &mut memory, operand).unwrap();
+        assert_eq!(0x1236, memory.read16(0, 0x1110));
+        assert_eq!(0x36, memory.read8(0, 0x1110));
 
         let instruction = AssemblyParser::parse(Rule::instruction, "inc byte ptr [1111h]")
             .unwrap()
@@ -540,9 +477,9 @@ mod tests {
         let mut inner = instruction.into_inner();
         let operand = inner.next().unwrap();
         assert_eq!("byte ptr [1111h]", operand.as_str());
-        handler_inc(&mut cpu, &mut memory, operand);
-        assert_eq!(0x1336, memory.read16(0x1110));
-        assert_eq!(0x13, memory.read8(0x1111));
+        handler_inc(&mut cpu, &mut memory, operand).unwrap();
+        assert_eq!(0x1336, memory.read16(0, 0x1110));
+        assert_eq!(0x13, memory.read8(0, 0x1111));
     }
 
     #[test]
@@ -550,7 +487,7 @@ mod tests {
         let mut cpu = crate::cpucontext::CpuContext::boot();
         let mut memory = crate::memory::Memory::boot();
 
-        memory.write16(0x1110, 0x1234);
+        memory.write16(0, 0x1110, 0x1234);
         cpu.set_register16("bx", 0x1000);
         cpu.set_register16("si", 0x100);
         let instruction = AssemblyParser::parse(Rule::instruction, "inc word ptr [bx + si + 10h]")
@@ -560,8 +497,8 @@ mod tests {
         let mut inner = instruction.into_inner();
         let operand = inner.next().unwrap();
         assert_eq!("word ptr [bx + si + 10h]", operand.as_str());
-        handler_inc(&mut cpu, &mut memory, operand);
-        assert_eq!(0x1235, memory.read16(0x1110));
+        handler_inc(&mut cpu, &mut memory, operand).unwrap();
+        assert_eq!(0x1235, memory.read16(0, 0x1110));
 
         cpu.set_register16("bx", 0x1000);
         cpu.set_register16("si", 0x100);
@@ -571,7 +508,7 @@ mod tests {
             .unwrap();
         let mut inner = instruction.into_inner();
         let operand = inner.next().unwrap();
-        handler_inc(&mut cpu, &mut memory, operand);
-        assert_eq!(0x1335, memory.read16(0x1110));
+        handler_inc(&mut cpu, &mut memory, operand).unwrap();
+        assert_eq!(0x1335, memory.read16(0, 0x1110));
     }
 }
@@ -1,9 +1,51 @@
-use crate::memory::Memory;
+use crate::assembler::{fits_in_i8, SymbolTable};
 use crate::parser::Rule;
-use crate::{cpucontext::CpuContext, define_handler_one};
-use paste::paste;
 use pest::iterators::Pair;
 
-define_handler_one!(jmp, first, _cpu, _memory, {
-    println!("jmp {:?}", first);
-});
+/*
+JMP rel16 $E9: IP of the next instruction + 16-bit displacement
+e.g. jmp label => E9 disp-low disp-high
+
+JMP rel8 $EB: IP of the next instruction + 8-bit displacement sign extended
+e.g. jmp label => EB disp
+The assembler picks it when the label is in -128..127 bytes.
+
+JMP ptr16:16 $EA: offset and segment of the label
+e.g. jmp far ptr label => EA offset-low offset-high segment-low segment-high
+*/
+
+pub const JMP_SIZE: usize = 3;
+pub const JMP_SHORT_SIZE: usize = 2;
+
+/// address: address of the jmp instruction
+pub fn assemble_jmp(
+    operand: &Pair<Rule>,
+    address: u16,
+    symbols: &SymbolTable,
+) -> Result<Vec<u8>, String> {
+    let target = match symbols.get(operand.as_str())? {
+        // A label defined later is assumed near: the sizing passes make the jump long if not.
+        _ if symbols.lenient() && !symbols.contains(operand.as_str()) => address,
+        target => target,
+    };
+    let disp = target.wrapping_sub(address.wrapping_add(JMP_SHORT_SIZE as u16));
+    if symbols.optimize() && fits_in_i8(disp) {
+        return Ok(vec![0xeb, disp as u8]);
+    }
+    let disp = target.wrapping_sub(address.wrapping_add(JMP_SIZE as u16));
+    Ok(vec![
+        0xe9,
+        (disp & 0xff) as u8,
+        ((disp & 0xff00) >> 8) as u8,
+    ])
+}
+
+/// Segment of the label is a relocation of the EXE.
+pub fn assemble_jmp_far(operand: &Pair<Rule>, symbols: &SymbolTable) -> Result<Vec<u8>, String> {
+    let offset = symbols.get(operand.as_str())?;
+    let segment = symbols.frame(operand.as_str())?;
+    let mut v = vec![0xea];
+    v.extend_from_slice(&offset.to_le_bytes());
+    v.extend_from_slice(&segment.to_le_bytes());
+    Ok(v)
+}
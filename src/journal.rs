@@ -0,0 +1,149 @@
+use crate::cpucontext::CpuContext;
+use std::collections::VecDeque;
+
+/*
+Undo journal of the executed instructions
+
+Each entry has the registers before the instruction and the old values of the bytes it wrote.
+Stepping back restores them in the reverse order.
+e.g. step 0 => the state after the build or the reload
+e.g. /rewind?to=3 => the state after the first 3 instructions
+The oldest entries are dropped when the journal is full.
+*/
+
+pub const MAX_JOURNAL_ENTRIES: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    // Registers before the instruction
+    pub cpu: CpuContext,
+    // hlt stopped the CPU before the instruction
+    pub halted: bool,
+    // Line of the instruction: /step continues from it after stepping back
+    pub line: usize,
+    // Physical address and old value of the bytes written by the instruction
+    pub writes: Vec<(usize, u8)>,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    entries: VecDeque<Entry>,
+    // Number of the entries dropped from the front
+    dropped: usize,
+    // Line after the last instruction
+    next_line: usize,
+    limit: usize,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Journal::with_limit(MAX_JOURNAL_ENTRIES)
+    }
+}
+
+impl Journal {
+    pub fn with_limit(limit: usize) -> Self {
+        Journal {
+            entries: VecDeque::new(),
+            dropped: 0,
+            next_line: 0,
+            limit,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.dropped = 0;
+        self.next_line = 0;
+    }
+
+    /// Drop the entries and keep the step and the next line
+    /// e.g. the debugger wrote the registers or the memory: the entries cannot restore the state before it.
+    pub fn forget(&mut self) {
+        self.dropped += self.entries.len();
+        self.entries.clear();
+    }
+
+    /// The instruction of the entry is executed and next_line is the line after it.
+    pub fn push(&mut self, entry: Entry, next_line: usize) {
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+            self.dropped += 1;
+        }
+        self.entries.push_back(entry);
+        self.next_line = next_line;
+    }
+
+    /// The last instruction to undo
+    pub fn pop(&mut self) -> Option<Entry> {
+        let entry = self.entries.pop_back()?;
+        self.next_line = entry.line;
+        Some(entry)
+    }
+
+    /// The last instruction executed
+    pub fn last(&self) -> Option<&Entry> {
+        self.entries.back()
+    }
+
+    /// Number of the instructions executed since the clear
+    pub fn step(&self) -> usize {
+        self.dropped + self.entries.len()
+    }
+
+    /// The first step that can be restored
+    pub fn first_step(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn next_line(&self) -> usize {
+        self.next_line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn entry(line: usize) -> Entry {
+        Entry {
+            cpu: CpuContext::boot(),
+            halted: false,
+            line,
+            writes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_journal() {
+        let mut journal = Journal::with_limit(2);
+        assert_eq!(None, journal.pop());
+        journal.push(entry(0), 1);
+        journal.push(entry(1), 2);
+        journal.push(entry(2), 5);
+        assert_eq!(Some(&entry(2)), journal.last());
+        assert_eq!(3, journal.step());
+        assert_eq!(1, journal.first_step());
+        assert_eq!(5, journal.next_line());
+
+        assert_eq!(Some(entry(2)), journal.pop());
+        assert_eq!(2, journal.next_line());
+        assert_eq!(Some(entry(1)), journal.pop());
+        // The first entry is dropped.
+        assert_eq!(None, journal.pop());
+        assert_eq!(1, journal.step());
+
+        journal.push(entry(1), 2);
+        journal.forget();
+        assert_eq!(None, journal.pop());
+        assert_eq!(
+            (2, 2, 2),
+            (journal.step(), journal.first_step(), journal.next_line())
+        );
+
+        journal.clear();
+        assert_eq!(0, journal.step());
+        assert_eq!(0, journal.next_line());
+    }
+}
@@ -0,0 +1,285 @@
+use crate::assembler::Assembly;
+use std::collections::HashMap;
+
+/*
+Linker: place the segments of the program into one image
+
+The segments are placed in the order of their first appearance.
+Each segment starts at its alignment: byte(1), word(2), para(16, default) or page(256).
+The frame of a segment is the paragraph of its start,
+and the offsets of its labels are relative to the frame.
+The segments in a group share the frame of the first segment of the group.
+
+e.g.
+data segment           ; start 00000h frame 0000h
+msg db 'Hello$'
+data ends
+code segment           ; start 00010h frame 0001h
+start:
+    mov ax, seg msg    ; B8 00 00 => relocation at 00011h
+code ends
+end start              ; CS:IP = 0001:0000
+
+seg label or a segment name is the paragraph of the frame.
+It depends on the load segment, so its position is a relocation of the EXE:
+the loader adds the load segment to the word at the position.
+
+EXE image: MZ header + relocation table + the image
+(see loader.rs for the MZ header fields)
+SS:SP is the end of the stack segment (combine type "stack").
+If there is no stack segment, STACK_SIZE bytes after the image are the stack.
+*/
+
+const MZ_HEADER_SIZE: usize = 0x1c;
+const PAGE_SIZE: usize = 512;
+const STACK_SIZE: usize = 0x100;
+const MAX_FRAME_SIZE: usize = 0x10000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub name: String,
+    // Alignment of the start in bytes
+    pub align: usize,
+    // Combine type "stack": SS:SP of the EXE
+    pub stack: bool,
+    // Size of the code and data in bytes
+    pub size: u16,
+    // Group of the segment
+    pub group: Option<String>,
+    // Position in the image: set by the layout
+    pub start: usize,
+    // Paragraph of the segment or its group in the image: set by the layout
+    pub frame: u16,
+}
+
+impl Segment {
+    pub fn new(name: &str, align: usize, stack: bool) -> Self {
+        Segment {
+            name: name.to_owned(),
+            align,
+            stack,
+            size: 0,
+            group: None,
+            start: 0,
+            frame: 0,
+        }
+    }
+
+    /// Offset of the segment start in the frame: the location counter starts here.
+    pub fn displacement(&self) -> u16 {
+        (self.start - self.frame as usize * 16) as u16
+    }
+}
+
+/// Alignment of the segment attribute: e.g. para => 16
+pub fn segment_align(align: &str) -> usize {
+    match align {
+        "byte" => 1,
+        "word" => 2,
+        "page" => 256,
+        _ => 16,
+    }
+}
+
+/// Set the start and frame of the segments
+/// groups: group name => segment names
+pub fn layout(
+    segments: &mut [Segment],
+    groups: &HashMap<String, Vec<String>>,
+) -> Result<(), String> {
+    let mut position: usize = 0;
+    for segment in segments.iter_mut() {
+        segment.start = position.next_multiple_of(segment.align);
+        segment.frame = (segment.start / 16) as u16;
+        segment.group = None;
+        position = segment.start + segment.size as usize;
+    }
+
+    let mut names: Vec<&String> = groups.keys().collect();
+    names.sort();
+    for name in names {
+        let members: Vec<usize> = (0..segments.len())
+            .filter(|i| groups[name].contains(&segments[*i].name))
+            .collect();
+        for member in groups[name].iter() {
+            if !segments.iter().any(|s| &s.name == member) {
+                return Err(format!(
+                    "Segment {} of group {} is not defined",
+                    member, name
+                ));
+            }
+        }
+        let Some(frame) = members.iter().map(|i| segments[*i].frame).min() else {
+            continue;
+        };
+        for i in members {
+            let segment = &mut segments[i];
+            if let Some(other) = &segment.group {
+                return Err(format!(
+                    "Segment {} is in the groups {} and {}",
+                    segment.name, other, name
+                ));
+            }
+            if segment.start + segment.size as usize - frame as usize * 16 > MAX_FRAME_SIZE {
+                return Err(format!("Group {} is larger than 64KB", name));
+            }
+            segment.group = Some(name.clone());
+            segment.frame = frame;
+        }
+    }
+    Ok(())
+}
+
+fn push_word(v: &mut Vec<u8>, word: u16) {
+    v.extend_from_slice(&word.to_le_bytes());
+}
+
+impl Assembly {
+    /// Positions of the words in the image that need the load segment
+    pub fn relocations(&self) -> Vec<usize> {
+        let mut v: Vec<usize> = Vec::new();
+        for line in self.lines.iter() {
+            if let Some(segment) = line.segment {
+                let start = self.segments[segment].frame as usize * 16 + line.address as usize;
+                v.extend(line.relocations.iter().map(|offset| start + offset));
+            }
+        }
+        v
+    }
+
+    /// MZ executable with the relocation table
+    pub fn exe(&self) -> Result<Vec<u8>, String> {
+        if self.segments.is_empty() {
+            return Err("EXE program should have segments".to_string());
+        }
+        let image = self.binary()?;
+        let relocations = self.relocations();
+
+        let header_size = (MZ_HEADER_SIZE + relocations.len() * 4).next_multiple_of(16);
+        let file_size = header_size + image.len();
+        let (ss, sp, min_alloc) = match self.segments.iter().find(|s| s.stack) {
+            Some(stack) => (stack.frame, stack.displacement() + stack.size, 0),
+            None => (
+                image.len().div_ceil(16) as u16,
+                STACK_SIZE as u16,
+                (STACK_SIZE / 16) as u16,
+            ),
+        };
+        let (cs, ip) = self.entry.unwrap_or((0, 0));
+
+        let mut v: Vec<u8> = Vec::new();
+        v.extend_from_slice(b"MZ");
+        push_word(&mut v, (file_size % PAGE_SIZE) as u16);
+        push_word(&mut v, file_size.div_ceil(PAGE_SIZE) as u16);
+        push_word(&mut v, relocations.len() as u16);
+        push_word(&mut v, (header_size / 16) as u16);
+        push_word(&mut v, min_alloc);
+        push_word(&mut v, 0xffff);
+        push_word(&mut v, ss);
+        push_word(&mut v, sp);
+        push_word(&mut v, 0); // checksum
+        push_word(&mut v, ip);
+        push_word(&mut v, cs);
+        push_word(&mut v, MZ_HEADER_SIZE as u16);
+        push_word(&mut v, 0); // overlay
+        for position in relocations {
+            // offset:segment of the word
+            push_word(&mut v, (position % 16) as u16);
+            push_word(&mut v, (position / 16) as u16);
+        }
+        v.resize(header_size, 0);
+        v.extend_from_slice(&image);
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assembler::{assemble, Options};
+    use crate::cpucontext::CpuContext;
+    use crate::loader::load_exe;
+    use crate::memory::Memory;
+
+    fn segment(name: &str, align: usize, size: u16) -> Segment {
+        Segment {
+            size,
+            ..Segment::new(name, align, false)
+        }
+    }
+
+    #[test]
+    fn test_linker_layout() {
+        let mut segments = vec![
+            segment("code", 16, 0x13),
+            segment("data", 1, 5),
+            segment("bss", 2, 4),
+            segment("stack", 256, 0x100),
+        ];
+        let mut groups = HashMap::new();
+        groups.insert(
+            "dgroup".to_string(),
+            vec!["data".to_string(), "bss".to_string()],
+        );
+        layout(&mut segments, &groups).unwrap();
+        assert_eq!(
+            vec![(0x00, 0), (0x13, 1), (0x18, 1), (0x100, 0x10)],
+            segments
+                .iter()
+                .map(|s| (s.start, s.frame))
+                .collect::<Vec<(usize, u16)>>()
+        );
+        assert_eq!(3, segments[1].displacement());
+        assert_eq!(8, segments[2].displacement());
+        assert_eq!(Some("dgroup".to_string()), segments[2].group);
+
+        groups.insert("other".to_string(), vec!["data".to_string()]);
+        assert!(layout(&mut segments, &groups).is_err());
+        groups.remove("other");
+        groups.insert("other".to_string(), vec!["nothing".to_string()]);
+        assert!(layout(&mut segments, &groups).is_err());
+    }
+
+    #[test]
+    fn test_linker_exe() {
+        let program: Vec<String> = [
+            "data segment",
+            "msg db 12h, 34h",
+            "data ends",
+            "code segment",
+            "assume cs:code, ds:data",
+            "start:",
+            "mov ax, seg msg",
+            "mov ds, ax",
+            "code ends",
+            "stack segment stack",
+            "db 20h dup(?)",
+            "stack ends",
+            "end start",
+        ]
+        .iter()
+        .map(|l| l.to_string())
+        .collect();
+        let include = |name: &str| Err(format!("{} is not found", name));
+        let assembly = assemble(&program, &include, &Options::default()).unwrap();
+        assert_eq!(vec![0x11], assembly.relocations());
+        let exe = assembly.exe().unwrap();
+        assert_eq!(b"MZ", &exe[0..2]);
+        // 1 relocation entry at 0001:0001
+        assert_eq!(vec![1, 0, 2, 0], exe[0x06..0x0a].to_vec());
+        assert_eq!(vec![1, 0, 1, 0], exe[0x1c..0x20].to_vec());
+
+        let mut cpu = CpuContext::boot();
+        let mut memory = Memory::boot();
+        load_exe(&mut cpu, &mut memory, &exe, 0x1000, "").unwrap();
+        // load segment 1010h + frame of code
+        assert_eq!(0x1011, cpu.get_register16("cs"));
+        assert_eq!(0, cpu.get_register16("ip"));
+        assert_eq!(0x1012, cpu.get_register16("ss"));
+        assert_eq!(0x20, cpu.get_register16("sp"));
+        // mov ax, 1010h: segment of data after relocation
+        assert_eq!(vec![0xb8, 0x10, 0x10], memory.dump(0x10110, 3));
+        assert_eq!(vec![0x12, 0x34], memory.dump(0x10100, 2));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::assembler::{AssembledLine, Assembly};
+
+/*
+Listing file (.lst): each source line with its address and machine code
+
+Line Addr Machine code       Source
+   1                         org 100h
+   2 0100 BB 10 00           mov bx, 10h
+   3 0103 C7 06 00 10 34 12  mov word ptr [1000h], 0x1234
+
+Machine code longer than BYTES_PER_ROW continues on the next rows without the source.
+The lines expanded from a macro call follow the call line with + mark.
+The lines of an include file follow the include line with + mark and the file name and line number:
+   5                         include "consts.inc"
+   5 0106 B8 01 00           + consts.inc(2) mov ax, 1
+The symbol table is at the end of the listing.
+The addresses of a program with segments are the offsets in the frame of each segment,
+and the segments placed by the linker follow the symbol table.
+*/
+
+const BYTES_PER_ROW: usize = 6;
+const CODE_WIDTH: usize = BYTES_PER_ROW * 3;
+
+fn hex_bytes(code: &[u8]) -> String {
+    code.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Line without machine code
+fn source_row(linenum: usize, source: &str) -> String {
+    format!(
+        "{:4} {:4} {:<width$}{}\n",
+        linenum + 1,
+        "",
+        "",
+        source,
+        width = CODE_WIDTH
+    )
+}
+
+// Machine code is split into the rows of BYTES_PER_ROW bytes
+fn code_rows(s: &mut String, linenum: usize, line: &AssembledLine, source: &str) {
+    let mut rows = line.code.chunks(BYTES_PER_ROW);
+    let first = rows.next().unwrap_or(&[]);
+    s.push_str(&format!(
+        "{:4} {:04X} {:<width$}{}\n",
+        linenum + 1,
+        line.address,
+        hex_bytes(first),
+        source,
+        width = CODE_WIDTH
+    ));
+    for (i, row) in rows.enumerate() {
+        let address = line.address.wrapping_add(((i + 1) * BYTES_PER_ROW) as u16);
+        s.push_str(&format!("     {:04X} {}\n", address, hex_bytes(row)));
+    }
+}
+
+pub fn listing(program: &[String], assembly: &Assembly) -> String {
+    let mut s = String::new();
+    s.push_str(&format!(
+        "Line Addr {:<width$} Source\n",
+        "Machine code",
+        width = CODE_WIDTH - 1
+    ));
+
+    let mut lines = assembly.lines.iter().peekable();
+    for (linenum, source) in program.iter().enumerate() {
+        let mut printed = false;
+        while let Some(line) = lines.next_if(|l| l.linenum == linenum) {
+            match &line.expansion {
+                Some(text) => {
+                    // Macro call line and the expanded lines marked with +
+                    if !printed {
+                        s.push_str(&source_row(linenum, source));
+                    }
+                    let text = match &line.location {
+                        Some((file, n)) => format!("+ {}({}) {}", file, n + 1, text),
+                        None => format!("+ {}", text),
+                    };
+                    code_rows(&mut s, linenum, line, &text);
+                }
+                None => code_rows(&mut s, linenum, line, source),
+            }
+            printed = true;
+        }
+        if !printed {
+            s.push_str(&source_row(linenum, source));
+        }
+    }
+
+    let mut symbols: Vec<(&String, &u16)> = assembly.symbols.iter().collect();
+    symbols.sort();
+    s.push_str("\nSymbols:\n");
+    s.push_str(&format!("{:<32} Address\n", "Name"));
+    for (name, address) in symbols {
+        s.push_str(&format!("{:<32} {:04X}\n", name, address));
+    }
+
+    if !assembly.segments.is_empty() {
+        s.push_str("\nSegments:\n");
+        s.push_str(&format!("{:<32} Start Size Frame Group\n", "Name"));
+        for segment in assembly.segments.iter() {
+            let row = format!(
+                "{:<32} {:05X} {:04X} {:04X}  {}",
+                segment.name,
+                segment.start,
+                segment.size,
+                segment.frame,
+                segment.group.as_deref().unwrap_or("")
+            );
+            s.push_str(row.trim_end());
+            s.push('\n');
+        }
+    }
+
+    if !assembly.constants.is_empty() {
+        let mut constants: Vec<(&String, &i64)> = assembly.constants.iter().collect();
+        constants.sort();
+        s.push_str("\nConstants:\n");
+        s.push_str(&format!("{:<32} Value\n", "Name"));
+        for (name, value) in constants {
+            s.push_str(&format!("{:<32} {}\n", name, value));
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assembler::{assemble, Options};
+
+    fn no_include(name: &str) -> Result<Vec<String>, String> {
+        Err(format!("{} is not found", name))
+    }
+
+    #[test]
+    fn test_listing() {
+        let program: Vec<String> = [
+            "org 100h",
+            "start:",
+            "mov word ptr [1000h], 0x1234",
+            "; comment",
+            "jmp start",
+            "count equ 2 * 3",
+        ]
+        .iter()
+        .map(|l| l.to_string())
+        .collect();
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        let expected = "\
+Line Addr Machine code      Source
+   1                        org 100h
+   2                        start:
+   3 0100 C7 06 00 10 34 12 mov word ptr [1000h], 0x1234
+   4                        ; comment
+   5 0106 EB F8             jmp start
+   6                        count equ 2 * 3
+
+Symbols:
+Name                             Address
+start                            0100
+
+Constants:
+Name                             Value
+count                            6
+";
+        assert_eq!(expected, listing(&program, &assembly));
+    }
+
+    #[test]
+    fn test_listing_macro() {
+        let program: Vec<String> = ["two macro", "inc ax", "inc ax", "endm", "two"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        let assembly = assemble(&program, &no_include, &Options::default()).unwrap();
+        let s = listing(&program, &assembly);
+        let rows: Vec<&str> = s.lines().collect();
+        assert_eq!("   4                        endm", rows[4]);
+        assert_eq!("   5                        two", rows[5]);
+        assert_eq!("   5 0000 40                + inc ax", rows[6]);
+        assert_eq!("   5 0001 40                + inc ax", rows[7]);
+    }
+
+    #[test]
+    fn test_listing_include() {
+        let program: Vec<String> = ["inc ax", "include \"two.inc\""]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        let include = |_: &str| Ok(vec!["inc bx".to_string(), "inc cx".to_string()]);
+        let assembly = assemble(&program, &include, &Options::default()).unwrap();
+        let s = listing(&program, &assembly);
+        let rows: Vec<&str> = s.lines().collect();
+        assert_eq!("   2                        include \"two.inc\"", rows[2]);
+        assert_eq!("   2 0001 43                + two.inc(1) inc bx", rows[3]);
+        assert_eq!("   2 0002 41                + two.inc(2) inc cx", rows[4]);
+    }
+
+    #[test]
+    fn test_listing_long_code() {
+        let assembly = Assembly {
+            origin: 0,
+            lines: vec![AssembledLine {
+                linenum: 0,
+                address: 0x10,
+                code: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                expansion: None,
+                location: None,
+                segment: None,
+                relocations: Vec::new(),
+            }],
+            ..Default::default()
+        };
+        let s = listing(&["data".to_string()], &assembly);
+        let rows: Vec<&str> = s.lines().collect();
+        assert_eq!("   1 0010 01 02 03 04 05 06 data", rows[1]);
+        assert_eq!("     0016 07 08", rows[2]);
+    }
+}
@@ -0,0 +1,447 @@
+use crate::cpucontext::CpuContext;
+use crate::memory::{physical_address, Memory};
+
+/*
+Program Segment Prefix (PSP): 256 bytes before the program
+offset | size | contents
+00h    | 2    | int 20h (CD 20): terminate program when the program jumps here
+02h    | 2    | segment of the first byte beyond the memory allocated to the program
+0Ah    | 4    | terminate address (IP, CS)
+2Ch    | 2    | environment segment
+80h    | 1    | length of the command tail
+81h    | 127  | command tail terminated with 0Dh
+
+.COM file is a raw binary image loaded at PSP:0100h.
+All segment registers are the PSP segment and the stack is at the top of the segment.
+e.g. org 100h in the assembly source for .COM file
+
+MZ header of .EXE file: all fields are 16-bit words
+offset | contents
+00h    | "MZ" signature
+02h    | bytes in the last 512-byte page (0 means the whole page)
+04h    | number of 512-byte pages in the file including the header
+06h    | number of relocation entries
+08h    | header size in paragraphs
+0Ah    | minimum extra paragraphs needed
+0Ch    | maximum extra paragraphs needed
+0Eh    | initial SS relative to the load segment
+10h    | initial SP
+12h    | checksum
+14h    | initial IP
+16h    | initial CS relative to the load segment
+18h    | offset of the relocation table
+1Ah    | overlay number
+
+The load module (the file after the header) is loaded at PSP + 10h.
+Each relocation entry is offset:segment of a word in the load module
+and the load segment is added to the word.
+*/
+
+/// Load the program at segment 0 as like org handler does,
+/// then the physical addresses are the offsets of the program.
+pub const DEFAULT_LOAD_SEGMENT: u16 = 0;
+pub const PSP_SIZE: usize = 0x100;
+/// 640KB: conventional memory for DOS programs
+const MEMORY_TOP_SEGMENT: u16 = 0xa000;
+const COM_MAX_SIZE: usize = 0x10000 - PSP_SIZE - 2; // leave space for the initial stack
+const COMMAND_TAIL_MAX: usize = 126;
+const MZ_HEADER_SIZE: usize = 0x1c;
+const PAGE_SIZE: usize = 512;
+
+const PSP_INT20: usize = 0x00;
+const PSP_MEMORY_TOP: usize = 0x02;
+const PSP_TERMINATE_ADDRESS: usize = 0x0a;
+const PSP_ENV_SEGMENT: usize = 0x2c;
+const PSP_COMMAND_TAIL: usize = 0x80;
+
+fn read_word(buf: &[u8], offset: usize) -> u16 {
+    // Little-endian: first low byte, second high byte
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+fn write_word(buf: &mut [u8], offset: usize, v: u16) {
+    // Little-endian: write lower byte first
+    buf[offset] = (v & 0xff) as u8;
+    buf[offset + 1] = ((v & 0xff00) >> 8) as u8;
+}
+
+/// Build the PSP at segment:0000
+/// Terminate address points the int 20h of PSP because there is no parent program.
+fn build_psp(
+    memory: &mut Memory,
+    segment: u16,
+    env_segment: u16,
+    memory_top: u16,
+    command_tail: &str,
+) -> Result<(), String> {
+    if command_tail.len() > COMMAND_TAIL_MAX {
+        return Err(format!(
+            "Command tail is longer than {} bytes",
+            COMMAND_TAIL_MAX
+        ));
+    }
+
+    let mut psp = [0u8; PSP_SIZE];
+    psp[PSP_INT20] = 0xcd;
+    psp[PSP_INT20 + 1] = 0x20;
+    write_word(&mut psp, PSP_MEMORY_TOP, memory_top);
+    write_word(&mut psp, PSP_TERMINATE_ADDRESS, PSP_INT20 as u16);
+    write_word(&mut psp, PSP_TERMINATE_ADDRESS + 2, segment);
+    write_word(&mut psp, PSP_ENV_SEGMENT, env_segment);
+
+    let tail = command_tail.as_bytes();
+    psp[PSP_COMMAND_TAIL] = tail.len() as u8;
+    psp[PSP_COMMAND_TAIL + 1..PSP_COMMAND_TAIL + 1 + tail.len()].copy_from_slice(tail);
+    psp[PSP_COMMAND_TAIL + 1 + tail.len()] = 0x0d;
+
+    memory.load(physical_address(segment, 0), &psp)
+}
+
+/// Build an empty environment block: no variables and no program name
+fn build_env(memory: &mut Memory, env_segment: u16) -> Result<(), String> {
+    memory.load(physical_address(env_segment, 0), &[0, 0, 0, 0])
+}
+
+/// Load .COM image at segment:0100h and set registers to run it
+/// CS=DS=ES=SS=segment, IP=0100h, SP=FFFEh
+pub fn load_com(
+    cpu: &mut CpuContext,
+    memory: &mut Memory,
+    image: &[u8],
+    segment: u16,
+    command_tail: &str,
+) -> Result<(), String> {
+    if image.len() > COM_MAX_SIZE {
+        return Err(format!(
+            "COM image is {} bytes but it should be smaller than {} bytes",
+            image.len(),
+            COM_MAX_SIZE
+        ));
+    }
+    // The program owns whole 64KB segment and the environment is placed after it.
+    let env_segment = segment
+        .checked_add(0x1000)
+        .ok_or_else(|| format!("Load segment {:04X} is too high", segment))?;
+
+    build_env(memory, env_segment)?;
+    build_psp(
+        memory,
+        segment,
+        env_segment,
+        MEMORY_TOP_SEGMENT,
+        command_tail,
+    )?;
+    memory.load(physical_address(segment, PSP_SIZE as u16), image)?;
+    // Return address of near ret: jump to int 20h in PSP
+    memory.load(physical_address(segment, 0xfffe), &[0, 0])?;
+
+    cpu.set_register16("cs", segment);
+    cpu.set_register16("ds", segment);
+    cpu.set_register16("es", segment);
+    cpu.set_register16("ss", segment);
+    cpu.set_register16("ip", PSP_SIZE as u16);
+    cpu.set_register16("sp", 0xfffe);
+    Ok(())
+}
+
+#[derive(Debug)]
+struct MzHeader {
+    last_page_bytes: u16,
+    pages: u16,
+    relocations: u16,
+    header_paragraphs: u16,
+    min_alloc: u16,
+    max_alloc: u16,
+    ss: u16,
+    sp: u16,
+    ip: u16,
+    cs: u16,
+    relocation_table: u16,
+}
+
+impl MzHeader {
+    fn parse(image: &[u8]) -> Result<Self, String> {
+        if image.len() < MZ_HEADER_SIZE || &image[0..2] != b"MZ" {
+            return Err("Not a MZ executable".to_string());
+        }
+        let last_page_bytes = read_word(image, 0x02);
+        let pages = read_word(image, 0x04);
+        if pages == 0 || last_page_bytes as usize >= PAGE_SIZE {
+            return Err(format!(
+                "Broken MZ header: {} pages and {} bytes in the last page",
+                pages, last_page_bytes
+            ));
+        }
+        Ok(MzHeader {
+            last_page_bytes,
+            pages,
+            relocations: read_word(image, 0x06),
+            header_paragraphs: read_word(image, 0x08),
+            min_alloc: read_word(image, 0x0a),
+            max_alloc: read_word(image, 0x0c),
+            ss: read_word(image, 0x0e),
+            sp: read_word(image, 0x10),
+            ip: read_word(image, 0x14),
+            cs: read_word(image, 0x16),
+            relocation_table: read_word(image, 0x18),
+        })
+    }
+
+    /// Size of the file described by the header
+    fn file_size(&self) -> usize {
+        let size = self.pages as usize * PAGE_SIZE;
+        if self.last_page_bytes == 0 {
+            size
+        } else {
+            size - PAGE_SIZE + self.last_page_bytes as usize
+        }
+    }
+}
+
+/// Load .EXE image with the PSP at segment:0000
+/// CS:IP and SS:SP come from the header, DS=ES=PSP segment.
+pub fn load_exe(
+    cpu: &mut CpuContext,
+    memory: &mut Memory,
+    image: &[u8],
+    segment: u16,
+    command_tail: &str,
+) -> Result<(), String> {
+    let header = MzHeader::parse(image)?;
+
+    let header_size = header.header_paragraphs as usize * 16;
+    let file_size = header.file_size();
+    if file_size > image.len() || header_size > file_size {
+        return Err(format!(
+            "Broken MZ header: file size {} header size {} but image is {} bytes",
+            file_size,
+            header_size,
+            image.len()
+        ));
+    }
+    let module = &image[header_size..file_size];
+
+    // Memory allocation in paragraphs: PSP + load module + extra paragraphs
+    // The last paragraph of the conventional memory is for the environment block.
+    let load_segment = segment as usize + PSP_SIZE / 16;
+    let module_paragraphs = module.len().div_ceil(16);
+    let available = (MEMORY_TOP_SEGMENT as usize - 1)
+        .checked_sub(load_segment + module_paragraphs)
+        .ok_or_else(|| format!("No memory to load {} bytes", module.len()))?;
+    if (header.min_alloc as usize) > available {
+        return Err(format!(
+            "Program needs {} paragraphs but only {} paragraphs are available",
+            header.min_alloc, available
+        ));
+    }
+    let extra = available.min(header.max_alloc as usize);
+    let memory_top = (load_segment + module_paragraphs + extra) as u16;
+    let env_segment = memory_top;
+
+    build_env(memory, env_segment)?;
+    build_psp(memory, segment, env_segment, memory_top, command_tail)?;
+    let load_address = load_segment * 16;
+    memory.load(load_address, module)?;
+
+    // Relocation: add the load segment to each word pointed by the entries
+    let table = header.relocation_table as usize;
+    for i in 0..header.relocations as usize {
+        let entry = table + i * 4;
+        if entry + 4 > image.len() {
+            return Err(format!("Relocation entry {} is out of the file", i));
+        }
+        let offset = read_word(image, entry);
+        let seg = read_word(image, entry + 2);
+        let address = load_address + seg as usize * 16 + offset as usize;
+        let word = memory.dump(address, 2);
+        let v = read_word(&word, 0).wrapping_add(load_segment as u16);
+        let mut word = [0u8; 2];
+        write_word(&mut word, 0, v);
+        memory.load(address, &word)?;
+    }
+
+    cpu.set_register16("cs", header.cs.wrapping_add(load_segment as u16));
+    cpu.set_register16("ip", header.ip);
+    cpu.set_register16("ss", header.ss.wrapping_add(load_segment as u16));
+    cpu.set_register16("sp", header.sp);
+    cpu.set_register16("ds", segment);
+    cpu.set_register16("es", segment);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::assembler::{assemble, Options};
+
+    #[test]
+    fn test_loader_com() {
+        let mut cpu = CpuContext::boot();
+        let mut memory = Memory::boot();
+        let image = [0xb8, 0x34, 0x12, 0x40];
+        load_com(&mut cpu, &mut memory, &image, 0x1000, " abc").unwrap();
+
+        assert_eq!(0x1000, cpu.get_register16("cs"));
+        assert_eq!(0x1000, cpu.get_register16("ds"));
+        assert_eq!(0x1000, cpu.get_register16("es"));
+        assert_eq!(0x1000, cpu.get_register16("ss"));
+        assert_eq!(0x100, cpu.get_register16("ip"));
+        assert_eq!(0xfffe, cpu.get_register16("sp"));
+
+        // image at PSP:0100h
+        assert_eq!(image.to_vec(), memory.dump(0x10100, 4));
+        // int 20h, memory top segment
+        assert_eq!(vec![0xcd, 0x20, 0x00, 0xa0], memory.dump(0x10000, 4));
+        // terminate address 1000:0000
+        assert_eq!(vec![0x00, 0x00, 0x00, 0x10], memory.dump(0x1000a, 4));
+        // environment segment
+        assert_eq!(vec![0x00, 0x20], memory.dump(0x1002c, 2));
+        // command tail
+        assert_eq!(
+            vec![0x04, b' ', b'a', b'b', b'c', 0x0d],
+            memory.dump(0x10080, 6)
+        );
+    }
+
+    #[test]
+    fn test_loader_com_failure() {
+        let mut cpu = CpuContext::boot();
+        let mut memory = Memory::boot();
+        let image = vec![0x90; 0x10000];
+        assert!(load_com(&mut cpu, &mut memory, &image, 0, "").is_err());
+        assert!(load_com(&mut cpu, &mut memory, &[0x90], 0xf800, "").is_err());
+        let tail = "a".repeat(127);
+        assert!(load_com(&mut cpu, &mut memory, &[0x90], 0, &tail).is_err());
+    }
+
+    /// Make a small EXE: 2-paragraph header with one relocation entry
+    /// code segment 0: mov ax, seg data(=1) ; data segment 1
+    fn make_exe(min_alloc: u16, max_alloc: u16) -> Vec<u8> {
+        let mut exe = vec![0u8; 0x20];
+        exe[0] = b'M';
+        exe[1] = b'Z';
+        let module = [
+            0xb8, 0x01, 0x00, 0xc3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34,
+        ];
+        let size = exe.len() + module.len();
+        write_word(&mut exe, 0x02, (size % PAGE_SIZE) as u16);
+        write_word(&mut exe, 0x04, size.div_ceil(PAGE_SIZE) as u16);
+        write_word(&mut exe, 0x06, 1);
+        write_word(&mut exe, 0x08, 2);
+        write_word(&mut exe, 0x0a, min_alloc);
+        write_word(&mut exe, 0x0c, max_alloc);
+        write_word(&mut exe, 0x0e, 2); // ss
+        write_word(&mut exe, 0x10, 0x100); // sp
+        write_word(&mut exe, 0x14, 0); // ip
+        write_word(&mut exe, 0x16, 0); // cs
+        write_word(&mut exe, 0x18, 0x1c);
+        // relocation entry: 0000:0001
+        write_word(&mut exe, 0x1c, 0x0001);
+        write_word(&mut exe, 0x1e, 0x0000);
+        exe.extend_from_slice(&module);
+        exe
+    }
+
+    #[test]
+    fn test_loader_exe() {
+        let mut cpu = CpuContext::boot();
+        let mut memory = Memory::boot();
+        let exe = make_exe(0x10, 0x20);
+        load_exe(&mut cpu, &mut memory, &exe, 0x1000, "").unwrap();
+
+        // load segment = PSP + 10h
+        assert_eq!(0x1010, cpu.get_register16("cs"));
+        assert_eq!(0, cpu.get_register16("ip"));
+        assert_eq!(0x1012, cpu.get_register16("ss"));
+        assert_eq!(0x100, cpu.get_register16("sp"));
+        assert_eq!(0x1000, cpu.get_register16("ds"));
+        assert_eq!(0x1000, cpu.get_register16("es"));
+
+        // mov ax, 1 => mov ax, 1011h after relocation
+        assert_eq!(vec![0xb8, 0x11, 0x10, 0xc3], memory.dump(0x10100, 4));
+        assert_eq!(vec![0x12, 0x34], memory.dump(0x10110, 2));
+        // memory top = load segment + 2 paragraphs of module + 20h max alloc
+        assert_eq!(vec![0x32, 0x10], memory.dump(0x10002, 2));
+    }
+
+    #[test]
+    fn test_loader_exe_failure() {
+        let mut cpu = CpuContext::boot();
+        let mut memory = Memory::boot();
+        assert!(load_exe(&mut cpu, &mut memory, &[0xb8, 0x34, 0x12], 0, "").is_err());
+
+        // too much minimum allocation
+        let exe = make_exe(0xa000, 0xffff);
+        assert!(load_exe(&mut cpu, &mut memory, &exe, 0, "").is_err());
+
+        // truncated file
+        let exe = make_exe(0, 0);
+        assert!(load_exe(&mut cpu, &mut memory, &exe[..0x22], 0, "").is_err());
+
+        // no pages, or the last page longer than a page
+        let mut exe = make_exe(0, 0);
+        write_word(&mut exe, 0x04, 0);
+        assert!(load_exe(&mut cpu, &mut memory, &exe, 0, "").is_err());
+        let mut exe = make_exe(0, 0);
+        write_word(&mut exe, 0x02, PAGE_SIZE as u16);
+        assert!(load_exe(&mut cpu, &mut memory, &exe, 0, "").is_err());
+    }
+
+    #[test]
+    fn test_loader_exe_run() {
+        let mut hardware = crate::Hardware8086::new();
+        hardware
+            .load_exe(&make_exe(0x10, 0x20), 0x1000, "")
+            .unwrap();
+        // mov ax, seg data with the relocated segment
+        assert_eq!(Ok(0), hardware.step(0));
+        assert_eq!(0x1011, hardware.cpu.get_register16("ax"));
+        assert_eq!(3, hardware.cpu.get_register16("ip"));
+        // ret pops 0 from SS:SP of the header.
+        assert_eq!(Ok(0), hardware.step(0));
+        assert_eq!(
+            (0x1010, 0),
+            (
+                hardware.cpu.get_register16("cs"),
+                hardware.cpu.get_register16("ip")
+            )
+        );
+        assert_eq!(0x102, hardware.cpu.get_register16("sp"));
+    }
+
+    #[test]
+    fn test_loader_exe_data() {
+        let program: Vec<String> = [
+            "data segment",
+            "pad db 0",
+            "msg dw 1234h",
+            "data ends",
+            "code segment",
+            "assume cs:code, ds:data",
+            "start:",
+            "mov ax, seg msg",
+            "mov ds, ax",
+            "mov bx, [msg]",
+            "inc word ptr [msg]",
+            "code ends",
+            "end start",
+        ]
+        .iter()
+        .map(|l| l.to_string())
+        .collect();
+        let include = |name: &str| Err(format!("{} is not found", name));
+        let assembly = assemble(&program, &include, &Options::default()).unwrap();
+        let mut hardware = crate::Hardware8086::new();
+        hardware
+            .load_exe(&assembly.exe().unwrap(), 0x1000, "")
+            .unwrap();
+        for _ in 0..4 {
+            assert_eq!(Ok(0), hardware.step(0));
+        }
+        // data is the first segment at the load segment 1010h after the PSP.
+        assert_eq!(0x1010, hardware.cpu.get_register16("ds"));
+        assert_eq!(0x1234, hardware.cpu.get_register16("bx"));
+        assert_eq!(vec![0x35, 0x12], hardware.memory.dump(0x10101, 2));
+    }
+}
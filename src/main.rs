@@ -1,40 +1,75 @@
 mod add;
 mod assembler;
+mod breakpoint;
+mod callstack;
+mod cli;
 mod common;
+mod condition;
 mod cpucontext;
+mod dap;
+mod data;
+mod disassembler;
+mod error;
+mod expr;
+mod gdbstub;
+mod hexfile;
 mod inc;
 mod jmp;
+mod journal;
+mod linker;
+mod listing;
+mod loader;
 mod memory;
+mod memview;
 mod mov;
 mod org;
 mod parser;
+mod snapshot;
+mod stack;
+mod trace;
 
 use paste::paste;
+use pest::iterators::Pair;
 use pest::Parser;
 use std::collections::HashMap;
 use std::sync::Mutex;
 
 use actix_cors::Cors;
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use breakpoint::{Location, Stop};
+use error::AsmError;
 use serde_json::Value;
 
+// A macro call line stops after this number of instructions: e.g. infinite loop in the macro
+const MAX_RANGE_STEPS: usize = 10000;
+// /run stops after this number of steps if the request has no max_steps
+const DEFAULT_RUN_STEPS: usize = 10000;
+// Request body of /restore: the compressed snapshot is usually a few KB.
+const MAX_SNAPSHOT_SIZE: usize = 4 * 1024 * 1024;
+// Segment of the program with segments: EXE is loaded after the PSP.
+const LOAD_SEGMENT: u16 = loader::DEFAULT_LOAD_SEGMENT + (loader::PSP_SIZE / 16) as u16;
+
 #[derive(Debug)]
 pub struct ProgramLine {
     code: String,
     // BUGBUG: It will be 20-bit address with segment:address
-    _start_address: u16,
-    _machine_code: Vec<u8>,
-    // If this line has a label,
-    label: bool,
+    start_address: u16,
+    // Empty if the line has no code or failed to be assembled
+    machine_code: Vec<u8>,
+    // Macro call: machine_code is the code of all the expanded lines
+    expanded: bool,
+    // Line removed or expanded by the macro preprocessor
+    preprocessed: bool,
 }
 
 impl ProgramLine {
     fn new(code: &str) -> Self {
         ProgramLine {
             code: code.to_owned(),
-            _start_address: 0,
-            _machine_code: Vec::new(),
-            label: false,
+            start_address: 0,
+            machine_code: Vec::new(),
+            expanded: false,
+            preprocessed: false,
         }
     }
 }
@@ -43,6 +78,23 @@ struct Hardware8086 {
     cpu: cpucontext::CpuContext,
     memory: memory::Memory,
     program: HashMap<usize, ProgramLine>,
+    // Result of the assembler: None if the program failed to be assembled
+    assembly: Option<assembler::Assembly>,
+    // A binary program is loaded instead of the assembly source
+    binary: bool,
+    // Errors and warnings of the last build
+    diagnostics: Vec<AsmError>,
+    // Options of the assembler for the build
+    options: assembler::Options,
+    breakpoints: breakpoint::Breakpoints,
+    // hlt stopped the CPU
+    halted: bool,
+    // Undo journal of the executed instructions for step_back and rewind
+    journal: journal::Journal,
+    // Source and include files of the last build for the snapshot
+    source: Vec<String>,
+    files: HashMap<String, Vec<String>>,
+    tracer: trace::Tracer,
 }
 
 impl Hardware8086 {
@@ -51,28 +103,169 @@ impl Hardware8086 {
             cpu: cpucontext::CpuContext::boot(),
             memory: memory::Memory::boot(),
             program: HashMap::new(),
+            assembly: None,
+            binary: false,
+            diagnostics: Vec::new(),
+            options: assembler::Options::default(),
+            breakpoints: breakpoint::Breakpoints::default(),
+            halted: false,
+            journal: journal::Journal::default(),
+            source: Vec::new(),
+            files: HashMap::new(),
+            tracer: trace::Tracer::default(),
         }
     }
 
     /// return: next line number
     fn handle_instruction(&mut self, linenum: usize) -> Result<usize, String> {
-        println!("Handle instruction:{}-line", linenum);
-        let programline: &ProgramLine = self.program.get(&linenum).unwrap();
+        let programline: &ProgramLine = self
+            .program
+            .get(&linenum)
+            .ok_or_else(|| format!("Line {} is not in the program", linenum + 1))?;
         // The line is not an instruction as like "mov ax, bx"
         // but also COMMENT, NEWLINE or WHITESPACE.
         // Therefore it uses Rule::program, not Rule::instruction when parsing the line
         // because Rule::instruction cannot handle COMMENT, NEWLINE and WHITESPACE.
-        let line: &str = &programline.code;
-        println!("line={:?}", line);
-        let program = parser::AssemblyParser::parse(parser::Rule::program, line)
-            .unwrap()
+        let line: String = programline.code.clone();
+        let address = programline.start_address;
+        let machine_code = programline.machine_code.clone();
+        if programline.expanded {
+            // Run all the expanded lines as one step of the macro call line
+            let end = address.wrapping_add(machine_code.len() as u16);
+            self.execute_range(address, end)?;
+            return Ok(linenum + 1);
+        }
+        if programline.preprocessed {
+            // Macro definition does nothing.
+            return Ok(linenum + 1);
+        }
+        let program = parser::AssemblyParser::parse(parser::Rule::program, &line)
+            .map_err(|e| e.to_string())?
             .next()
             .unwrap();
 
         assert_eq!(parser::Rule::program, program.as_rule());
-        let instruction = program.into_inner().next().unwrap();
+        let mut pairs = program.into_inner();
+        let mut instruction = pairs.next().unwrap();
+        // Run the instruction after the label on the same line
+        if instruction.as_rule() == parser::Rule::label {
+            if let Some(next) = pairs.next().filter(|p| p.as_rule() != parser::Rule::EOI) {
+                instruction = next;
+            }
+        }
         let mut nextline = linenum + 1;
 
+        match instruction.as_rule() {
+            // Empty lines, comments, labels, data, constants and segment directives do nothing.
+            parser::Rule::EOI
+            | parser::Rule::label
+            | parser::Rule::data
+            | parser::Rule::equ
+            | parser::Rule::assign
+            | parser::Rule::segment
+            | parser::Rule::ends
+            | parser::Rule::section
+            | parser::Rule::assume
+            | parser::Rule::group
+            | parser::Rule::end => {}
+            parser::Rule::jmp => {
+                // The target address is in the machine code: jmp rel16 or jmp rel8
+                let next = address.wrapping_add(machine_code.len() as u16);
+                let target = match machine_code[..] {
+                    [0xe9, low, high] => Some(next.wrapping_add(u16::from_le_bytes([low, high]))),
+                    [0xeb, rel] => Some(next.wrapping_add(rel as i8 as u16)),
+                    _ => None,
+                };
+                if let Some(target) = target {
+                    self.cpu.set_register16("ip", target);
+                }
+                let segment = self.line_segment(linenum);
+                nextline = target
+                    .and_then(|target| self.label_line(segment, target))
+                    .unwrap_or(nextline);
+            }
+            parser::Rule::hlt => {
+                self.cpu.set_register16("ip", address.wrapping_add(1));
+                self.halted = true;
+            }
+            parser::Rule::call if machine_code.len() == stack::CALL_SIZE => {
+                let next = address.wrapping_add(stack::CALL_SIZE as u16);
+                let target =
+                    next.wrapping_add(u16::from_le_bytes([machine_code[1], machine_code[2]]));
+                stack::push(&mut self.cpu, &mut self.memory, next);
+                self.cpu.set_register16("ip", target);
+                let segment = self.line_segment(linenum);
+                nextline = self.label_line(segment, target).unwrap_or(nextline);
+            }
+            parser::Rule::ret => {
+                let ip = stack::pop(&mut self.cpu, &self.memory);
+                self.cpu.set_register16("ip", ip);
+                if let [0xc2, low, high] = machine_code[..] {
+                    let sp = self.cpu.get_register16("sp");
+                    self.cpu
+                        .set_register16("sp", sp.wrapping_add(u16::from_le_bytes([low, high])));
+                }
+                // Returning to no code ends the program.
+                let segment = self.line_segment(linenum);
+                nextline = self.code_line(segment, ip).unwrap_or(self.program.len());
+            }
+            parser::Rule::jmp_far if machine_code.len() == 5 => {
+                // CS:IP is set by the machine code and the next line is the label.
+                let ip = u16::from_le_bytes([machine_code[1], machine_code[2]]);
+                let cs = u16::from_le_bytes([machine_code[3], machine_code[4]]);
+                self.cpu.set_register16("ip", ip);
+                self.cpu.set_register16("cs", cs);
+                let segment = self.assembly.as_ref().and_then(|assembly| {
+                    assembly
+                        .segments
+                        .iter()
+                        .position(|s| LOAD_SEGMENT.wrapping_add(s.frame) == cs)
+                });
+                nextline = self.label_line(segment, ip).unwrap_or(nextline);
+            }
+            // Symbols are resolved only in the machine code
+            _ if !machine_code.is_empty() => self.execute_machine_code(&machine_code, address)?,
+            _ => self.execute(instruction)?,
+        }
+        Ok(nextline)
+    }
+
+    /// Line of the label at the address in the segment
+    /// A label alone on the line continues at the next line.
+    fn label_line(&self, segment: Option<usize>, address: u16) -> Option<usize> {
+        let assembly = self.assembly.as_ref()?;
+        let label = assembly
+            .labels
+            .iter()
+            .find(|l| l.segment == segment && l.address == address)?;
+        if assembly.lines.iter().any(|l| l.linenum == label.linenum) {
+            Some(label.linenum)
+        } else {
+            Some(label.linenum + 1)
+        }
+    }
+
+    /// Line of the code at the address in the segment
+    fn code_line(&self, segment: Option<usize>, address: u16) -> Option<usize> {
+        let assembly = self.assembly.as_ref()?;
+        let line = assembly.lines.iter().find(|l| {
+            l.segment == segment
+                && !l.code.is_empty()
+                && l.address <= address
+                && (address as usize) < l.address as usize + l.code.len()
+        })?;
+        Some(line.linenum)
+    }
+
+    /// Segment of the code of the line
+    fn line_segment(&self, linenum: usize) -> Option<usize> {
+        let assembly = self.assembly.as_ref()?;
+        let line = assembly.lines.iter().find(|l| l.linenum == linenum)?;
+        line.segment
+    }
+
+    /// Call the handler of the instruction
+    fn execute(&mut self, instruction: Pair<parser::Rule>) -> Result<(), String> {
         match instruction.as_rule() {
             parser::Rule::mov => {
                 caller_two!(mov, self.cpu, self.memory, instruction);
@@ -86,39 +279,432 @@ impl Hardware8086 {
             parser::Rule::inc => {
                 caller_one!(inc, self.cpu, self.memory, instruction);
             }
-            parser::Rule::label => {
-                println!("Meet label and do nothing");
+            // Handlers of the stack module: push and pop
+            parser::Rule::push => {
+                let operand = instruction.into_inner().next().unwrap();
+                stack::handler_push(&mut self.cpu, &mut self.memory, operand)?;
             }
-            parser::Rule::jmp => {
-                let operand = instruction.clone().into_inner().next().unwrap();
-                assert_eq!(parser::Rule::name, operand.as_rule());
-                caller_one!(jmp, self.cpu, self.memory, instruction);
-                for (linenum, programline) in self.program.iter() {
-                    println!("linenum {}: programline {}", linenum, programline.code);
-                    // BUGBUG: start_with() is not best to compare "labelname:" and "labelname"
-                    if programline.label == true && programline.code.starts_with(operand.as_str()) {
-                        nextline = *linenum + 1;
-                        println!("set next={}", nextline);
-                    }
+            parser::Rule::pop => {
+                let operand = instruction.into_inner().next().unwrap();
+                stack::handler_pop(&mut self.cpu, &mut self.memory, operand)?;
+            }
+            _ => {
+                return Err(format!(
+                    "{} is not implemented yet",
+                    instruction.as_str().trim()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute one machine instruction at CS:IP
+    /// It is used to run a binary program that has no source code.
+    fn step_machine(&mut self) -> Result<(), String> {
+        let cs = self.cpu.get_register16("cs");
+        let ip = self.cpu.get_register16("ip");
+        // 6 bytes is the longest instruction the disassembler can decode.
+        let code = self.memory.dump(memory::physical_address(cs, ip), 6);
+
+        // Control transfers are handled here because the parser has no form for them.
+        match code[0] {
+            0xcd if code[1] == 0x20 => {
+                return Err(format!(
+                    "Program terminated by int 20h at {:04X}:{:04X}",
+                    cs, ip
+                ));
+            }
+            0xc3 | 0xc2 => {
+                // near ret: pop IP and remove the arguments of ret imm16
+                let ip = stack::pop(&mut self.cpu, &self.memory);
+                self.cpu.set_register16("ip", ip);
+                if code[0] == 0xc2 {
+                    let sp = self.cpu.get_register16("sp");
+                    let n = u16::from_le_bytes([code[1], code[2]]);
+                    self.cpu.set_register16("sp", sp.wrapping_add(n));
                 }
+                return Ok(());
             }
-            _ => println!("NOT implemented yet:{}", &programline.code),
+            0xe8 => {
+                // call rel16: push IP of the next instruction
+                let next = ip.wrapping_add(stack::CALL_SIZE as u16);
+                stack::push(&mut self.cpu, &mut self.memory, next);
+                let rel = u16::from_le_bytes([code[1], code[2]]);
+                self.cpu.set_register16("ip", next.wrapping_add(rel));
+                return Ok(());
+            }
+            0xea => {
+                // jmp ptr16:16: offset and segment
+                self.cpu
+                    .set_register16("ip", code[1] as u16 | (code[2] as u16) << 8);
+                self.cpu
+                    .set_register16("cs", code[3] as u16 | (code[4] as u16) << 8);
+                return Ok(());
+            }
+            0xe9 => {
+                // jmp rel16: relative to the next instruction
+                let rel = code[1] as u16 | (code[2] as u16) << 8;
+                self.cpu
+                    .set_register16("ip", ip.wrapping_add(3).wrapping_add(rel));
+                return Ok(());
+            }
+            0xeb => {
+                // jmp rel8: sign extended
+                let rel = code[1] as i8 as i16 as u16;
+                self.cpu
+                    .set_register16("ip", ip.wrapping_add(2).wrapping_add(rel));
+                return Ok(());
+            }
+            0xf4 => {
+                // hlt: IP is the next instruction
+                self.cpu.set_register16("ip", ip.wrapping_add(1));
+                self.halted = true;
+                return Ok(());
+            }
+            _ => {}
         }
-        println!("After instruction: {:?}", self.cpu);
 
-        println!("nextline={}", nextline);
-        Ok(nextline)
+        self.execute_machine_code(&code, ip)
+    }
+
+    /// Run the machine code from start until IP leaves start..end
+    fn execute_range(&mut self, start: u16, end: u16) -> Result<(), String> {
+        self.cpu.set_register16("ip", start);
+        for _ in 0..MAX_RANGE_STEPS {
+            let ip = self.cpu.get_register16("ip");
+            if ip < start || ip >= end {
+                return Ok(());
+            }
+            self.step_machine()?;
+        }
+        Err(format!(
+            "Too many steps between {:04X} and {:04X}",
+            start, end
+        ))
+    }
+
+    /// Decode the machine code at ip and call the handler
+    /// IP points the next instruction before the handler is called.
+    fn execute_machine_code(&mut self, code: &[u8], ip: u16) -> Result<(), String> {
+        let (text, len) = disassembler::decode(code)?;
+        let instruction = parser::AssemblyParser::parse(parser::Rule::instruction, &text)
+            .map_err(|e| e.to_string())?
+            .next()
+            .unwrap();
+        self.cpu.set_register16("ip", ip.wrapping_add(len as u16));
+        self.execute(instruction)
     }
 
     fn reboot(&mut self) {
         self.cpu.reboot();
         self.memory.reboot();
+        self.halted = false;
+        self.journal.clear();
+        self.tracer.clear();
+    }
+
+    /// Execute one instruction and record it in the journal
+    /// The binary program runs the instruction at CS:IP.
+    /// return: next line number
+    fn step(&mut self, linenum: usize) -> Result<usize, String> {
+        let cpu = self.cpu.clone();
+        let halted = self.halted;
+        let instruction = self
+            .tracer
+            .enabled
+            .then(|| self.instruction_at(linenum))
+            .flatten();
+        self.memory.begin_journal();
+        if instruction.is_some() {
+            self.memory.begin_trace();
+        }
+        let result = if self.binary {
+            self.step_machine().map(|_| linenum)
+        } else {
+            self.handle_instruction(linenum)
+        };
+        let writes = self.memory.end_journal();
+        let accesses = self.memory.end_trace();
+        if let Ok(next_line) = result {
+            if let Some((cs, ip, bytes, text)) = instruction {
+                self.tracer.push(trace::Record {
+                    step: self.journal.step() + 1,
+                    cs,
+                    ip,
+                    bytes,
+                    text,
+                    changes: trace::Record::changes(&cpu, &self.cpu),
+                    flags: self.cpu.get_register16("flags"),
+                    memory: accesses,
+                });
+            }
+            let entry = journal::Entry {
+                cpu,
+                halted,
+                line: linenum,
+                writes,
+            };
+            self.journal.push(entry, next_line);
+        }
+        result
+    }
+
+    /// CS:IP, bytes and text of the next instruction for the trace
+    /// The line mode has the source line and its machine code: None if the line has no code.
+    fn instruction_at(&self, linenum: usize) -> Option<(u16, u16, Vec<u8>, String)> {
+        if self.binary {
+            let cs = self.cpu.get_register16("cs");
+            let ip = self.cpu.get_register16("ip");
+            let code = self.memory.dump(memory::physical_address(cs, ip), 6);
+            let (text, len) = disassembler::machine_instruction(&code, ip)
+                .unwrap_or_else(|_| (format!("db {:02X}h", code[0]), 1));
+            return Some((cs, ip, code[..len].to_vec(), text));
+        }
+        let line = self.program.get(&linenum)?;
+        if line.machine_code.is_empty() {
+            return None;
+        }
+        let (cs, ip) = self.line_address(linenum)?;
+        Some((
+            cs,
+            ip,
+            line.machine_code.clone(),
+            line.code.trim().to_string(),
+        ))
+    }
+
+    /// Undo the last instruction
+    /// return: line of the instruction
+    fn step_back(&mut self) -> Result<usize, String> {
+        let entry = self
+            .journal
+            .pop()
+            .ok_or_else(|| "No instruction to step back".to_string())?;
+        self.memory.undo(&entry.writes);
+        self.cpu = entry.cpu;
+        self.halted = entry.halted;
+        Ok(entry.line)
+    }
+
+    /// Undo the instructions until the first `to` instructions are executed
+    /// return: next line number
+    fn rewind(&mut self, to: usize) -> Result<usize, String> {
+        if to > self.journal.step() {
+            return Err(format!(
+                "Step {} is not executed: the last step is {}",
+                to,
+                self.journal.step()
+            ));
+        }
+        if to < self.journal.first_step() {
+            return Err(format!(
+                "Step {} is not in the journal: the first step is {}",
+                to,
+                self.journal.first_step()
+            ));
+        }
+        while self.journal.step() > to {
+            self.step_back()?;
+        }
+        Ok(self.journal.next_line())
+    }
+
+    /// CS:IP of the code of the line
+    /// The segment of the line is loaded after the PSP, otherwise CS is not changed.
+    fn line_address(&self, linenum: usize) -> Option<(u16, u16)> {
+        let assembly = self.assembly.as_ref()?;
+        let line = assembly.lines.iter().find(|l| l.linenum == linenum)?;
+        let cs = match line.segment {
+            Some(index) => LOAD_SEGMENT.wrapping_add(assembly.segments[index].frame),
+            None => self.cpu.get_register16("cs"),
+        };
+        Some((cs, line.address))
+    }
+
+    /// The location is the next instruction: the line of the source or CS:IP of the binary
+    fn at_location(&self, location: &Location, linenum: Option<usize>) -> bool {
+        let address = match linenum {
+            Some(linenum) => self.line_address(linenum),
+            None => Some((self.cpu.get_register16("cs"), self.cpu.get_register16("ip"))),
+        };
+        match location {
+            Location::Line(line) => linenum == Some(*line),
+            Location::Address(cs, ip) => address == Some((*cs, *ip)),
+            Location::Anywhere => true,
+            Location::Watch(..) => false,
+            Location::Label(name) => {
+                let Some(label) = self
+                    .assembly
+                    .as_ref()
+                    .and_then(|assembly| assembly.labels.iter().find(|l| &l.name == name))
+                else {
+                    return false;
+                };
+                match linenum {
+                    Some(_) => self.label_line(label.segment, label.address) == linenum,
+                    None => address.is_some_and(|(_, ip)| ip == label.address),
+                }
+            }
+        }
+    }
+
+    /// Condition of the breakpoint with the symbols of the program
+    fn condition_holds(&self, text: &str) -> Result<bool, String> {
+        let constants = HashMap::new();
+        let symbols = match &self.assembly {
+            Some(assembly) => assembler::SymbolTable::new(&assembly.symbols, false)
+                .with_constants(&constants, &assembly.constants),
+            None => assembler::SymbolTable::default(),
+        };
+        condition::holds(text, &self.cpu, &self.memory, &symbols)
+    }
+
+    /// Run from the line until a breakpoint, a watchpoint, hlt, an error or max_steps
+    /// The binary program runs from CS:IP.
+    /// return: next line, the reason of the stop and the number of steps
+    fn run(&mut self, linenum: usize, max_steps: usize) -> (usize, Stop, usize) {
+        self.run_until(linenum, max_steps, |_, _, _| false)
+    }
+
+    /// Execute the next line, or the whole procedure if it is a call
+    /// int and rep string instructions are not emulated: they are always one step.
+    fn step_over(&mut self, linenum: usize, max_steps: usize) -> (usize, Stop, usize) {
+        let is_call = self.opcode_at(linenum) == Some(0xe8);
+        let sp = self.cpu.get_register16("sp");
+        // The call is finished when the stack is back to SP.
+        self.run_until(linenum, max_steps, |hardware, _, _| {
+            !is_call || stack_distance(hardware, sp) >= 0
+        })
+    }
+
+    /// Run until a ret returns from the procedure
+    /// The depth of the calls is the stack: a ret popping above SP returns to the caller.
+    fn step_out(&mut self, linenum: usize, max_steps: usize) -> (usize, Stop, usize) {
+        let sp = self.cpu.get_register16("sp");
+        // The return address of the procedure is above SP: pop does not return.
+        self.run_until(linenum, max_steps, |hardware, _, opcode| {
+            matches!(opcode, Some(0xc3) | Some(0xc2)) && stack_distance(hardware, sp) > 0
+        })
+    }
+
+    /// Run until the next instruction is at the location as like a temporary breakpoint
+    fn run_to(
+        &mut self,
+        linenum: usize,
+        location: &Location,
+        max_steps: usize,
+    ) -> (usize, Stop, usize) {
+        self.run_until(linenum, max_steps, |hardware, line, _| {
+            hardware.at_location(location, (!hardware.binary).then_some(line))
+        })
+    }
+
+    /// run_steps with the watchpoints of the breakpoints
+    fn run_until(
+        &mut self,
+        linenum: usize,
+        max_steps: usize,
+        done: impl Fn(&Self, usize, Option<u8>) -> bool,
+    ) -> (usize, Stop, usize) {
+        self.memory.set_watches(self.breakpoints.watches());
+        let result = self.run_steps(linenum, max_steps, done);
+        self.memory.set_watches(Vec::new());
+        result
+    }
+
+    /// Opcode of the instruction of the line, or at CS:IP of the binary
+    fn opcode_at(&self, linenum: usize) -> Option<u8> {
+        if self.binary {
+            let cs = self.cpu.get_register16("cs");
+            let ip = self.cpu.get_register16("ip");
+            return Some(self.memory.dump(memory::physical_address(cs, ip), 1)[0]);
+        }
+        self.program.get(&linenum)?.machine_code.first().copied()
+    }
+
+    /// Run the steps until a breakpoint, hlt or done
+    /// done is called after each step with the next line and the opcode of the instruction:
+    /// Stop::Step if it is true.
+    fn run_steps(
+        &mut self,
+        linenum: usize,
+        max_steps: usize,
+        done: impl Fn(&Self, usize, Option<u8>) -> bool,
+    ) -> (usize, Stop, usize) {
+        let mut line = linenum;
+        self.halted = false;
+        for step in 0..max_steps {
+            if !self.binary && line >= self.program.len() {
+                return (line, Stop::End, step);
+            }
+            let at = (!self.binary).then_some(line);
+            // The 1st instruction continues from the breakpoint.
+            if step > 0 {
+                match self.breakpoints.find(
+                    |l| self.at_location(l, at),
+                    |condition| self.condition_holds(condition),
+                ) {
+                    Ok(Some(id)) => {
+                        self.breakpoints.hit(id);
+                        return (line, Stop::Breakpoint(id), step);
+                    }
+                    Ok(None) => {}
+                    Err(e) => return (line, Stop::Error(e), step),
+                }
+            }
+            let ip = self.cpu.get_register16("ip");
+            let opcode = self.opcode_at(line);
+            let result = self.step(line).map(|next| line = next);
+            if let Err(e) = result {
+                return (line, Stop::Error(e), step);
+            }
+            if let Some(&(id, address)) = self.memory.take_watch_hits().first() {
+                self.breakpoints.hit(id);
+                return (line, Stop::Watchpoint { id, address, ip }, step + 1);
+            }
+            if self.halted {
+                return (line, Stop::Halt, step + 1);
+            }
+            if done(self, line, opcode) {
+                return (line, Stop::Step, step + 1);
+            }
+        }
+        (line, Stop::Budget, max_steps)
+    }
+
+    /// Bytes changed by the last instruction: physical address, old and new value
+    /// The bytes written with the same value are not changed.
+    fn last_changes(&self) -> Vec<(usize, u8, u8)> {
+        let mut changes: Vec<(usize, u8, u8)> = Vec::new();
+        let writes = self
+            .journal
+            .last()
+            .map_or(&[][..], |entry| &entry.writes[..]);
+        // The first write of the address has the value before the instruction.
+        for &(address, old) in writes.iter() {
+            if changes.iter().all(|(a, _, _)| *a != address) {
+                let value = self.memory.dump(address, 1)[0];
+                changes.push((address, old, value));
+            }
+        }
+        changes.retain(|(_, old, value)| old != value);
+        changes.sort();
+        changes
+    }
+
+    /// The memory changed by the last instruction: [{"address": 510, "old": 0, "value": 9}]
+    fn changes_json(&self) -> Vec<Value> {
+        self.last_changes()
+            .iter()
+            .map(|(address, old, value)| {
+                serde_json::json!({ "address": address, "old": old, "value": value })
+            })
+            .collect()
     }
 
     /// Return CPU context in Json format
-    /// "Reg": "value"
+    /// "Reg": "value" and the memory changed by the last instruction
     fn program_response(&self, nextline: usize) -> serde_json::Value {
-        let m = format!("{}", self.memory);
         serde_json::json!({
             "nextline": nextline,
             "AX": self.cpu.get_register16("ax").to_string(),
@@ -135,25 +721,230 @@ impl Hardware8086 {
             "SS": self.cpu.get_register16("ss").to_string(),
             "IP": self.cpu.get_register16("ip").to_string(),
             "FLAGS": self.cpu.get_register16("flags").to_string(),
-            "memory": m,
+            "changes": self.changes_json(),
+            "step": self.journal.step(),
+            "call_stack": callstack::to_json(self, &callstack::frames(self, nextline)),
         })
     }
 
-    pub fn build_program_table(&mut self, program: &Vec<String>) {
+    /// Response of /step: only the deltas of the last instruction
+    /// "registers" has the registers changed by the instruction: e.g. {"AX": "1", "IP": "259"}
+    /// "call_stack" is only sent when SS, SP or BP changed: "frame" is the innermost frame if not.
+    fn step_response(&self, nextline: usize) -> serde_json::Value {
+        let Some(entry) = self.journal.last() else {
+            return self.program_response(nextline);
+        };
+        let changes = trace::Record::changes(&entry.cpu, &self.cpu);
+        let mut registers = serde_json::Map::new();
+        for (reg, _, new) in changes.iter() {
+            registers.insert(reg.to_ascii_uppercase(), new.to_string().into());
+        }
+        let mut response = serde_json::json!({
+            "nextline": nextline,
+            "registers": registers,
+            "changes": self.changes_json(),
+            "step": self.journal.step(),
+        });
+        let frames = callstack::frames(self, nextline);
+        if changes
+            .iter()
+            .any(|(reg, _, _)| ["ss", "sp", "bp"].contains(reg))
+        {
+            response["call_stack"] = callstack::to_json(self, &frames);
+        } else {
+            response["frame"] = callstack::to_json(self, &frames[..1])[0].clone();
+        }
+        response
+    }
+
+    /// Build the program with the include files: file name => lines
+    pub fn build_program_table(
+        &mut self,
+        program: &[String],
+        files: &HashMap<String, Vec<String>>,
+    ) {
         // Clear program table to read new program
         self.program.clear();
+        self.binary = false;
+        self.journal.clear();
+        self.tracer.clear();
+        self.diagnostics.clear();
+        self.source = program.to_vec();
+        self.files = files.clone();
         for (i, instruction) in program.iter().enumerate() {
-            let mut p = ProgramLine::new(instruction);
-            if instruction.ends_with(":") {
-                p.label = true;
+            self.program.insert(i, ProgramLine::new(instruction));
+        }
+
+        // Only the lines not changed by the preprocessor run with the source line.
+        let include = |name: &str| {
+            files
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("{} is not found", name))
+        };
+        if let Ok(source) = parser::preprocess(program, &include) {
+            for p in self.program.values_mut() {
+                p.preprocessed = true;
+            }
+            for line in source.iter().filter(|l| !l.expanded) {
+                self.program.get_mut(&line.linenum).unwrap().preprocessed = false;
             }
-            self.program.insert(i, p);
         }
+
+        // Unsupported instructions can still run with the source line.
+        self.assembly = match assembler::assemble(program, &include, &self.options) {
+            Ok(assembly) => {
+                // Data should be in memory before the instructions access it.
+                // A program with segments is loaded as EXE to set the segment registers.
+                let image = if assembly.segments.is_empty() {
+                    assembly.binary().and_then(|image| {
+                        self.memory
+                            .load(memory::physical_address(0, assembly.origin), &image)
+                    })
+                } else {
+                    assembly.exe().and_then(|exe| {
+                        loader::load_exe(
+                            &mut self.cpu,
+                            &mut self.memory,
+                            &exe,
+                            loader::DEFAULT_LOAD_SEGMENT,
+                            "",
+                        )
+                    })
+                };
+                self.diagnostics = assembly.warnings.clone();
+                if let Err(e) = image {
+                    println!("Failed to load the program: {}", e);
+                    self.diagnostics.push(AsmError::new(&e));
+                }
+                for line in assembly.lines.iter() {
+                    let p = self.program.get_mut(&line.linenum).unwrap();
+                    // A macro call line has several expanded lines.
+                    if p.machine_code.is_empty() {
+                        p.start_address = line.address;
+                    }
+                    match line.segment {
+                        // Relocated code in memory: e.g. jmp far ptr start
+                        Some(index) => {
+                            let frame = LOAD_SEGMENT + assembly.segments[index].frame;
+                            let address = memory::physical_address(frame, line.address);
+                            p.machine_code
+                                .extend(self.memory.dump(address, line.code.len()));
+                        }
+                        None => p.machine_code.extend_from_slice(&line.code),
+                    }
+                    p.expanded |= line.expansion.is_some();
+                }
+                Some(assembly)
+            }
+            Err(e) => {
+                println!("Failed to assemble: {}", error::join(&e));
+                self.diagnostics = e;
+                None
+            }
+        };
+    }
+
+    /// Load .COM binary and step it with machine code instead of the source lines
+    pub fn load_com(
+        &mut self,
+        image: &[u8],
+        segment: u16,
+        command_tail: &str,
+    ) -> Result<(), String> {
+        self.reboot();
+        self.program.clear();
+        self.assembly = None;
+        loader::load_com(
+            &mut self.cpu,
+            &mut self.memory,
+            image,
+            segment,
+            command_tail,
+        )?;
+        self.binary = true;
+        Ok(())
+    }
+
+    /// Load .EXE binary and step it with machine code instead of the source lines
+    pub fn load_exe(
+        &mut self,
+        image: &[u8],
+        segment: u16,
+        command_tail: &str,
+    ) -> Result<(), String> {
+        self.reboot();
+        self.program.clear();
+        self.assembly = None;
+        loader::load_exe(
+            &mut self.cpu,
+            &mut self.memory,
+            image,
+            segment,
+            command_tail,
+        )?;
+        self.binary = true;
+        Ok(())
+    }
+
+    /// Snapshot of the machine: line is the next line of the source
+    fn snapshot(&self, line: usize) -> snapshot::Snapshot {
+        let program = match self.binary {
+            true => snapshot::Program::Binary,
+            false => snapshot::Program::Source {
+                lines: self.source.clone(),
+                files: self.files.clone(),
+                optimize: self.options.optimize,
+            },
+        };
+        snapshot::Snapshot {
+            cpu: self.cpu.clone(),
+            halted: self.halted,
+            line,
+            memory: self.memory.dump(0, memory::MEMORY_SIZE),
+            program,
+            breakpoints: self.breakpoints.list().to_vec(),
+            last_id: self.breakpoints.last_id(),
+        }
+    }
+
+    /// Restore the machine of the snapshot: the source is built again.
+    /// return: next line of the source
+    fn restore(&mut self, snapshot: snapshot::Snapshot) -> Result<usize, String> {
+        self.reboot();
+        match snapshot.program {
+            snapshot::Program::Source {
+                lines,
+                files,
+                optimize,
+            } => {
+                self.options.optimize = optimize;
+                self.build_program_table(&lines, &files);
+            }
+            snapshot::Program::Binary => {
+                self.program.clear();
+                self.assembly = None;
+                self.binary = true;
+                self.source.clear();
+                self.files.clear();
+            }
+        }
+        self.memory.load(0, &snapshot.memory)?;
+        self.cpu = snapshot.cpu;
+        self.halted = snapshot.halted;
+        self.breakpoints =
+            breakpoint::Breakpoints::from_list(snapshot.breakpoints, snapshot.last_id);
+        Ok(snapshot.line)
     }
 
     // TODO: fn get_memory(&self) -> serde_json::Value {}
 }
 
+/// Bytes popped since SP was sp: negative while a procedure called after it runs
+fn stack_distance(hardware: &Hardware8086, sp: u16) -> i16 {
+    hardware.cpu.get_register16("sp").wrapping_sub(sp) as i16
+}
+
 struct HardwareLock {
     hardware: Mutex<Hardware8086>,
 }
@@ -161,13 +952,462 @@ struct HardwareLock {
 async fn handle_step(req_body: String, data: web::Data<HardwareLock>) -> impl Responder {
     println!("/step: Receive data={}", req_body);
     let mut hardware = data.hardware.lock().unwrap();
-    let v: Value = serde_json::from_str(&req_body).unwrap();
-    let linenum: usize = v["line"].as_u64().unwrap() as usize; // BUGBUG
-    let nextline = hardware.handle_instruction(linenum).unwrap();
-    HttpResponse::Ok().json(hardware.program_response(nextline))
+    let v: Value = match serde_json::from_str(&req_body) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    // Binary program has no line: step the instruction at CS:IP
+    let linenum = match v["line"].as_u64() {
+        _ if hardware.binary => 0,
+        Some(linenum) => linenum as usize,
+        None => return HttpResponse::BadRequest().body("line should be a number"),
+    };
+    match hardware.step(linenum) {
+        Ok(nextline) => HttpResponse::Ok().json(hardware.step_response(nextline)),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
     //HttpResponse::Ok()
 }
 
+/// Undo the last instruction: the response has the line of the instruction in "nextline"
+async fn handle_step_back(data: web::Data<HardwareLock>) -> impl Responder {
+    println!("/step_back");
+    let mut hardware = data.hardware.lock().unwrap();
+    match hardware.step_back() {
+        Ok(line) => HttpResponse::Ok().json(hardware.program_response(line)),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// Undo the instructions until the "step" of the response is N: /rewind?to=N
+/// /rewind?to=0 restores the state after the build.
+async fn handle_rewind(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<HardwareLock>,
+) -> impl Responder {
+    println!("/rewind: {:?}", query);
+    let to = match query.get("to").map(|to| to.parse::<usize>()) {
+        Some(Ok(to)) => to,
+        _ => return HttpResponse::BadRequest().body("to should be a step number"),
+    };
+    let mut hardware = data.hardware.lock().unwrap();
+    match hardware.rewind(to) {
+        Ok(line) => HttpResponse::Ok().json(hardware.program_response(line)),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// Run until a breakpoint, hlt, an error or max_steps
+/// req_body: {"line": 0, "max_steps": 10000}
+/// The response has the reason: e.g. {"nextline": 3, "reason": "breakpoint", "breakpoint": 1, "steps": 5, ...}
+async fn handle_run(req_body: String, data: web::Data<HardwareLock>) -> impl Responder {
+    println!("/run: Receive data={}", req_body);
+    let mut hardware = data.hardware.lock().unwrap();
+    let v: Value = match serde_json::from_str(&req_body) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let (linenum, max_steps) = run_request(&v);
+    let result = hardware.run(linenum, max_steps);
+    HttpResponse::Ok().json(run_response(&hardware, result))
+}
+
+/// Line and max_steps of /run, /step_over, /step_out and /run_to
+fn run_request(v: &Value) -> (usize, usize) {
+    let linenum = v["line"].as_u64().unwrap_or(0) as usize;
+    let max_steps = v["max_steps"]
+        .as_u64()
+        .map_or(DEFAULT_RUN_STEPS, |n| n as usize);
+    (linenum, max_steps)
+}
+
+/// Registers with the reason of the stop and the number of steps
+fn run_response(hardware: &Hardware8086, (nextline, stop, steps): (usize, Stop, usize)) -> Value {
+    let mut response = hardware.program_response(nextline);
+    if let (Value::Object(response), Value::Object(stop)) = (&mut response, stop.to_json()) {
+        response.extend(stop);
+    }
+    response["steps"] = serde_json::json!(steps);
+    response
+}
+
+/// Step over the line: {"line": 3, "max_steps": 1000}
+/// A call runs until it returns: "reason" is "step" after the return.
+async fn handle_step_over(req_body: String, data: web::Data<HardwareLock>) -> impl Responder {
+    println!("/step_over: Receive data={}", req_body);
+    let mut hardware = data.hardware.lock().unwrap();
+    let v: Value = match serde_json::from_str(&req_body) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let (linenum, max_steps) = run_request(&v);
+    let result = hardware.step_over(linenum, max_steps);
+    HttpResponse::Ok().json(run_response(&hardware, result))
+}
+
+/// Run until a ret returns from the procedure: {"line": 9}
+async fn handle_step_out(req_body: String, data: web::Data<HardwareLock>) -> impl Responder {
+    println!("/step_out: Receive data={}", req_body);
+    let mut hardware = data.hardware.lock().unwrap();
+    let v: Value = match serde_json::from_str(&req_body) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let (linenum, max_steps) = run_request(&v);
+    let result = hardware.step_out(linenum, max_steps);
+    HttpResponse::Ok().json(run_response(&hardware, result))
+}
+
+/// Run to the location: {"line": 0, "to": {"line": 12}}, {"to": {"cs": 0, "ip": 267}} or {"to": {"label": "done"}}
+/// The breakpoints before the location stop it as like /run.
+async fn handle_run_to(req_body: String, data: web::Data<HardwareLock>) -> impl Responder {
+    println!("/run_to: Receive data={}", req_body);
+    let mut hardware = data.hardware.lock().unwrap();
+    let v: Value = match serde_json::from_str(&req_body) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let location = match breakpoint::request_location(&v["to"]) {
+        Ok(location @ (Location::Line(_) | Location::Address(..) | Location::Label(_))) => location,
+        Ok(_) => return HttpResponse::BadRequest().body("to should be a line, CS:IP or label"),
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let (linenum, max_steps) = run_request(&v);
+    let result = hardware.run_to(linenum, &location, max_steps);
+    HttpResponse::Ok().json(run_response(&hardware, result))
+}
+
+/// List of the breakpoints: {"breakpoints":[{"id":1,"line":3,"enabled":true,"hits":0}]}
+async fn handle_breakpoints(data: web::Data<HardwareLock>) -> impl Responder {
+    let hardware = data.hardware.lock().unwrap();
+    HttpResponse::Ok().json(hardware.breakpoints.to_json())
+}
+
+/// Add a breakpoint: {"line": 3}, {"cs": 0, "ip": 259} or {"label": "again"}
+/// with {"condition": "cx == 0 && zf"}, or a watchpoint: {"start": 512, "len": 2, "access": "write"}
+/// {"id": 1, "enabled": false} disables the breakpoint and {"id": 1, "remove": true} removes it.
+async fn handle_set_breakpoint(req_body: String, data: web::Data<HardwareLock>) -> impl Responder {
+    println!("/breakpoints: Receive data={}", req_body);
+    let mut hardware = data.hardware.lock().unwrap();
+    let v: Value = match serde_json::from_str(&req_body) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let result = match v["id"].as_u64() {
+        Some(id) if v["remove"].as_bool() == Some(true) => hardware.breakpoints.remove(id as usize),
+        Some(id) => match v["enabled"].as_bool() {
+            Some(enabled) => hardware.breakpoints.enable(id as usize, enabled),
+            None => Err("enabled should be a boolean".to_string()),
+        },
+        None => breakpoint::request_breakpoint(&v).map(|(location, condition)| {
+            hardware.breakpoints.add(location, condition);
+        }),
+    };
+    match result {
+        Ok(()) => HttpResponse::Ok().json(hardware.breakpoints.to_json()),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// Assemble the code and return the machine code as a file
+/// req_body: {"code":["org 100h","mov ax, 1h"], "format":"com", "optimize":false}
+/// format is "com" or "bin"(flat binary, default)
+async fn handle_binary(req_body: String) -> impl Responder {
+    println!("/binary: Receive data={}", req_body);
+    let v: Value = match serde_json::from_str(&req_body) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let program: Vec<String> = match serde_json::from_value(v["code"].clone()) {
+        Ok(p) => p,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let format = v["format"].as_str().unwrap_or("bin");
+    let files = match request_files(&v) {
+        Ok(files) => files,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let include = |name: &str| {
+        files
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("{} is not found", name))
+    };
+    let image = assembler::assemble(&program, &include, &request_options(&v))
+        .map_err(|e| error::join(&e))
+        .and_then(|assembly| match format {
+            "com" => assembly.com(),
+            "exe" => assembly.exe(),
+            "bin" => assembly.binary(),
+            _ => Err(format!("Unknown format {}", format)),
+        });
+    match image {
+        Ok(image) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header((
+                actix_web::http::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"program.{}\"", format),
+            ))
+            .body(image),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// Options of the assembler in the request: {"optimize":false} for the longest encodings
+fn request_options(v: &Value) -> assembler::Options {
+    assembler::Options {
+        optimize: v["optimize"].as_bool().unwrap_or(true),
+    }
+}
+
+/// Include files of the request: {"files":{"consts.inc":"count equ 3\nsize equ 10h"}}
+fn request_files(v: &Value) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut files: HashMap<String, Vec<String>> = HashMap::new();
+    if let Some(map) = v["files"].as_object() {
+        for (name, content) in map.iter() {
+            let content = content
+                .as_str()
+                .ok_or_else(|| format!("Content of {} should be a string", name))?;
+            files.insert(
+                name.clone(),
+                content.lines().map(|l| l.to_string()).collect(),
+            );
+        }
+    }
+    Ok(files)
+}
+
+/// Get a hex number from the query string
+fn query_hex(query: &HashMap<String, String>, key: &str) -> Result<Option<usize>, String> {
+    match query.get(key) {
+        Some(s) => usize::from_str_radix(s.trim_start_matches("0x"), 16)
+            .map(Some)
+            .map_err(|_| format!("Invalid {} {}", key, s)),
+        None => Ok(None),
+    }
+}
+
+/// Export memory as Intel HEX or S-record: start and len are hex numbers
+/// e.g. curl "http://127.0.0.1:8080/export?format=ihex&start=100&len=40"
+async fn handle_export(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<HardwareLock>,
+) -> impl Responder {
+    println!("/export: {:?}", query);
+    let hardware = data.hardware.lock().unwrap();
+    let r = query_hex(&query, "start").and_then(|start| {
+        let start = start.unwrap_or(0);
+        let len = query_hex(&query, "len")?.unwrap_or(0x100);
+        match query.get("format").map(|f| f.as_str()) {
+            Some("ihex") | None => hexfile::to_intel_hex(&hardware.memory, start, len),
+            Some("srec") => hexfile::to_srecord(&hardware.memory, start, len),
+            Some(f) => Err(format!("Unknown format {}", f)),
+        }
+    });
+    match r {
+        Ok(text) => HttpResponse::Ok().content_type("text/plain").body(text),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// View of the memory: seg, off and len are hex numbers as like /export
+/// format: bytes (default), words, ascii or disassembly
+/// e.g. curl "http://127.0.0.1:8080/memory?seg=0&off=100&len=40&format=disassembly"
+async fn handle_memory(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<HardwareLock>,
+) -> impl Responder {
+    println!("/memory: {:?}", query);
+    let hardware = data.hardware.lock().unwrap();
+    match memory_view(&hardware, &query) {
+        Ok(view) => HttpResponse::Ok().json(view),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// The view of the query with the bytes changed by the last instruction
+fn memory_view(hardware: &Hardware8086, query: &HashMap<String, String>) -> Result<Value, String> {
+    let word = |key: &str| -> Result<u16, String> {
+        let n = query_hex(query, key)?.unwrap_or(0);
+        u16::try_from(n).map_err(|_| format!("{} should be a 16-bit number", key))
+    };
+    let len = query_hex(query, "len")?.unwrap_or(memview::DEFAULT_VIEW_LEN);
+    let format = match query.get("format") {
+        Some(name) => memview::Format::from_name(name)?,
+        None => memview::Format::Bytes,
+    };
+    let changed: Vec<usize> = hardware.last_changes().iter().map(|c| c.0).collect();
+    memview::view(
+        &hardware.memory,
+        word("seg")?,
+        word("off")?,
+        len,
+        format,
+        &changed,
+    )
+}
+
+/// Import Intel HEX or S-record into memory: the request body is the text of the records
+/// e.g. curl --data-binary @image.hex "http://127.0.0.1:8080/import?format=ihex"
+async fn handle_import(
+    req_body: String,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<HardwareLock>,
+) -> impl Responder {
+    println!("/import: {:?}", query);
+    let mut hardware = data.hardware.lock().unwrap();
+    let r = match query.get("format").map(|f| f.as_str()) {
+        Some("ihex") | None => hexfile::load_intel_hex(&mut hardware.memory, &req_body),
+        Some("srec") => hexfile::load_srecord(&mut hardware.memory, &req_body),
+        Some(f) => Err(format!("Unknown format {}", f)),
+    };
+    match r {
+        Ok(range) => {
+            println!("Imported range: {:X?}", range);
+            HttpResponse::Ok().json(hardware.program_response(0))
+        }
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// Trace settings: {"enabled": true, "capacity": 10000, "clear": true,
+/// "filter": {"start": 256, "end": 511, "mnemonics": ["add", "mov"]}}
+/// The response is the settings and the number of the records.
+async fn handle_set_trace(req_body: String, data: web::Data<HardwareLock>) -> impl Responder {
+    println!("/trace: Receive data={}", req_body);
+    let v: Value = match serde_json::from_str(&req_body) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let mut hardware = data.hardware.lock().unwrap();
+    let tracer = &mut hardware.tracer;
+    let r = (|| {
+        if let Some(capacity) = v["capacity"].as_u64() {
+            tracer.set_capacity(capacity as usize)?;
+        }
+        if !v["filter"].is_null() {
+            tracer.filter = trace::request_filter(&v["filter"])?;
+        }
+        if let Some(enabled) = v["enabled"].as_bool() {
+            tracer.enabled = enabled;
+        }
+        if v["clear"].as_bool() == Some(true) {
+            tracer.clear();
+        }
+        Ok::<(), String>(())
+    })();
+    match r {
+        Ok(()) => HttpResponse::Ok().json(tracer.to_json()),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// Export the trace: format is text (default) or jsonl
+/// e.g. curl "http://127.0.0.1:8080/trace?format=jsonl"
+async fn handle_trace(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<HardwareLock>,
+) -> impl Responder {
+    println!("/trace: {:?}", query);
+    let hardware = data.hardware.lock().unwrap();
+    match query.get("format").map(|f| f.as_str()) {
+        Some("text") | None => HttpResponse::Ok()
+            .content_type("text/plain")
+            .body(hardware.tracer.to_text()),
+        Some("jsonl") => HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .body(hardware.tracer.to_jsonl()),
+        Some(f) => HttpResponse::BadRequest().body(format!("Unknown format {}", f)),
+    }
+}
+
+/// Download the snapshot of the machine: line is the next line of the source
+/// e.g. curl -o machine.snap "http://127.0.0.1:8080/snapshot?line=3"
+async fn handle_snapshot(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<HardwareLock>,
+) -> impl Responder {
+    println!("/snapshot: {:?}", query);
+    let line = match query.get("line").map(|l| l.parse::<usize>()) {
+        Some(Ok(line)) => line,
+        None => 0,
+        Some(Err(_)) => return HttpResponse::BadRequest().body("line should be a number"),
+    };
+    let hardware = data.hardware.lock().unwrap();
+    match hardware.snapshot(line).to_bytes() {
+        Ok(bytes) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header((
+                actix_web::http::header::CONTENT_DISPOSITION,
+                "attachment; filename=\"machine.snap\"",
+            ))
+            .body(bytes),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// Restore the machine: the request body is the snapshot file
+/// e.g. curl --data-binary @machine.snap http://127.0.0.1:8080/restore
+async fn handle_restore(req_body: web::Bytes, data: web::Data<HardwareLock>) -> impl Responder {
+    println!("/restore: Receive {} bytes", req_body.len());
+    let mut hardware = data.hardware.lock().unwrap();
+    let r = snapshot::Snapshot::from_bytes(&req_body).and_then(|s| hardware.restore(s));
+    match r {
+        Ok(line) => {
+            let mut response = hardware.program_response(line);
+            response["code"] = serde_json::json!(hardware.source);
+            response["breakpoints"] = hardware.breakpoints.to_json()["breakpoints"].clone();
+            HttpResponse::Ok().json(response)
+        }
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// Get the load segment (hex) and the command tail from the query string
+fn load_options(query: &HashMap<String, String>) -> Result<(u16, &str), String> {
+    let segment = match query.get("segment") {
+        Some(s) => u16::from_str_radix(s.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("Invalid segment {}", s))?,
+        None => loader::DEFAULT_LOAD_SEGMENT,
+    };
+    let tail = query.get("tail").map(|t| t.as_str()).unwrap_or("");
+    Ok((segment, tail))
+}
+
+/// Load .COM file: the request body is the raw binary image
+/// e.g. curl --data-binary @hello.com "http://127.0.0.1:8080/load_com?segment=0&tail=abc"
+async fn handle_load_com(
+    req_body: web::Bytes,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<HardwareLock>,
+) -> impl Responder {
+    println!("/load_com: Receive {} bytes", req_body.len());
+    let mut hardware = data.hardware.lock().unwrap();
+    let r = load_options(&query)
+        .and_then(|(segment, tail)| hardware.load_com(&req_body, segment, tail));
+    if let Err(e) = r {
+        return HttpResponse::BadRequest().body(e);
+    }
+    HttpResponse::Ok().json(hardware.program_response(0))
+}
+
+/// Load .EXE file: the request body is the raw binary image
+/// e.g. curl --data-binary @hello.exe "http://127.0.0.1:8080/load_exe?segment=1000"
+async fn handle_load_exe(
+    req_body: web::Bytes,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<HardwareLock>,
+) -> impl Responder {
+    println!("/load_exe: Receive {} bytes", req_body.len());
+    let mut hardware = data.hardware.lock().unwrap();
+    let r = load_options(&query)
+        .and_then(|(segment, tail)| hardware.load_exe(&req_body, segment, tail));
+    if let Err(e) = r {
+        return HttpResponse::BadRequest().body(e);
+    }
+    HttpResponse::Ok().json(hardware.program_response(0))
+}
+
 async fn handle_reload(req_body: String, data: web::Data<HardwareLock>) -> impl Responder {
     println!("/reload: Receive data={}", req_body);
     let mut hardware = data.hardware.lock().unwrap();
@@ -177,22 +1417,56 @@ async fn handle_reload(req_body: String, data: web::Data<HardwareLock>) -> impl
 
 async fn handle_build(req_body: String, data: web::Data<HardwareLock>) -> impl Responder {
     println!("/build: Receive data={}", req_body);
-    // req_body: {"code":["mov ax, 1","mov bx, 1"]}
+    // req_body: {"code":["mov ax, 1","mov bx, 1"], "files":{"consts.inc":"count equ 3"}, "optimize":true}
+    let v: Value = match serde_json::from_str(&req_body) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let program: Vec<String> = match serde_json::from_value(v["code"].clone()) {
+        Ok(p) => p,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let files = match request_files(&v) {
+        Ok(files) => files,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
     let mut hardware = data.hardware.lock().unwrap();
     hardware.reboot();
-    let program: HashMap<String, Vec<String>> = serde_json::from_str(&req_body).unwrap();
-    hardware.build_program_table(&program["code"]);
+    hardware.options = request_options(&v);
+    hardware.build_program_table(&program, &files);
     println!("Build new program table: {:?}", hardware.program);
-    HttpResponse::Ok().json(hardware.program_response(0))
+    let mut response = hardware.program_response(0);
+    if let Some(assembly) = &hardware.assembly {
+        response["listing"] = serde_json::json!(listing::listing(&program, assembly));
+    }
+    // e.g. [{"file":null,"line":3,"column":9,"severity":"error","message":"..."}]
+    let errors: Vec<Value> = hardware.diagnostics.iter().map(|e| e.to_json()).collect();
+    response["errors"] = Value::Array(errors);
+    HttpResponse::Ok().json(response)
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // Run a command without the web-server: e.g. remu8086 build example.as
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     println!("Rust web-server started at 127.0.0.1:8080");
 
     let myserverdata = web::Data::new(HardwareLock {
         hardware: Mutex::new(Hardware8086::new()),
     });
+    // gdb debugs the same machine as the web page.
+    match gdbstub::listen(gdbstub::GDB_ADDRESS, myserverdata.clone().into_inner()) {
+        Ok(()) => println!("GDB stub started at {}", gdbstub::GDB_ADDRESS),
+        Err(e) => println!("GDB stub is not started: {}", e),
+    }
 
     HttpServer::new(move || {
         App::new()
@@ -202,12 +1476,35 @@ async fn main() -> std::io::Result<()> {
                     .allow_any_origin() // Allow all domain: necessary for local file index.html
                     .allowed_methods(vec!["GET", "POST"]) // 허용할 HTTP 메서드
                     .allowed_headers(vec![actix_web::http::header::CONTENT_TYPE])
+                    .expose_headers(vec![actix_web::http::header::CONTENT_DISPOSITION])
                     .max_age(3600),
             )
             .app_data(myserverdata.clone())
             .route("/step", web::post().to(handle_step))
+            .route("/step_back", web::post().to(handle_step_back))
+            .route("/rewind", web::post().to(handle_rewind))
+            .route("/run", web::post().to(handle_run))
+            .route("/memory", web::get().to(handle_memory))
+            .route("/step_over", web::post().to(handle_step_over))
+            .route("/step_out", web::post().to(handle_step_out))
+            .route("/run_to", web::post().to(handle_run_to))
+            .route("/breakpoints", web::get().to(handle_breakpoints))
+            .route("/breakpoints", web::post().to(handle_set_breakpoint))
             .route("/reload", web::post().to(handle_reload))
             .route("/build", web::post().to(handle_build))
+            .route("/binary", web::post().to(handle_binary))
+            .route("/load_com", web::post().to(handle_load_com))
+            .route("/load_exe", web::post().to(handle_load_exe))
+            .route("/export", web::get().to(handle_export))
+            .route("/snapshot", web::get().to(handle_snapshot))
+            .route("/trace", web::get().to(handle_trace))
+            .route("/trace", web::post().to(handle_set_trace))
+            .service(
+                web::resource("/restore")
+                    .app_data(web::PayloadConfig::new(MAX_SNAPSHOT_SIZE))
+                    .route(web::post().to(handle_restore)),
+            )
+            .route("/import", web::post().to(handle_import))
     })
     .bind(("127.0.0.1", 8080))?
     .run()
@@ -219,6 +1516,11 @@ mod tests {
     use super::*;
     use std::fs::read_to_string;
 
+    /// Source lines of the test program
+    fn program(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
     #[test]
     fn test_main_run_example_file() {
         //
@@ -232,9 +1534,752 @@ mod tests {
             .lines()
             .map(|l| l.to_owned())
             .collect::<Vec<String>>();
-        hardware.build_program_table(&program);
+        hardware.build_program_table(&program, &HashMap::new());
         for i in 0..program.len() {
             let _ = hardware.handle_instruction(i).unwrap();
         }
     }
+
+    #[test]
+    fn test_main_run_com() {
+        // mov ax, 0x1234
+        // add ax, 0x1
+        // mov word ptr [0x200], ax
+        // jmp short $+2
+        // inc word ptr [0x200]
+        // ret
+        let image = [
+            0xb8, 0x34, 0x12, 0x05, 0x01, 0x00, 0xa3, 0x00, 0x02, 0xeb, 0x00, 0xff, 0x06, 0x00,
+            0x02, 0xc3,
+        ];
+        let mut hardware = Hardware8086::new();
+        hardware.load_com(&image, 0, "").unwrap();
+        for _ in 0..6 {
+            hardware.step_machine().unwrap();
+        }
+        assert_eq!(0x1235, hardware.cpu.get_register16("ax"));
+        assert_eq!(0x1236, hardware.memory.read16(0, 0x200));
+        // ret jumps to int 20h in PSP
+        assert_eq!(0, hardware.cpu.get_register16("ip"));
+        assert!(hardware.step_machine().is_err());
+    }
+
+    #[test]
+    fn test_main_run_data() {
+        let program = program(&[
+            "org 100h",
+            "mov ax, [value]",
+            "mov bx, offset table",
+            "add ax, word ptr [table]",
+            "mov [value], ax",
+            "value dw 1234h",
+            "table dw 1h, 2h",
+        ]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        for i in 0..5 {
+            hardware.handle_instruction(i).unwrap();
+        }
+        assert_eq!(0x1235, hardware.cpu.get_register16("ax"));
+        assert_eq!(0x10f, hardware.cpu.get_register16("bx"));
+        assert_eq!(0x1235, hardware.memory.read16(0, 0x10d));
+        assert_eq!(0x10d, hardware.cpu.get_register16("ip"));
+    }
+
+    #[test]
+    fn test_main_run_macro() {
+        let program = program(&[
+            "addtwo macro reg",
+            "  inc reg",
+            "  inc reg",
+            "endm",
+            "org 100h",
+            "addtwo ax",
+            "rept 2",
+            "  inc bx",
+            "endm",
+        ]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        let mut line = 0;
+        while line < program.len() {
+            line = hardware.handle_instruction(line).unwrap();
+        }
+        assert_eq!(2, hardware.cpu.get_register16("ax"));
+        assert_eq!(2, hardware.cpu.get_register16("bx"));
+        assert_eq!(0x104, hardware.cpu.get_register16("ip"));
+    }
+
+    #[test]
+    fn test_main_run_include() {
+        let program = program(&["org 100h", "include \"regs.inc\"", "inc cx"]);
+        let v = serde_json::json!({"files": {"regs.inc": "inc ax\ninc bx"}});
+        let files = request_files(&v).unwrap();
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &files);
+        let mut line = 0;
+        while line < program.len() {
+            line = hardware.handle_instruction(line).unwrap();
+        }
+        assert_eq!(1, hardware.cpu.get_register16("ax"));
+        assert_eq!(1, hardware.cpu.get_register16("bx"));
+        assert_eq!(1, hardware.cpu.get_register16("cx"));
+        assert_eq!(0x103, hardware.cpu.get_register16("ip"));
+    }
+
+    #[test]
+    fn test_main_run_uppercase() {
+        let program = program(&[
+            "\tORG\t100H",
+            "AGAIN:\tINC\tCX\t; label and instruction",
+            "\tMOV\tWORD PTR [200H], CX",
+            "\tJMP\tAGAIN",
+        ]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        assert!(hardware.diagnostics.is_empty());
+        let mut line = 0;
+        for _ in 0..7 {
+            line = hardware.handle_instruction(line).unwrap();
+        }
+        // The jump goes back to the line of the label to run inc cx again.
+        assert_eq!(1, line);
+        assert_eq!(2, hardware.cpu.get_register16("cx"));
+        assert_eq!(2, hardware.memory.read16(0, 0x200));
+    }
+
+    #[test]
+    fn test_main_run_local_labels() {
+        let program = program(&[
+            "org 100h",
+            "main:",
+            "1:  inc ax",
+            "    jmp .skip",
+            "    inc bx",
+            ".skip:",
+            "    jmp 1b",
+        ]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        let mut lines = Vec::new();
+        let mut line = 0;
+        for _ in 0..6 {
+            line = hardware.handle_instruction(line).unwrap();
+            lines.push(line);
+        }
+        // .skip is alone on the line: the next line runs after the jump.
+        assert_eq!(vec![1, 2, 3, 6, 2, 3], lines);
+        assert_eq!(2, hardware.cpu.get_register16("ax"));
+        assert_eq!(0, hardware.cpu.get_register16("bx"));
+    }
+
+    #[test]
+    fn test_main_run_optimized() {
+        let program = program(&[
+            "org 100h",
+            "mov bx, offset value",
+            "add bx, -2",
+            "add word ptr [value], 5",
+            "mov ax, [value]",
+            "jmp done",
+            "inc cx",
+            "done: inc dx",
+            "value dw 10",
+        ]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        let assembly = hardware.assembly.as_ref().unwrap();
+        assert_eq!(vec![0x83, 0x06, 0x12, 0x01, 5], assembly.lines[2].code);
+        assert_eq!(vec![0xeb, 0x01], assembly.lines[4].code);
+        let mut line = 0;
+        for _ in 0..6 {
+            line = hardware.handle_instruction(line).unwrap();
+        }
+        // IP of the jmp is the label.
+        assert_eq!((7, 0x111), (line, hardware.cpu.get_register16("ip")));
+        assert_eq!(Ok(8), hardware.handle_instruction(line));
+        assert_eq!(15, hardware.cpu.get_register16("ax"));
+        assert_eq!(0x110, hardware.cpu.get_register16("bx"));
+        assert_eq!(0, hardware.cpu.get_register16("cx"));
+        assert_eq!(1, hardware.cpu.get_register16("dx"));
+
+        hardware.options.optimize = false;
+        hardware.build_program_table(&program, &HashMap::new());
+        let assembly = hardware.assembly.as_ref().unwrap();
+        assert_eq!(vec![0xe9, 0x01, 0x00], assembly.lines[4].code);
+    }
+
+    #[test]
+    fn test_main_run_breakpoints() {
+        let source = program(&["org 100h", "start:", "  inc ax", "  inc bx", "  jmp start"]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&source, &HashMap::new());
+        assert_eq!((4, Stop::Budget, 10), hardware.run(0, 10));
+
+        hardware.reboot();
+        // The run from the breakpoint continues and stops at it again.
+        let line = hardware.breakpoints.add(Location::Line(3), None);
+        assert_eq!((3, Stop::Breakpoint(line), 1), hardware.run(2, 10));
+        assert_eq!((3, Stop::Breakpoint(line), 3), hardware.run(3, 10));
+        assert_eq!(2, hardware.cpu.get_register16("ax"));
+        assert_eq!(1, hardware.cpu.get_register16("bx"));
+
+        // start: is alone on the line: it stops at the next line.
+        hardware.breakpoints.enable(line, false).unwrap();
+        let label = hardware
+            .breakpoints
+            .add(Location::Label("start".to_string()), None);
+        assert_eq!((2, Stop::Breakpoint(label), 2), hardware.run(3, 10));
+        hardware.breakpoints.remove(label).unwrap();
+        let address = hardware.breakpoints.add(Location::Address(0, 0x101), None);
+        assert_eq!((3, Stop::Breakpoint(address), 1), hardware.run(2, 10));
+        assert_eq!(
+            serde_json::json!([2, 1]),
+            hardware.breakpoints.to_json()["breakpoints"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|b| b["hits"].clone())
+                .collect::<Value>()
+        );
+
+        let halt = program(&["inc ax", "hlt", "inc bx"]);
+        hardware.breakpoints = breakpoint::Breakpoints::default();
+        hardware.reboot();
+        hardware.build_program_table(&halt, &HashMap::new());
+        assert_eq!((2, Stop::Halt, 2), hardware.run(0, 10));
+        assert_eq!((3, Stop::End, 1), hardware.run(2, 10));
+    }
+
+    #[test]
+    fn test_main_run_conditions() {
+        let program = program(&["org 100h", "start:", "  inc ax", "  inc bx", "  jmp start"]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        let id = hardware
+            .breakpoints
+            .add(Location::Line(2), Some("bx == 2".to_string()));
+        assert_eq!((2, Stop::Breakpoint(id), 6), hardware.run(2, 20));
+        hardware.breakpoints.remove(id).unwrap();
+
+        hardware.reboot();
+        let id = hardware
+            .breakpoints
+            .add(Location::Anywhere, Some("ax == 3 && !zf".to_string()));
+        assert_eq!((3, Stop::Breakpoint(id), 7), hardware.run(2, 20));
+        assert_eq!(2, hardware.cpu.get_register16("bx"));
+
+        hardware.breakpoints.enable(id, false).unwrap();
+        let id = hardware
+            .breakpoints
+            .add(Location::Anywhere, Some("[nowhere] == 1".to_string()));
+        let (_, stop, _) = hardware.run(2, 20);
+        assert!(matches!(stop, Stop::Error(e) if e.starts_with("Condition of breakpoint 3")));
+        hardware.breakpoints.remove(id).unwrap();
+    }
+
+    #[test]
+    fn test_main_run_watchpoints() {
+        let program = program(&[
+            "org 100h",
+            "  mov ax, 1",
+            "  add word ptr [value], 5",
+            "  inc cx",
+            "  inc dx",
+            "  mov bx, [value]",
+            "  hlt",
+            "value dw 2",
+        ]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        let value = hardware.assembly.as_ref().unwrap().symbols["value"];
+        // 2 + 5 does not change the high byte.
+        let high = hardware.breakpoints.add(
+            Location::Watch(value as usize + 1, 1, memory::Access::Change),
+            None,
+        );
+        assert_eq!((7, Stop::Halt, 7), hardware.run(0, 10));
+        hardware.breakpoints.remove(high).unwrap();
+
+        hardware.reboot();
+        hardware.build_program_table(&program, &HashMap::new());
+        let change = hardware.breakpoints.add(
+            Location::Watch(value as usize, 1, memory::Access::Change),
+            None,
+        );
+        let read = hardware.breakpoints.add(
+            Location::Watch(value as usize, 2, memory::Access::Read),
+            None,
+        );
+        hardware.breakpoints.enable(read, false).unwrap();
+
+        // It stops after the instruction writing the value.
+        let (line, stop, _) = hardware.run(0, 10);
+        assert_eq!(3, line);
+        assert_eq!(
+            Stop::Watchpoint {
+                id: change,
+                address: value as usize,
+                ip: 0x103
+            },
+            stop
+        );
+        assert_eq!(7, hardware.memory.read16(0, value));
+
+        hardware.breakpoints.enable(read, true).unwrap();
+        let id = hardware.breakpoints.add(
+            Location::Anywhere,
+            Some("word ptr [value] == 100".to_string()),
+        );
+        // The condition reads the memory without the watchpoints.
+        assert_eq!((5, Stop::Budget, 2), hardware.run(3, 2));
+        hardware.breakpoints.remove(id).unwrap();
+        assert_eq!(
+            (
+                6,
+                Stop::Watchpoint {
+                    id: read,
+                    address: value as usize,
+                    ip: 0x10a
+                },
+                1
+            ),
+            hardware.run(5, 10)
+        );
+        assert_eq!(7, hardware.cpu.get_register16("bx"));
+        assert_eq!((7, Stop::Halt, 1), hardware.run(6, 10));
+    }
+
+    #[test]
+    fn test_main_step_back() {
+        let program = program(&[
+            "org 100h",
+            "  mov ax, 1",
+            "  add word ptr [value], 5",
+            "  inc cx",
+            "  hlt",
+            "value dw 2",
+        ]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        let value = hardware.assembly.as_ref().unwrap().symbols["value"];
+        assert!(hardware.step_back().is_err());
+        assert_eq!((5, Stop::Halt, 5), hardware.run(0, 10));
+        assert_eq!(5, hardware.journal.step());
+        assert_eq!(7, hardware.memory.read16(0, value));
+
+        assert_eq!(Ok(4), hardware.step_back());
+        assert!(!hardware.halted);
+        assert_eq!(Ok(3), hardware.step_back());
+        assert_eq!(0, hardware.cpu.get_register16("cx"));
+        assert_eq!(Ok(2), hardware.step_back());
+        assert_eq!(2, hardware.memory.read16(0, value));
+        assert_eq!(1, hardware.cpu.get_register16("ax"));
+        assert_eq!(0x103, hardware.cpu.get_register16("ip"));
+
+        // The instruction runs again after stepping back.
+        assert_eq!(Ok(3), hardware.step(2));
+        assert_eq!(7, hardware.memory.read16(0, value));
+        assert_eq!(Ok(2), hardware.rewind(2));
+        assert_eq!(2, hardware.memory.read16(0, value));
+        assert!(hardware.rewind(3).is_err());
+        assert_eq!(Ok(0), hardware.rewind(0));
+        assert_eq!(0, hardware.cpu.get_register16("ax"));
+        assert_eq!(0, hardware.program_response(0)["step"].as_u64().unwrap());
+    }
+
+    #[test]
+    fn test_main_snapshot() {
+        let program = program(&[
+            "org 100h",
+            "include \"consts.inc\"",
+            "  mov ax, count",
+            "  add word ptr [value], ax",
+            "  inc cx",
+            "  hlt",
+            "value dw 2",
+        ]);
+        let mut files = HashMap::new();
+        files.insert("consts.inc".to_string(), vec!["count equ 3".to_string()]);
+        let mut hardware = Hardware8086::new();
+        hardware.options.optimize = false;
+        hardware.build_program_table(&program, &files);
+        let id = hardware.breakpoints.add(Location::Line(4), None);
+        assert_eq!((4, Stop::Breakpoint(id), 2), hardware.run(2, 10));
+        let bytes = hardware.snapshot(4).to_bytes().unwrap();
+
+        let mut restored = Hardware8086::new();
+        let snapshot = snapshot::Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(Ok(4), restored.restore(snapshot));
+        assert!(!restored.options.optimize);
+        assert_eq!(program, restored.source);
+        assert_eq!(hardware.cpu, restored.cpu);
+        assert_eq!(
+            hardware.memory.dump(0, memory::MEMORY_SIZE),
+            restored.memory.dump(0, memory::MEMORY_SIZE)
+        );
+        assert_eq!(
+            hardware.breakpoints.to_json(),
+            restored.breakpoints.to_json()
+        );
+        // ids continue after the breakpoints of the snapshot.
+        assert_eq!(2, restored.breakpoints.add(Location::Line(5), None));
+        restored.breakpoints.remove(2).unwrap();
+        // The restored machine continues from the line.
+        assert_eq!((6, Stop::Halt, 2), restored.run(4, 10));
+        assert_eq!(1, restored.cpu.get_register16("cx"));
+        let value = restored.assembly.as_ref().unwrap().symbols["value"];
+        assert_eq!(5, restored.memory.read16(0, value));
+    }
+
+    #[test]
+    fn test_main_trace() {
+        let program = program(&[
+            "org 100h",
+            "  mov ax, 3",
+            "  add word ptr [value], ax",
+            "  inc cx",
+            "  hlt",
+            "value dw 2",
+        ]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        hardware.run(0, 2);
+        hardware.tracer.enabled = true;
+        assert_eq!((5, Stop::Halt, 3), hardware.run(2, 10));
+        let value = hardware.assembly.as_ref().unwrap().symbols["value"];
+        let records: Vec<&trace::Record> = hardware.tracer.records().collect();
+        assert_eq!(3, records.len());
+        assert_eq!(3, records[0].step);
+        assert_eq!((0, 0x103), (records[0].cs, records[0].ip));
+        assert_eq!("add word ptr [value], ax", records[0].text);
+        assert_eq!(vec![("ip", 0x103, 0x107)], records[0].changes);
+        assert_eq!(
+            vec![
+                memory::MemoryAccess {
+                    access: memory::Access::Read,
+                    address: value as usize,
+                    len: 2,
+                    value: 2
+                },
+                memory::MemoryAccess {
+                    access: memory::Access::Write,
+                    address: value as usize,
+                    len: 2,
+                    value: 5
+                },
+            ],
+            records[0].memory
+        );
+        assert_eq!(vec![0xf4], records[2].bytes);
+
+        // The filter of the mnemonics
+        hardware.reboot();
+        hardware.tracer.filter =
+            trace::request_filter(&serde_json::json!({"mnemonics": ["inc"]})).unwrap();
+        hardware.run(0, 10);
+        let texts: Vec<&str> = hardware.tracer.records().map(|r| r.text.as_str()).collect();
+        assert_eq!(vec!["inc cx"], texts);
+    }
+
+    #[test]
+    fn test_main_trace_binary() {
+        // inc ax, jmp short 0104h, nop, hlt
+        let image = [0x40, 0xeb, 0x01, 0x90, 0xf4];
+        let mut hardware = Hardware8086::new();
+        hardware.load_com(&image, 0, "").unwrap();
+        hardware.tracer.enabled = true;
+        assert_eq!((0, Stop::Halt, 3), hardware.run(0, 10));
+        assert_eq!(
+            "0000:0100 40                 inc ax                         ax=0001 ip=0101 flags=0000\n\
+             0000:0101 EB 01              jmp 0104h                      ip=0104 flags=0000\n\
+             0000:0104 F4                 hlt                            ip=0105 flags=0000\n",
+            hardware.tracer.to_text()
+        );
+    }
+
+    #[test]
+    fn test_main_run_binary_breakpoints() {
+        // inc ax, inc bx, hlt, int 20h
+        let image = [0x40, 0x43, 0xf4, 0xcd, 0x20];
+        let mut hardware = Hardware8086::new();
+        hardware.load_com(&image, 0, "").unwrap();
+        let id = hardware.breakpoints.add(Location::Address(0, 0x101), None);
+        assert_eq!((0, Stop::Breakpoint(id), 1), hardware.run(0, 10));
+        assert_eq!((0, Stop::Halt, 2), hardware.run(0, 10));
+        assert_eq!(0x103, hardware.cpu.get_register16("ip"));
+        let (_, stop, _) = hardware.run(0, 10);
+        assert_eq!(
+            Stop::Error("Program terminated by int 20h at 0000:0103".to_string()),
+            stop
+        );
+        // The binary program steps back to the previous CS:IP.
+        assert_eq!(Ok(0), hardware.rewind(1));
+        assert_eq!(0x101, hardware.cpu.get_register16("ip"));
+        assert_eq!(1, hardware.cpu.get_register16("ax"));
+        assert_eq!(0, hardware.cpu.get_register16("bx"));
+    }
+
+    #[test]
+    fn test_main_build_errors() {
+        let source = program(&["inc ax", "mov ax,, 1", "add ax, [nowhere]"]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&source, &HashMap::new());
+        assert!(hardware.assembly.is_none());
+        assert_eq!(
+            vec![(2, 8), (3, 1)],
+            hardware
+                .diagnostics
+                .iter()
+                .map(|e| (e.line, e.column))
+                .collect::<Vec<(usize, usize)>>()
+        );
+        // The bad lines fail without panic.
+        assert_eq!(1, hardware.handle_instruction(0).unwrap());
+        assert!(hardware.handle_instruction(1).is_err());
+        assert!(hardware.handle_instruction(2).is_err());
+        assert!(hardware.handle_instruction(3).is_err());
+        // An instruction without the handler stops the run with the error of its machine code.
+        let unsupported = program(&["org 100h", "mov ah, 12h", "hlt"]);
+        hardware.build_program_table(&unsupported, &HashMap::new());
+        let (line, stop, _) = hardware.run(0, 10);
+        assert_eq!(
+            (
+                1,
+                Stop::Error("mov ah, 0x12 is not supported yet".to_string())
+            ),
+            (line, stop)
+        );
+    }
+
+    #[test]
+    fn test_main_run_segments() {
+        let program = program(&[
+            "data segment",
+            "msg dw 1234h",
+            "data ends",
+            "code segment",
+            "assume cs:code, ds:data",
+            "start:",
+            "mov ax, seg msg",
+            "mov ds, ax",
+            "jmp far ptr next",
+            "code ends",
+            "other segment",
+            "next:",
+            "inc bx",
+            "other ends",
+            "end start",
+        ]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        // EXE is loaded at 0010h after the PSP: code is the frame 1
+        assert_eq!(0x11, hardware.cpu.get_register16("cs"));
+        assert_eq!(0, hardware.cpu.get_register16("ip"));
+        assert_eq!(vec![0x34, 0x12], hardware.memory.dump(0x100, 2));
+
+        let mut line = 0;
+        while line < program.len() {
+            line = hardware.handle_instruction(line).unwrap();
+        }
+        assert_eq!(0x10, hardware.cpu.get_register16("ax"));
+        assert_eq!(0x10, hardware.cpu.get_register16("ds"));
+        assert_eq!(1, hardware.cpu.get_register16("bx"));
+        assert_eq!(0x12, hardware.cpu.get_register16("cs"));
+        assert_eq!(1, hardware.cpu.get_register16("ip"));
+    }
+
+    #[test]
+    fn test_main_data_segment() {
+        let program = program(&[
+            "data segment",
+            "pad db 0",
+            "msg dw 1234h",
+            "data ends",
+            "code segment",
+            "assume cs:code, ds:data",
+            "start:",
+            "mov ax, seg msg",
+            "mov ds, ax",
+            "mov bx, [msg]",
+            "inc word ptr [msg]",
+            "hlt",
+            "code ends",
+            "end start",
+        ]);
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&program, &HashMap::new());
+        assert!(hardware.diagnostics.is_empty());
+        let (_, stop, _) = hardware.run(0, 20);
+        assert_eq!(Stop::Halt, stop);
+        // [msg] is DS:0001 after the PSP, not the int 20h of the PSP at 0000:0000
+        assert_eq!(0x10, hardware.cpu.get_register16("ds"));
+        assert_eq!(0x1234, hardware.cpu.get_register16("bx"));
+        assert_eq!(vec![0x35, 0x12], hardware.memory.dump(0x101, 2));
+        assert_eq!(0x1235, hardware.memory.read16(0x10, 1));
+    }
+
+    fn call_program() -> Vec<String> {
+        program(&[
+            "org 100h",
+            "  mov sp, 200h",
+            "  mov ax, 1",
+            "  call double",
+            "  inc ax",
+            "  hlt",
+            "double:",
+            "  push bp",
+            "  mov bp, sp",
+            "  add ax, ax",
+            "  call nothing",
+            "  pop bp",
+            "  ret",
+            "nothing:",
+            "  ret",
+        ])
+    }
+
+    #[test]
+    fn test_main_call_ret() {
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&call_program(), &HashMap::new());
+        assert!(hardware.diagnostics.is_empty());
+        assert_eq!((6, Stop::Halt, 13), hardware.run(0, 100));
+        assert_eq!(3, hardware.cpu.get_register16("ax"));
+        assert_eq!(0x200, hardware.cpu.get_register16("sp"));
+        assert_eq!(0x10b, hardware.cpu.get_register16("ip"));
+
+        // call pushes the return address and step back removes it.
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&call_program(), &HashMap::new());
+        assert_eq!((3, Stop::Budget, 3), hardware.run(0, 3));
+        assert_eq!(Ok(7), hardware.step(3));
+        assert_eq!(0x10b, hardware.cpu.get_register16("ip"));
+        assert_eq!(0x1fe, hardware.cpu.get_register16("sp"));
+        assert_eq!(0x109, hardware.memory.read16(0, 0x1fe));
+        assert_eq!(Ok(3), hardware.step_back());
+        assert_eq!(0, hardware.memory.read16(0, 0x1fe));
+        assert_eq!(0x200, hardware.cpu.get_register16("sp"));
+
+        // The binary program runs the same code.
+        let image = hardware.assembly.as_ref().unwrap().binary().unwrap();
+        hardware.load_com(&image, 0, "").unwrap();
+        assert_eq!((0, Stop::Halt, 12), hardware.run(0, 100));
+        assert_eq!(3, hardware.cpu.get_register16("ax"));
+        assert_eq!(0x200, hardware.cpu.get_register16("sp"));
+        assert_eq!(0x109, hardware.memory.read_stack(0, 0x1fe));
+        assert_eq!(0x113, hardware.memory.read_stack(0, 0x1fa));
+        // The same program in the other segment
+        hardware.load_com(&image, 0x1000, "").unwrap();
+        assert_eq!((0, Stop::Halt, 12), hardware.run(0, 100));
+        assert_eq!(3, hardware.cpu.get_register16("ax"));
+        assert_eq!(0x109, hardware.memory.read_stack(0x1000, 0x1fe));
+    }
+
+    #[test]
+    fn test_main_step_over_out() {
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&call_program(), &HashMap::new());
+        assert_eq!((3, Stop::Budget, 3), hardware.run(0, 3));
+        assert_eq!((4, Stop::Step, 8), hardware.step_over(3, 100));
+        assert_eq!(2, hardware.cpu.get_register16("ax"));
+        assert_eq!(0x200, hardware.cpu.get_register16("sp"));
+        // A line without call is one step.
+        assert_eq!((5, Stop::Step, 1), hardware.step_over(4, 100));
+
+        hardware.build_program_table(&call_program(), &HashMap::new());
+        hardware.run(0, 3);
+        assert_eq!(Ok(7), hardware.step(3));
+        assert_eq!((8, Stop::Step, 1), hardware.step_over(7, 100));
+        hardware.run(8, 2);
+        assert_eq!((11, Stop::Step, 2), hardware.step_over(10, 100));
+        // pop bp does not return from double.
+        assert_eq!((4, Stop::Step, 2), hardware.step_out(11, 100));
+        assert_eq!(0x109, hardware.cpu.get_register16("ip"));
+
+        // A breakpoint in the procedure stops step over.
+        hardware.build_program_table(&call_program(), &HashMap::new());
+        hardware.run(0, 3);
+        let id = hardware.breakpoints.add(Location::Line(9), None);
+        assert_eq!((9, Stop::Breakpoint(id), 3), hardware.step_over(3, 100));
+        assert_eq!((4, Stop::Step, 5), hardware.step_out(9, 100));
+    }
+
+    #[test]
+    fn test_main_changes() {
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&call_program(), &HashMap::new());
+        assert_eq!(
+            Value::Array(Vec::new()),
+            hardware.program_response(0)["changes"]
+        );
+        hardware.run(0, 3);
+        assert_eq!(Ok(7), hardware.step(3));
+        // call pushes the return address 0109h.
+        assert_eq!(
+            vec![(0x1fe, 0, 0x09), (0x1ff, 0, 0x01)],
+            hardware.last_changes()
+        );
+        let response = hardware.program_response(7);
+        assert_eq!(
+            serde_json::json!({ "address": 0x1fe, "old": 0, "value": 9 }),
+            response["changes"][0]
+        );
+        assert_eq!(Value::Null, response["memory"]);
+
+        // push bp writes 0 on 0: the bytes are not changed.
+        assert_eq!(Ok(8), hardware.step(7));
+        assert!(hardware.last_changes().is_empty());
+        // The changes are of the last instruction after stepping back.
+        assert_eq!(Ok(7), hardware.step_back());
+        assert_eq!(2, hardware.last_changes().len());
+    }
+
+    #[test]
+    fn test_main_step_response() {
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&call_program(), &HashMap::new());
+        // No instruction has run: all the registers
+        assert_eq!("0", hardware.step_response(0)["AX"]);
+        hardware.run(0, 3);
+        assert_eq!(Ok(7), hardware.step(3));
+        let response = hardware.step_response(7);
+        assert_eq!(
+            serde_json::json!({ "SP": "510", "IP": "267" }),
+            response["registers"]
+        );
+        assert_eq!(Value::Null, response["AX"]);
+        assert_eq!(2, response["call_stack"].as_array().unwrap().len());
+        assert_eq!(Value::Null, response["frame"]);
+
+        // add ax, ax does not change the stack: only the innermost frame
+        hardware.run(7, 2);
+        assert_eq!(Ok(10), hardware.step(9));
+        let response = hardware.step_response(10);
+        assert_eq!("2", response["registers"]["AX"]);
+        assert_eq!(Value::Null, response["registers"]["SP"]);
+        assert_eq!(Value::Null, response["call_stack"]);
+        assert_eq!(serde_json::json!(10), response["frame"]["line"]);
+        assert_eq!(serde_json::json!("double"), response["frame"]["name"]);
+    }
+
+    #[test]
+    fn test_main_run_to() {
+        let mut hardware = Hardware8086::new();
+        hardware.build_program_table(&call_program(), &HashMap::new());
+        assert_eq!(
+            (9, Stop::Step, 6),
+            hardware.run_to(0, &Location::Line(9), 100)
+        );
+        assert_eq!(0x1fc, hardware.cpu.get_register16("sp"));
+        assert_eq!(
+            (14, Stop::Step, 2),
+            hardware.run_to(9, &Location::Label("nothing".to_string()), 100)
+        );
+        // The line is not reached: the program halts.
+        assert_eq!(
+            (6, Stop::Halt, 5),
+            hardware.run_to(14, &Location::Line(2), 100)
+        );
+    }
 }
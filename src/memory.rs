@@ -1,11 +1,80 @@
 use std::cell::RefCell;
 use std::fmt;
 
+pub const MEMORY_SIZE: usize = 1024 * 1024;
+
+/// Convert segment:offset into 20-bit physical address
+/// The address wraps around at 1MB as like the real 8086.
+pub fn physical_address(segment: u16, offset: u16) -> usize {
+    (((segment as usize) << 4) + offset as usize) % MEMORY_SIZE
+}
+
+/// Access to the memory stopping the execution
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    // Write of a different value
+    Change,
+}
+
+impl Access {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Change => "change",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "read" => Ok(Access::Read),
+            "write" => Ok(Access::Write),
+            "change" => Ok(Access::Change),
+            _ => Err(format!("{} is not read, write or change", name)),
+        }
+    }
+}
+
+/// Read or write of the instruction recorded by the trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryAccess {
+    pub access: Access,
+    // Physical address of the first byte
+    pub address: usize,
+    // 1 for read8 and write8, 2 for read16 and write16
+    pub len: u16,
+    // Value read or written
+    pub value: u16,
+}
+
+/// Watchpoint on the physical bytes from start to start + len - 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watch {
+    pub id: usize,
+    pub start: usize,
+    pub len: u16,
+    pub access: Access,
+}
+
+impl Watch {
+    fn covers(&self, address: usize) -> bool {
+        self.start <= address && address < self.start + self.len as usize
+    }
+}
+
 pub struct Memory {
     data: Box<[u8; 1024 * 1024]>, // 1MB 크기의 배열
-    // BUGBUG: Add segment register for last address
-    // last_address: RefCell<(u16, u16)>
-    last_address: RefCell<u16>,
+    // Physical address of the last access
+    last_address: RefCell<usize>,
+    watches: Vec<Watch>,
+    // id of the watchpoint and the physical address accessed
+    watch_hits: RefCell<Vec<(usize, usize)>>,
+    // Physical address and old value of the bytes written since begin_journal
+    journal: Option<Vec<(usize, u8)>>,
+    // Accesses since begin_trace
+    trace: RefCell<Option<Vec<MemoryAccess>>>,
 }
 
 impl Memory {
@@ -16,50 +85,182 @@ impl Memory {
         Memory {
             data: vec![0; 1024 * 1024].try_into().unwrap(), // 배열을 0으로 초기화
             last_address: RefCell::new(0),
+            watches: Vec::new(),
+            watch_hits: RefCell::new(Vec::new()),
+            journal: None,
+            trace: RefCell::new(None),
         }
     }
 
     pub fn reboot(&mut self) {
         self.data.fill(0);
         *self.last_address.borrow_mut() = 0;
+        self.watch_hits.borrow_mut().clear();
+        self.journal = None;
+        self.trace.take();
+    }
+
+    /// Record the reads and writes for the trace
+    pub fn begin_trace(&mut self) {
+        self.trace.replace(Some(Vec::new()));
+    }
+
+    /// Stop recording: the accesses in the order of the instruction
+    pub fn end_trace(&mut self) -> Vec<MemoryAccess> {
+        self.trace.take().unwrap_or_default()
+    }
+
+    fn trace(&self, access: Access, address: usize, len: u16, value: u16) {
+        if let Some(trace) = self.trace.borrow_mut().as_mut() {
+            trace.push(MemoryAccess {
+                access,
+                address,
+                len,
+                value,
+            });
+        }
+    }
+
+    /// Record the old values of the bytes written by write8 and write16
+    pub fn begin_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    /// Stop recording: the old values in the order of the writes
+    pub fn end_journal(&mut self) -> Vec<(usize, u8)> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    /// Restore the old values of end_journal
+    /// The last write is restored first and the watchpoints are not checked.
+    pub fn undo(&mut self, writes: &[(usize, u8)]) {
+        for (address, value) in writes.iter().rev() {
+            self.data[*address] = *value;
+        }
+    }
+
+    fn record(&mut self, address: usize) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push((address, self.data[address]));
+        }
+    }
+
+    /// Watchpoints checked by read8, read16, write8 and write16
+    pub fn set_watches(&mut self, watches: Vec<Watch>) {
+        self.watches = watches;
+        self.watch_hits.borrow_mut().clear();
+    }
+
+    /// Watchpoints hit since the last call: (id, physical address)
+    pub fn take_watch_hits(&self) -> Vec<(usize, usize)> {
+        self.watch_hits.take()
+    }
+
+    /// bytes: the physical address of each byte of the access and whether it changes,
+    /// the first address is reported.
+    /// A change watchpoint hits only when a byte in its range changes.
+    fn watch(&self, bytes: &[(usize, bool)], access: Access) {
+        for w in self.watches.iter() {
+            let hit = match w.access {
+                Access::Change => {
+                    access == Access::Write
+                        && bytes.iter().any(|(a, changed)| *changed && w.covers(*a))
+                }
+                _ => w.access == access && bytes.iter().any(|(a, _)| w.covers(*a)),
+            };
+            if hit {
+                self.watch_hits.borrow_mut().push((w.id, bytes[0].0));
+            }
+        }
     }
 
     pub fn _get(&self) -> Box<[u8; 1024 * 1024]> {
         self.data.clone()
     }
 
-    //
-    // BUGBUG!! Add address range check and return error
-    //
+    /// Copy an image into the physical address
+    /// It is used by the loader and does not change last_address.
+    pub fn load(&mut self, address: usize, image: &[u8]) -> Result<(), String> {
+        if address
+            .checked_add(image.len())
+            .is_none_or(|end| end > MEMORY_SIZE)
+        {
+            return Err(format!(
+                "Image of {} bytes does not fit at {:05X}",
+                image.len(),
+                address
+            ));
+        }
+        self.data[address..address + image.len()].copy_from_slice(image);
+        Ok(())
+    }
 
-    //
-    // BUGBUG!! User segment:address address
-    // read8(&self, segment: u16, address: u16)
-    //
+    /// Read bytes from the physical address without changing last_address
+    /// Reading beyond 1MB wraps around to 0.
+    pub fn dump(&self, address: usize, len: usize) -> Vec<u8> {
+        // Both terms are below 1MB: the sum does not overflow.
+        let address = address % MEMORY_SIZE;
+        (0..len)
+            .map(|i| self.data[(address + i % MEMORY_SIZE) % MEMORY_SIZE])
+            .collect()
+    }
 
-    pub fn read8(&self, address: u16) -> u8 {
+    // Memory operands are segment:offset: a word at offset FFFFh wraps around to 0 of the segment.
+
+    pub fn read8(&self, segment: u16, address: u16) -> u8 {
+        let address = physical_address(segment, address);
         *self.last_address.borrow_mut() = address;
-        self.data[address as usize]
+        self.watch(&[(address, false)], Access::Read);
+        let value = self.data[address];
+        self.trace(Access::Read, address, 1, value as u16);
+        value
     }
-    pub fn read16(&self, address: u16) -> u16 {
-        *self.last_address.borrow_mut() = address;
+    pub fn read16(&self, segment: u16, address: u16) -> u16 {
+        let low = physical_address(segment, address);
+        let high = physical_address(segment, address.wrapping_add(1));
+        *self.last_address.borrow_mut() = low;
+        self.watch(&[(low, false), (high, false)], Access::Read);
         // Little-endian: read first address and the lower byte
-        self.data[address as usize] as u16 | (self.data[(address + 1) as usize] as u16) << 8
+        let value = self.data[low] as u16 | (self.data[high] as u16) << 8;
+        self.trace(Access::Read, low, 2, value);
+        value
     }
 
     // 메모리에 쓰기
-    pub fn write8(&mut self, address: u16, value: u8) {
+    pub fn write8(&mut self, segment: u16, address: u16, value: u8) {
+        let address = physical_address(segment, address);
         *self.last_address.borrow_mut() = address;
-        self.data[address as usize] = value;
-        println!("{:?}", self);
+        self.watch(&[(address, self.data[address] != value)], Access::Write);
+        self.record(address);
+        self.trace(Access::Write, address, 1, value as u16);
+        self.data[address] = value;
     }
 
-    pub fn write16(&mut self, address: u16, value: u16) {
+    pub fn write16(&mut self, segment: u16, address: u16, value: u16) {
         // Little-endian: write lower byte first
-        *self.last_address.borrow_mut() = address;
-        self.data[address as usize] = (value & 0xff) as u8;
-        self.data[(address + 1) as usize] = ((value & 0xff00) >> 8) as u8;
-        println!("{:?}", self);
+        let low = physical_address(segment, address);
+        let high = physical_address(segment, address.wrapping_add(1));
+        *self.last_address.borrow_mut() = low;
+        let [value_low, value_high] = value.to_le_bytes();
+        self.watch(
+            &[
+                (low, self.data[low] != value_low),
+                (high, self.data[high] != value_high),
+            ],
+            Access::Write,
+        );
+        self.record(low);
+        self.record(high);
+        self.trace(Access::Write, low, 2, value);
+        self.data[low] = value_low;
+        self.data[high] = value_high;
+    }
+
+    /// Word at SS:SP for the call stack
+    /// It is not checked by the watchpoints nor recorded by the trace: push and pop use read16 and write16.
+    pub fn read_stack(&self, ss: u16, sp: u16) -> u16 {
+        let bytes = self.dump(physical_address(ss, sp), 2);
+        u16::from_le_bytes([bytes[0], bytes[1]])
     }
 }
 
@@ -68,14 +269,13 @@ impl fmt::Debug for Memory {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut s = String::new();
 
-        // BUGBUG: masking will be 0xffff0 when last_address is changed into (seg:addr)
-        let start = *self.last_address.borrow() & 0xffff;
+        let start = *self.last_address.borrow();
         let end = start + 0xf;
         s.push_str(&format!("{:05X}", start));
         s.push(' ');
         for i in start..=end {
             // DO NOT USE read/write method because it changes last_address value
-            let d = self.data[i as usize];
+            let d = self.data[i % MEMORY_SIZE];
             let ss = format!("{:02X}", d);
             s.push_str(&ss);
             if i != end {
@@ -116,37 +316,135 @@ mod tests {
     #[test]
     fn test_memory_read8_write8() {
         let mut memory = Memory::boot();
-        memory.write8(0, 0xAB);
+        memory.write8(0, 0, 0xAB);
         assert_eq!(0, *memory.last_address.borrow());
-        memory.write8(1, 0xCD);
+        memory.write8(0, 1, 0xCD);
         assert_eq!(1, *memory.last_address.borrow());
-        assert_eq!(0xAB, memory.read8(0));
+        assert_eq!(0xAB, memory.read8(0, 0));
         assert_eq!(0, *memory.last_address.borrow());
-        assert_eq!(0xCD, memory.read8(1));
+        assert_eq!(0xCD, memory.read8(0, 1));
         assert_eq!(1, *memory.last_address.borrow());
     }
 
     #[test]
     fn test_memory_read16_write16() {
         let mut memory = Memory::boot();
-        memory.write8(0, 0xCD);
+        memory.write8(0, 0, 0xCD);
         assert_eq!(0, *memory.last_address.borrow());
-        memory.write8(1, 0xAB);
+        memory.write8(0, 1, 0xAB);
         assert_eq!(1, *memory.last_address.borrow());
         // Check the little-endian reading
-        assert_eq!(0xABCD, memory.read16(0));
+        assert_eq!(0xABCD, memory.read16(0, 0));
         assert_eq!(0, *memory.last_address.borrow());
-        memory.write16(0, 0xabcd);
+        memory.write16(0, 0, 0xabcd);
         // Check the little-endian writing
-        assert_eq!(0xcd, memory.read8(0));
-        assert_eq!(0xab, memory.read8(1));
+        assert_eq!(0xcd, memory.read8(0, 0));
+        assert_eq!(0xab, memory.read8(0, 1));
+    }
+
+    #[test]
+    fn test_memory_load_dump() {
+        let mut memory = Memory::boot();
+        let address = physical_address(0x1000, 0x100);
+        assert_eq!(0x10100, address);
+        memory.load(address, &[0x12, 0x34, 0x56]).unwrap();
+        assert_eq!(vec![0x12, 0x34, 0x56, 0x00], memory.dump(address, 4));
+        assert!(memory.load(MEMORY_SIZE - 1, &[0x12, 0x34]).is_err());
+        assert!(memory.load(usize::MAX, &[0x12]).is_err());
+        memory.load(0, &[0x12]).unwrap();
+        assert_eq!(vec![0x00, 0x12], memory.dump(usize::MAX, 2));
+
+        // segment:offset wraps around at 1MB
+        assert_eq!(0xffef, physical_address(0xffff, 0xffff));
     }
 
     #[test]
     fn test_memory_debug() {
         let mut memory = Memory::boot();
-        memory.write16(0x100, 0xabcd);
+        memory.write16(0, 0x100, 0xabcd);
         let s = format!("{:?}", memory);
         assert_eq!("00100 CD AB 00 00 00 00 00 00 00 00 00 00 00 00 00 00", s);
     }
+
+    #[test]
+    fn test_memory_watches() {
+        let mut memory = Memory::boot();
+        let watch = |id, start, len, access| Watch {
+            id,
+            start,
+            len,
+            access,
+        };
+        memory.set_watches(vec![
+            watch(1, 0x200, 2, Access::Write),
+            watch(2, 0x201, 1, Access::Read),
+            watch(3, 0x300, 1, Access::Change),
+        ]);
+        memory.write8(0, 0x1ff, 1);
+        memory.read8(0, 0x200);
+        assert_eq!(Vec::<(usize, usize)>::new(), memory.take_watch_hits());
+        // The word at 0x1ff overlaps 0x200.
+        memory.write16(0, 0x1ff, 0x1234);
+        memory.read16(0, 0x200);
+        assert_eq!(vec![(1, 0x1ff), (2, 0x200)], memory.take_watch_hits());
+        assert_eq!(Vec::<(usize, usize)>::new(), memory.take_watch_hits());
+
+        // The same value is not a change.
+        memory.write8(0, 0x300, 0);
+        memory.write8(0, 0x300, 5);
+        memory.write16(0, 0x2ff, 0x0500);
+        memory.write16(0, 0x2ff, 0x0600);
+        // Only the byte at 0x2ff outside the watchpoint changes.
+        memory.write16(0, 0x2ff, 0x0601);
+        assert_eq!(vec![(3, 0x300), (3, 0x2ff)], memory.take_watch_hits());
+        // dump and load are not watched.
+        memory.dump(0x200, 2);
+        memory.load(0x300, &[7]).unwrap();
+        assert_eq!(Vec::<(usize, usize)>::new(), memory.take_watch_hits());
+    }
+
+    #[test]
+    fn test_memory_journal() {
+        let mut memory = Memory::boot();
+        memory.write16(0, 0x200, 0x1234);
+        memory.begin_journal();
+        memory.write8(0, 0x201, 0x56);
+        memory.write16(0, 0x200, 0xabcd);
+        let writes = memory.end_journal();
+        assert_eq!(vec![(0x201, 0x12), (0x200, 0x34), (0x201, 0x56)], writes);
+        // Writes after end_journal are not recorded.
+        memory.write8(0, 0x300, 1);
+        assert!(memory.end_journal().is_empty());
+
+        memory.undo(&writes);
+        assert_eq!(0x1234, memory.read16(0, 0x200));
+    }
+
+    #[test]
+    fn test_memory_trace() {
+        let mut memory = Memory::boot();
+        memory.write8(0, 0x100, 1);
+        memory.begin_trace();
+        memory.write16(0, 0x200, 0x1234);
+        memory.read8(0, 0x201);
+        assert_eq!(
+            vec![
+                MemoryAccess {
+                    access: Access::Write,
+                    address: 0x200,
+                    len: 2,
+                    value: 0x1234
+                },
+                MemoryAccess {
+                    access: Access::Read,
+                    address: 0x201,
+                    len: 1,
+                    value: 0x12
+                },
+            ],
+            memory.end_trace()
+        );
+        memory.read16(0, 0x200);
+        assert!(memory.end_trace().is_empty());
+    }
 }
@@ -0,0 +1,189 @@
+use crate::disassembler;
+use crate::memory::{physical_address, Memory};
+use serde_json::{json, Value};
+
+/*
+Memory viewer
+
+A view is the rows of the range from seg:off: the offset wraps around in the segment.
+format      | row
+bytes       | 16 bytes: 0000:0100 B8 01 00 05 02 00 F4 00 ...
+words       | 8 little-endian words: 0000:0100 01B8 0500 0002 00F4 ...
+ascii       | 16 characters: 0000:0100 ..........Hello, ...
+disassembly | one instruction: 0000:0100 B8 01 00  mov ax, 0x1
+Each row has the offset, the physical address, the values, the text
+and the indexes of the values with the bytes changed by the last instruction.
+e.g. {"off":256,"address":256,"values":[184,1,0,...],"text":"0000:0100 B8 01 00 ...","changed":[1,2]}
+*/
+
+pub const DEFAULT_VIEW_LEN: usize = 0x100;
+pub const MAX_VIEW_LEN: usize = 0x1000;
+const ROW_BYTES: usize = 16;
+// The longest instruction of the 8086 without the prefixes
+const MAX_INSTRUCTION_LEN: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Bytes,
+    Words,
+    Ascii,
+    Disassembly,
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Bytes => "bytes",
+            Format::Words => "words",
+            Format::Ascii => "ascii",
+            Format::Disassembly => "disassembly",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "bytes" => Ok(Format::Bytes),
+            "words" => Ok(Format::Words),
+            "ascii" => Ok(Format::Ascii),
+            "disassembly" => Ok(Format::Disassembly),
+            _ => Err(format!(
+                "{} is not bytes, words, ascii or disassembly",
+                name
+            )),
+        }
+    }
+}
+
+/// Rows of len bytes from seg:off
+/// changed: physical addresses changed by the last instruction
+pub fn view(
+    memory: &Memory,
+    seg: u16,
+    off: u16,
+    len: usize,
+    format: Format,
+    changed: &[usize],
+) -> Result<Value, String> {
+    if len > MAX_VIEW_LEN {
+        return Err(format!("len should be {:X} or less", MAX_VIEW_LEN));
+    }
+    let bytes = |off: u16, len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|i| memory.dump(physical_address(seg, off.wrapping_add(i as u16)), 1)[0])
+            .collect()
+    };
+    let is_changed = |off: u16, len: usize| {
+        (0..len).any(|i| changed.contains(&physical_address(seg, off.wrapping_add(i as u16))))
+    };
+
+    let mut rows = Vec::new();
+    let mut start = 0;
+    while start < len {
+        let row_off = off.wrapping_add(start as u16);
+        let prefix = format!("{:04X}:{:04X}", seg, row_off);
+        // Bytes of the row and of each value to highlight
+        let (row_len, stride, values, text) = match format {
+            Format::Bytes | Format::Ascii => {
+                let code = bytes(row_off, ROW_BYTES.min(len - start));
+                let text: String = match format {
+                    Format::Bytes => code.iter().map(|b| format!(" {:02X}", b)).collect(),
+                    _ => code
+                        .iter()
+                        .map(|b| match b {
+                            0x20..=0x7e => *b as char,
+                            _ => '.',
+                        })
+                        .collect(),
+                };
+                let values: Vec<u16> = code.iter().map(|b| *b as u16).collect();
+                let separator = if format == Format::Ascii { " " } else { "" };
+                (
+                    code.len(),
+                    1,
+                    values,
+                    format!("{}{}{}", prefix, separator, text),
+                )
+            }
+            Format::Words => {
+                let code = bytes(row_off, ROW_BYTES.min(len - start).div_ceil(2) * 2);
+                let values: Vec<u16> = code
+                    .chunks(2)
+                    .map(|w| u16::from_le_bytes([w[0], w[1]]))
+                    .collect();
+                let text: String = values.iter().map(|w| format!(" {:04X}", w)).collect();
+                (code.len(), 2, values, format!("{}{}", prefix, text))
+            }
+            Format::Disassembly => {
+                let code = bytes(row_off, MAX_INSTRUCTION_LEN);
+                let (text, size) = disassembler::machine_instruction(&code, row_off)
+                    .unwrap_or_else(|_| (format!("db {:02X}h", code[0]), 1));
+                let hex: String = code[..size].iter().map(|b| format!(" {:02X}", b)).collect();
+                let values: Vec<u16> = code[..size].iter().map(|b| *b as u16).collect();
+                let text = format!("{}{:<20}  {}", prefix, hex, text);
+                (size, 1, values, text)
+            }
+        };
+        let row_changed: Vec<usize> = (0..row_len / stride)
+            .filter(|i| is_changed(row_off.wrapping_add((i * stride) as u16), stride))
+            .collect();
+        rows.push(json!({
+            "off": row_off,
+            "address": physical_address(seg, row_off),
+            "values": values,
+            "text": text,
+            "changed": row_changed,
+        }));
+        start += row_len;
+    }
+    Ok(json!({
+        "seg": seg,
+        "off": off,
+        "len": len,
+        "format": format.name(),
+        "rows": rows,
+        "changed": changed,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_memview_formats() {
+        let mut memory = Memory::boot();
+        memory
+            .load(0x1100, &[0xb8, 0x01, 0x00, 0x48, 0x69, 0xf4, 0xff])
+            .unwrap();
+        let rows = |format: Format, len: usize| {
+            view(&memory, 0x100, 0x100, len, format, &[0x1104]).unwrap()["rows"].clone()
+        };
+        let bytes = rows(Format::Bytes, 7);
+        assert_eq!("0100:0100 B8 01 00 48 69 F4 FF", bytes[0]["text"]);
+        assert_eq!(0x1100, bytes[0]["address"]);
+        assert_eq!(json!([4]), bytes[0]["changed"]);
+        let words = rows(Format::Words, 4);
+        assert_eq!(json!([0x1b8, 0x4800]), words[0]["values"]);
+        assert_eq!(json!([]), words[0]["changed"]);
+        assert_eq!(json!([2]), rows(Format::Words, 7)[0]["changed"]);
+        assert_eq!("0100:0100 ...Hi..", rows(Format::Ascii, 7)[0]["text"]);
+
+        let disassembly = rows(Format::Disassembly, 6);
+        assert_eq!(4, disassembly.as_array().unwrap().len());
+        assert!(disassembly[0]["text"]
+            .as_str()
+            .unwrap()
+            .ends_with("mov ax, 0x1"));
+        assert_eq!(json!([0]), disassembly[2]["changed"]);
+        assert_eq!(0x105, disassembly[3]["off"]);
+
+        // 16 bytes per row and the offset wraps around in the segment
+        let wrap = view(&memory, 0, 0xfff8, 0x20, Format::Bytes, &[]).unwrap();
+        assert_eq!(2, wrap["rows"].as_array().unwrap().len());
+        assert_eq!(0x8, wrap["rows"][1]["off"]);
+
+        assert!(Format::from_name("hex").is_err());
+        assert!(view(&memory, 0, 0, MAX_VIEW_LEN + 1, Format::Bytes, &[]).is_err());
+    }
+}
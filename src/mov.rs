@@ -1,3 +1,7 @@
+use crate::assembler::{
+    immediate, memory_address, modrm_table, operand_rule, operand_wbit, register_table,
+    segment_register_table, SymbolTable,
+};
 use crate::memory::Memory;
 use crate::parser::{imm_to_num, mem_to_num, Rule};
 use crate::{cpucontext::CpuContext, define_handler_two};
@@ -19,42 +23,208 @@ use pest::iterators::Pair;
 /// MOV sreg, r/m16 $8E, xx0 sreg xxx(ModR/M byte)
 */
 
+const REG_SHIFT: u8 = 3;
+
+fn is_segment_register(operand: &Pair<Rule>) -> bool {
+    segment_register_table(operand.as_str()).is_ok()
+}
+
+pub fn assemble_mov(
+    first: &Pair<Rule>,
+    second: &Pair<Rule>,
+    symbols: &SymbolTable,
+) -> Result<Vec<u8>, String> {
+    let mut v: Vec<u8> = Vec::new();
+    if is_segment_register(first) || is_segment_register(second) {
+        // MOV sreg, r/m16 or MOV r/m16, sreg
+        let (opcode, sreg, rm) = if is_segment_register(first) {
+            (0x8e, first, second)
+        } else {
+            (0x8c, second, first)
+        };
+        if operand_rule(rm) == Rule::imm || operand_wbit(rm)? != 1 || is_segment_register(rm) {
+            return Err(format!(
+                "Unknown format of MOV instruction, mov {}, {}",
+                first.as_str(),
+                second.as_str()
+            ));
+        }
+        v.push(opcode);
+        let mut modrm = modrm_table(rm, symbols)?;
+        modrm[0] |= segment_register_table(sreg.as_str())? << REG_SHIFT;
+        v.extend(modrm);
+        return Ok(v);
+    }
+
+    match (first.as_rule(), operand_rule(second)) {
+        (Rule::reg16, Rule::imm) | (Rule::reg8, Rule::imm) => {
+            // MOV reg, imm: 1011_wreg
+            let wbit = operand_wbit(first)?;
+            let imm = immediate(second, wbit, symbols)?;
+            v.push(0xb0 | wbit << 3 | register_table(first.as_str())?);
+            v.push((imm & 0xff) as u8);
+            if wbit == 1 {
+                v.push(((imm & 0xff00) >> 8) as u8);
+            }
+        }
+        (Rule::mem16, Rule::imm)
+        | (Rule::mem8, Rule::imm)
+        | (Rule::indirect16, Rule::imm)
+        | (Rule::indirect8, Rule::imm) => {
+            // MOV r/m, imm: 1100_011w mod 000 r/m
+            let wbit = operand_wbit(first)?;
+            let imm = immediate(second, wbit, symbols)?;
+            v.push(0xc6 | wbit);
+            v.extend(modrm_table(first, symbols)?);
+            v.push((imm & 0xff) as u8);
+            if wbit == 1 {
+                v.push(((imm & 0xff00) >> 8) as u8);
+            }
+        }
+        (Rule::reg16, Rule::mem16) | (Rule::reg8, Rule::mem8)
+            if symbols.optimize() && register_table(first.as_str())? == 0 =>
+        {
+            // MOV AL/AX, moffs: 1010_000w addr-low addr-high
+            v.push(0xa0 | operand_wbit(first)?);
+            v.extend(memory_address(second, symbols)?.to_le_bytes());
+        }
+        (Rule::mem16, Rule::reg16) | (Rule::mem8, Rule::reg8)
+            if symbols.optimize() && register_table(second.as_str())? == 0 =>
+        {
+            // MOV moffs, AL/AX: 1010_001w addr-low addr-high
+            v.push(0xa2 | operand_wbit(second)?);
+            v.extend(memory_address(first, symbols)?.to_le_bytes());
+        }
+        (Rule::reg16, Rule::reg16)
+        | (Rule::reg8, Rule::reg8)
+        | (Rule::reg16, Rule::mem16)
+        | (Rule::reg8, Rule::mem8)
+        | (Rule::reg16, Rule::indirect16)
+        | (Rule::reg8, Rule::indirect8) => {
+            // MOV reg, r/m: 1000_101w mod reg r/m
+            v.push(0x8a | operand_wbit(first)?);
+            let mut modrm = modrm_table(second, symbols)?;
+            modrm[0] |= register_table(first.as_str())? << REG_SHIFT;
+            v.extend(modrm);
+        }
+        (Rule::mem16, Rule::reg16)
+        | (Rule::mem8, Rule::reg8)
+        | (Rule::indirect16, Rule::reg16)
+        | (Rule::indirect8, Rule::reg8) => {
+            // MOV r/m, reg: 1000_100w mod reg r/m
+            v.push(0x88 | operand_wbit(first)?);
+            let mut modrm = modrm_table(first, symbols)?;
+            modrm[0] |= register_table(second.as_str())? << REG_SHIFT;
+            v.extend(modrm);
+        }
+        _ => {
+            return Err(format!(
+                "Unknown format of MOV instruction, mov {}, {}",
+                first.as_str(),
+                second.as_str()
+            ))
+        }
+    }
+    Ok(v)
+}
+
 define_handler_two!(mov, first, second, cpu, memory, {
     match (first.as_rule(), second.as_rule()) {
         (Rule::reg16, Rule::reg16) => {
-            cpu.set_register(first.as_str(), cpu.get_register(second.as_str()).unwrap())
-                .unwrap();
+            cpu.set_register(first.as_str(), cpu.get_register(second.as_str())?)?;
         }
         (Rule::reg16, Rule::imm) => {
-            let v = imm_to_num(&second).unwrap();
-            cpu.set_register(first.as_str(), v).unwrap();
+            let v = imm_to_num(&second)?;
+            cpu.set_register(first.as_str(), v)?;
         }
         (Rule::mem16, Rule::reg16) => {
-            let address = mem_to_num(&first).unwrap();
-            let v = cpu.get_register(second.as_str()).unwrap();
-            memory.write16(address, v);
+            let address = mem_to_num(&first)?;
+            let v = cpu.get_register(second.as_str())?;
+            memory.write16(cpu.get_register16("ds"), address, v);
         }
         (Rule::reg16, Rule::mem16) => {
-            let address = mem_to_num(&second).unwrap();
-            let v = memory.read16(address);
-            cpu.set_register(first.as_str(), v).unwrap();
+            let address = mem_to_num(&second)?;
+            let v = memory.read16(cpu.get_register16("ds"), address);
+            cpu.set_register(first.as_str(), v)?;
         }
         (Rule::mem16, Rule::imm) => {
-            let address = mem_to_num(&first).unwrap();
-            let v = imm_to_num(&second).unwrap();
-            memory.write16(address, v);
+            let address = mem_to_num(&first)?;
+            let v = imm_to_num(&second)?;
+            memory.write16(cpu.get_register16("ds"), address, v);
+        }
+        _ => {
+            return Err(format!(
+                "mov {}, {} is not supported yet",
+                first.as_str(),
+                second.as_str()
+            ))
         }
-        _ => println!("Not supported yet:{:?} {:?}", first, second),
     }
 });
 
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
-    //use super::*;
+    use super::*;
+    use crate::parser::AssemblyParser;
+    use pest::Parser;
+
+    fn assemble(s: &str) -> Result<Vec<u8>, String> {
+        let instruction = AssemblyParser::parse(Rule::instruction, s)
+            .unwrap()
+            .next()
+            .unwrap();
+        let mut inner = instruction.into_inner();
+        let first = inner.next().unwrap();
+        let second = inner.next().unwrap();
+        assemble_mov(&first, &second, &SymbolTable::default())
+    }
 
     #[test]
     fn test_mov_flags() {}
 
+    #[test]
+    fn test_mov_assemble() {
+        assert_eq!(Ok(vec![0xbb, 0x10, 0x00]), assemble("mov bx, 10h"));
+        assert_eq!(Ok(vec![0xb4, 0x12]), assemble("mov ah, 12h"));
+        assert_eq!(Ok(vec![0x8b, 0xc3]), assemble("mov ax, bx"));
+        assert_eq!(Ok(vec![0x8a, 0xe3]), assemble("mov ah, bl"));
+        assert_eq!(Ok(vec![0x89, 0x0e, 0x00, 0x00]), assemble("mov [0h], cx"));
+        assert_eq!(
+            Ok(vec![0x8b, 0x06, 0x34, 0x12]),
+            assemble("mov ax, [1234h]")
+        );
+        assert_eq!(
+            Ok(vec![0xc7, 0x06, 0x00, 0x10, 0x34, 0x12]),
+            assemble("mov word ptr [1000h], 0x1234")
+        );
+        assert_eq!(
+            Ok(vec![0xc6, 0x87, 0x10, 0x00, 0x12]),
+            assemble("mov byte ptr [bx + 10h], 12h")
+        );
+        assert_eq!(Ok(vec![0x8b, 0x00]), assemble("mov ax, [bx + si]"));
+        assert_eq!(Ok(vec![0x8e, 0xd8]), assemble("mov ds, ax"));
+        assert_eq!(Ok(vec![0x8c, 0xc8]), assemble("mov ax, cs"));
+
+        assert!(assemble("mov ds, 0x10").is_err());
+        assert!(assemble("mov ax, bl").is_err());
+    }
+
+    #[test]
+    fn test_mov_unsupported() {
+        let mut cpu = CpuContext::boot();
+        let mut memory = Memory::boot();
+        let mut inner = AssemblyParser::parse(Rule::instruction, "mov ah, 12h")
+            .unwrap()
+            .next()
+            .unwrap()
+            .into_inner();
+        let (first, second) = (inner.next().unwrap(), inner.next().unwrap());
+        assert_eq!(
+            Err("mov ah, 12h is not supported yet".to_string()),
+            handler_mov(&mut cpu, &mut memory, first, second)
+        );
+    }
+
     // TODO: make test cases for each case: reg-reg, reg-mem, mem-reg and etc...
 }
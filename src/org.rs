@@ -11,10 +11,10 @@ org 100h
 define_handler_one!(org, first, cpu, _memory, {
     match first.as_rule() {
         Rule::imm => {
-            let ip: u16 = imm_to_num(&first).unwrap();
+            let ip: u16 = imm_to_num(&first)?;
             cpu.set_register16("cs", 0);
             cpu.set_register16("ip", ip);
         }
-        _ => println!("Not supported operand for org:{:?}", first),
+        _ => return Err(format!("org {} is not supported", first.as_str())),
     }
 });
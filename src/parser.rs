@@ -1,5 +1,10 @@
+use crate::assembler::SymbolTable;
+use crate::error::AsmError;
+use crate::expr;
 use pest::iterators::Pair;
+use pest::Parser;
 use pest_derive::Parser;
+use std::collections::HashMap;
 
 /*
 This derive(Parser) generates Rule implicitly.
@@ -9,18 +14,44 @@ This derive(Parser) generates Rule implicitly.
 pub struct AssemblyParser;
 
 // Separate function for unittest
-fn _imm_to_num(s: &str) -> Result<u16, String> {
-    if s.starts_with("0x") {
-        u16::from_str_radix(&s[2..], 16).map_err(|_| "Invalid hex number".to_string())
-    } else if s.ends_with('h') {
-        u16::from_str_radix(&s[..s.len() - 1], 16).map_err(|_| "Invalid hex number".to_string())
+// Value of the number literal up to 32-bit
+fn _imm_to_value(s: &str) -> Result<u32, String> {
+    if s.len() == 3 && s.starts_with('\'') && s.ends_with('\'') {
+        // character: 'A' => 0x41
+        return Ok(s.as_bytes()[1] as u32);
+    }
+    // The prefix and suffix are case-insensitive: e.g. 0FFH, 0X1234
+    let lower = s.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        (hex, 16)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        (bin, 2)
+    } else if let Some(bin) = lower.strip_suffix('b') {
+        (bin, 2)
+    } else if let Some(oct) = lower.strip_suffix(['o', 'q']) {
+        (oct, 8)
     } else {
-        Err("imm is not a valid hex format".to_string())
+        (lower.as_str(), 10)
+    };
+    u32::from_str_radix(digits, radix).map_err(|_| format!("Invalid number {}", s))
+}
+
+// 16-bit value of the number literal
+fn _imm_to_num(s: &str) -> Result<u16, String> {
+    u16::try_from(_imm_to_value(s)?).map_err(|_| format!("{} does not fit in 16 bits", s))
+}
+
+pub fn imm_to_value(s: &Pair<Rule>) -> Result<u32, String> {
+    if s.as_rule() != Rule::imm {
+        return Err("Tried to parse something else imm".to_string());
     }
+    _imm_to_value(s.as_str())
 }
 
 pub fn imm_to_num(s: &Pair<Rule>) -> Result<u16, String> {
-    // Three imm forms: 0x1234, 0abcdh, 1abch
+    // hex 0x1234, 0abcdh, binary 0b101, 101b, octal 17o, 17q, decimal 1234, character 'A'
     if s.as_rule() != Rule::imm {
         return Err("Tried to parse something else imm".to_string());
     }
@@ -34,14 +65,387 @@ pub fn mem_to_num(s: &Pair<Rule>) -> Result<u16, String> {
     if let Some(start) = s.find('[') {
         if let Some(end) = s.find(']') {
             if start < end {
-                let r: u16 = _imm_to_num(&s[start + 1..end]).unwrap();
-                return Ok(r);
+                return _imm_to_num(s[start + 1..end].trim());
             }
         }
     }
     Err("Failed to parse memory address: No valid number found between brackets".to_string())
 }
 
+/*
+Macro preprocessor
+
+The program lines are expanded before the two assembler passes.
+
+name macro param1, param2   ; definition
+    local again             ; unique label for each expansion: ??0000, ??0001, ...
+again:
+    mov ax, param1
+endm
+
+rept 3                      ; repeat the lines 3 times
+    inc ax
+endm
+
+irp reg, <ax, bx, cx>       ; repeat the lines for each item
+    inc reg
+endm
+
+include "consts.inc"        ; insert the lines of the file
+
+The parameters are replaced by the arguments as a whole word.
+& joins a parameter with the text around it: e.g. val&n => val1
+Every expanded line has the line number of the macro call, rept, irp or include line.
+The included lines also have the file name and their line number in the file.
+*/
+
+// Recursive macro should stop
+const MAX_EXPANSION_DEPTH: usize = 32;
+// rept with a large count or nested rept should stop
+const MAX_EXPANDED_LINES: usize = 100_000;
+
+/// Line after the macro expansion
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    // Line number of the source: the macro call or include line for the expanded lines
+    pub linenum: usize,
+    pub text: String,
+    // The line is generated by macro, rept, irp or include
+    pub expanded: bool,
+    // Include file name and line number: None for the lines of the main program
+    pub location: Option<(String, usize)>,
+}
+
+impl SourceLine {
+    /// Error at the column of the line: the included line has the file name.
+    pub fn error_at(&self, column: usize, e: &str) -> AsmError {
+        match &self.location {
+            Some((file, linenum)) => AsmError::at(Some(file.clone()), linenum + 1, column, e),
+            None => AsmError::at(None, self.linenum + 1, column, e),
+        }
+    }
+
+    pub fn error(&self, e: &str) -> AsmError {
+        self.error_at(1, e)
+    }
+}
+
+/// Read the lines of an include file by the name
+pub type IncludeFile<'a> = &'a dyn Fn(&str) -> Result<Vec<String>, String>;
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+// Remove the comment but not ';' in a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, ';') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// First word and the rest of the line
+fn split_word(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(|c: char| c.is_whitespace()) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    }
+}
+
+// Split the arguments by comma except the commas in <...> and quotes
+// <...> is removed: e.g. "<1, 2>, 3" => ["1, 2", "3"]
+fn split_args(s: &str) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    if s.trim().is_empty() {
+        return args;
+    }
+    let mut arg = String::new();
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    for c in s.chars() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, '<') => {
+                depth += 1;
+                if depth == 1 {
+                    continue;
+                }
+            }
+            (None, '>') => {
+                depth -= 1;
+                if depth == 0 {
+                    continue;
+                }
+            }
+            (None, ',') if depth == 0 => {
+                args.push(arg.trim().to_string());
+                arg.clear();
+                continue;
+            }
+            _ => {}
+        }
+        arg.push(c);
+    }
+    args.push(arg.trim().to_string());
+    args
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '?' || c == '@'
+}
+
+// Replace the whole words and remove & between the words
+fn substitute(line: &str, names: &HashMap<String, String>) -> String {
+    let mut s = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(q) => {
+                if q == c {
+                    quote = None;
+                }
+                s.push(c);
+            }
+            None if c == '\'' || c == '"' => {
+                quote = Some(c);
+                s.push(c);
+            }
+            None if c == '&' => {}
+            None if is_name_char(c) => {
+                let mut word = c.to_string();
+                while let Some(&n) = chars.peek() {
+                    if !is_name_char(n) {
+                        break;
+                    }
+                    word.push(n);
+                    chars.next();
+                }
+                // A number is not a name: e.g. 10h
+                match names.get(&word) {
+                    Some(v) if !c.is_ascii_digit() => s.push_str(v),
+                    _ => s.push_str(&word),
+                }
+            }
+            None => s.push(c),
+        }
+    }
+    s
+}
+
+// Value of the repeat count: constant expression without symbols
+fn count_value(s: &str) -> Result<usize, String> {
+    let expr = AssemblyParser::parse(Rule::expr, s.trim())
+        .map_err(|_| format!("Invalid count {}", s))?
+        .next()
+        .unwrap();
+    if expr.as_str() != s.trim() {
+        return Err(format!("Invalid count {}", s));
+    }
+    let v = expr::evaluate(&expr, &SymbolTable::default())?;
+    usize::try_from(v).map_err(|_| format!("Invalid count {}", s))
+}
+
+#[derive(Default)]
+struct Preprocessor<'a> {
+    macros: HashMap<String, Macro>,
+    // Number of the local labels: ??0000, ??0001, ...
+    locals: usize,
+    include: Option<IncludeFile<'a>>,
+    // Files being included to find the recursive include
+    including: Vec<String>,
+}
+
+impl Preprocessor<'_> {
+    // Find endm of the block starting at lines[start]
+    // return: body lines and the index of endm
+    fn block(lines: &[SourceLine], start: usize) -> Result<(Vec<String>, usize), AsmError> {
+        let mut depth = 0;
+        for (i, line) in lines.iter().enumerate().skip(start + 1) {
+            let (first, rest) = split_word(strip_comment(&line.text));
+            let (second, _) = split_word(rest);
+            if second.eq_ignore_ascii_case("macro")
+                || first.eq_ignore_ascii_case("rept")
+                || first.eq_ignore_ascii_case("irp")
+            {
+                depth += 1;
+            } else if first.eq_ignore_ascii_case("endm") {
+                if depth == 0 {
+                    let body = lines[start + 1..i].iter().map(|l| l.text.clone()).collect();
+                    return Ok((body, i));
+                }
+                depth -= 1;
+            }
+        }
+        Err(lines[start].error("endm is not found"))
+    }
+
+    // Make the lines of a macro call
+    fn call(&mut self, m: &Macro, args: &[String]) -> Result<Vec<String>, String> {
+        if args.len() > m.params.len() {
+            return Err(format!(
+                "{} arguments are given to the macro with {} parameters",
+                args.len(),
+                m.params.len()
+            ));
+        }
+        let mut names: HashMap<String, String> = HashMap::new();
+        for (i, param) in m.params.iter().enumerate() {
+            names.insert(param.clone(), args.get(i).cloned().unwrap_or_default());
+        }
+        let mut body: Vec<&String> = Vec::new();
+        for line in m.body.iter() {
+            let (first, rest) = split_word(strip_comment(line));
+            if first.eq_ignore_ascii_case("local") {
+                for local in split_args(rest) {
+                    names.insert(local, format!("??{:04}", self.locals));
+                    self.locals += 1;
+                }
+            } else {
+                body.push(line);
+            }
+        }
+        Ok(body.iter().map(|line| substitute(line, &names)).collect())
+    }
+
+    fn expand(
+        &mut self,
+        lines: &[SourceLine],
+        depth: usize,
+        out: &mut Vec<SourceLine>,
+    ) -> Result<(), AsmError> {
+        let mut i = 0;
+        while i < lines.len() {
+            let line = &lines[i];
+            let line_error = |e: String| line.error(&e);
+            if depth > MAX_EXPANSION_DEPTH {
+                return Err(line_error("Too deep macro expansion".to_string()));
+            }
+            let (first, rest) = split_word(strip_comment(&line.text));
+            let (second, params) = split_word(rest);
+            // Lines generated by this line
+            let generated: Vec<String> = if second.eq_ignore_ascii_case("macro") {
+                let (body, end) = Self::block(lines, i)?;
+                let params = split_args(params);
+                self.macros
+                    .insert(first.to_string(), Macro { params, body });
+                i = end + 1;
+                continue;
+            } else if first.eq_ignore_ascii_case("rept") {
+                let (body, end) = Self::block(lines, i)?;
+                let count = count_value(rest).map_err(line_error)?;
+                if count.saturating_mul(body.len()) > MAX_EXPANDED_LINES - out.len() {
+                    return Err(line_error(format!(
+                        "rept makes more than {} lines",
+                        MAX_EXPANDED_LINES
+                    )));
+                }
+                i = end;
+                (0..count).flat_map(|_| body.clone()).collect()
+            } else if first.eq_ignore_ascii_case("irp") {
+                let (body, end) = Self::block(lines, i)?;
+                let (param, items) = rest
+                    .split_once(',')
+                    .ok_or_else(|| line_error("irp needs a parameter and items".to_string()))?;
+                let m = Macro {
+                    params: vec![param.trim().to_string()],
+                    body,
+                };
+                i = end;
+                let mut v: Vec<String> = Vec::new();
+                for item in split_args(items.trim()).iter().flat_map(|s| split_args(s)) {
+                    v.extend(self.call(&m, &[item]).map_err(line_error)?);
+                }
+                v
+            } else if first.eq_ignore_ascii_case("include") {
+                let name = rest.trim_matches(|c| c == '"' || c == '\'');
+                if self.including.iter().any(|f| f == name) {
+                    return Err(line_error(format!("{} is included recursively", name)));
+                }
+                let include = self
+                    .include
+                    .ok_or_else(|| line_error(format!("{} cannot be included", name)))?;
+                let included: Vec<SourceLine> = include(name)
+                    .map_err(line_error)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(linenum, text)| SourceLine {
+                        linenum: line.linenum,
+                        text,
+                        expanded: true,
+                        location: Some((name.to_string(), linenum)),
+                    })
+                    .collect();
+                self.including.push(name.to_string());
+                self.expand(&included, depth + 1, out)?;
+                self.including.pop();
+                i += 1;
+                continue;
+            } else if first.eq_ignore_ascii_case("endm") || first.eq_ignore_ascii_case("local") {
+                return Err(line_error(format!("{} is not in a macro", first)));
+            } else if let Some(m) = self.macros.get(first).cloned() {
+                self.call(&m, &split_args(rest)).map_err(line_error)?
+            } else {
+                if out.len() == MAX_EXPANDED_LINES {
+                    return Err(line_error(format!(
+                        "Expansion makes more than {} lines",
+                        MAX_EXPANDED_LINES
+                    )));
+                }
+                out.push(line.clone());
+                i += 1;
+                continue;
+            };
+
+            let generated: Vec<SourceLine> = generated
+                .into_iter()
+                .map(|text| SourceLine {
+                    linenum: line.linenum,
+                    text,
+                    expanded: true,
+                    location: line.location.clone(),
+                })
+                .collect();
+            self.expand(&generated, depth + 1, out)?;
+            i += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Expand macro, rept, irp and include in the program
+/// The definitions are removed and the other lines are not changed.
+pub fn preprocess(program: &[String], include: IncludeFile) -> Result<Vec<SourceLine>, AsmError> {
+    let lines: Vec<SourceLine> = program
+        .iter()
+        .enumerate()
+        .map(|(linenum, text)| SourceLine {
+            linenum,
+            text: text.clone(),
+            expanded: false,
+            location: None,
+        })
+        .collect();
+    let mut out: Vec<SourceLine> = Vec::new();
+    let mut preprocessor = Preprocessor {
+        include: Some(include),
+        ..Default::default()
+    };
+    preprocessor.expand(&lines, 0, &mut out)?;
+    Ok(out)
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -73,9 +477,9 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Decimal is not allowed")]
+    #[should_panic(expected = "Hex number needs h-suffix")]
     fn test_parser_imm_failure() {
-        AssemblyParser::parse(Rule::imm, "1234").expect("Decimal is not allowed");
+        AssemblyParser::parse(Rule::imm, "12ab").expect("Hex number needs h-suffix");
     }
 
     #[test]
@@ -258,6 +662,115 @@ mod tests {
         assert_eq!(Rule::jmp, jmp.as_rule());
     }
 
+    #[test]
+    fn test_parser_case_and_whitespace() {
+        // Uppercase, tabs, indentation and trailing comments
+        let program = "\tMOV\tAX, WORD PTR [1234H] ; load\n  Add Cx, Bx\t; add\nAGAIN:\tINC BYTE PTR [BX+SI+2]\t; label and instruction\n\t; comment only";
+        let file = AssemblyParser::parse(Rule::program, program)
+            .unwrap()
+            .next()
+            .unwrap();
+        let rules: Vec<Rule> = file.into_inner().map(|p| p.as_rule()).collect();
+        assert_eq!(
+            vec![Rule::mov, Rule::add, Rule::label, Rule::inc, Rule::EOI],
+            rules
+        );
+
+        let mem16 = AssemblyParser::parse(Rule::mem16, "Word Ptr [0FFH]")
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(Ok(0xff), mem_to_num(&mem16));
+        let reg = AssemblyParser::parse(Rule::register, "DL")
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(Rule::reg8, reg.as_rule());
+        // A name starting with a register name is not a register
+        assert!(AssemblyParser::parse(Rule::register, "SIZE").is_err());
+    }
+
+    #[test]
+    fn test_parser_data() {
+        let data = AssemblyParser::parse(Rule::instruction, "msg db 'Hi $', 0dh, 2h dup(?)")
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(Rule::data, data.as_rule());
+        let mut inner = data.into_inner();
+        let name = inner.next().unwrap();
+        assert_eq!(Rule::name, name.as_rule());
+        assert_eq!("msg", name.as_str());
+        let db = inner.next().unwrap();
+        assert_eq!(Rule::db, db.as_rule());
+        let items: Vec<Rule> = db.into_inner().map(|p| p.as_rule()).collect();
+        assert_eq!(vec![Rule::string, Rule::imm, Rule::dup], items);
+
+        // Name of data is optional
+        let data = AssemblyParser::parse(Rule::instruction, "dw ?, msg")
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(Rule::dw, data.into_inner().next().unwrap().as_rule());
+    }
+
+    #[test]
+    fn test_parser_expr() {
+        let instruction = AssemblyParser::parse(Rule::instruction, "mov dx, offset msg")
+            .unwrap()
+            .next()
+            .unwrap();
+        let expr = instruction.into_inner().nth(1).unwrap();
+        assert_eq!(Rule::expr, expr.as_rule());
+        let inner: Vec<Rule> = expr.into_inner().map(|p| p.as_rule()).collect();
+        assert_eq!(vec![Rule::op_offset, Rule::name], inner);
+
+        // A name starting with a register name is not a register
+        let instruction = AssemblyParser::parse(Rule::instruction, "mov ax, size")
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(
+            Rule::expr,
+            instruction.into_inner().nth(1).unwrap().as_rule()
+        );
+
+        // A single number is imm
+        let instruction = AssemblyParser::parse(Rule::instruction, "mov ax, 10 ; ten")
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(
+            Rule::imm,
+            instruction.into_inner().nth(1).unwrap().as_rule()
+        );
+
+        let expr = AssemblyParser::parse(Rule::expr, "-(2 + $) * count mod 3 shl 1")
+            .unwrap()
+            .next()
+            .unwrap();
+        let inner: Vec<Rule> = expr.into_inner().map(|p| p.as_rule()).collect();
+        assert_eq!(
+            vec![
+                Rule::op_neg,
+                Rule::expr,
+                Rule::op_mul,
+                Rule::name,
+                Rule::op_mod,
+                Rule::imm,
+                Rule::op_shl,
+                Rule::imm
+            ],
+            inner
+        );
+
+        let mem16 = AssemblyParser::parse(Rule::mem16, "word ptr [msg + 2]")
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(Rule::expr, mem16.into_inner().next().unwrap().as_rule());
+    }
+
     #[test]
     fn test_parser_imm_to_num() {
         assert_eq!(Ok(0x1a3), _imm_to_num("0x1a3"));
@@ -265,7 +778,21 @@ mod tests {
         assert_eq!(Ok(0xabc), _imm_to_num("0abch"));
         assert_eq!(Ok(0x45), _imm_to_num("045h"));
 
-        assert_eq!(Err("Invalid hex number".to_owned()), _imm_to_num("0xghi"));
+        assert_eq!(Ok(1234), _imm_to_num("1234"));
+        assert_eq!(Ok(0b1010), _imm_to_num("0b1010"));
+        assert_eq!(Ok(0b1010), _imm_to_num("1010b"));
+        assert_eq!(Ok(0o17), _imm_to_num("17o"));
+        assert_eq!(Ok(0o17), _imm_to_num("17q"));
+        assert_eq!(Ok(0x41), _imm_to_num("'A'"));
+        assert_eq!(Ok(100000), _imm_to_value("100000"));
+
+        assert_eq!(Ok(0xffff), _imm_to_num("0FFFFH"));
+        assert_eq!(Ok(0x1a3), _imm_to_num("0X1A3"));
+        assert_eq!(Ok(0b101), _imm_to_num("101B"));
+        assert_eq!(Ok(0x61), _imm_to_num("'a'"));
+
+        assert_eq!(Err("Invalid number 0xghi".to_owned()), _imm_to_num("0xghi"));
+        assert!(_imm_to_num("100000").is_err());
     }
 
     #[test]
@@ -413,4 +940,124 @@ mod tests {
         assert_eq!(Rule::imm, disp.as_rule());
         assert_eq!("1234h", disp.as_str());
     }
+
+    fn texts(lines: &[SourceLine]) -> Vec<(usize, &str, bool)> {
+        lines
+            .iter()
+            .map(|l| (l.linenum, l.text.trim(), l.expanded))
+            .collect()
+    }
+
+    fn program(s: &str) -> Vec<String> {
+        s.lines().map(|l| l.to_owned()).collect()
+    }
+
+    fn no_include(name: &str) -> Result<Vec<String>, String> {
+        Err(format!("{} is not found", name))
+    }
+
+    #[test]
+    fn test_parser_preprocess_macro() {
+        let lines = preprocess(&program(
+            "store macro dst, src ; comment\n  local again\nagain:\n  mov dst, src\n  jmp again\nendm\norg 100h\nstore ax, <[bx + si]>\nstore cx, 'A'",
+        ), &no_include)
+        .unwrap();
+        assert_eq!(
+            vec![
+                (6, "org 100h", false),
+                (7, "??0000:", true),
+                (7, "mov ax, [bx + si]", true),
+                (7, "jmp ??0000", true),
+                (8, "??0001:", true),
+                (8, "mov cx, 'A'", true),
+                (8, "jmp ??0001", true),
+            ],
+            texts(&lines)
+        );
+    }
+
+    #[test]
+    fn test_parser_preprocess_repeat() {
+        let lines = preprocess(&program(
+            "rept 2 * 1\n  inc ax\nendm\nirp reg, <bx, cx>\n  inc reg\nendm\nput macro n\n  db n, val&n\nendm\nput 1",
+        ), &no_include)
+        .unwrap();
+        assert_eq!(
+            vec![
+                (0, "inc ax", true),
+                (0, "inc ax", true),
+                (3, "inc bx", true),
+                (3, "inc cx", true),
+                (9, "db 1, val1", true),
+            ],
+            texts(&lines)
+        );
+
+        // Nested macro call and rept in a macro
+        let lines = preprocess(
+            &program("one macro\n  inc ax\nendm\ntwo macro\n  rept 2\n    one\n  endm\nendm\ntwo"),
+            &no_include,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![(8, "inc ax", true), (8, "inc ax", true)],
+            texts(&lines)
+        );
+    }
+
+    #[test]
+    fn test_parser_preprocess_failure() {
+        assert!(preprocess(&program("rept 2\ninc ax"), &no_include).is_err());
+        assert!(preprocess(&program("endm"), &no_include).is_err());
+        assert!(preprocess(&program("local x1"), &no_include).is_err());
+        assert!(preprocess(&program("rept count\nendm"), &no_include).is_err());
+        assert!(preprocess(&program("one macro p1\nendm\none 1, 2"), &no_include).is_err());
+        // Recursive macro
+        assert!(preprocess(&program("loop macro\nloop\nendm\nloop"), &no_include).is_err());
+        // Too many lines of rept and nested rept on the rept line
+        let e = preprocess(
+            &program("inc ax\nrept 100000000\ninc ax\nendm"),
+            &no_include,
+        );
+        assert_eq!(2, e.unwrap_err().line);
+        let nested = "rept 1000\nrept 1000\ninc ax\nendm\nendm";
+        assert_eq!(
+            1,
+            preprocess(&program(nested), &no_include).unwrap_err().line
+        );
+    }
+
+    #[test]
+    fn test_parser_preprocess_include() {
+        let include = |name: &str| match name {
+            "macros.inc" => Ok(program("two macro reg\n  inc reg\n  inc reg\nendm")),
+            "main.inc" => Ok(program("include 'macros.inc'\ntwo bx")),
+            "self.inc" => Ok(program("include \"self.inc\"")),
+            _ => Err(format!("{} is not found", name)),
+        };
+        let lines =
+            preprocess(&program("org 100h\ninclude \"main.inc\"\ntwo ax"), &include).unwrap();
+        assert_eq!(
+            vec![
+                (0, "org 100h", false),
+                (1, "inc bx", true),
+                (1, "inc bx", true),
+                (2, "inc ax", true),
+                (2, "inc ax", true)
+            ],
+            texts(&lines)
+        );
+        // Macro call in the include file has the line of the call
+        assert_eq!(Some(("main.inc".to_string(), 1)), lines[1].location);
+        assert_eq!(None, lines[3].location);
+
+        assert_eq!(
+            Err("Line 1: nothing.inc is not found".to_string()),
+            preprocess(&program("include \"nothing.inc\""), &include).map_err(|e| e.to_string())
+        );
+        assert_eq!(
+            Err("self.inc line 1: self.inc is included recursively".to_string()),
+            preprocess(&program("include \"self.inc\""), &include).map_err(|e| e.to_string())
+        );
+    }
 }
@@ -0,0 +1,366 @@
+use crate::breakpoint::{Breakpoint, Location};
+use crate::cpucontext::{CpuContext, REGISTERS16};
+use crate::memory::{Access, MEMORY_SIZE};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/*
+Snapshot of the whole machine
+
+The file is "R86S", the version and the zlib stream of the machine.
+Numbers are little-endian and a string is its length (u32) and UTF-8 bytes.
+field       | contents
+registers   | AX BX CX DX SI DI BP SP CS DS ES SS IP FLAGS: u16 each
+halted      | u8
+line        | u32: next line of the source
+memory      | 1MB
+program     | u8 0: the source, its include files and the optimize option, 1: binary program
+breakpoints | last id (u32), count (u32) and the breakpoints
+The emulator has no devices: the registers and the memory are the whole state of the CPU.
+The source is built again when the snapshot is restored and the memory overwrites its image.
+*/
+
+const MAGIC: &[u8; 4] = b"R86S";
+// 2: the start of a watchpoint is a physical address (u32)
+const VERSION: u8 = 2;
+// Limit of the uncompressed machine: 1MB memory and the source
+const MAX_DATA_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Program {
+    // Source lines, include files and the optimize option of the assembler
+    Source {
+        lines: Vec<String>,
+        files: HashMap<String, Vec<String>>,
+        optimize: bool,
+    },
+    // .COM or .EXE program in the memory
+    Binary,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub cpu: CpuContext,
+    pub halted: bool,
+    pub line: usize,
+    // 1MB image of the memory
+    pub memory: Vec<u8>,
+    pub program: Program,
+    pub breakpoints: Vec<Breakpoint>,
+    pub last_id: usize,
+}
+
+impl Snapshot {
+    /// Compressed bytes of the snapshot file
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut w = Vec::new();
+        for reg in REGISTERS16 {
+            put_u16(&mut w, self.cpu.get_register16(reg));
+        }
+        w.push(self.halted as u8);
+        put_u32(&mut w, self.line)?;
+        w.extend_from_slice(&self.memory);
+        match &self.program {
+            Program::Source {
+                lines,
+                files,
+                optimize,
+            } => {
+                w.push(0);
+                w.push(*optimize as u8);
+                put_lines(&mut w, lines)?;
+                // Sorted names: the same machine makes the same file.
+                let mut names: Vec<&String> = files.keys().collect();
+                names.sort();
+                put_u32(&mut w, names.len())?;
+                for name in names {
+                    put_str(&mut w, name)?;
+                    put_lines(&mut w, &files[name])?;
+                }
+            }
+            Program::Binary => w.push(1),
+        }
+        put_u32(&mut w, self.last_id)?;
+        put_u32(&mut w, self.breakpoints.len())?;
+        for b in self.breakpoints.iter() {
+            put_breakpoint(&mut w, b)?;
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&w).map_err(|e| e.to_string())?;
+        let compressed = encoder.finish().map_err(|e| e.to_string())?;
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend(compressed);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 5 || &bytes[..4] != MAGIC {
+            return Err("Not a snapshot file".to_string());
+        }
+        if bytes[4] != VERSION {
+            return Err(format!("Snapshot version {} is not supported", bytes[4]));
+        }
+        let mut data = Vec::new();
+        ZlibDecoder::new(&bytes[5..])
+            .take(MAX_DATA_SIZE + 1)
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Broken snapshot: {}", e))?;
+        if data.len() as u64 > MAX_DATA_SIZE {
+            return Err("Snapshot is too large".to_string());
+        }
+        let mut r = Reader {
+            data: &data,
+            pos: 0,
+        };
+
+        let mut cpu = CpuContext::boot();
+        for reg in REGISTERS16 {
+            cpu.set_register16(reg, r.u16()?);
+        }
+        let halted = r.u8()? != 0;
+        let line = r.u32()?;
+        let memory = r.bytes(MEMORY_SIZE)?.to_vec();
+        let program = match r.u8()? {
+            0 => {
+                let optimize = r.u8()? != 0;
+                let lines = r.lines()?;
+                let mut files = HashMap::new();
+                for _ in 0..r.u32()? {
+                    let name = r.str()?;
+                    files.insert(name, r.lines()?);
+                }
+                Program::Source {
+                    lines,
+                    files,
+                    optimize,
+                }
+            }
+            1 => Program::Binary,
+            n => return Err(format!("Broken snapshot: program type {}", n)),
+        };
+        let last_id = r.u32()?;
+        let mut breakpoints = Vec::new();
+        for _ in 0..r.u32()? {
+            breakpoints.push(r.breakpoint()?);
+        }
+        if r.pos != data.len() {
+            return Err("Broken snapshot: extra bytes at the end".to_string());
+        }
+        Ok(Snapshot {
+            cpu,
+            halted,
+            line,
+            memory,
+            program,
+            breakpoints,
+            last_id,
+        })
+    }
+}
+
+fn put_u16(w: &mut Vec<u8>, value: u16) {
+    w.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(w: &mut Vec<u8>, value: usize) -> Result<(), String> {
+    let value = u32::try_from(value).map_err(|_| format!("{} is too large", value))?;
+    w.extend_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+fn put_str(w: &mut Vec<u8>, s: &str) -> Result<(), String> {
+    put_u32(w, s.len())?;
+    w.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn put_lines(w: &mut Vec<u8>, lines: &[String]) -> Result<(), String> {
+    put_u32(w, lines.len())?;
+    for line in lines {
+        put_str(w, line)?;
+    }
+    Ok(())
+}
+
+// Location: 0 line, 1 CS:IP, 2 label, 3 anywhere, 4 watchpoint
+fn put_breakpoint(w: &mut Vec<u8>, b: &Breakpoint) -> Result<(), String> {
+    put_u32(w, b.id)?;
+    w.push(b.enabled as u8);
+    put_u32(w, b.hits)?;
+    match &b.location {
+        Location::Line(line) => {
+            w.push(0);
+            put_u32(w, *line)?;
+        }
+        Location::Address(cs, ip) => {
+            w.push(1);
+            put_u16(w, *cs);
+            put_u16(w, *ip);
+        }
+        Location::Label(name) => {
+            w.push(2);
+            put_str(w, name)?;
+        }
+        Location::Anywhere => w.push(3),
+        Location::Watch(start, len, access) => {
+            w.push(4);
+            put_u32(w, *start)?;
+            put_u16(w, *len);
+            w.push(*access as u8);
+        }
+    }
+    match &b.condition {
+        Some(condition) => {
+            w.push(1);
+            put_str(w, condition)?;
+        }
+        None => w.push(0),
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or("Broken snapshot: unexpected end")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()?;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| "Broken snapshot: invalid UTF-8".to_string())
+    }
+
+    fn lines(&mut self) -> Result<Vec<String>, String> {
+        (0..self.u32()?).map(|_| self.str()).collect()
+    }
+
+    fn breakpoint(&mut self) -> Result<Breakpoint, String> {
+        let id = self.u32()?;
+        let enabled = self.u8()? != 0;
+        let hits = self.u32()?;
+        let location = match self.u8()? {
+            0 => Location::Line(self.u32()?),
+            1 => Location::Address(self.u16()?, self.u16()?),
+            2 => Location::Label(self.str()?),
+            3 => Location::Anywhere,
+            4 => {
+                let (start, len) = (self.u32()?, self.u16()?);
+                let access = match self.u8()? {
+                    0 => Access::Read,
+                    1 => Access::Write,
+                    2 => Access::Change,
+                    n => return Err(format!("Broken snapshot: access {}", n)),
+                };
+                Location::Watch(start, len, access)
+            }
+            n => return Err(format!("Broken snapshot: location {}", n)),
+        };
+        let condition = match self.u8()? {
+            0 => None,
+            _ => Some(self.str()?),
+        };
+        Ok(Breakpoint {
+            id,
+            location,
+            enabled,
+            hits,
+            condition,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_snapshot_bytes() {
+        let mut cpu = CpuContext::boot();
+        cpu.set_register16("ax", 0x1234);
+        cpu.set_register16("ip", 0x103);
+        cpu.set_ZF();
+        let mut memory = vec![0; MEMORY_SIZE];
+        memory[0x100] = 0x40;
+        memory[MEMORY_SIZE - 1] = 0xff;
+        let mut files = HashMap::new();
+        files.insert("consts.inc".to_string(), vec!["count equ 3".to_string()]);
+        let snapshot = Snapshot {
+            cpu,
+            halted: true,
+            line: 3,
+            memory,
+            program: Program::Source {
+                lines: vec!["org 100h".to_string(), "inc ax".to_string()],
+                files,
+                optimize: false,
+            },
+            breakpoints: vec![
+                Breakpoint {
+                    id: 1,
+                    location: Location::Label("again".to_string()),
+                    enabled: false,
+                    hits: 2,
+                    condition: Some("cx == 0".to_string()),
+                },
+                Breakpoint {
+                    id: 3,
+                    location: Location::Watch(0x10200, 2, Access::Change),
+                    enabled: true,
+                    hits: 0,
+                    condition: None,
+                },
+            ],
+            last_id: 3,
+        };
+        let bytes = snapshot.to_bytes().unwrap();
+        // 1MB of zeros is compressed.
+        assert!(bytes.len() < 4096);
+        assert_eq!(b"R86S\x02", &bytes[..5]);
+        assert_eq!(Ok(snapshot.clone()), Snapshot::from_bytes(&bytes));
+
+        let binary = Snapshot {
+            program: Program::Binary,
+            breakpoints: Vec::new(),
+            ..snapshot
+        };
+        let bytes = binary.to_bytes().unwrap();
+        assert_eq!(Ok(binary), Snapshot::from_bytes(&bytes));
+
+        assert!(Snapshot::from_bytes(b"R86").is_err());
+        // The watchpoints of the version 1 have 16-bit addresses.
+        assert!(Snapshot::from_bytes(b"R86S\x01").is_err());
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 4]).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use crate::assembler::{register_table, SymbolTable};
+use crate::memory::Memory;
+use crate::parser::Rule;
+use crate::{cpucontext::CpuContext, define_handler_one};
+use paste::paste;
+use pest::iterators::Pair;
+
+/*
+Stack instructions
+
+PUSH reg16 $50+reg, POP reg16 $58+reg
+e.g. push bp => 55, pop bp => 5D
+PUSH sreg 000sr110, POP sreg 000sr111
+e.g. push es => 06, push cs => 0E, push ss => 16, push ds => 1E
+e.g. pop es => 07, pop ss => 17, pop ds => 1F (pop cs is not an 8086 instruction)
+
+CALL rel16 $E8: push IP of the next instruction and jump to IP + 16-bit displacement
+e.g. call proc => E8 disp-low disp-high
+RET $C3: pop IP
+RET imm16 $C2: pop IP and remove imm16 bytes of the arguments
+e.g. ret 4 => C2 04 00
+
+The stack is at SS:SP and grows down. push sp pushes SP after the decrement as like the 8086.
+The stack accesses are read16 and write16 in SS: the journal, the watchpoints and the trace have them.
+*/
+
+pub const CALL_SIZE: usize = 3;
+
+/// Code of the segment register in 000sr11x
+fn sreg_table(reg: &str) -> Option<u8> {
+    match reg.to_ascii_lowercase().as_str() {
+        "es" => Some(0),
+        "cs" => Some(1),
+        "ss" => Some(2),
+        "ds" => Some(3),
+        _ => None,
+    }
+}
+
+pub fn assemble_push(operand: &Pair<Rule>) -> Result<Vec<u8>, String> {
+    match sreg_table(operand.as_str()) {
+        Some(sreg) => Ok(vec![sreg << 3 | 0x06]),
+        None => Ok(vec![0x50 | register_table(operand.as_str())?]),
+    }
+}
+
+pub fn assemble_pop(operand: &Pair<Rule>) -> Result<Vec<u8>, String> {
+    match sreg_table(operand.as_str()) {
+        Some(1) => Err("pop cs is not supported".to_string()),
+        Some(sreg) => Ok(vec![sreg << 3 | 0x07]),
+        None => Ok(vec![0x58 | register_table(operand.as_str())?]),
+    }
+}
+
+/// address: address of the call instruction
+pub fn assemble_call(
+    operand: &Pair<Rule>,
+    address: u16,
+    symbols: &SymbolTable,
+) -> Result<Vec<u8>, String> {
+    let target = match symbols.get(operand.as_str())? {
+        // A label defined later is resolved by the next pass: call has one size.
+        _ if symbols.lenient() && !symbols.contains(operand.as_str()) => address,
+        target => target,
+    };
+    let disp = target.wrapping_sub(address.wrapping_add(CALL_SIZE as u16));
+    let mut v = vec![0xe8];
+    v.extend_from_slice(&disp.to_le_bytes());
+    Ok(v)
+}
+
+/// ret or ret imm16
+pub fn assemble_ret(operand: Option<Pair<Rule>>) -> Result<Vec<u8>, String> {
+    match operand {
+        Some(imm) => {
+            let n = crate::parser::imm_to_num(&imm)?;
+            let mut v = vec![0xc2];
+            v.extend_from_slice(&n.to_le_bytes());
+            Ok(v)
+        }
+        None => Ok(vec![0xc3]),
+    }
+}
+
+/// Push the value on SS:SP
+pub fn push(cpu: &mut CpuContext, memory: &mut Memory, value: u16) {
+    let sp = cpu.get_register16("sp").wrapping_sub(2);
+    cpu.set_register16("sp", sp);
+    memory.write16(cpu.get_register16("ss"), sp, value);
+}
+
+/// Pop the value from SS:SP
+pub fn pop(cpu: &mut CpuContext, memory: &Memory) -> u16 {
+    let sp = cpu.get_register16("sp");
+    let value = memory.read16(cpu.get_register16("ss"), sp);
+    cpu.set_register16("sp", sp.wrapping_add(2));
+    value
+}
+
+define_handler_one!(push, first, cpu, memory, {
+    let sp = cpu.get_register16("sp").wrapping_sub(2);
+    cpu.set_register16("sp", sp);
+    let value = cpu.get_register16(first.as_str());
+    memory.write16(cpu.get_register16("ss"), sp, value);
+});
+
+define_handler_one!(pop, first, cpu, memory, {
+    let value = pop(cpu, memory);
+    cpu.set_register16(first.as_str(), value);
+});
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::AssemblyParser;
+
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::memory::{Access, Watch};
+    use pest::Parser;
+
+    fn operand(text: &str) -> Pair<'_, Rule> {
+        AssemblyParser::parse(Rule::instruction, text)
+            .unwrap()
+            .next()
+            .unwrap()
+            .into_inner()
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_stack_assemble() {
+        assert_eq!(Ok(vec![0x55]), assemble_push(&operand("push bp")));
+        assert_eq!(Ok(vec![0x1e]), assemble_push(&operand("push ds")));
+        assert_eq!(Ok(vec![0x0e]), assemble_push(&operand("PUSH CS")));
+        assert_eq!(Ok(vec![0x5d]), assemble_pop(&operand("pop bp")));
+        assert_eq!(Ok(vec![0x07]), assemble_pop(&operand("pop es")));
+        assert!(assemble_pop(&operand("pop cs")).is_err());
+        assert_eq!(Ok(vec![0xc3]), assemble_ret(None));
+        assert_eq!(
+            Ok(vec![0xc2, 0x04, 0x00]),
+            assemble_ret(Some(operand("ret 4")))
+        );
+
+        let table = [("proc1".to_string(), 0x100)].into_iter().collect();
+        let symbols = SymbolTable::new(&table, false);
+        assert_eq!(
+            Ok(vec![0xe8, 0xfd, 0xff]),
+            assemble_call(&operand("call proc1"), 0x100, &symbols)
+        );
+        assert_eq!(
+            Ok(vec![0xe8, 0xfa, 0xff]),
+            assemble_call(&operand("call proc1"), 0x103, &symbols)
+        );
+    }
+
+    #[test]
+    fn test_stack_push_pop() {
+        let mut cpu = CpuContext::boot();
+        let mut memory = Memory::boot();
+        cpu.set_register16("ax", 0x1234);
+        cpu.set_register16("sp", 0x100);
+        handler_push(&mut cpu, &mut memory, operand("push ax")).unwrap();
+        assert_eq!(0xfe, cpu.get_register16("sp"));
+        assert_eq!(0x1234, memory.read16(0, 0xfe));
+        // push sp pushes SP after the decrement.
+        handler_push(&mut cpu, &mut memory, operand("push sp")).unwrap();
+        assert_eq!(0xfc, memory.read16(0, 0xfc));
+
+        handler_pop(&mut cpu, &mut memory, operand("pop bx")).unwrap();
+        assert_eq!(0xfc, cpu.get_register16("bx"));
+        handler_pop(&mut cpu, &mut memory, operand("pop ds")).unwrap();
+        assert_eq!(0x1234, cpu.get_register16("ds"));
+        assert_eq!(0x100, cpu.get_register16("sp"));
+
+        // SS:SP is the physical address.
+        cpu.set_register16("ss", 0x1000);
+        push(&mut cpu, &mut memory, 0xabcd);
+        assert_eq!(vec![0xcd, 0xab], memory.dump(0x100fe, 2));
+        assert_eq!(0xabcd, pop(&mut cpu, &memory));
+    }
+
+    #[test]
+    fn test_stack_watch_trace() {
+        let mut cpu = CpuContext::boot();
+        let mut memory = Memory::boot();
+        cpu.set_register16("ss", 0x1000);
+        cpu.set_register16("sp", 0x100);
+        memory.set_watches(vec![Watch {
+            id: 1,
+            start: 0x100ff,
+            len: 1,
+            access: Access::Write,
+        }]);
+        memory.begin_trace();
+        push(&mut cpu, &mut memory, 0xabcd);
+        assert_eq!(0xabcd, pop(&mut cpu, &memory));
+        // The watchpoint and the trace have the physical address of SS:SP.
+        assert_eq!(vec![(1, 0x100fe)], memory.take_watch_hits());
+        let trace: Vec<_> = memory
+            .end_trace()
+            .iter()
+            .map(|a| (a.access, a.address, a.value))
+            .collect();
+        assert_eq!(
+            vec![
+                (Access::Write, 0x100fe, 0xabcd),
+                (Access::Read, 0x100fe, 0xabcd)
+            ],
+            trace
+        );
+    }
+}
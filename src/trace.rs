@@ -0,0 +1,362 @@
+use crate::cpucontext::{CpuContext, REGISTERS16};
+use crate::memory::{Access, MemoryAccess};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+
+/*
+Execution trace of the instructions
+
+Each record has CS:IP, the bytes, the disassembly, the registers changed by the instruction,
+FLAGS after it and the memory accesses.
+The line mode records the source line and its machine code: a macro call is one record.
+The filter keeps the records of an IP range and of the mnemonics.
+The oldest records are dropped when the ring buffer is full.
+
+Text: one line per record
+e.g. 0000:0103 01 06 0D 01  add word ptr [value], ax  ip=0107 flags=0004 r[010D]=0002 w[010D]=0005
+JSON Lines: one object per record
+e.g. {"step":2,"cs":0,"ip":259,"bytes":"01060D01","text":"add word ptr [value], ax",
+      "changes":{"ip":[259,263]},"flags":4,"memory":[{"access":"read","address":269,"len":2,"value":2},...]}
+*/
+
+pub const DEFAULT_TRACE_CAPACITY: usize = 10000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    // Step of the journal after the instruction: 1 is the first step since the reboot
+    pub step: usize,
+    pub cs: u16,
+    pub ip: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    // Register name, value before and after the instruction
+    pub changes: Vec<(&'static str, u16, u16)>,
+    // FLAGS after the instruction
+    pub flags: u16,
+    pub memory: Vec<MemoryAccess>,
+}
+
+impl Record {
+    /// Registers changed from before to after
+    pub fn changes(before: &CpuContext, after: &CpuContext) -> Vec<(&'static str, u16, u16)> {
+        REGISTERS16
+            .iter()
+            .map(|reg| (*reg, before.get_register16(reg), after.get_register16(reg)))
+            .filter(|(_, old, new)| old != new)
+            .collect()
+    }
+
+    pub fn mnemonic(&self) -> String {
+        self.text
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase()
+    }
+
+    pub fn to_text(&self) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let mut s = format!(
+            "{:04X}:{:04X} {:<18} {:<30}",
+            self.cs,
+            self.ip,
+            bytes.join(" "),
+            self.text
+        );
+        for (reg, _, new) in self.changes.iter().filter(|(reg, _, _)| *reg != "flags") {
+            s.push_str(&format!(" {}={:04X}", reg, new));
+        }
+        s.push_str(&format!(" flags={:04X}", self.flags));
+        for m in self.memory.iter() {
+            let access = if m.access == Access::Read { 'r' } else { 'w' };
+            let width = m.len as usize * 2;
+            s.push_str(&format!(
+                " {}[{:04X}]={:0width$X}",
+                access,
+                m.address,
+                m.value,
+                width = width
+            ));
+        }
+        s.trim_end().to_string()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let bytes: String = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let mut changes = serde_json::Map::new();
+        for (reg, old, new) in self.changes.iter() {
+            changes.insert(reg.to_string(), json!([old, new]));
+        }
+        let memory: Vec<Value> = self
+            .memory
+            .iter()
+            .map(|m| {
+                json!({
+                    "access": m.access.name(),
+                    "address": m.address,
+                    "len": m.len,
+                    "value": m.value,
+                })
+            })
+            .collect();
+        json!({
+            "step": self.step,
+            "cs": self.cs,
+            "ip": self.ip,
+            "bytes": bytes,
+            "text": self.text,
+            "changes": changes,
+            "flags": self.flags,
+            "memory": memory,
+        })
+    }
+}
+
+/// Records only in the IP range and of the mnemonics: empty mnemonics are all
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    pub start: Option<u16>,
+    // The last IP of the range
+    pub end: Option<u16>,
+    pub mnemonics: Vec<String>,
+}
+
+impl Filter {
+    pub fn accepts(&self, record: &Record) -> bool {
+        self.start.is_none_or(|start| record.ip >= start)
+            && self.end.is_none_or(|end| record.ip <= end)
+            && (self.mnemonics.is_empty() || self.mnemonics.contains(&record.mnemonic()))
+    }
+
+    /// e.g. {"start": 256, "end": 511, "mnemonics": ["add", "mov"]}
+    pub fn to_json(&self) -> Value {
+        json!({ "start": self.start, "end": self.end, "mnemonics": self.mnemonics })
+    }
+}
+
+/// Filter in the request: {"start": 256, "end": 511, "mnemonics": ["add"]}
+/// null or a missing key is not filtered.
+pub fn request_filter(v: &Value) -> Result<Filter, String> {
+    let address = |key: &str| -> Result<Option<u16>, String> {
+        match &v[key] {
+            Value::Null => Ok(None),
+            n => n
+                .as_u64()
+                .and_then(|n| u16::try_from(n).ok())
+                .map(Some)
+                .ok_or_else(|| format!("{} should be a 16-bit number", key)),
+        }
+    };
+    let mnemonics = match &v["mnemonics"] {
+        Value::Null => Vec::new(),
+        Value::Array(list) => list
+            .iter()
+            .map(|m| {
+                m.as_str()
+                    .map(|m| m.to_ascii_lowercase())
+                    .ok_or_else(|| "mnemonics should be strings".to_string())
+            })
+            .collect::<Result<_, _>>()?,
+        _ => return Err("mnemonics should be a list".to_string()),
+    };
+    Ok(Filter {
+        start: address("start")?,
+        end: address("end")?,
+        mnemonics,
+    })
+}
+
+#[derive(Debug)]
+pub struct Tracer {
+    pub enabled: bool,
+    pub filter: Filter,
+    records: VecDeque<Record>,
+    capacity: usize,
+    // Number of the records dropped from the ring buffer
+    dropped: usize,
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Tracer {
+            enabled: false,
+            filter: Filter::default(),
+            records: VecDeque::new(),
+            capacity: DEFAULT_TRACE_CAPACITY,
+            dropped: 0,
+        }
+    }
+}
+
+impl Tracer {
+    /// The oldest records are dropped if the buffer has more records.
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<(), String> {
+        if capacity == 0 {
+            return Err("capacity should not be 0".to_string());
+        }
+        self.capacity = capacity;
+        while self.records.len() > capacity {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.dropped = 0;
+    }
+
+    /// Add the record accepted by the filter
+    pub fn push(&mut self, record: Record) {
+        if !self.filter.accepts(&record) {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.records.iter()
+    }
+
+    pub fn to_text(&self) -> String {
+        self.records().map(|r| r.to_text() + "\n").collect()
+    }
+
+    pub fn to_jsonl(&self) -> String {
+        self.records()
+            .map(|r| r.to_json().to_string() + "\n")
+            .collect()
+    }
+
+    /// Settings and the number of the records
+    pub fn to_json(&self) -> Value {
+        json!({
+            "enabled": self.enabled,
+            "capacity": self.capacity,
+            "filter": self.filter.to_json(),
+            "records": self.records.len(),
+            "dropped": self.dropped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn record(step: usize, ip: u16, text: &str) -> Record {
+        Record {
+            step,
+            cs: 0,
+            ip,
+            bytes: vec![0x40],
+            text: text.to_string(),
+            changes: vec![("ax", 0, 1), ("ip", ip, ip + 1)],
+            flags: 0,
+            memory: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_trace_record() {
+        let mut before = CpuContext::boot();
+        before.set_register16("ax", 1);
+        let mut after = CpuContext::boot();
+        after.set_register16("ax", 2);
+        after.set_register16("ip", 3);
+        assert_eq!(
+            vec![("ax", 1, 2), ("ip", 0, 3)],
+            Record::changes(&before, &after)
+        );
+
+        let mut r = record(2, 0x103, "ADD word ptr [value], ax");
+        r.bytes = vec![0x01, 0x06, 0x0d, 0x01];
+        r.changes = vec![("ip", 0x103, 0x107), ("flags", 0, 4)];
+        r.flags = 4;
+        r.memory = vec![
+            MemoryAccess {
+                access: Access::Read,
+                address: 0x10d,
+                len: 2,
+                value: 2,
+            },
+            MemoryAccess {
+                access: Access::Write,
+                address: 0x10d,
+                len: 1,
+                value: 5,
+            },
+        ];
+        assert_eq!("add", r.mnemonic());
+        assert_eq!(
+            "0000:0103 01 06 0D 01        ADD word ptr [value], ax       ip=0107 flags=0004 r[010D]=0002 w[010D]=05",
+            r.to_text()
+        );
+        assert_eq!(
+            json!({
+                "step": 2, "cs": 0, "ip": 0x103, "bytes": "01060D01", "text": "ADD word ptr [value], ax",
+                "changes": {"ip": [0x103, 0x107], "flags": [0, 4]}, "flags": 4,
+                "memory": [
+                    {"access": "read", "address": 0x10d, "len": 2, "value": 2},
+                    {"access": "write", "address": 0x10d, "len": 1, "value": 5},
+                ]
+            }),
+            r.to_json()
+        );
+    }
+
+    #[test]
+    fn test_request_filter() {
+        assert_eq!(Ok(Filter::default()), request_filter(&json!({})));
+        assert_eq!(
+            Ok(Filter {
+                start: Some(0x100),
+                end: None,
+                mnemonics: vec!["add".to_string()]
+            }),
+            request_filter(&json!({"start": 0x100, "mnemonics": ["ADD"]}))
+        );
+        assert!(request_filter(&json!({"end": 0x10000})).is_err());
+        assert!(request_filter(&json!({"mnemonics": "add"})).is_err());
+    }
+
+    #[test]
+    fn test_tracer() {
+        let mut tracer = Tracer::default();
+        tracer.set_capacity(2).unwrap();
+        assert!(tracer.set_capacity(0).is_err());
+        for (i, text) in ["inc ax", "mov bx, 1", "inc cx"].iter().enumerate() {
+            tracer.push(record(i + 1, 0x100 + i as u16, text));
+        }
+        let steps: Vec<usize> = tracer.records().map(|r| r.step).collect();
+        assert_eq!(vec![2, 3], steps);
+        assert_eq!(1, tracer.to_json()["dropped"]);
+        assert_eq!(2, tracer.to_jsonl().lines().count());
+
+        tracer.clear();
+        tracer.set_capacity(10).unwrap();
+        tracer.filter = Filter {
+            start: Some(0x101),
+            end: Some(0x102),
+            mnemonics: vec!["inc".to_string()],
+        };
+        for (i, text) in ["inc ax", "mov bx, 1", "inc cx", "inc dx"]
+            .iter()
+            .enumerate()
+        {
+            tracer.push(record(i + 1, 0x100 + i as u16, text));
+        }
+        let steps: Vec<usize> = tracer.records().map(|r| r.step).collect();
+        assert_eq!(vec![3], steps);
+        assert_eq!(
+            "0000:0102 40                 inc cx                         ax=0001 ip=0103 flags=0000\n",
+            tracer.to_text()
+        );
+    }
+}